@@ -0,0 +1,14 @@
+use balena_migrate::{common::MigErrorKind, list_devices};
+
+fn main() {
+    if let Err(error) = list_devices() {
+        match error.kind() {
+            MigErrorKind::Displayed => {
+                println!("balena-list-devices failed with an error, see messages above");
+            }
+            _ => {
+                println!("balena-list-devices failed with an error: {}", error);
+            }
+        }
+    }
+}
@@ -1,12 +1,14 @@
 //pub mod mig_error;
 use failure::ResultExt;
-use log::debug;
 use log::trace;
 use regex::Regex;
-use std::fs::{metadata, read_to_string, File};
-use std::io::{copy, BufRead, BufReader, Read};
+use std::fs::{metadata, File};
+use std::io::{copy, BufRead, BufReader, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus, Stdio};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::mpsc::{sync_channel, RecvTimeoutError};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
 
 use crate::defs::BALENA_FILE_TAG_REGEX;
 
@@ -16,15 +18,19 @@ pub(crate) mod mig_error;
 pub(crate) mod os_release;
 
 pub(crate) mod os_api;
+pub(crate) mod os_policy;
 
 pub(crate) mod boot_manager;
 pub(crate) mod device;
+pub(crate) mod device_catalog;
 
 pub(crate) mod device_info;
 pub(crate) mod path_info;
 
 pub(crate) mod file_digest;
 
+pub(crate) mod bmap;
+
 pub(crate) mod disk_util;
 
 pub(crate) mod backup;
@@ -34,6 +40,7 @@ pub(crate) mod migrate_info;
 pub(crate) mod config;
 // pub(crate) mod config_helper;
 pub(crate) mod file_info;
+pub(crate) mod file_type;
 
 pub(crate) mod stage2_config;
 
@@ -55,6 +62,14 @@ pub(crate) struct CmdRes {
     pub status: ExitStatus,
 }
 
+#[derive(Debug)]
+pub(crate) struct CopyRes {
+    // the number of bytes copied from stdout
+    pub size: u64,
+    pub stderr: String,
+    pub status: ExitStatus,
+}
+
 pub(crate) fn path_append<P1: AsRef<Path>, P2: AsRef<Path>>(base: P1, append: P2) -> PathBuf {
     let base = base.as_ref();
     let append = append.as_ref();
@@ -106,35 +121,6 @@ pub(crate) fn is_balena_file<P: AsRef<Path>>(file_name: P) -> Result<bool, MigEr
     }
 }
 
-pub(crate) fn parse_file<P: AsRef<Path>>(
-    fname: P,
-    regex: &Regex,
-) -> Result<Option<Vec<String>>, MigError> {
-    let path = fname.as_ref();
-    let os_info = read_to_string(path).context(MigErrCtx::from_remark(
-        MigErrorKind::Upstream,
-        &format!("File read '{}'", path.display()),
-    ))?;
-
-    for line in os_info.lines() {
-        debug!("parse_file: line: '{}'", line);
-
-        if let Some(ref captures) = regex.captures(line) {
-            let mut results: Vec<String> = Vec::new();
-            for cap in captures.iter() {
-                if let Some(cap) = cap {
-                    results.push(String::from(cap.as_str()));
-                } else {
-                    results.push(String::from(""));
-                }
-            }
-            return Ok(Some(results));
-        };
-    }
-
-    Ok(None)
-}
-
 pub fn dir_exists<P: AsRef<Path>>(name: P) -> Result<bool, MigError> {
     let path = name.as_ref();
     if path.exists() {
@@ -247,9 +233,218 @@ pub(crate) fn call(cmd: &str, args: &[&str], trim_stdout: bool) -> Result<CmdRes
     })
 }
 
+// read a pipe of a child process in a separate thread
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    spawn(move || {
+        let mut buffer: Vec<u8> = Vec::new();
+        if let Some(mut pipe) = pipe {
+            if let Err(why) = pipe.read_to_end(&mut buffer) {
+                trace!("read_pipe: failed to read from pipe, error: {:?}", why);
+            }
+        }
+        buffer
+    })
+}
+
+// run a command and kill it if it has not terminated after timeout seconds, the output is
+// collected in memory
+pub(crate) fn output_timeout(cmd: &str, args: &[&str], timeout: u64) -> Result<Output, MigError> {
+    trace!(
+        "output_timeout: '{}' called with {:?}, {}",
+        cmd,
+        args,
+        timeout
+    );
+
+    let mut child = Command::new(cmd)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "output_timeout: failed to execute: command {} '{:?}'",
+                cmd, args
+            ),
+        ))?;
+
+    let stdout_reader = read_pipe(child.stdout.take());
+    let stderr_reader = read_pipe(child.stderr.take());
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "output_timeout: failed to wait for command {} '{:?}'",
+                cmd, args
+            ),
+        ))? {
+            break status;
+        }
+
+        if start.elapsed() > Duration::from_secs(timeout) {
+            let _res = child.kill();
+            let _res = child.wait();
+            return Err(MigError::from_remark(
+                MigErrorKind::Timeout,
+                &format!(
+                    "output_timeout: command {} '{:?}' did not terminate within {} seconds",
+                    cmd, args, timeout
+                ),
+            ));
+        }
+        sleep(Duration::from_millis(100));
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout_reader.join().unwrap_or_else(|_| Vec::new()),
+        stderr: stderr_reader.join().unwrap_or_else(|_| Vec::new()),
+    })
+}
+
+// size and number of stdout chunks buffered by copy_timeout
+const COPY_CHUNK_SIZE: usize = 0x10000;
+const COPY_CHUNKS: usize = 4;
+
+// run a command and kill it if it has not terminated after timeout seconds, stdout is copied to
+// output while the command runs, so only a few chunks of it are held in memory
+pub(crate) fn copy_timeout<W: Write>(
+    cmd: &str,
+    args: &[&str],
+    timeout: u64,
+    output: &mut W,
+) -> Result<CopyRes, MigError> {
+    trace!(
+        "copy_timeout: '{}' called with {:?}, {}",
+        cmd,
+        args,
+        timeout
+    );
+
+    let mut child = Command::new(cmd)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "copy_timeout: failed to execute: command {} '{:?}'",
+                cmd, args
+            ),
+        ))?;
+
+    let stderr_reader = read_pipe(child.stderr.take());
+
+    // stdout is read in a separate thread, the channel is closed when stdout is closed
+    let (sender, receiver) = sync_channel::<Vec<u8>>(COPY_CHUNKS);
+    let stdout = child.stdout.take();
+    spawn(move || {
+        if let Some(mut pipe) = stdout {
+            loop {
+                let mut chunk = vec![0; COPY_CHUNK_SIZE];
+                match pipe.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(bytes_read) => {
+                        chunk.truncate(bytes_read);
+                        if sender.send(chunk).is_err() {
+                            break;
+                        }
+                    }
+                    Err(ref why) if why.kind() == ErrorKind::Interrupted => continue,
+                    Err(why) => {
+                        trace!("copy_timeout: failed to read from pipe, error: {:?}", why);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let mut size: u64 = 0;
+    let mut stdout_open = true;
+    let status = loop {
+        if stdout_open {
+            match receiver.recv_timeout(Duration::from_millis(100)) {
+                Ok(chunk) => {
+                    if let Err(why) = output.write_all(&chunk) {
+                        let _res = child.kill();
+                        let _res = child.wait();
+                        return Err(MigError::from_remark(
+                            MigErrorKind::Upstream,
+                            &format!(
+                                "copy_timeout: failed to write output of command {} '{:?}', error: {}",
+                                cmd, args, why
+                            ),
+                        ));
+                    }
+                    size += chunk.len() as u64;
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => stdout_open = false,
+            }
+        } else if let Some(status) = child.try_wait().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "copy_timeout: failed to wait for command {} '{:?}'",
+                cmd, args
+            ),
+        ))? {
+            break status;
+        } else {
+            sleep(Duration::from_millis(100));
+        }
+
+        if start.elapsed() > Duration::from_secs(timeout) {
+            let _res = child.kill();
+            let _res = child.wait();
+            return Err(MigError::from_remark(
+                MigErrorKind::Timeout,
+                &format!(
+                    "copy_timeout: command {} '{:?}' did not terminate within {} seconds",
+                    cmd, args, timeout
+                ),
+            ));
+        }
+    };
+
+    Ok(CopyRes {
+        size,
+        stderr: String::from(String::from_utf8_lossy(
+            &stderr_reader.join().unwrap_or_else(|_| Vec::new()),
+        )),
+        status,
+    })
+}
+
+// call a command and kill it if it has not terminated after timeout seconds
+pub(crate) fn call_timeout(
+    cmd: &str,
+    args: &[&str],
+    trim_stdout: bool,
+    timeout: u64,
+) -> Result<CmdRes, MigError> {
+    let output = output_timeout(cmd, args, timeout)?;
+
+    Ok(CmdRes {
+        stdout: if trim_stdout {
+            String::from(String::from_utf8_lossy(&output.stdout).trim())
+        } else {
+            String::from(String::from_utf8_lossy(&output.stdout))
+        },
+        stderr: String::from(String::from_utf8_lossy(&output.stderr)),
+        status: output.status,
+    })
+}
+
 pub fn check_tcp_connect(host: &str, port: u16, timeout: u64) -> Result<(), MigError> {
     use std::net::{Shutdown, TcpStream, ToSocketAddrs};
-    use std::time::Duration;
     let url = format!("{}:{}", host, port);
     let mut addrs_iter = url.to_socket_addrs().context(MigErrCtx::from_remark(
         MigErrorKind::Upstream,
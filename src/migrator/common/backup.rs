@@ -2,29 +2,349 @@ use failure::{Fail, ResultExt};
 use flate2::{write::GzEncoder, Compression};
 use log::{debug, error, info, trace, warn};
 use regex::Regex;
-use std::fs::{create_dir_all, read_dir, remove_dir_all, File};
+use std::fs::{
+    create_dir_all, metadata, read_dir, remove_dir_all, remove_file, set_permissions, write, File,
+    OpenOptions,
+};
+use std::io::{copy, sink, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use tar::Builder;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tar::{Builder, Header};
 
 #[cfg(target_os = "linux")]
-use std::os::unix::fs::symlink;
+use std::os::unix::fs::{symlink, PermissionsExt};
 
 use crate::common::{
-    call, config::migrate_config::VolumeConfig, dir_exists, path_append, MigErrCtx, MigError,
-    MigErrorKind,
+    call,
+    config::migrate_config::{CommandConfig, ItemFilter, VolumeConfig},
+    copy_timeout, dir_exists,
+    file_digest::DigestReader,
+    file_exists, format_size_with_unit, path_append, MigErrCtx, MigError, MigErrorKind,
 };
-use crate::defs::BACKUP_FILE;
 use crate::linux::linux_defs::{MKTEMP_CMD, TAR_CMD};
 
+mod quiesce;
+use quiesce::Quiesced;
+
+mod manifest;
+pub(crate) use manifest::verify_backup;
+use manifest::{Manifest, BACKUP_MANIFEST};
+
 // Recurse through directories
 
+const REGEX_PREFIX: &str = "regex:";
+// number of included and excluded entries listed in the backup sample
+const SAMPLE_SIZE: usize = 20;
+// mode of archive entries created from data
+const DATA_MODE: u32 = 0o644;
+
 trait Archiver {
     fn add_file(&mut self, target: &Path, source: &Path) -> Result<(), MigError>;
+    // archive the output of a command
+    fn add_output(&mut self, target: &Path, command: &CommandConfig) -> Result<(), MigError>;
+    // run a command and archive its output or the file it created
+    fn add_command(&mut self, target: &Path, command: &CommandConfig) -> Result<(), MigError> {
+        if let Some(ref output) = command.output {
+            run_command(command, &mut sink())?;
+            if !file_exists(output) {
+                error!(
+                    "The backup command '{}' did not create '{}'",
+                    command.cmd,
+                    output.display()
+                );
+                return Err(MigError::displayed());
+            }
+            self.add_file(target, output)
+        } else {
+            self.add_output(target, command)
+        }
+    }
+    // a file or directory excluded by the item filter
+    fn exclude(&mut self, source: &Path, reason: &str) {
+        debug!("excluded source: '{}', {}", source.display(), reason);
+    }
     fn finish(&mut self) -> Result<(), MigError>;
 }
 
+// run a backup command writing its output to output, returns the size of the output
+fn run_command<W: Write>(command: &CommandConfig, output: &mut W) -> Result<u64, MigError> {
+    info!(
+        "Running backup command '{}' with arguments {:?}",
+        command.cmd, command.args
+    );
+    let args: Vec<&str> = command.args.iter().map(|arg| arg.as_str()).collect();
+    let copy_res = copy_timeout(&command.cmd, &args, command.get_timeout(), output).context(
+        MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to run backup command '{}'", command.cmd),
+        ),
+    )?;
+
+    match copy_res.status.code() {
+        Some(code) if command.get_exit_codes().contains(&code) => {
+            debug!(
+                "run_command: '{}' returned {}, {} bytes of output",
+                command.cmd, code, copy_res.size
+            );
+            Ok(copy_res.size)
+        }
+        _ => {
+            error!(
+                "The backup command '{}' failed with {}, stderr: '{}'",
+                command.cmd,
+                copy_res.status,
+                copy_res.stderr.trim()
+            );
+            Err(MigError::displayed())
+        }
+    }
+}
+
+fn new_regex(regex: &str) -> Result<Regex, MigError> {
+    Ok(Regex::new(regex).context(MigErrCtx::from_remark(
+        MigErrorKind::InvParam,
+        &format!(
+            "Failed to create regular expression from filter '{}'",
+            regex
+        ),
+    ))?)
+}
+
+// translate a glob to an anchored regular expression, '*' and '?' do not match '/', '**' does
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => {
+                if chars.peek() == Some(&'*') {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        regex.push_str("(.*/)?");
+                    } else {
+                        regex.push_str(".*");
+                    }
+                } else {
+                    regex.push_str("[^/]*");
+                }
+            }
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                for c in &mut chars {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                regex.push(']');
+            }
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[derive(Debug)]
+enum Pattern {
+    // matched against the path relative to the item source or against the file name
+    Glob { regex: Regex, on_path: bool },
+    // matched against the full source path
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Result<Pattern, MigError> {
+        if let Some(regex) = pattern.strip_prefix(REGEX_PREFIX) {
+            Ok(Pattern::Regex(new_regex(regex)?))
+        } else {
+            // a leading '/' anchors the glob to the item source, a trailing '/' is ignored
+            Ok(Pattern::Glob {
+                regex: new_regex(&glob_to_regex(pattern.trim_matches('/')))?,
+                on_path: pattern.trim_end_matches('/').contains('/'),
+            })
+        }
+    }
+
+    fn is_match(&self, source: &Path, rel_path: &Path) -> bool {
+        match self {
+            Pattern::Glob { regex, on_path } => {
+                if *on_path {
+                    regex.is_match(&rel_path.to_string_lossy())
+                } else if let Some(file_name) = rel_path.file_name() {
+                    regex.is_match(&file_name.to_string_lossy())
+                } else {
+                    false
+                }
+            }
+            Pattern::Regex(regex) => regex.is_match(&source.to_string_lossy()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BackupFilter {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+    max_size: Option<u64>,
+    hidden: bool,
+}
+
+impl BackupFilter {
+    fn from_config(filter: &Option<ItemFilter>) -> Result<BackupFilter, MigError> {
+        let mut backup_filter = BackupFilter {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_size: None,
+            hidden: true,
+        };
+
+        match filter {
+            Some(ItemFilter::Regex(regex)) => {
+                backup_filter.allow.push(Pattern::Regex(new_regex(regex)?))
+            }
+            Some(ItemFilter::Sets(sets)) => {
+                for pattern in &sets.allow {
+                    backup_filter.allow.push(Pattern::new(pattern)?);
+                }
+                for pattern in &sets.deny {
+                    backup_filter.deny.push(Pattern::new(pattern)?);
+                }
+                backup_filter.max_size = sets.max_size;
+                backup_filter.hidden = sets.is_hidden();
+            }
+            None => (),
+        }
+
+        Ok(backup_filter)
+    }
+
+    fn is_hidden(rel_path: &Path) -> bool {
+        if let Some(file_name) = rel_path.file_name() {
+            file_name.to_string_lossy().starts_with('.')
+        } else {
+            false
+        }
+    }
+
+    // the reason to exclude a directory, None if it is to be descended into
+    fn exclude_dir(&self, source: &Path, rel_path: &Path) -> Option<String> {
+        if !self.hidden && BackupFilter::is_hidden(rel_path) {
+            Some(String::from("hidden directory"))
+        } else if self.deny.iter().any(|deny| deny.is_match(source, rel_path)) {
+            Some(String::from("denied directory"))
+        } else {
+            None
+        }
+    }
+
+    // the reason to exclude a file, None if it is to be backed up
+    fn exclude_file(&self, source: &Path, rel_path: &Path, size: u64) -> Option<String> {
+        if !self.hidden && BackupFilter::is_hidden(rel_path) {
+            Some(String::from("hidden file"))
+        } else if self.deny.iter().any(|deny| deny.is_match(source, rel_path)) {
+            Some(String::from("denied file"))
+        } else if !self.allow.is_empty()
+            && !self
+                .allow
+                .iter()
+                .any(|allow| allow.is_match(source, rel_path))
+        {
+            Some(String::from("no allow pattern matches"))
+        } else {
+            match self.max_size {
+                Some(max_size) if size > max_size => Some(format!(
+                    "size {} exceeds {}",
+                    format_size_with_unit(size),
+                    format_size_with_unit(max_size)
+                )),
+                _ => None,
+            }
+        }
+    }
+}
+
+// lists what would be backed up, used in pretend mode
+struct SampleArchiver {
+    included: Vec<(PathBuf, String)>,
+    excluded: Vec<(PathBuf, String)>,
+}
+
+impl Archiver for SampleArchiver {
+    fn add_file(&mut self, target: &Path, source: &Path) -> Result<(), MigError> {
+        self.included
+            .push((PathBuf::from(target), format!("'{}'", source.display())));
+        Ok(())
+    }
+
+    fn add_output(&mut self, target: &Path, command: &CommandConfig) -> Result<(), MigError> {
+        self.included.push((
+            PathBuf::from(target),
+            format!("output of command '{}'", command.cmd),
+        ));
+        Ok(())
+    }
+
+    // commands are not run in pretend mode
+    fn add_command(&mut self, target: &Path, command: &CommandConfig) -> Result<(), MigError> {
+        self.add_output(target, command)
+    }
+
+    fn exclude(&mut self, source: &Path, reason: &str) {
+        self.excluded
+            .push((PathBuf::from(source), String::from(reason)));
+    }
+
+    fn finish(&mut self) -> Result<(), MigError> {
+        info!(
+            "Backup sample: {} files included, {} files or directories excluded",
+            self.included.len(),
+            self.excluded.len()
+        );
+        for (target, source) in self.included.iter().take(SAMPLE_SIZE) {
+            info!("  included: {} as '{}'", source, target.display());
+        }
+        if self.included.len() > SAMPLE_SIZE {
+            info!("  ... {} more included", self.included.len() - SAMPLE_SIZE);
+        }
+        for (source, reason) in self.excluded.iter().take(SAMPLE_SIZE) {
+            info!("  excluded: '{}', {}", source.display(), reason);
+        }
+        if self.excluded.len() > SAMPLE_SIZE {
+            info!("  ... {} more excluded", self.excluded.len() - SAMPLE_SIZE);
+        }
+        Ok(())
+    }
+}
+
+// tar header for data that is not read from a file
+fn data_header(size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(DATA_MODE);
+    header.set_mtime(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0),
+    );
+    header
+}
+
 pub struct RustTarArchiver {
     archive: Builder<GzEncoder<File>>,
+    manifest: Manifest,
+    // temporary file next to the archive receiving command output
+    output_path: PathBuf,
 }
 
 impl RustTarArchiver {
@@ -40,26 +360,134 @@ impl RustTarArchiver {
                 ))?,
                 Compression::default(),
             )),
+            manifest: Manifest::new(),
+            output_path: PathBuf::from(format!("{}.output", file.as_ref().display())),
         })
     }
+
+    // append data, returns its size and digest
+    fn append<R: Read>(
+        &mut self,
+        header: &mut Header,
+        target: &Path,
+        data: R,
+    ) -> Result<(u64, String), MigError> {
+        let mut reader = DigestReader::new(data);
+        self.archive
+            .append_data(header, target, &mut reader)
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to append to archive path: '{}'", target.display()),
+            ))?;
+        Ok((reader.get_size(), reader.get_digest()))
+    }
+
+    // run a command writing its output to output_path, then archive the file
+    fn append_output(&mut self, target: &Path, command: &CommandConfig) -> Result<(), MigError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.output_path)
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to create '{}'", self.output_path.display()),
+            ))?;
+
+        let size = run_command(command, &mut file)?;
+        file.seek(SeekFrom::Start(0))
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to read '{}'", self.output_path.display()),
+            ))?;
+
+        let mut header = data_header(size);
+        let (arch_size, sha256) = self.append(&mut header, target, file.take(size))?;
+        if arch_size != size {
+            error!(
+                "The output of the backup command '{}' was truncated in '{}'",
+                command.cmd,
+                self.output_path.display()
+            );
+            return Err(MigError::displayed());
+        }
+
+        self.manifest.add_entry(target, size, DATA_MODE, sha256);
+        Ok(())
+    }
 }
 
 impl Archiver for RustTarArchiver {
+    // regular files are digested while they are archived
     fn add_file(&mut self, target: &Path, source: &Path) -> Result<(), MigError> {
-        Ok(self
-            .archive
-            .append_path_with_name(&source, &target)
-            .context(MigErrCtx::from_remark(
-                MigErrorKind::Upstream,
-                &format!(
-                    "Failed to append file: '{}' to archive path: '{}'",
-                    source.display(),
-                    target.display()
-                ),
-            ))?)
+        let metadata = source.metadata().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "Failed to retrieve metadata for file: '{}'",
+                source.display()
+            ),
+        ))?;
+
+        let mut header = Header::new_gnu();
+        header.set_metadata(&metadata);
+        let mode = header.mode().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to retrieve mode of file: '{}'", source.display()),
+        ))?;
+
+        if !metadata.is_file() {
+            // special files are archived as they are, without a digest
+            self.archive
+                .append_path_with_name(source, target)
+                .context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "Failed to append file: '{}' to archive path: '{}'",
+                        source.display(),
+                        target.display()
+                    ),
+                ))?;
+            self.manifest.add_other(target, mode);
+            return Ok(());
+        }
+
+        let file = File::open(source).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to open file: '{}'", source.display()),
+        ))?;
+
+        let (size, sha256) = self.append(&mut header, target, file.take(metadata.len()))?;
+        if size != metadata.len() {
+            error!(
+                "The file '{}' was modified while it was archived",
+                source.display()
+            );
+            return Err(MigError::displayed());
+        }
+
+        self.manifest.add_entry(target, size, mode, sha256);
+        Ok(())
+    }
+
+    // the size has to be known for the tar header, so the output is written to a file first
+    fn add_output(&mut self, target: &Path, command: &CommandConfig) -> Result<(), MigError> {
+        let res = self.append_output(target, command);
+        if let Err(why) = remove_file(&self.output_path) {
+            warn!(
+                "Failed to delete temporary file '{}' error: {:?}",
+                self.output_path.display(),
+                why
+            );
+        }
+        res
     }
 
     fn finish(&mut self) -> Result<(), MigError> {
+        let manifest = self.manifest.to_yaml()?;
+        let mut header = data_header(manifest.len() as u64);
+        self.append(&mut header, Path::new(BACKUP_MANIFEST), manifest.as_bytes())?;
+
         Ok(self.archive.finish().context(MigErrCtx::from_remark(
             MigErrorKind::Upstream,
             "Failed to create backup archive",
@@ -91,16 +519,23 @@ impl ExtTarArchiver {
             archive: PathBuf::from(file.as_ref()),
         })
     }
-}
 
-#[cfg(target_os = "linux")]
-impl Archiver for ExtTarArchiver {
-    fn add_file(&mut self, target: &Path, source: &Path) -> Result<(), MigError> {
-        debug!(
-            "ExtTarArchiver::add_file: '{}' , '{}'",
-            target.display(),
-            source.display()
-        );
+    // files are read by tar after they have been digested, so the manifest is written as late as
+    // possible
+    fn write_manifest(&self) -> Result<(), MigError> {
+        let mut manifest = Manifest::new();
+        add_dir_entries(&mut manifest, &self.tmp_dir, Path::new(""))?;
+
+        let manifest_path = path_append(&self.tmp_dir, BACKUP_MANIFEST);
+        write(&manifest_path, manifest.to_yaml()?).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to write '{}'", manifest_path.display()),
+        ))?;
+        Ok(())
+    }
+
+    // create the parent directory of target in the temporary directory
+    fn create_parent_dir(&self, target: &Path) -> Result<(), MigError> {
         if let Some(parent_dir) = target.parent() {
             let parent_dir = path_append(&self.tmp_dir, parent_dir);
             if !dir_exists(&parent_dir).context(MigErrCtx::from_remark(
@@ -108,7 +543,7 @@ impl Archiver for ExtTarArchiver {
                 &format!("Failed to access directory '{}'", parent_dir.display()),
             ))? {
                 debug!(
-                    "ExtTarArchiver::add_file: create directory '{}'",
+                    "ExtTarArchiver::create_parent_dir: create directory '{}'",
                     parent_dir.display()
                 );
                 create_dir_all(&parent_dir).context(MigErrCtx::from_remark(
@@ -117,8 +552,78 @@ impl Archiver for ExtTarArchiver {
                 ))?;
             }
         }
+        Ok(())
+    }
+}
+
+// add the entries below dir to the manifest the way tar -h archives them, following symbolic links
+#[cfg(target_os = "linux")]
+fn add_dir_entries(manifest: &mut Manifest, dir: &Path, target_dir: &Path) -> Result<(), MigError> {
+    let mut sources: Vec<PathBuf> = Vec::new();
+    for entry in read_dir(dir).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to list directory '{}'", dir.display()),
+    ))? {
+        sources.push(
+            entry
+                .context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!("Failed to read entry of directory '{}'", dir.display()),
+                ))?
+                .path(),
+        );
+    }
+    sources.sort();
+
+    for source in sources {
+        let target = if let Some(file_name) = source.file_name() {
+            target_dir.join(file_name)
+        } else {
+            continue;
+        };
+
+        let metadata = metadata(&source).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "Failed to retrieve metadata for file: '{}'",
+                source.display()
+            ),
+        ))?;
+        let mode = metadata.permissions().mode() & 0o7777;
+
+        if metadata.is_dir() {
+            manifest.add_other(&target, mode);
+            add_dir_entries(manifest, &source, &target)?;
+        } else if metadata.is_file() {
+            let file = File::open(&source).context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to open file: '{}'", source.display()),
+            ))?;
+            let mut reader = DigestReader::new(file);
+            copy(&mut reader, &mut sink()).context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to read file: '{}'", source.display()),
+            ))?;
+            let size = reader.get_size();
+            manifest.add_entry(&target, size, mode, reader.get_digest());
+        } else {
+            manifest.add_other(&target, mode);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+impl Archiver for ExtTarArchiver {
+    fn add_file(&mut self, target: &Path, source: &Path) -> Result<(), MigError> {
+        debug!(
+            "ExtTarArchiver::add_file: '{}' , '{}'",
+            target.display(),
+            source.display()
+        );
+        self.create_parent_dir(target)?;
 
-        let lnk_target = path_append(&self.tmp_dir, &target);
+        let lnk_target = path_append(&self.tmp_dir, target);
 
         debug!(
             "ExtTarArchiver::add_file: link '{}' to '{}'",
@@ -137,13 +642,43 @@ impl Archiver for ExtTarArchiver {
         Ok(())
     }
 
+    // the output is written to the temporary directory, so it is not held in memory
+    fn add_output(&mut self, target: &Path, command: &CommandConfig) -> Result<(), MigError> {
+        debug!(
+            "ExtTarArchiver::add_output: '{}' , command '{}'",
+            target.display(),
+            command.cmd
+        );
+        self.create_parent_dir(target)?;
+
+        let file_target = path_append(&self.tmp_dir, target);
+        let mut file = File::create(&file_target).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to create '{}'", file_target.display()),
+        ))?;
+        run_command(command, &mut file)?;
+        file.sync_all().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to write '{}'", file_target.display()),
+        ))?;
+        set_permissions(&file_target, PermissionsExt::from_mode(DATA_MODE)).context(
+            MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to set permissions of '{}'", file_target.display()),
+            ),
+        )?;
+        Ok(())
+    }
+
     fn finish(&mut self) -> Result<(), MigError> {
+        self.write_manifest()?;
+
         let cmd_res = call(
             TAR_CMD,
             &[
                 "-h",
                 "-czf",
-                BACKUP_FILE,
+                &*self.archive.to_string_lossy(),
                 "-C",
                 &*self.tmp_dir.to_string_lossy(),
                 ".",
@@ -180,10 +715,11 @@ impl Archiver for ExtTarArchiver {
 }
 
 fn archive_dir<'a>(
+    source_root: &Path,
     dir_path: &Path,
     target_path: &Path,
     archiver: &'a mut impl Archiver,
-    filter: &Option<Regex>,
+    filter: &BackupFilter,
 ) -> Result<bool, MigError> {
     trace!(
         "archive_dir: dir_path: '{}', target_path: '{}' filter: {:?}",
@@ -204,6 +740,9 @@ fn archive_dir<'a>(
             Ok(dir_entry) => {
                 let source_path = dir_entry.path();
                 let source_file = source_path.file_name().unwrap();
+                let rel_path = source_path
+                    .strip_prefix(source_root)
+                    .unwrap_or(&source_path);
                 debug!("processing source: '{}'", source_path.display());
                 let metadata = dir_entry.metadata().context(MigErrCtx::from_remark(
                     MigErrorKind::Upstream,
@@ -214,36 +753,21 @@ fn archive_dir<'a>(
                 ))?;
 
                 if metadata.is_dir() {
-                    if archive_dir(
+                    if let Some(reason) = filter.exclude_dir(&source_path, rel_path) {
+                        archiver.exclude(&source_path, &reason);
+                    } else if archive_dir(
+                        source_root,
                         &source_path,
                         &path_append(&target_path, &source_file),
                         archiver,
-                        &filter,
+                        filter,
                     )? {
                         written = true;
                     }
-                } else if let Some(filter) = filter {
-                    if filter.is_match(&source_path.to_string_lossy()) {
-                        let target = path_append(target_path, &source_file);
-                        archiver
-                            .add_file(target.as_path(), source_path.as_path())
-                            .context(MigErrCtx::from_remark(
-                                MigErrorKind::Upstream,
-                                &format!(
-                                    "Failed to append file: '{}' to archive path: '{}'",
-                                    source_path.display(),
-                                    target.display()
-                                ),
-                            ))?;
-                        written = true;
-                        debug!(
-                            "appended source: '{}'  to archive as '{}'",
-                            source_path.display(),
-                            target.display()
-                        );
-                    } else {
-                        debug!("No match on file: '{}'", &source_path.display());
-                    }
+                } else if let Some(reason) =
+                    filter.exclude_file(&source_path, rel_path, metadata.len())
+                {
+                    archiver.exclude(&source_path, &reason);
                 } else {
                     let target = path_append(target_path, &source_file);
                     archiver
@@ -281,7 +805,11 @@ pub(crate) fn create_ext(file: &Path, config: &[VolumeConfig]) -> Result<bool, M
     if !config.is_empty() {
         debug!("creating new backup in '{}", file.display());
         let mut archiver = ExtTarArchiver::new(file)?;
-        create_int(&mut archiver, config)
+        // files are read when the archive is finished
+        let mut quiesced = Quiesced::stop(config)?;
+        let written = create_int(&mut archiver, config)?;
+        quiesced.resume();
+        Ok(written)
     } else {
         info!("The backup configuration was empty - nothing backed up");
         Ok(false)
@@ -292,19 +820,35 @@ pub(crate) fn create(file: &Path, config: &[VolumeConfig]) -> Result<bool, MigEr
     if !config.is_empty() {
         debug!("creating new backup in '{}", file.display());
         let mut archiver = RustTarArchiver::new(file)?;
-        create_int(&mut archiver, config)
+        let mut quiesced = Quiesced::stop(config)?;
+        let written = create_int(&mut archiver, config)?;
+        quiesced.resume();
+        Ok(written)
     } else {
         info!("The backup configuration was empty - nothing backed up");
         Ok(false)
     }
 }
 
+// list included and excluded files without creating a backup
+pub(crate) fn sample(config: &[VolumeConfig]) -> Result<bool, MigError> {
+    if !config.is_empty() {
+        let mut archiver = SampleArchiver {
+            included: Vec::new(),
+            excluded: Vec::new(),
+        };
+        Quiesced::list(config);
+        create_int(&mut archiver, config)
+    } else {
+        info!("The backup configuration was empty - nothing to back up");
+        Ok(false)
+    }
+}
+
 fn create_int<'a>(
     archiver: &'a mut impl Archiver,
     config: &[VolumeConfig],
 ) -> Result<bool, MigError> {
-    // TODO: stop selected services, containers, add this to backup config
-
     trace!("create_int entered with: {:?}", config);
 
     let mut written = false;
@@ -313,13 +857,43 @@ fn create_int<'a>(
         info!("backup to volume: '{}'", volume.volume);
 
         for item in &volume.items {
-            let item_src =
-                PathBuf::from(&item.source)
-                    .canonicalize()
-                    .context(MigErrCtx::from_remark(
-                        MigErrorKind::Upstream,
-                        &format!("Failed to process source '{}'", item.source),
-                    ))?;
+            let source = match (&item.source, &item.command) {
+                (Some(source), None) => source,
+                (None, Some(command)) => {
+                    let target = if let Some(ref target) = item.target {
+                        path_append(PathBuf::from(&volume.volume), target)
+                    } else {
+                        error!(
+                            "The backup command '{}' in volume '{}' requires a target",
+                            command.cmd, volume.volume
+                        );
+                        return Err(MigError::displayed());
+                    };
+
+                    archiver.add_command(&target, command)?;
+                    written = true;
+                    debug!(
+                        "appended output of command '{}' to archive as '{}'",
+                        command.cmd,
+                        target.display()
+                    );
+                    continue;
+                }
+                _ => {
+                    error!(
+                        "A backup item in volume '{}' requires either a source or a command",
+                        volume.volume
+                    );
+                    return Err(MigError::displayed());
+                }
+            };
+
+            let item_src = PathBuf::from(source)
+                .canonicalize()
+                .context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!("Failed to process source '{}'", source),
+                ))?;
             debug!("processing item: source. '{}'", item_src.display());
 
             if let Ok(metadata) = item_src.metadata() {
@@ -331,19 +905,9 @@ fn create_int<'a>(
                     };
 
                     debug!("source: '{}' is a directory", item_src.display());
-                    let filter = if let Some(ref filter) = item.filter {
-                        Some(Regex::new(filter).context(MigErrCtx::from_remark(
-                            MigErrorKind::Upstream,
-                            &format!(
-                                "Failed to create regular expression from filter '{}'",
-                                filter
-                            ),
-                        ))?)
-                    } else {
-                        None
-                    };
+                    let filter = BackupFilter::from_config(&item.filter)?;
 
-                    if archive_dir(&item_src, &target_path, archiver, &filter)? {
+                    if archive_dir(&item_src, &item_src, &target_path, archiver, &filter)? {
                         written = true;
                     }
                 } else {
@@ -378,7 +942,7 @@ fn create_int<'a>(
             } else {
                 return Err(MigError::from_remark(
                     MigErrorKind::NotFound,
-                    &format!("Missing source for backup: '{}'", item.source),
+                    &format!("Missing source for backup: '{}'", source),
                 ));
             }
         }
@@ -392,3 +956,200 @@ fn create_int<'a>(
     debug!("create_int: returning {}", written);
     Ok(written)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_backup() {
+        assert_eq!(glob_to_regex("*.log"), "^[^/]*\\.log$");
+        assert_eq!(
+            glob_to_regex("**/cache/?[!0-9]"),
+            "^(.*/)?cache/[^/][^0-9]$"
+        );
+
+        let sets = r##"
+allow:
+  - '*.db'
+  - 'config/**'
+deny:
+  - node_modules
+  - '/data/tmp'
+  - 'regex:\.bak\.db$'
+max_size: 1000
+hidden: false
+"##;
+        let filter =
+            BackupFilter::from_config(&Some(ItemFilter::Sets(serde_yaml::from_str(sets).unwrap())))
+                .unwrap();
+        let root = Path::new("/srv/app");
+        let check_file = |rel_path: &str, size: u64| {
+            filter
+                .exclude_file(&root.join(rel_path), Path::new(rel_path), size)
+                .is_none()
+        };
+        let check_dir = |rel_path: &str| {
+            filter
+                .exclude_dir(&root.join(rel_path), Path::new(rel_path))
+                .is_none()
+        };
+
+        assert!(check_file("data/app.db", 100));
+        assert!(check_file("config/app/settings.json", 100));
+        assert!(!check_file("data/app.db", 1001));
+        assert!(!check_file("data/app.bak.db", 100));
+        assert!(!check_file("data/app.log", 100));
+        assert!(!check_file("data/.app.db", 100));
+        assert!(check_dir("data"));
+        assert!(check_dir("data/tmp/data/tmp"));
+        assert!(!check_dir("data/tmp"));
+        assert!(!check_dir("web/node_modules"));
+        assert!(!check_dir(".git"));
+
+        let filter =
+            BackupFilter::from_config(&Some(ItemFilter::Regex(String::from("balena-.*")))).unwrap();
+        assert!(filter
+            .exclude_file(Path::new("/srv/balena-app"), Path::new("balena-app"), 0)
+            .is_none());
+        assert!(filter
+            .exclude_file(Path::new("/srv/app"), Path::new("app"), 0)
+            .is_some());
+        assert!(BackupFilter::from_config(&None)
+            .unwrap()
+            .exclude_dir(Path::new("/srv/.cache"), Path::new(".cache"))
+            .is_none());
+    }
+
+    #[test]
+    fn verify_manifest() {
+        use crate::common::file_digest::{get_sha256_digest, HashInfo};
+        use std::env::temp_dir;
+        use std::fs::remove_file;
+
+        let backup_path = temp_dir().join(format!("backup-test-{}.tgz", std::process::id()));
+        let mut archiver = RustTarArchiver::new(&backup_path).unwrap();
+        archiver
+            .add_file(Path::new("volume/Cargo.toml"), Path::new("Cargo.toml"))
+            .unwrap();
+        // directories are listed without a digest
+        archiver
+            .add_file(Path::new("volume/src"), Path::new("src"))
+            .unwrap();
+        assert!(archiver
+            .manifest
+            .to_yaml()
+            .unwrap()
+            .contains("path: volume/src\n"));
+        // command output goes through a temporary file that is removed afterwards
+        let command: CommandConfig =
+            serde_yaml::from_str("cmd: head\nargs: ['-c', '1000000', '/dev/zero']\n").unwrap();
+        archiver
+            .add_command(Path::new("volume/db/zero.bin"), &command)
+            .unwrap();
+        assert!(!archiver.output_path.exists());
+        assert!(archiver
+            .manifest
+            .to_yaml()
+            .unwrap()
+            .contains("size: 1000000\n"));
+        archiver.finish().unwrap();
+        drop(archiver);
+
+        let digest = get_sha256_digest(&backup_path).unwrap();
+        let res = verify_backup(&backup_path, &digest);
+        let bad_res = verify_backup(&backup_path, &HashInfo::Sha256(String::from("00")));
+        remove_file(&backup_path).unwrap();
+        assert!(res.is_ok());
+        assert!(bad_res.is_err());
+    }
+
+    #[test]
+    fn verify_unlisted_entry() {
+        use crate::common::file_digest::get_sha256_digest;
+        use std::env::temp_dir;
+        use std::fs::remove_file;
+
+        let backup_path =
+            temp_dir().join(format!("backup-test-unlisted-{}.tgz", std::process::id()));
+        let mut archiver = RustTarArchiver::new(&backup_path).unwrap();
+        archiver
+            .add_file(Path::new("volume/Cargo.toml"), Path::new("Cargo.toml"))
+            .unwrap();
+        // appended without a manifest entry
+        let data = b"CREATE TABLE test;";
+        archiver
+            .append(
+                &mut data_header(data.len() as u64),
+                Path::new("volume/db/dump.sql"),
+                &data[..],
+            )
+            .unwrap();
+        archiver.finish().unwrap();
+        drop(archiver);
+
+        let digest = get_sha256_digest(&backup_path).unwrap();
+        let res = verify_backup(&backup_path, &digest);
+        remove_file(&backup_path).unwrap();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn verify_ext_manifest() {
+        use crate::common::file_digest::get_sha256_digest;
+        use std::env::temp_dir;
+        use std::fs::remove_file;
+
+        let backup_path = temp_dir().join(format!("backup-test-ext-{}.tgz", std::process::id()));
+        let mut archiver = ExtTarArchiver::new(&backup_path).unwrap();
+        // sources are linked into the temporary directory, so they need an absolute path
+        archiver
+            .add_file(
+                Path::new("volume/Cargo.toml"),
+                &Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"),
+            )
+            .unwrap();
+        let command: CommandConfig =
+            serde_yaml::from_str("cmd: sh\nargs: ['-c', 'printf dump']\n").unwrap();
+        archiver
+            .add_command(Path::new("volume/db/output.sql"), &command)
+            .unwrap();
+        archiver.finish().unwrap();
+
+        let digest = get_sha256_digest(&backup_path).unwrap();
+        let res = verify_backup(&backup_path, &digest);
+        remove_file(&backup_path).unwrap();
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn command_output() {
+        let command: CommandConfig =
+            serde_yaml::from_str("cmd: sh\nargs: ['-c', 'printf dump; exit 3']\n").unwrap();
+        assert!(run_command(&command, &mut sink()).is_err());
+
+        let command: CommandConfig = serde_yaml::from_str(
+            "cmd: sh\nargs: ['-c', 'printf dump; exit 3']\nexit_codes: [0, 3]\n",
+        )
+        .unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        assert_eq!(run_command(&command, &mut output).unwrap(), 4);
+        assert_eq!(output, b"dump");
+
+        // the output is streamed, it is not limited
+        let command: CommandConfig =
+            serde_yaml::from_str("cmd: head\nargs: ['-c', '1000000', '/dev/zero']\n").unwrap();
+        assert_eq!(run_command(&command, &mut sink()).unwrap(), 1_000_000);
+
+        let command: CommandConfig =
+            serde_yaml::from_str("cmd: sleep\nargs: ['10']\ntimeout: 1\n").unwrap();
+        assert!(run_command(&command, &mut sink()).is_err());
+
+        // the command is waited for after closing its output
+        let command: CommandConfig = serde_yaml::from_str(
+            "cmd: sh\nargs: ['-c', 'printf dump; exec >&-; sleep 10']\ntimeout: 1\n",
+        )
+        .unwrap();
+        assert!(run_command(&command, &mut sink()).is_err());
+    }
+}
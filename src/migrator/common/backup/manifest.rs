@@ -0,0 +1,234 @@
+use failure::ResultExt;
+use flate2::read::GzDecoder;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{copy, sink, Read};
+use std::path::Path;
+use tar::Archive;
+
+use crate::common::{
+    file_digest::{check_digest, DigestReader, HashInfo},
+    MigErrCtx, MigError, MigErrorKind,
+};
+
+// *************************************************************************************************
+// The backup manifest lists path and mode of every entry in the backup, regular files with their
+// size and sha256 digest. It is added to the archive as its last entry, stage2 verifies the
+// archive against it.
+// *************************************************************************************************
+
+pub(crate) const BACKUP_MANIFEST: &str = "backup-manifest.yml";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub mode: u32,
+    // directories, links and special files have no digest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+
+// archive paths created by external tar start with './', directories end with '/'
+fn archive_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    String::from(path.trim_start_matches("./").trim_end_matches('/'))
+}
+
+impl Manifest {
+    pub fn new() -> Manifest {
+        Manifest {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn add_entry(&mut self, path: &Path, size: u64, mode: u32, sha256: String) {
+        self.entries.push(ManifestEntry {
+            path: archive_path(path),
+            size,
+            mode,
+            sha256: Some(sha256),
+        });
+    }
+
+    // an entry that is not a regular file
+    pub fn add_other(&mut self, path: &Path, mode: u32) {
+        self.entries.push(ManifestEntry {
+            path: archive_path(path),
+            size: 0,
+            mode,
+            sha256: None,
+        });
+    }
+
+    pub fn to_yaml(&self) -> Result<String, MigError> {
+        Ok(serde_yaml::to_string(self).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            "Failed to serialize backup manifest",
+        ))?)
+    }
+}
+
+// check the digest of the backup and every entry listed in its manifest
+pub(crate) fn verify_backup(path: &Path, digest: &HashInfo) -> Result<(), MigError> {
+    if !check_digest(path, digest)? {
+        error!(
+            "The backup '{}' is corrupt, its digest does not match",
+            path.display()
+        );
+        return Err(MigError::displayed());
+    }
+
+    let file = File::open(path).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to open backup '{}'", path.display()),
+    ))?;
+
+    let mut manifest: Option<Manifest> = None;
+    // mode of every entry and size and digest of regular files
+    let mut archived: HashMap<String, (u32, Option<(u64, String)>)> = HashMap::new();
+
+    let mut archive = Archive::new(GzDecoder::new(file));
+    for entry in archive.entries().context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to read backup '{}'", path.display()),
+    ))? {
+        let mut entry = entry.context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to read entry from backup '{}'", path.display()),
+        ))?;
+
+        let entry_path = archive_path(&entry.path().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Invalid entry path in backup '{}'", path.display()),
+        ))?);
+
+        // the root directory created by external tar
+        if entry_path.is_empty() {
+            continue;
+        }
+
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_file() && entry_path == BACKUP_MANIFEST {
+            let mut manifest_str = String::new();
+            entry
+                .read_to_string(&mut manifest_str)
+                .context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    "Failed to read backup manifest",
+                ))?;
+            manifest = Some(serde_yaml::from_str(&manifest_str).context(
+                MigErrCtx::from_remark(MigErrorKind::Upstream, "Failed to parse backup manifest"),
+            )?);
+            continue;
+        }
+
+        if entry_type.is_hard_link() {
+            // external tar stores files linked more than once as hard links to the first one
+            let link_name = entry.link_name().context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Invalid link name of backup entry '{}'", entry_path),
+            ))?;
+            if let Some(link_name) = link_name {
+                if let Some(linked) = archived.get(&archive_path(&link_name)).cloned() {
+                    archived.insert(entry_path, linked);
+                    continue;
+                }
+            }
+        }
+
+        let mode = entry.header().mode().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Invalid mode of backup entry '{}'", entry_path),
+        ))?;
+
+        if !entry_type.is_file() {
+            archived.insert(entry_path, (mode, None));
+            continue;
+        }
+
+        let mut reader = DigestReader::new(&mut entry);
+        copy(&mut reader, &mut sink()).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to read backup entry '{}'", entry_path),
+        ))?;
+        let size = reader.get_size();
+        archived.insert(entry_path, (mode, Some((size, reader.get_digest()))));
+    }
+
+    let manifest = if let Some(manifest) = manifest {
+        manifest
+    } else {
+        error!(
+            "The backup '{}' does not contain a manifest",
+            path.display()
+        );
+        return Err(MigError::displayed());
+    };
+
+    let mut corrupt = 0;
+    for entry in &manifest.entries {
+        let is_ok = match (archived.get(&entry.path), &entry.sha256) {
+            (Some((_mode, Some((size, sha256)))), Some(expected)) => {
+                *size == entry.size && sha256 == expected
+            }
+            (Some((mode, None)), None) => *mode == entry.mode,
+            _ => false,
+        };
+
+        match archived.get(&entry.path) {
+            Some(_) if is_ok => debug!("verify_backup: '{}' is ok", entry.path),
+            Some(_) => {
+                error!("The backup entry '{}' is corrupt", entry.path);
+                corrupt += 1;
+            }
+            None => {
+                error!("The backup entry '{}' is missing", entry.path);
+                corrupt += 1;
+            }
+        }
+    }
+
+    // entries added to the archive behind the back of the manifest
+    let listed: HashSet<&str> = manifest
+        .entries
+        .iter()
+        .map(|entry| entry.path.as_str())
+        .collect();
+    let mut unlisted: Vec<&String> = archived
+        .keys()
+        .filter(|entry_path| !listed.contains(entry_path.as_str()))
+        .collect();
+    unlisted.sort();
+    for entry_path in unlisted {
+        error!(
+            "The backup entry '{}' is not listed in the manifest",
+            entry_path
+        );
+        corrupt += 1;
+    }
+
+    if corrupt > 0 {
+        error!(
+            "The backup '{}' is corrupt, {} of {} entries failed to verify",
+            path.display(),
+            corrupt,
+            manifest.entries.len()
+        );
+        Err(MigError::displayed())
+    } else {
+        info!(
+            "The backup '{}' was verified, {} entries are ok",
+            path.display(),
+            manifest.entries.len()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+use log::{debug, error, info, warn};
+
+use crate::{
+    common::{call_timeout, config::migrate_config::VolumeConfig, MigError},
+    linux::{
+        linux_common::whereis,
+        linux_defs::{DOCKER_CMD, PODMAN_CMD, SYSTEMCTL_CMD},
+    },
+};
+
+// *************************************************************************************************
+// systemd units and containers listed in the quiesce section of the backup volumes are stopped or
+// paused while the backup is created and resumed afterwards.
+// Everything stopped is recorded and resumed if creating the backup fails.
+// *************************************************************************************************
+
+#[derive(Debug)]
+enum Stopped {
+    Unit {
+        name: String,
+        timeout: u64,
+    },
+    Container {
+        engine: String,
+        name: String,
+        timeout: u64,
+    },
+}
+
+// run a command, logging failures
+fn run(cmd: &str, args: &[&str], timeout: u64) -> Result<bool, MigError> {
+    let cmd_res = call_timeout(cmd, args, true, timeout)?;
+    if cmd_res.status.success() {
+        Ok(true)
+    } else {
+        error!(
+            "Command {} '{:?}' failed with: {}",
+            cmd, args, cmd_res.stderr
+        );
+        Ok(false)
+    }
+}
+
+// docker is preferred, podman accepts the same commands
+fn get_container_engine() -> Result<String, MigError> {
+    for engine in &[DOCKER_CMD, PODMAN_CMD] {
+        if let Ok(cmd_path) = whereis(engine) {
+            return Ok(cmd_path);
+        }
+    }
+    error!(
+        "Could not find '{}' or '{}' to pause containers",
+        DOCKER_CMD, PODMAN_CMD
+    );
+    Err(MigError::displayed())
+}
+
+pub(crate) struct Quiesced {
+    stopped: Vec<Stopped>,
+}
+
+impl Quiesced {
+    // stop the units and pause the containers of all volumes
+    pub fn stop(config: &[VolumeConfig]) -> Result<Quiesced, MigError> {
+        // anything stopped before a failure is resumed when quiesced is dropped
+        let mut quiesced = Quiesced {
+            stopped: Vec::new(),
+        };
+
+        for volume in config {
+            let quiesce = if let Some(ref quiesce) = volume.quiesce {
+                quiesce
+            } else {
+                continue;
+            };
+
+            let timeout = quiesce.get_timeout();
+            for unit in &quiesce.units {
+                if quiesced.is_unit_stopped(unit) {
+                    continue;
+                }
+
+                // units that are not running are left alone and not started afterwards
+                if !call_timeout(
+                    SYSTEMCTL_CMD,
+                    &["is-active", "--quiet", unit],
+                    true,
+                    timeout,
+                )?
+                .status
+                .success()
+                {
+                    info!(
+                        "Unit '{}' of volume '{}' is not active",
+                        unit, volume.volume
+                    );
+                    continue;
+                }
+
+                info!(
+                    "Stopping unit '{}' for the backup of volume '{}'",
+                    unit, volume.volume
+                );
+                if !run(SYSTEMCTL_CMD, &["stop", unit], timeout)? {
+                    error!("Failed to stop unit '{}'", unit);
+                    return Err(MigError::displayed());
+                }
+                quiesced.stopped.push(Stopped::Unit {
+                    name: unit.clone(),
+                    timeout,
+                });
+            }
+
+            if quiesce.containers.is_empty() {
+                continue;
+            }
+
+            let engine = get_container_engine()?;
+            for container in &quiesce.containers {
+                if quiesced.is_container_stopped(container) {
+                    continue;
+                }
+
+                let cmd_res = call_timeout(
+                    &engine,
+                    &["inspect", "--format", "{{.State.Status}}", container],
+                    true,
+                    timeout,
+                )?;
+                if !cmd_res.status.success() {
+                    error!(
+                        "Failed to inspect container '{}' of volume '{}': {}",
+                        container, volume.volume, cmd_res.stderr
+                    );
+                    return Err(MigError::displayed());
+                }
+
+                if cmd_res.stdout != "running" {
+                    info!(
+                        "Container '{}' of volume '{}' is not running, state: '{}'",
+                        container, volume.volume, cmd_res.stdout
+                    );
+                    continue;
+                }
+
+                info!(
+                    "Pausing container '{}' for the backup of volume '{}'",
+                    container, volume.volume
+                );
+                if !run(&engine, &["pause", container], timeout)? {
+                    error!("Failed to pause container '{}'", container);
+                    return Err(MigError::displayed());
+                }
+                quiesced.stopped.push(Stopped::Container {
+                    engine: engine.clone(),
+                    name: container.clone(),
+                    timeout,
+                });
+            }
+        }
+
+        Ok(quiesced)
+    }
+
+    // log what would be stopped, used in pretend mode
+    pub fn list(config: &[VolumeConfig]) {
+        for volume in config {
+            if let Some(ref quiesce) = volume.quiesce {
+                for unit in &quiesce.units {
+                    info!(
+                        "Unit '{}' would be stopped for the backup of volume '{}'",
+                        unit, volume.volume
+                    );
+                }
+                for container in &quiesce.containers {
+                    info!(
+                        "Container '{}' would be paused for the backup of volume '{}'",
+                        container, volume.volume
+                    );
+                }
+            }
+        }
+    }
+
+    fn is_unit_stopped(&self, unit: &str) -> bool {
+        self.stopped.iter().any(|stopped| match stopped {
+            Stopped::Unit { name, .. } => name == unit,
+            _ => false,
+        })
+    }
+
+    fn is_container_stopped(&self, container: &str) -> bool {
+        self.stopped.iter().any(|stopped| match stopped {
+            Stopped::Container { name, .. } => name == container,
+            _ => false,
+        })
+    }
+
+    // resume in reverse order, returns false if anything failed to resume
+    pub fn resume(&mut self) -> bool {
+        let mut success = true;
+        while let Some(stopped) = self.stopped.pop() {
+            let (resumed, descr) = match stopped {
+                Stopped::Unit { ref name, timeout } => {
+                    info!("Starting unit '{}'", name);
+                    (
+                        run(SYSTEMCTL_CMD, &["start", name], timeout),
+                        format!("unit '{}'", name),
+                    )
+                }
+                Stopped::Container {
+                    ref engine,
+                    ref name,
+                    timeout,
+                } => {
+                    info!("Unpausing container '{}'", name);
+                    (
+                        run(engine, &["unpause", name], timeout),
+                        format!("container '{}'", name),
+                    )
+                }
+            };
+
+            match resumed {
+                Ok(true) => debug!("resume: resumed {}", descr),
+                Ok(false) => {
+                    error!("Failed to resume {}", descr);
+                    success = false;
+                }
+                Err(why) => {
+                    error!("Failed to resume {}, error: {:?}", descr, why);
+                    success = false;
+                }
+            }
+        }
+        success
+    }
+}
+
+impl Drop for Quiesced {
+    fn drop(&mut self) {
+        if !self.stopped.is_empty() {
+            warn!(
+                "Resuming {} stopped units and containers after a failure",
+                self.stopped.len()
+            );
+            self.resume();
+        }
+    }
+}
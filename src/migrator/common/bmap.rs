@@ -0,0 +1,423 @@
+use failure::{Fail, ResultExt};
+use lazy_static::lazy_static;
+use log::{debug, trace};
+use regex::Regex;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::fs::read_to_string;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::common::{MigErrCtx, MigError, MigErrorKind};
+
+// ******************************************************************
+// Block map (bmap) files as created by bmaptool.
+// A bmap lists the block ranges of an image that contain data
+// together with a checksum for each range. Unmapped ranges can be
+// skipped when flashing the image.
+// ******************************************************************
+
+const BMAP_VERSION_REGEX: &str = r#"<bmap\s+version\s*=\s*"(\d+)\.(\d+)"\s*>"#;
+const BMAP_RANGE_REGEX: &str = r#"<Range(\s+(sha1|sha256|chksum)\s*=\s*"([0-9a-fA-F]+)")?\s*>\s*(\d+)(\s*-\s*(\d+))?\s*</Range>"#;
+const BMAP_COMMENT_REGEX: &str = r"(?s)<!--.*?-->";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ChecksumType {
+    Sha1,
+    Sha256,
+}
+
+pub(crate) enum RangeHasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl RangeHasher {
+    pub fn new(checksum_type: ChecksumType) -> RangeHasher {
+        match checksum_type {
+            ChecksumType::Sha1 => RangeHasher::Sha1(Sha1::new()),
+            ChecksumType::Sha256 => RangeHasher::Sha256(Sha256::new()),
+        }
+    }
+
+    pub fn input(&mut self, data: &[u8]) {
+        match self {
+            RangeHasher::Sha1(ref mut hasher) => hasher.input(data),
+            RangeHasher::Sha256(ref mut hasher) => hasher.input(data),
+        }
+    }
+
+    pub fn result_str(self) -> String {
+        let digest = match self {
+            RangeHasher::Sha1(hasher) => hasher.result().to_vec(),
+            RangeHasher::Sha256(hasher) => hasher.result().to_vec(),
+        };
+
+        let mut res = String::new();
+        for byte in &digest {
+            res.push_str(&format!("{:02x}", byte));
+        }
+        res
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BlockRange {
+    pub first: u64,
+    pub last: u64,
+    pub checksum: Option<String>,
+}
+
+impl BlockRange {
+    // byte offsets (start, end) of the range, the last block might be truncated by the image size
+    pub fn get_byte_range(&self, block_size: u64, image_size: u64) -> (u64, u64) {
+        let end = (self.last + 1) * block_size;
+        (
+            self.first * block_size,
+            if end > image_size { image_size } else { end },
+        )
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct BlockMap {
+    image_size: u64,
+    block_size: u64,
+    blocks_count: u64,
+    mapped_blocks: u64,
+    checksum_type: ChecksumType,
+    ranges: Vec<BlockRange>,
+}
+
+impl<'a> BlockMap {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<BlockMap, MigError> {
+        let path = path.as_ref();
+        trace!("from_file: entered with '{}'", path.display());
+        let content = read_to_string(path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to read bmap file '{}'", path.display()),
+        ))?;
+
+        match BlockMap::from_str(&content) {
+            Ok(block_map) => Ok(block_map),
+            Err(why) => Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "Failed to parse bmap file '{}', error: {:?}",
+                    path.display(),
+                    why
+                ),
+            )),
+        }
+    }
+
+    pub fn from_str(content: &str) -> Result<BlockMap, MigError> {
+        lazy_static! {
+            static ref VERSION_RE: Regex = Regex::new(BMAP_VERSION_REGEX).unwrap();
+            static ref RANGE_RE: Regex = Regex::new(BMAP_RANGE_REGEX).unwrap();
+            static ref COMMENT_RE: Regex = Regex::new(BMAP_COMMENT_REGEX).unwrap();
+        }
+
+        let stripped = COMMENT_RE.replace_all(content, "");
+
+        let major_version = if let Some(captures) = VERSION_RE.captures(&stripped) {
+            parse_number(captures.get(1).unwrap().as_str(), "bmap version")?
+        } else {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                "BlockMap::from_str: no bmap version found",
+            ));
+        };
+
+        let checksum_type = match major_version {
+            1 => ChecksumType::Sha1,
+            2 => match get_tag_value(&stripped, "ChecksumType")? {
+                "sha1" => ChecksumType::Sha1,
+                "sha256" => ChecksumType::Sha256,
+                checksum_type => {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::InvParam,
+                        &format!(
+                            "BlockMap::from_str: unsupported checksum type: '{}'",
+                            checksum_type
+                        ),
+                    ));
+                }
+            },
+            _ => {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!(
+                        "BlockMap::from_str: unsupported bmap version: {}",
+                        major_version
+                    ),
+                ));
+            }
+        };
+
+        let mut ranges: Vec<BlockRange> = Vec::new();
+        for captures in RANGE_RE.captures_iter(&stripped) {
+            let first = parse_number(captures.get(4).unwrap().as_str(), "range start")?;
+            ranges.push(BlockRange {
+                first,
+                last: if let Some(last) = captures.get(6) {
+                    parse_number(last.as_str(), "range end")?
+                } else {
+                    first
+                },
+                checksum: captures
+                    .get(3)
+                    .map(|checksum| checksum.as_str().to_lowercase()),
+            });
+        }
+
+        let block_map = BlockMap {
+            image_size: get_tag_number(&stripped, "ImageSize")?,
+            block_size: get_tag_number(&stripped, "BlockSize")?,
+            blocks_count: get_tag_number(&stripped, "BlocksCount")?,
+            mapped_blocks: get_tag_number(&stripped, "MappedBlocksCount")?,
+            checksum_type,
+            ranges,
+        };
+
+        block_map.check_file_checksum(content)?;
+        block_map.check_ranges()?;
+
+        debug!(
+            "BlockMap::from_str: image size: {}, block size: {}, blocks: {}, mapped: {}, ranges: {}",
+            block_map.image_size,
+            block_map.block_size,
+            block_map.blocks_count,
+            block_map.mapped_blocks,
+            block_map.ranges.len()
+        );
+
+        Ok(block_map)
+    }
+
+    pub fn get_image_size(&self) -> u64 {
+        self.image_size
+    }
+
+    pub fn get_block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn get_mapped_size(&self) -> u64 {
+        self.ranges.iter().fold(0, |size, range| {
+            let (start, end) = range.get_byte_range(self.block_size, self.image_size);
+            size + end - start
+        })
+    }
+
+    pub fn get_checksum_type(&self) -> ChecksumType {
+        self.checksum_type
+    }
+
+    pub fn get_ranges(&'a self) -> &'a [BlockRange] {
+        self.ranges.as_slice()
+    }
+
+    // the checksum of the bmap file is calculated with the checksum value itself set to all '0'
+    fn check_file_checksum(&self, content: &str) -> Result<(), MigError> {
+        let tag = if let ChecksumType::Sha1 = self.checksum_type {
+            if get_tag_value(content, "BmapFileSHA1").is_ok() {
+                "BmapFileSHA1"
+            } else {
+                "BmapFileChecksum"
+            }
+        } else {
+            "BmapFileChecksum"
+        };
+
+        let checksum = if let Ok(checksum) = get_tag_value(content, tag) {
+            checksum
+        } else {
+            // older bmap files come without a checksum
+            debug!("check_file_checksum: no file checksum found");
+            return Ok(());
+        };
+
+        let mut hasher = RangeHasher::new(self.checksum_type);
+        hasher.input(
+            content
+                .replacen(checksum, &"0".repeat(checksum.len()), 1)
+                .as_bytes(),
+        );
+        let computed = hasher.result_str();
+        if computed == checksum.to_lowercase() {
+            Ok(())
+        } else {
+            Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "BlockMap::check_file_checksum: checksum mismatch, expected: {}, computed: {}",
+                    checksum, computed
+                ),
+            ))
+        }
+    }
+
+    fn check_ranges(&self) -> Result<(), MigError> {
+        if self.block_size == 0 {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                "BlockMap::check_ranges: invalid block size 0",
+            ));
+        }
+
+        let max_size = if let Some(max_size) = self.blocks_count.checked_mul(self.block_size) {
+            max_size
+        } else {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "BlockMap::check_ranges: {} blocks of {} bytes are out of range",
+                    self.blocks_count, self.block_size
+                ),
+            ));
+        };
+
+        if self.image_size > max_size {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "BlockMap::check_ranges: image size {} exceeds {} blocks of {} bytes",
+                    self.image_size, self.blocks_count, self.block_size
+                ),
+            ));
+        }
+
+        let mut mapped_blocks: u64 = 0;
+        let mut next_block: u64 = 0;
+        for range in &self.ranges {
+            if range.first < next_block || range.last < range.first {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!(
+                        "BlockMap::check_ranges: invalid or unordered range {}-{}",
+                        range.first, range.last
+                    ),
+                ));
+            }
+
+            if range.last >= self.blocks_count {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!(
+                        "BlockMap::check_ranges: range {}-{} exceeds the block count {}",
+                        range.first, range.last, self.blocks_count
+                    ),
+                ));
+            }
+
+            mapped_blocks += range.last - range.first + 1;
+            next_block = range.last + 1;
+        }
+
+        if mapped_blocks != self.mapped_blocks {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "BlockMap::check_ranges: ranges contain {} blocks, expected {}",
+                    mapped_blocks, self.mapped_blocks
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn get_tag_value<'a>(content: &'a str, tag: &str) -> Result<&'a str, MigError> {
+    let regex = Regex::new(&format!(r"<{0}>\s*([^<]*?)\s*</{0}>", tag)).unwrap();
+    if let Some(captures) = regex.captures(content) {
+        Ok(captures.get(1).unwrap().as_str())
+    } else {
+        Err(MigError::from_remark(
+            MigErrorKind::NotFound,
+            &format!("BlockMap: tag '{}' was not found", tag),
+        ))
+    }
+}
+
+fn get_tag_number(content: &str, tag: &str) -> Result<u64, MigError> {
+    parse_number(get_tag_value(content, tag)?, &format!("tag '{}'", tag))
+}
+
+fn parse_number<T: FromStr>(value: &str, what: &str) -> Result<T, MigError>
+where
+    <T as FromStr>::Err: Fail,
+{
+    Ok(value.parse::<T>().context(MigErrCtx::from_remark(
+        MigErrorKind::InvParam,
+        &format!("BlockMap: failed to parse value of {}: '{}'", what, value),
+    ))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockMap, ChecksumType, RangeHasher};
+
+    const TEST_BMAP: &str = r#"<?xml version="1.0" ?>
+<!-- This file contains the block map for an image file, which is basically
+     a list of useful (mapped) block numbers in the image file. -->
+<bmap version="2.0">
+    <!-- Image size in bytes: 40.0 KiB -->
+    <ImageSize> 40000 </ImageSize>
+    <BlockSize> 4096 </BlockSize>
+    <BlocksCount> 10 </BlocksCount>
+    <MappedBlocksCount> 4 </MappedBlocksCount>
+    <ChecksumType> sha256 </ChecksumType>
+    <BmapFileChecksum> 0000000000000000000000000000000000000000000000000000000000000000 </BmapFileChecksum>
+    <BlockMap>
+        <Range chksum="RANGE_0"> 0 </Range>
+        <Range chksum="RANGE_1"> 7-9 </Range>
+    </BlockMap>
+</bmap>
+"#;
+
+    fn range_checksum(data: &[u8]) -> String {
+        let mut hasher = RangeHasher::new(ChecksumType::Sha256);
+        hasher.input(data);
+        hasher.result_str()
+    }
+
+    fn make_bmap() -> String {
+        let bmap = TEST_BMAP
+            .replace("RANGE_0", &range_checksum(&[1u8; 4096]))
+            .replace("RANGE_1", &range_checksum(&[2u8; 40000 - 7 * 4096]));
+        let checksum = range_checksum(bmap.as_bytes());
+        bmap.replacen(&"0".repeat(64), &checksum, 1)
+    }
+
+    #[test]
+    fn parse_bmap() {
+        let block_map = BlockMap::from_str(&make_bmap()).unwrap();
+        assert_eq!(block_map.get_image_size(), 40000);
+        assert_eq!(block_map.get_block_size(), 4096);
+        assert_eq!(block_map.get_checksum_type(), ChecksumType::Sha256);
+        assert_eq!(block_map.get_mapped_size(), 4096 + 40000 - 7 * 4096);
+        let ranges = block_map.get_ranges();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!((ranges[0].first, ranges[0].last), (0, 0));
+        assert_eq!(ranges[1].get_byte_range(4096, 40000), (7 * 4096, 40000));
+        assert_eq!(ranges[0].checksum, Some(range_checksum(&[1u8; 4096])));
+    }
+
+    #[test]
+    fn parse_bmap_out_of_range() {
+        let bmap = TEST_BMAP
+            .replace("RANGE_0", "00")
+            .replace("RANGE_1", "00")
+            .replace("7-9", "7-99999999999999999999");
+        let why = BlockMap::from_str(&bmap).unwrap_err();
+        assert!(format!("{}", why).contains("range end"));
+    }
+
+    #[test]
+    fn parse_bmap_bad_checksum() {
+        let bmap = make_bmap().replace("<BlocksCount> 10", "<BlocksCount> 11");
+        assert!(BlockMap::from_str(&bmap).is_err());
+    }
+}
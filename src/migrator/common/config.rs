@@ -284,7 +284,10 @@ impl<'a> Config {
 mod tests {
     use super::*;
     use crate::{
-        common::config::{balena_config::FileRef, migrate_config::MigrateWifis},
+        common::config::{
+            balena_config::{FileRef, FlasherImage, ImageType},
+            migrate_config::MigrateWifis,
+        },
         defs::FailMode,
     };
     use std::path::PathBuf;
@@ -317,6 +320,7 @@ mod tests {
             }
         );
         assert_eq!(config.migrate.get_fail_mode(), &FailMode::Reboot);
+        assert!(config.migrate.is_flash_verify());
         /*        assert_eq!(
                     config.migrate.get_force_slug(),
                     Some(String::from("dummy_device"))
@@ -327,7 +331,24 @@ mod tests {
         assert_eq!(bckup_vols.len(), 3);
         assert_eq!(bckup_vols.get(0).unwrap().volume, "test volume 1");
 
-        // assert_eq!(config.balena.get_image_path(), Path::new("image.gz"));
+        if let ImageType::Flasher(ref flasher_img) = config.balena.get_image_path() {
+            assert_eq!(
+                flasher_img,
+                &FlasherImage {
+                    image: FileRef {
+                        path: PathBuf::from("image.gz"),
+                        hash: None
+                    },
+                    bmap: Some(FileRef {
+                        path: PathBuf::from("image.bmap"),
+                        hash: None
+                    }),
+                    device_slug: None,
+                }
+            );
+        } else {
+            panic!("unexpected image type");
+        }
         assert_eq!(
             config.balena.get_config_path(),
             &FileRef {
@@ -456,6 +477,8 @@ migrate:
   fail_mode: Reboot
   ## forced use of a device slug other than the one detected
   force_slug: 'dummy_device'
+  ## read back and compare the flashed image
+  flash_verify: true
 balena:
   ## the balena image version to download (not yet implemented)
   version:
@@ -463,6 +486,8 @@ balena:
   image:
     dd:
       path: image.gz
+      bmap:
+        path: image.bmap
   ## the balena config file to use (can be auto generated in future versions)
   config:
     path: "config.json"
@@ -13,6 +13,16 @@ use crate::defs::DEFAULT_API_CHECK_TIMEOUT;
 pub(crate) struct PartDump {
     pub blocks: u64,
     pub archive: FileRef,
+    // GPT partition type GUID, defaults depend on the partition
+    pub type_guid: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) enum PartTable {
+    #[serde(rename = "dos")]
+    Dos,
+    #[serde(rename = "gpt")]
+    Gpt,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -32,6 +42,8 @@ pub(crate) struct FSDump {
     pub check: Option<PartCheck>,
     pub max_data: Option<bool>,
     pub mkfs_direct: Option<bool>,
+    // partition table type, defaults to dos
+    pub part_table: Option<PartTable>,
     pub boot: PartDump,
     pub root_a: PartDump,
     pub root_b: PartDump,
@@ -45,11 +57,21 @@ pub(crate) struct FileRef {
     pub hash: Option<HashInfo>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct FlasherImage {
+    #[serde(flatten)]
+    pub image: FileRef,
+    // optional block map (bmaptool) of the uncompressed image, enables sparse flashing
+    pub bmap: Option<FileRef>,
+    // device slug the image was built for, required for GPT / UEFI device types
+    pub device_slug: Option<String>,
+}
+
 #[allow(clippy::large_enum_variant)] //TODO refactor to remove clippy warning
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) enum ImageType {
     #[serde(rename = "dd")]
-    Flasher(FileRef),
+    Flasher(FlasherImage),
     #[serde(rename = "fs")]
     FileSystems(FSDump),
 }
@@ -128,9 +150,13 @@ impl<'a> BalenaConfig {
     }
 
     pub fn set_image_path(&mut self, image_path: &str) {
-        self.image = Some(ImageType::Flasher(FileRef {
-            path: PathBuf::from(image_path),
-            hash: None,
+        self.image = Some(ImageType::Flasher(FlasherImage {
+            image: FileRef {
+                path: PathBuf::from(image_path),
+                hash: None,
+            },
+            bmap: None,
+            device_slug: None,
         }));
     }
 
@@ -14,6 +14,8 @@ const NO_NMGR_FILES: &[PathBuf] = &[];
 
 const NO_BACKUP_VOLUMES: &[VolumeConfig] = &[];
 
+const NO_RPI_CONFIG: &[String] = &[];
+
 #[derive(Debug, PartialEq, Deserialize, Clone)]
 pub(crate) enum MigMode {
     //    #[serde(rename = "agent")]
@@ -51,10 +53,26 @@ pub(crate) enum UEnvStrategy {
     Manual,
 }
 
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub(crate) enum UBootEnvMode {
+    // use the u-boot environment if it does not load uEnv.txt
+    #[serde(rename = "auto")]
+    Auto,
+    #[serde(rename = "uenv")]
+    UEnv,
+    #[serde(rename = "bootcmd")]
+    BootCmd,
+    #[serde(rename = "altbootcmd")]
+    AltBootCmd,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) struct UBootCfg {
     pub strategy: Option<UEnvStrategy>,
     pub mmc_index: Option<u8>,
+    pub env_mode: Option<UBootEnvMode>,
+    // fw_env.config style description of the u-boot environment location
+    pub env_config: Option<PathBuf>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -72,17 +90,115 @@ pub(crate) struct UBootEnv {
 }
 */
 
+// Patterns are globs matched against the path relative to the item source - or against the file
+// name if they do not contain a '/' - or regular expressions matched against the full source path
+// if prefixed with 'regex:'
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct FilterSets {
+    // files matching any of these are included, all files if empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+    // files and directories matching any of these are excluded, directories are not descended into
+    #[serde(default)]
+    pub deny: Vec<String>,
+    // files larger than this (bytes) are excluded
+    pub max_size: Option<u64>,
+    // include files and directories starting with '.', defaults to true
+    pub hidden: Option<bool>,
+}
+
+impl FilterSets {
+    pub fn is_hidden(&self) -> bool {
+        if let Some(val) = self.hidden {
+            val
+        } else {
+            true
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum ItemFilter {
+    // a regular expression matched against the full source path of files
+    Regex(String),
+    Sets(FilterSets),
+}
+
+const DEFAULT_COMMAND_TIMEOUT: u64 = 600;
+const DEFAULT_EXIT_CODES: &[i32] = &[0];
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CommandConfig {
+    // the program to run, it is not run through a shell
+    pub cmd: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    // a file created by the command, archived instead of its output
+    pub output: Option<PathBuf>,
+    // seconds to wait for the command to terminate
+    pub timeout: Option<u64>,
+    // exit codes indicating success
+    pub exit_codes: Option<Vec<i32>>,
+}
+
+impl<'a> CommandConfig {
+    pub fn get_timeout(&self) -> u64 {
+        if let Some(val) = self.timeout {
+            val
+        } else {
+            DEFAULT_COMMAND_TIMEOUT
+        }
+    }
+
+    pub fn get_exit_codes(&'a self) -> &'a [i32] {
+        if let Some(ref val) = self.exit_codes {
+            val.as_slice()
+        } else {
+            DEFAULT_EXIT_CODES
+        }
+    }
+}
+
+// an item is either a file or directory source or a command
 #[derive(Debug, Deserialize)]
 pub(crate) struct ItemConfig {
-    pub source: String,
+    pub source: Option<String>,
+    pub command: Option<CommandConfig>,
+    // required for commands
     pub target: Option<String>,
-    // TODO: filter.allow, filter.deny
-    pub filter: Option<String>,
+    // filters apply to the contents of directory sources
+    pub filter: Option<ItemFilter>,
+}
+
+const DEFAULT_QUIESCE_TIMEOUT: u64 = 60;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct QuiesceConfig {
+    // systemd units stopped while the backup is created
+    #[serde(default)]
+    pub units: Vec<String>,
+    // docker or podman containers paused while the backup is created
+    #[serde(default)]
+    pub containers: Vec<String>,
+    // seconds to wait for a unit or container to stop or resume
+    pub timeout: Option<u64>,
+}
+
+impl QuiesceConfig {
+    pub fn get_timeout(&self) -> u64 {
+        if let Some(val) = self.timeout {
+            val
+        } else {
+            DEFAULT_QUIESCE_TIMEOUT
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct VolumeConfig {
     pub volume: String,
+    pub quiesce: Option<QuiesceConfig>,
     pub items: Vec<ItemConfig>,
 }
 
@@ -117,13 +233,18 @@ pub(crate) struct MigrateConfig {
     // TODO: find a good way to do digests on NetworkManager files
     nwmgr_files: Option<Vec<PathBuf>>,
     require_nwmgr_config: Option<bool>,
+    // migrate OSses the device's OS policy classifies as untested
+    allow_untested_os: Option<bool>,
     gzip_internal: Option<bool>,
+    flash_verify: Option<bool>,
     tar_internal: Option<bool>,
     watchdogs: Option<Vec<WatchdogCfg>>,
     delay: Option<u64>,
     kernel_opts: Option<String>,
     force_flash_device: Option<PathBuf>,
     uboot: Option<UBootCfg>,
+    // raspberry pi config.txt directives to carry over to balenaOS
+    rpi_config: Option<Vec<String>>,
 }
 
 impl<'a> MigrateConfig {
@@ -143,13 +264,16 @@ impl<'a> MigrateConfig {
             backup: None,
             nwmgr_files: None,
             require_nwmgr_config: None,
+            allow_untested_os: None,
             gzip_internal: None,
+            flash_verify: None,
             tar_internal: None,
             watchdogs: None,
             delay: None,
             kernel_opts: None,
             force_flash_device: None,
             uboot: None,
+            rpi_config: None,
         }
     }
 
@@ -194,6 +318,14 @@ impl<'a> MigrateConfig {
         }
     }
 
+    pub fn is_flash_verify(&self) -> bool {
+        if let Some(val) = self.flash_verify {
+            val
+        } else {
+            false
+        }
+    }
+
     pub fn is_tar_internal(&self) -> bool {
         if let Some(val) = self.tar_internal {
             val
@@ -217,6 +349,14 @@ impl<'a> MigrateConfig {
         true
     }
 
+    pub fn allow_untested_os(&self) -> bool {
+        if let Some(val) = self.allow_untested_os {
+            val
+        } else {
+            false
+        }
+    }
+
     pub fn get_nwmgr_files(&'a self) -> &'a [PathBuf] {
         if let Some(ref val) = self.nwmgr_files {
             return val.as_slice();
@@ -252,6 +392,14 @@ impl<'a> MigrateConfig {
         }
     }
 
+    pub fn get_rpi_config(&'a self) -> &'a [String] {
+        if let Some(ref val) = self.rpi_config {
+            val.as_slice()
+        } else {
+            NO_RPI_CONFIG
+        }
+    }
+
     pub fn get_watchdogs(&'a self) -> Option<&'a Vec<WatchdogCfg>> {
         if let Some(ref val) = self.watchdogs {
             Some(val)
@@ -1,19 +1,20 @@
 use crate::{
     common::{
+        device_catalog::DeviceEntry,
         migrate_info::MigrateInfo,
         path_info::PathInfo,
         stage2_config::{Stage2Config, Stage2ConfigBuilder},
         Config, MigError,
     },
-    defs::{BootType, DeviceType},
+    defs::BootType,
 };
 
 #[cfg(target_os = "linux")]
 use crate::linux::stage2::mounts::Mounts;
 
 pub(crate) trait Device {
-    fn get_device_slug(&self) -> &'static str;
-    fn get_device_type(&self) -> DeviceType;
+    fn get_device_slug(&self) -> &str;
+    fn get_device_entry(&self) -> &DeviceEntry;
     fn get_boot_type(&self) -> BootType;
     // TODO: make return reference
     // TODO: return device_info instead of path_info
@@ -0,0 +1,530 @@
+use clap::{App, Arg};
+use failure::ResultExt;
+use log::{debug, info};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    common::{
+        file_exists,
+        os_policy::{check_os_policy, OsIdent, OsRule, OsSupport},
+        path_append, MigErrCtx, MigError, MigErrorKind,
+    },
+    defs::{FileType, VERSION},
+};
+
+// ******************************************************************
+// The catalog of supported devices. It is built into the binary and
+// can be extended from a devices.yml in the work directory, see
+// device_catalog.yml for a description of the entries.
+// ******************************************************************
+
+const BUILTIN_CATALOG: &str = include_str!("device_catalog.yml");
+
+pub(crate) const DEVICE_CATALOG_FILE: &str = "devices.yml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub(crate) enum DeviceArch {
+    #[serde(rename = "amd64")]
+    AMD64,
+    #[serde(rename = "armhf")]
+    ARMHF,
+    #[serde(rename = "aarch64")]
+    AARCH64,
+}
+
+impl DeviceArch {
+    // the migrate kernel has to match the balena OS architecture
+    pub fn get_kernel_type(self) -> FileType {
+        match self {
+            DeviceArch::AMD64 => FileType::KernelAMD64,
+            DeviceArch::ARMHF => FileType::KernelARMHF,
+            DeviceArch::AARCH64 => FileType::KernelAARCH64,
+        }
+    }
+}
+
+impl Display for DeviceArch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DeviceArch::AMD64 => write!(f, "amd64"),
+            DeviceArch::ARMHF => write!(f, "armhf"),
+            DeviceArch::AARCH64 => write!(f, "aarch64"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) enum BootManagerCfg {
+    #[serde(rename = "raspi")]
+    Raspi { dtb_files: Vec<String> },
+    #[serde(rename = "u-boot")]
+    UBoot { mmc_index: u8, dtb_file: String },
+    #[serde(rename = "grub")]
+    Grub { prefer_efi: bool },
+}
+
+impl Display for BootManagerCfg {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            BootManagerCfg::Raspi { .. } => write!(f, "raspi"),
+            BootManagerCfg::UBoot { .. } => write!(f, "u-boot"),
+            BootManagerCfg::Grub { prefer_efi } => {
+                if *prefer_efi {
+                    write!(f, "efi/grub")
+                } else {
+                    write!(f, "grub")
+                }
+            }
+        }
+    }
+}
+
+// DMI (SMBIOS) strings as found in /sys/class/dmi/id
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DmiInfo {
+    pub sys_vendor: String,
+    pub product_name: String,
+    pub board_vendor: String,
+    pub board_name: String,
+}
+
+// regexes matched against the DMI strings, all given regexes have to match
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct DmiMatch {
+    pub sys_vendor: Option<String>,
+    pub product_name: Option<String>,
+    pub board_vendor: Option<String>,
+    pub board_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct DeviceEntry {
+    pub name: String,
+    pub slug: String,
+    pub arch: DeviceArch,
+    pub model: Option<String>,
+    pub compatible: Option<String>,
+    pub dmi: Option<DmiMatch>,
+    pub boot_manager: BootManagerCfg,
+    pub kernel_opts: Option<String>,
+    #[serde(default)]
+    pub os_policy: Vec<OsRule>,
+    pub emmc_boot: Option<bool>,
+    pub gpt_image: Option<bool>,
+}
+
+impl DeviceEntry {
+    fn get_regex(&self, regex: &str) -> Result<Regex, MigError> {
+        Ok(Regex::new(regex).context(MigErrCtx::from_remark(
+            MigErrorKind::InvParam,
+            &format!("Invalid regex '{}' in device '{}'", regex, self.name),
+        ))?)
+    }
+
+    // match the device tree model string and compatible list
+    pub fn is_model(&self, model: &str, compatible: &[String]) -> Result<bool, MigError> {
+        if let Some(ref model_re) = self.model {
+            if self.get_regex(model_re)?.is_match(model) {
+                return Ok(true);
+            }
+        }
+
+        if let Some(ref dt_compatible) = self.compatible {
+            if compatible.contains(dt_compatible) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    // match the DMI strings, devices without DMI regexes never match
+    pub fn is_dmi(&self, dmi_info: &DmiInfo) -> Result<bool, MigError> {
+        if let Some(ref dmi) = self.dmi {
+            let checks = [
+                (&dmi.sys_vendor, &dmi_info.sys_vendor),
+                (&dmi.product_name, &dmi_info.product_name),
+                (&dmi.board_vendor, &dmi_info.board_vendor),
+                (&dmi.board_name, &dmi_info.board_name),
+            ];
+            for (dmi_re, value) in &checks {
+                if let Some(dmi_re) = dmi_re {
+                    if !self.get_regex(dmi_re)?.is_match(value) {
+                        return Ok(false);
+                    }
+                }
+            }
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    // returns the outcome of the device's OS policy and the reason for it
+    pub fn get_os_support(&self, os: &OsIdent) -> Result<(OsSupport, String), MigError> {
+        check_os_policy(&self.os_policy, os)
+    }
+
+    pub fn get_kernel_opts(&self) -> &str {
+        if let Some(ref kernel_opts) = self.kernel_opts {
+            kernel_opts
+        } else {
+            ""
+        }
+    }
+}
+
+pub(crate) struct DeviceCatalog {
+    devices: Vec<DeviceEntry>,
+}
+
+impl DeviceCatalog {
+    // load the built in catalog, extended by devices.yml in work_dir if present
+    pub fn load(work_dir: Option<&Path>) -> Result<DeviceCatalog, MigError> {
+        let mut catalog = DeviceCatalog::from_str(BUILTIN_CATALOG)?;
+
+        if let Some(work_dir) = work_dir {
+            let catalog_path = path_append(work_dir, DEVICE_CATALOG_FILE);
+            if file_exists(&catalog_path) {
+                info!("Loading devices from '{}'", catalog_path.display());
+                let devices = DeviceCatalog::from_str(&read_to_string(&catalog_path).context(
+                    MigErrCtx::from_remark(
+                        MigErrorKind::Upstream,
+                        &format!("Failed to read file '{}'", catalog_path.display()),
+                    ),
+                )?)?;
+                catalog.extend(devices);
+            }
+        }
+
+        Ok(catalog)
+    }
+
+    fn from_str(catalog_str: &str) -> Result<DeviceCatalog, MigError> {
+        Ok(DeviceCatalog {
+            devices: serde_yaml::from_str(catalog_str).context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                "Failed to parse device catalog",
+            ))?,
+        })
+    }
+
+    // devices replace those of the same name, new devices are matched first
+    fn extend(&mut self, other: DeviceCatalog) {
+        let mut added: Vec<DeviceEntry> = Vec::new();
+        for device in other.devices {
+            if let Some(pos) = self
+                .devices
+                .iter()
+                .position(|curr| curr.name == device.name)
+            {
+                debug!("extend: replacing device '{}'", device.name);
+                self.devices[pos] = device;
+            } else {
+                debug!("extend: adding device '{}'", device.name);
+                added.push(device);
+            }
+        }
+        added.append(&mut self.devices);
+        self.devices = added;
+    }
+
+    pub fn get_devices(&self) -> &[DeviceEntry] {
+        &self.devices
+    }
+
+    pub fn find_by_model(
+        &self,
+        model: &str,
+        compatible: &[String],
+    ) -> Result<Vec<&DeviceEntry>, MigError> {
+        let mut found: Vec<&DeviceEntry> = Vec::new();
+        for device in &self.devices {
+            if device.is_model(model, compatible)? {
+                found.push(device);
+            }
+        }
+        Ok(found)
+    }
+
+    pub fn find_by_dmi(
+        &self,
+        arch: DeviceArch,
+        dmi_info: &DmiInfo,
+    ) -> Result<Vec<&DeviceEntry>, MigError> {
+        let mut found: Vec<&DeviceEntry> = Vec::new();
+        for device in &self.devices {
+            if device.arch == arch && device.is_dmi(dmi_info)? {
+                found.push(device);
+            }
+        }
+        Ok(found)
+    }
+
+    // devices that are identified by their architecture only
+    pub fn find_by_arch(&self, arch: DeviceArch) -> Vec<&DeviceEntry> {
+        self.devices
+            .iter()
+            .filter(|device| {
+                device.arch == arch
+                    && device.model.is_none()
+                    && device.compatible.is_none()
+                    && device.dmi.is_none()
+            })
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    pub fn find_by_slug(&self, slug: &str) -> Option<&DeviceEntry> {
+        self.devices.iter().find(|device| device.slug == slug)
+    }
+}
+
+// print the device catalog, including devices.yml in the given work directory
+pub(crate) fn list_devices() -> Result<(), MigError> {
+    let arg_matches = App::new("balena-list-devices")
+        .version(VERSION)
+        .author("Thomas Runte <thomasr@balena.io>")
+        .about("Lists the devices supported by balena-migrate")
+        .arg(
+            Arg::with_name("work_dir")
+                .short("w")
+                .long("work_dir")
+                .value_name("DIR")
+                .help("Work directory containing additional device definitions"),
+        )
+        .get_matches();
+
+    let work_dir = arg_matches.value_of("work_dir").map(PathBuf::from);
+    let catalog = DeviceCatalog::load(work_dir.as_deref())?;
+
+    println!("{:<24} {:<8} {:<10} NAME", "SLUG", "ARCH", "BOOT");
+    for device in catalog.get_devices() {
+        println!(
+            "{:<24} {:<8} {:<10} {}",
+            device.slug,
+            device.arch.to_string(),
+            device.boot_manager.to_string(),
+            device.name
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeviceArch, DeviceCatalog, DeviceEntry, DmiInfo};
+    use crate::common::os_policy::{OsIdent, OsSupport};
+
+    fn os_support(device: &DeviceEntry, os_release: &str) -> OsSupport {
+        device
+            .get_os_support(&OsIdent::from_os_release(os_release))
+            .unwrap()
+            .0
+    }
+
+    fn model_names(catalog: &DeviceCatalog, model: &str) -> Vec<String> {
+        catalog
+            .find_by_model(model, &[])
+            .unwrap()
+            .iter()
+            .map(|device| device.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn match_builtin_devices() {
+        let catalog = DeviceCatalog::load(None).unwrap();
+
+        assert_eq!(
+            model_names(&catalog, "Raspberry Pi 3 Model B Plus Rev 1.3"),
+            vec!["Raspberry Pi 3", "Raspberry Pi 3 (64 bit)"]
+        );
+        assert_eq!(
+            model_names(&catalog, "Raspberry Pi 2 Model B Rev 1.1"),
+            vec!["Raspberry Pi 2"]
+        );
+        assert_eq!(
+            model_names(&catalog, "Raspberry Pi Zero W Rev 1.1"),
+            vec!["Raspberry Pi Zero"]
+        );
+        assert_eq!(
+            model_names(&catalog, "Raspberry Pi Zero 2 W Rev 1.0"),
+            vec!["Raspberry Pi Zero 2 W"]
+        );
+        assert_eq!(
+            model_names(&catalog, "Raspberry Pi Compute Module 4 Rev 1.0"),
+            vec!["Raspberry Pi Compute Module 4"]
+        );
+        assert_eq!(
+            model_names(&catalog, "Raspberry Pi Compute Module 3 Plus Rev 1.0"),
+            vec!["Raspberry Pi Compute Module 3"]
+        );
+        assert_eq!(
+            model_names(&catalog, "Raspberry Pi 400 Rev 1.0"),
+            vec!["Raspberry Pi 400"]
+        );
+        assert!(model_names(&catalog, "Raspberry Pi Model B Rev 2").is_empty());
+        assert_eq!(
+            model_names(&catalog, "TI AM335x BeagleBone"),
+            vec!["Beaglebone Green"]
+        );
+        assert_eq!(
+            model_names(&catalog, "TI AM335x BeagleBone Black"),
+            vec!["Beaglebone Black"]
+        );
+        assert_eq!(
+            model_names(&catalog, "TI OMAP3 BeagleBoard xM"),
+            vec!["Beagleboard xM"]
+        );
+
+        let generic = catalog.find_by_arch(DeviceArch::AMD64);
+        assert_eq!(generic.len(), 1);
+        assert_eq!(generic[0].slug, "generic-amd64");
+
+        let mut dmi_info = DmiInfo {
+            sys_vendor: String::from("Intel(R) Client Systems"),
+            product_name: String::from("NUC7i5BNH"),
+            board_vendor: String::from("Intel Corporation"),
+            board_name: String::from("NUC7i5BNB"),
+        };
+        let nuc = catalog.find_by_dmi(DeviceArch::AMD64, &dmi_info).unwrap();
+        assert_eq!(nuc.len(), 1);
+        assert_eq!(nuc[0].slug, "intel-nuc");
+        dmi_info.product_name = String::from("VirtualBox");
+        assert!(catalog
+            .find_by_dmi(DeviceArch::AMD64, &dmi_info)
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            os_support(nuc[0], "ID=ubuntu\nID_LIKE=debian\nVERSION_ID=\"18.04\"\n"),
+            OsSupport::Supported
+        );
+        assert_eq!(
+            os_support(
+                nuc[0],
+                "ID=linuxmint\nID_LIKE=\"ubuntu debian\"\nVERSION_ID=\"19\"\n"
+            ),
+            OsSupport::Untested
+        );
+        assert_eq!(
+            os_support(nuc[0], "ID=fedora\nVERSION_ID=30\n"),
+            OsSupport::Untested
+        );
+        let rpi4 = catalog.find_by_slug("raspberrypi4-64").unwrap();
+        assert_eq!(
+            os_support(rpi4, "ID=raspbian\nID_LIKE=debian\nVERSION_ID=\"12\"\n"),
+            OsSupport::Supported
+        );
+        assert_eq!(
+            os_support(rpi4, "ID=ubuntu-core\nID_LIKE=ubuntu\nVERSION_ID=\"22\"\n"),
+            OsSupport::Blocked
+        );
+    }
+
+    #[test]
+    fn match_dmi() {
+        let catalog = DeviceCatalog::from_str(
+            r##"
+- name: Some Board
+  slug: some-board
+  arch: amd64
+  dmi:
+    board_vendor: '^Some Vendor$'
+    board_name: '^SB-\d+$'
+  boot_manager:
+    grub:
+      prefer_efi: true
+- name: Bad Regex
+  slug: bad-regex
+  arch: amd64
+  dmi:
+    product_name: '('
+  boot_manager:
+    grub:
+      prefer_efi: true
+- name: No DMI
+  slug: no-dmi
+  arch: amd64
+  boot_manager:
+    grub:
+      prefer_efi: true
+"##,
+        )
+        .unwrap();
+        let devices = catalog.get_devices();
+
+        let mut dmi_info = DmiInfo {
+            sys_vendor: String::from("Anything"),
+            product_name: String::from("Anything"),
+            board_vendor: String::from("Some Vendor"),
+            board_name: String::from("SB-100"),
+        };
+
+        // strings without a regex are not checked, all given regexes have to match
+        assert!(devices[0].is_dmi(&dmi_info).unwrap());
+        assert!(!devices[2].is_dmi(&dmi_info).unwrap());
+        assert!(devices[1].is_dmi(&dmi_info).is_err());
+        dmi_info.board_name = String::from("SB-X");
+        assert!(!devices[0].is_dmi(&dmi_info).unwrap());
+        dmi_info.board_name = String::from("SB-100");
+        dmi_info.board_vendor = String::new();
+        assert!(!devices[0].is_dmi(&dmi_info).unwrap());
+
+        // the architecture has to match as well
+        dmi_info.board_vendor = String::from("Some Vendor");
+        assert!(catalog
+            .find_by_dmi(DeviceArch::AARCH64, &dmi_info)
+            .unwrap()
+            .is_empty());
+        assert!(catalog.find_by_dmi(DeviceArch::AMD64, &dmi_info).is_err());
+    }
+
+    #[test]
+    fn extend_catalog() {
+        let mut catalog = DeviceCatalog::load(None).unwrap();
+        let count = catalog.get_devices().len();
+
+        catalog.extend(
+            DeviceCatalog::from_str(
+                r##"
+- name: Raspberry Pi 4
+  slug: raspberrypi4-64
+  arch: aarch64
+  compatible: raspberrypi,4-model-b
+  boot_manager:
+    raspi:
+      dtb_files: [ bcm2711-rpi-4-b.dtb ]
+- name: Custom Board
+  slug: custom-board
+  arch: armhf
+  model: '^Custom Board$'
+  boot_manager:
+    u-boot:
+      mmc_index: 0
+      dtb_file: custom.dtb
+  kernel_opts: rootwait
+"##,
+            )
+            .unwrap(),
+        );
+
+        assert_eq!(catalog.get_devices().len(), count + 1);
+        assert_eq!(catalog.get_devices()[0].slug, "custom-board");
+        assert_eq!(catalog.get_devices()[0].get_kernel_opts(), "rootwait");
+        assert!(model_names(&catalog, "Raspberry Pi 4 Model B Rev 1.4").is_empty());
+        assert_eq!(
+            catalog
+                .find_by_model("", &[String::from("raspberrypi,4-model-b")])
+                .unwrap()[0]
+                .name,
+            "Raspberry Pi 4"
+        );
+    }
+}
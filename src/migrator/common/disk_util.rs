@@ -9,7 +9,9 @@ use crate::{
 };
 
 mod image_file;
-pub(crate) use image_file::ImageFile;
+pub(crate) use image_file::{ImageFile, ImageFormat};
+
+mod inflate;
 
 mod gzip_file;
 pub(crate) use gzip_file::GZipFile;
@@ -17,9 +19,25 @@ pub(crate) use gzip_file::GZipFile;
 mod plain_file;
 pub(crate) use plain_file::PlainFile;
 
-// TODO: implement GPT partition
+mod zip_file;
+pub(crate) use zip_file::ZipFile;
 
-#[derive(Debug)]
+mod stream_file;
+pub(crate) use stream_file::{StreamFile, StreamType};
+
+mod gpt_table;
+pub(crate) use gpt_table::Guid;
+use gpt_table::{read_gpt, GptPartition, GptTable};
+
+// GPT partition type GUIDs
+pub(crate) const GPT_TYPE_EFI_SYSTEM: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+pub(crate) const GPT_TYPE_MS_BASIC_DATA: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+pub(crate) const GPT_TYPE_LINUX_FS: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+
+// MBR partition type of the protective MBR, also reported for partitions read from a GPT
+const GPT_PROTECTIVE_PTYPE: u8 = 0xee;
+
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum PartitionType {
     Container,
     Fat,
@@ -42,6 +60,20 @@ impl PartitionType {
             _ => PartitionType::Other,
         }
     }
+
+    pub fn from_type_guid(type_guid: &Guid) -> PartitionType {
+        if type_guid.is_nil() {
+            PartitionType::Empty
+        } else if Some(*type_guid) == Guid::from_str(GPT_TYPE_EFI_SYSTEM)
+            || Some(*type_guid) == Guid::from_str(GPT_TYPE_MS_BASIC_DATA)
+        {
+            PartitionType::Fat
+        } else if Some(*type_guid) == Guid::from_str(GPT_TYPE_LINUX_FS) {
+            PartitionType::Linux
+        } else {
+            PartitionType::Other
+        }
+    }
 }
 
 #[repr(C, packed)]
@@ -95,10 +127,38 @@ impl MasterBootRecord {
 #[derive(Debug, Clone)]
 pub(crate) struct PartInfo {
     pub index: usize,
+    // MBR partition type, GPT_PROTECTIVE_PTYPE for GPT partitions
     pub ptype: u8,
     pub status: u8,
     pub start_lba: u64,
     pub num_sectors: u64,
+    // only set for GPT partitions
+    pub type_guid: Option<Guid>,
+    pub part_guid: Option<Guid>,
+    pub name: Option<String>,
+}
+
+impl PartInfo {
+    fn from_gpt_partition(part_idx: usize, partition: &GptPartition) -> PartInfo {
+        PartInfo {
+            index: part_idx,
+            ptype: GPT_PROTECTIVE_PTYPE,
+            status: 0,
+            start_lba: partition.first_lba,
+            num_sectors: partition.last_lba + 1 - partition.first_lba,
+            type_guid: Some(partition.type_guid),
+            part_guid: Some(partition.part_guid),
+            name: Some(partition.name.clone()),
+        }
+    }
+
+    pub fn get_part_type(&self) -> PartitionType {
+        if let Some(ref type_guid) = self.type_guid {
+            PartitionType::from_type_guid(type_guid)
+        } else {
+            PartitionType::from_ptype(self.ptype)
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -123,9 +183,28 @@ pub(crate) struct Disk {
 }
 
 impl Disk {
-    pub fn from_gzip_img<P: AsRef<Path>>(image: P) -> Result<Disk, MigError> {
+    // with cache_index set the gzip access point index is cached next to the image
+    pub fn from_gzip_img<P: AsRef<Path>>(image: P, cache_index: bool) -> Result<Disk, MigError> {
         Ok(Disk {
-            disk: Box::new(GZipFile::new(image.as_ref())?),
+            disk: Box::new(GZipFile::new(image.as_ref(), cache_index)?),
+            // writable: false,
+            block_size: DEF_BLOCK_SIZE as u64,
+        })
+    }
+
+    // open a plain or compressed OS image, the container format is detected from the file
+    pub fn from_image<P: AsRef<Path>>(image: P, cache_index: bool) -> Result<Disk, MigError> {
+        let image = image.as_ref();
+        let disk: Box<dyn ImageFile> = match ImageFormat::from_file(image)? {
+            ImageFormat::Plain => return Disk::from_drive_file(image, None),
+            ImageFormat::GZip => return Disk::from_gzip_img(image, cache_index),
+            ImageFormat::Zip => Box::new(ZipFile::new(image)?),
+            ImageFormat::Xz => Box::new(StreamFile::new(image, StreamType::Xz)?),
+            ImageFormat::Zstd => Box::new(StreamFile::new(image, StreamType::Zstd)?),
+        };
+
+        Ok(Disk {
+            disk,
             // writable: false,
             block_size: DEF_BLOCK_SIZE as u64,
         })
@@ -188,6 +267,19 @@ impl Disk {
         Ok(mbr)
     }
 
+    fn read_gpt(&mut self, mbr: &MasterBootRecord) -> Result<GptTable, MigError> {
+        // the protective partition spans the whole disk, so it ends on the backup header,
+        // unless the disk is too big to be described in the MBR
+        let prot_part = &mbr.part_tbl[0];
+        let backup_lba = if prot_part.num_sectors != 0xFFFF_FFFF {
+            Some(u64::from(prot_part.first_lba) + u64::from(prot_part.num_sectors) - 1)
+        } else {
+            None
+        };
+
+        read_gpt(self.disk.as_mut(), self.block_size, backup_lba)
+    }
+
     /*
         pub fn get_partition_iterator(&mut self) -> Result<PartitionIterator, MigError> {
             Ok(PartitionIterator::new(self)?)
@@ -212,6 +304,7 @@ pub(crate) struct PartitionIterator<'a> {
     index: usize,
     part_idx: usize,
     disk_id: Option<u32>,
+    gpt: Option<GptTable>,
 }
 
 impl<'a> PartitionIterator<'a> {
@@ -220,6 +313,18 @@ impl<'a> PartitionIterator<'a> {
         let mbr = disk.read_mbr(offset)?;
         let disk_id = mbr.get_disk_id();
 
+        let gpt = if let PartitionType::GPT = PartitionType::from_ptype(mbr.part_tbl[0].ptype) {
+            let gpt = disk.read_gpt(&mbr)?;
+            debug!(
+                "PartitionIterator::new: found GPT with disk guid {}, {} partitions",
+                gpt.disk_guid,
+                gpt.partitions.len()
+            );
+            Some(gpt)
+        } else {
+            None
+        };
+
         Ok(PartitionIterator {
             disk,
             mbr: Some(mbr),
@@ -227,6 +332,7 @@ impl<'a> PartitionIterator<'a> {
             index: 0,
             part_idx: 0,
             disk_id,
+            gpt,
         })
     }
 
@@ -234,6 +340,29 @@ impl<'a> PartitionIterator<'a> {
     pub fn get_disk_id(&'a self) -> &'a Option<u32> {
         &self.disk_id
     }
+
+    #[allow(dead_code)]
+    pub fn get_disk_guid(&'a self) -> Option<&'a Guid> {
+        if let Some(ref gpt) = self.gpt {
+            Some(&gpt.disk_guid)
+        } else {
+            None
+        }
+    }
+
+    fn next_gpt(&mut self) -> Option<PartInfo> {
+        if let Some(ref gpt) = self.gpt {
+            if let Some(partition) = gpt.partitions.get(self.index) {
+                self.index += 1;
+                self.part_idx += 1;
+                Some(PartInfo::from_gpt_partition(self.part_idx, partition))
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
 }
 
 // TODO: make functions for partition type:
@@ -249,6 +378,10 @@ impl<'a> Iterator for PartitionIterator<'a> {
         trace!("PartitionIterator::next: entered");
         // TODO: check for 0 size partition ?
 
+        if self.gpt.is_some() {
+            return self.next_gpt();
+        }
+
         #[allow(clippy::large_enum_variant)] //TODO refactor to remove clippy warning
         enum SetMbr {
             Leave,
@@ -289,6 +422,9 @@ impl<'a> Iterator for PartitionIterator<'a> {
                                     status: part.status,
                                     start_lba: u64::from(part.first_lba),
                                     num_sectors: u64::from(part.num_sectors),
+                                    type_guid: None,
+                                    part_guid: None,
+                                    name: None,
                                 }),
                                 SetMbr::ToNone,
                             )
@@ -305,6 +441,9 @@ impl<'a> Iterator for PartitionIterator<'a> {
                                     status: part.status,
                                     start_lba: u64::from(part.first_lba),
                                     num_sectors: u64::from(part.num_sectors),
+                                    type_guid: None,
+                                    part_guid: None,
+                                    name: None,
                                 }),
                                 SetMbr::Leave,
                             )
@@ -351,6 +490,9 @@ impl<'a> Iterator for PartitionIterator<'a> {
                                                     start_lba: self.offset
                                                         + u64::from(part.first_lba),
                                                     num_sectors: u64::from(part.num_sectors),
+                                                    type_guid: None,
+                                                    part_guid: None,
+                                                    name: None,
                                                 }),
                                                 SetMbr::ToMbr(mbr),
                                             )
@@ -411,6 +553,9 @@ impl<'a> Iterator for PartitionIterator<'a> {
                                     status: part.status,
                                     start_lba: self.offset + u64::from(part.first_lba),
                                     num_sectors: u64::from(part.num_sectors),
+                                    type_guid: None,
+                                    part_guid: None,
+                                    name: None,
                                 }),
                                 SetMbr::ToMbr(mbr),
                             )
@@ -528,15 +673,151 @@ impl<'a> Read for PartitionReader<'a> {
 #[cfg(test)]
 mod test {
 
+    use flate2::Crc;
     use mod_logger::{Level, Logger};
+    use std::fs::{read, remove_file, write, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
 
     use crate::common::disk_util::PartitionIterator;
-    use crate::common::disk_util::{Disk, LabelType};
+    use crate::common::disk_util::{
+        Disk, Guid, LabelType, PartitionType, GPT_TYPE_EFI_SYSTEM, GPT_TYPE_LINUX_FS,
+    };
+
+    const GPT_DISK_BLOCKS: u64 = 128;
+    const GPT_NUM_ENTRIES: u32 = 128;
+    const GPT_ENTRY_BLOCKS: u64 = 32;
+    const GPT_PARTS: &[(&str, &str, u64, u64)] = &[
+        (GPT_TYPE_EFI_SYSTEM, "resin-boot", 40, 63),
+        (GPT_TYPE_LINUX_FS, "resin-rootA", 64, 94),
+    ];
+
+    fn crc32(buffer: &[u8]) -> u32 {
+        let mut crc = Crc::new();
+        crc.update(buffer);
+        crc.sum()
+    }
+
+    fn make_gpt_header(my_lba: u64, alt_lba: u64, entry_lba: u64, entries_crc: u32) -> Vec<u8> {
+        let mut header: Vec<u8> = Vec::new();
+        header.extend_from_slice(b"EFI PART");
+        header.extend_from_slice(&0x0001_0000u32.to_le_bytes());
+        header.extend_from_slice(&92u32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes()); // crc
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&my_lba.to_le_bytes());
+        header.extend_from_slice(&alt_lba.to_le_bytes());
+        header.extend_from_slice(&34u64.to_le_bytes());
+        header.extend_from_slice(&(GPT_DISK_BLOCKS - 34).to_le_bytes());
+        header.extend_from_slice(
+            Guid::from_str("11111111-2222-3333-4444-555555555555")
+                .unwrap()
+                .as_bytes(),
+        );
+        header.extend_from_slice(&entry_lba.to_le_bytes());
+        header.extend_from_slice(&GPT_NUM_ENTRIES.to_le_bytes());
+        header.extend_from_slice(&128u32.to_le_bytes());
+        header.extend_from_slice(&entries_crc.to_le_bytes());
+        let crc = crc32(&header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+
+    // create a disk image with a protective MBR, primary & backup GPT
+    fn make_gpt_image(name: &str, corrupt_primary: bool) -> PathBuf {
+        make_gpt_image_with(name, GPT_PARTS, corrupt_primary)
+    }
+
+    fn make_gpt_image_with(
+        name: &str,
+        parts: &[(&str, &str, u64, u64)],
+        corrupt_primary: bool,
+    ) -> PathBuf {
+        let block_size = 512usize;
+        let mut image: Vec<u8> = vec![0; GPT_DISK_BLOCKS as usize * block_size];
+
+        // protective MBR
+        let mbr_part = &mut image[446..462];
+        mbr_part[4] = 0xee;
+        mbr_part[8..12].copy_from_slice(&1u32.to_le_bytes());
+        mbr_part[12..16].copy_from_slice(&(GPT_DISK_BLOCKS as u32 - 1).to_le_bytes());
+        image[510] = 0x55;
+        image[511] = 0xAA;
+
+        let mut entries: Vec<u8> = vec![0; GPT_NUM_ENTRIES as usize * 128];
+        for (idx, (type_guid, name, first_lba, last_lba)) in parts.iter().enumerate() {
+            let entry = &mut entries[idx * 128..(idx + 1) * 128];
+            entry[0..16].copy_from_slice(Guid::from_str(type_guid).unwrap().as_bytes());
+            entry[16] = idx as u8 + 1;
+            entry[32..40].copy_from_slice(&first_lba.to_le_bytes());
+            entry[40..48].copy_from_slice(&last_lba.to_le_bytes());
+            for (c_idx, c) in name.encode_utf16().enumerate() {
+                entry[56 + c_idx * 2..58 + c_idx * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        let entries_crc = crc32(&entries);
+
+        let backup_lba = GPT_DISK_BLOCKS - 1;
+        let backup_entry_lba = backup_lba - GPT_ENTRY_BLOCKS;
+
+        let primary = make_gpt_header(1, backup_lba, 2, entries_crc);
+        let backup = make_gpt_header(backup_lba, 1, backup_entry_lba, entries_crc);
+
+        image[block_size..block_size + primary.len()].copy_from_slice(&primary);
+        let offset = 2 * block_size;
+        image[offset..offset + entries.len()].copy_from_slice(&entries);
+        let offset = backup_entry_lba as usize * block_size;
+        image[offset..offset + entries.len()].copy_from_slice(&entries);
+        let offset = backup_lba as usize * block_size;
+        image[offset..offset + backup.len()].copy_from_slice(&backup);
+
+        if corrupt_primary {
+            image[block_size + 40] ^= 0xFF;
+        }
+
+        let path = std::env::temp_dir().join(name);
+        write(&path, &image).unwrap();
+        path
+    }
+
+    fn check_gpt_image(path: &PathBuf) {
+        check_gpt_disk(Disk::from_drive_file(path, None).unwrap());
+    }
+
+    fn check_gpt_disk(mut disk: Disk) {
+        if let LabelType::GPT = disk.get_label().unwrap() {
+            let iterator = PartitionIterator::new(&mut disk).unwrap();
+            let partitions: Vec<_> = iterator.collect();
+            assert_eq!(partitions.len(), GPT_PARTS.len());
+            assert_eq!(partitions[0].get_part_type(), PartitionType::Fat);
+            assert_eq!(partitions[0].name, Some(String::from("resin-boot")));
+            assert_eq!(partitions[0].start_lba, 40);
+            assert_eq!(partitions[0].num_sectors, 24);
+            assert_eq!(
+                partitions[0].part_guid.unwrap().to_string(),
+                "00000001-0000-0000-0000-000000000000"
+            );
+            assert_eq!(partitions[1].get_part_type(), PartitionType::Linux);
+            assert_eq!(partitions[1].name, Some(String::from("resin-rootA")));
+            assert_eq!(partitions[1].index, 2);
+            assert_eq!(
+                partitions[1].type_guid.unwrap().to_string(),
+                GPT_TYPE_LINUX_FS
+            );
+            assert_eq!(
+                partitions[1].part_guid.unwrap().to_string(),
+                "00000002-0000-0000-0000-000000000000"
+            );
+        } else {
+            panic!("Invalid label type - not GPT");
+        }
+    }
 
     #[test]
     fn read_gzipped_part() {
         Logger::set_default_level(&Level::Debug);
-        let mut disk = Disk::from_gzip_img("./test_data/part.img.gz").unwrap();
+        let mut disk = Disk::from_gzip_img("./test_data/part.img.gz", false).unwrap();
         if let LabelType::Dos = disk.get_label().unwrap() {
             let mut count = 0;
             let iterator = PartitionIterator::new(&mut disk).unwrap();
@@ -553,4 +834,73 @@ mod test {
             panic!("Invalid label type - not Dos");
         }
     }
+
+    #[test]
+    fn read_gpt_part() {
+        let path = make_gpt_image("balena-migrate-test-gpt.img", false);
+        check_gpt_image(&path);
+        let _res = remove_file(&path);
+    }
+
+    #[test]
+    fn read_gpt_backup() {
+        let path = make_gpt_image("balena-migrate-test-gpt-bckup.img", true);
+        check_gpt_image(&path);
+        let _res = remove_file(&path);
+    }
+
+    #[test]
+    fn reject_gpt_ranges() {
+        // reversed and outside the usable area, both tables carry valid checksums
+        for parts in &[
+            [(GPT_TYPE_LINUX_FS, "reversed", 64, 40)],
+            [(GPT_TYPE_LINUX_FS, "outside", 2, 63)],
+            [(GPT_TYPE_LINUX_FS, "outside", 40, GPT_DISK_BLOCKS)],
+        ] {
+            let path = make_gpt_image_with("balena-migrate-test-gpt-range.img", parts, false);
+            let mut disk = Disk::from_drive_file(&path, None).unwrap();
+            let res = PartitionIterator::new(&mut disk);
+            assert!(res.is_err());
+            drop(res);
+            let _res = remove_file(&path);
+        }
+    }
+
+    #[test]
+    fn read_compressed_gpt_part() {
+        let path = make_gpt_image("balena-migrate-test-gpt-compr.img", false);
+        let image = read(&path).unwrap();
+
+        for method in &[CompressionMethod::Stored, CompressionMethod::Deflated] {
+            let zip_path = path.with_extension("zip");
+            let mut writer = ZipWriter::new(File::create(&zip_path).unwrap());
+            writer
+                .start_file(
+                    "balena.img",
+                    FileOptions::default().compression_method(*method),
+                )
+                .unwrap();
+            writer.write_all(&image).unwrap();
+            writer.finish().unwrap();
+            check_gpt_disk(Disk::from_image(&zip_path, false).unwrap());
+            let _res = remove_file(&zip_path);
+        }
+
+        let xz_path = path.with_extension("xz");
+        let mut encoder = xz2::write::XzEncoder::new(File::create(&xz_path).unwrap(), 6);
+        encoder.write_all(&image).unwrap();
+        encoder.finish().unwrap();
+        check_gpt_disk(Disk::from_image(&xz_path, false).unwrap());
+        let _res = remove_file(&xz_path);
+
+        let zstd_path = path.with_extension("zst");
+        let mut encoder =
+            zstd::stream::write::Encoder::new(File::create(&zstd_path).unwrap(), 3).unwrap();
+        encoder.write_all(&image).unwrap();
+        encoder.finish().unwrap();
+        check_gpt_disk(Disk::from_image(&zstd_path, false).unwrap());
+        let _res = remove_file(&zstd_path);
+
+        let _res = remove_file(&path);
+    }
 }
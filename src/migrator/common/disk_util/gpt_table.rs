@@ -0,0 +1,339 @@
+use flate2::Crc;
+use log::{debug, trace, warn};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::mem;
+
+use crate::common::{disk_util::image_file::ImageFile, MigError, MigErrorKind};
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HDR_BLOCK_SIZE: usize = 512;
+const GPT_MIN_HDR_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+// entries are 128 bytes in practice, bigger entries only come from corrupt headers
+const GPT_MAX_ENTRY_SIZE: usize = 4096;
+// the spec requires at least 16KiB for the entry array, anything bigger than this is bogus
+const GPT_MAX_ENTRIES: usize = 1024;
+// offset of header_crc32 in GptHeader
+const GPT_HDR_CRC_OFFSET: usize = 16;
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct Guid([u8; 16]);
+
+impl Guid {
+    pub fn from_bytes(bytes: [u8; 16]) -> Guid {
+        Guid(bytes)
+    }
+
+    // parse a GUID from its canonical string form, eg. C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+    pub fn from_str(guid: &str) -> Option<Guid> {
+        let hex: Vec<char> = guid.chars().filter(|c| *c != '-').collect();
+        if hex.len() != 32 || guid.len() != 36 {
+            return None;
+        }
+
+        let mut raw: [u8; 16] = [0; 16];
+        for (idx, byte) in raw.iter_mut().enumerate() {
+            let digits: String = hex[idx * 2..idx * 2 + 2].iter().collect();
+            *byte = u8::from_str_radix(&digits, 16).ok()?;
+        }
+
+        // the first three fields are stored little endian
+        let mut bytes = raw;
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        Some(Guid(bytes))
+    }
+
+    #[allow(dead_code)]
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0.iter().all(|byte| *byte == 0)
+    }
+}
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9], b[10], b[11], b[12], b[13],
+            b[14], b[15]
+        )
+    }
+}
+
+impl Debug for Guid {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+#[repr(C, packed)]
+struct GptHeader {
+    signature: [u8; 8],
+    revision: u32,
+    header_size: u32,
+    header_crc32: u32,
+    reserved: u32,
+    my_lba: u64,
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: [u8; 16],
+    part_entry_lba: u64,
+    num_part_entries: u32,
+    part_entry_size: u32,
+    part_entry_array_crc32: u32,
+    reserved2: [u8; 420],
+}
+
+#[repr(C, packed)]
+struct GptPartEntry {
+    type_guid: [u8; 16],
+    part_guid: [u8; 16],
+    first_lba: u64,
+    last_lba: u64,
+    attributes: u64,
+    name: [u16; 36],
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct GptPartition {
+    pub type_guid: Guid,
+    pub part_guid: Guid,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub name: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct GptTable {
+    pub disk_guid: Guid,
+    pub partitions: Vec<GptPartition>,
+}
+
+fn crc32(buffer: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(buffer);
+    crc.sum()
+}
+
+fn read_header(
+    image: &mut dyn ImageFile,
+    lba: u64,
+    block_size: u64,
+) -> Result<GptHeader, MigError> {
+    trace!("read_header: entered with lba: {}", lba);
+    let mut buffer: [u8; GPT_HDR_BLOCK_SIZE] = [0; GPT_HDR_BLOCK_SIZE];
+    image.fill(lba * block_size, &mut buffer)?;
+
+    if &buffer[0..8] != GPT_SIGNATURE {
+        return Err(MigError::from_remark(
+            MigErrorKind::InvParam,
+            &format!("No GPT header signature found at lba {}", lba),
+        ));
+    }
+
+    let header: GptHeader = unsafe { mem::transmute(buffer) };
+
+    let header_size = header.header_size as usize;
+    if !(GPT_MIN_HDR_SIZE..=GPT_HDR_BLOCK_SIZE).contains(&header_size) {
+        return Err(MigError::from_remark(
+            MigErrorKind::InvParam,
+            &format!(
+                "Invalid GPT header size {} encountered at lba {}",
+                header_size, lba
+            ),
+        ));
+    }
+
+    // the checksum is calculated with the checksum field zeroed
+    let mut crc_buffer = buffer;
+    for byte in &mut crc_buffer[GPT_HDR_CRC_OFFSET..GPT_HDR_CRC_OFFSET + 4] {
+        *byte = 0;
+    }
+
+    let header_crc = header.header_crc32;
+    let calc_crc = crc32(&crc_buffer[0..header_size]);
+    if calc_crc != header_crc {
+        return Err(MigError::from_remark(
+            MigErrorKind::InvParam,
+            &format!(
+                "GPT header checksum mismatch at lba {}, expected {:x}, got {:x}",
+                lba, header_crc, calc_crc
+            ),
+        ));
+    }
+
+    let my_lba = header.my_lba;
+    if my_lba != lba {
+        return Err(MigError::from_remark(
+            MigErrorKind::InvParam,
+            &format!(
+                "GPT header at lba {} claims to be located at lba {}",
+                lba, my_lba
+            ),
+        ));
+    }
+
+    Ok(header)
+}
+
+fn read_entries(
+    image: &mut dyn ImageFile,
+    header: &GptHeader,
+    block_size: u64,
+) -> Result<Vec<GptPartition>, MigError> {
+    let num_entries = header.num_part_entries as usize;
+    let entry_size = header.part_entry_size as usize;
+    let entry_lba = header.part_entry_lba;
+
+    trace!(
+        "read_entries: entered with lba: {}, entries: {}, entry size: {}",
+        entry_lba,
+        num_entries,
+        entry_size
+    );
+
+    if !(GPT_MIN_ENTRY_SIZE..=GPT_MAX_ENTRY_SIZE).contains(&entry_size)
+        || !entry_size.is_multiple_of(GPT_MIN_ENTRY_SIZE)
+        || num_entries > GPT_MAX_ENTRIES
+    {
+        return Err(MigError::from_remark(
+            MigErrorKind::InvParam,
+            &format!(
+                "Invalid GPT partition entry array dimensions: {} entries of size {}",
+                num_entries, entry_size
+            ),
+        ));
+    }
+
+    let mut buffer: Vec<u8> = vec![0; num_entries * entry_size];
+    image.fill(entry_lba * block_size, &mut buffer)?;
+
+    let array_crc = header.part_entry_array_crc32;
+    let calc_crc = crc32(&buffer);
+    if calc_crc != array_crc {
+        return Err(MigError::from_remark(
+            MigErrorKind::InvParam,
+            &format!(
+                "GPT partition entry array checksum mismatch at lba {}, expected {:x}, got {:x}",
+                entry_lba, array_crc, calc_crc
+            ),
+        ));
+    }
+
+    let mut partitions: Vec<GptPartition> = Vec::new();
+    for raw_entry in buffer.chunks(entry_size) {
+        let mut entry_buf: [u8; GPT_MIN_ENTRY_SIZE] = [0; GPT_MIN_ENTRY_SIZE];
+        entry_buf.copy_from_slice(&raw_entry[0..GPT_MIN_ENTRY_SIZE]);
+        let entry: GptPartEntry = unsafe { mem::transmute(entry_buf) };
+
+        let type_guid = Guid::from_bytes(entry.type_guid);
+        if type_guid.is_nil() {
+            // unused entry
+            continue;
+        }
+
+        // partitions have to be ordered and within the usable area of the disk
+        let first_lba = entry.first_lba;
+        let last_lba = entry.last_lba;
+        let first_usable_lba = header.first_usable_lba;
+        let last_usable_lba = header.last_usable_lba;
+        if first_lba > last_lba
+            || !(first_usable_lba..=last_usable_lba).contains(&first_lba)
+            || last_lba > last_usable_lba
+        {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "Invalid GPT partition range {}-{} encountered, usable range is {}-{}",
+                    first_lba, last_lba, first_usable_lba, last_usable_lba
+                ),
+            ));
+        }
+
+        let name_utf16 = entry.name;
+        let name_len = name_utf16
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(name_utf16.len());
+
+        let partition = GptPartition {
+            type_guid,
+            part_guid: Guid::from_bytes(entry.part_guid),
+            first_lba,
+            last_lba,
+            name: String::from_utf16_lossy(&name_utf16[0..name_len]),
+        };
+
+        debug!("read_entries: found partition {:?}", partition);
+        partitions.push(partition);
+    }
+
+    Ok(partitions)
+}
+
+/******************************************************************
+ * Read the GPT from an image file.
+ * The primary header & entry array are tried first, falling back to
+ * the backup header on a failed signature or checksum test.
+ * backup_lba is used as location of the backup header, if the primary
+ * header is unusable. It is typically derived from the protective MBR.
+ ******************************************************************/
+
+pub(crate) fn read_gpt(
+    image: &mut dyn ImageFile,
+    block_size: u64,
+    backup_lba: Option<u64>,
+) -> Result<GptTable, MigError> {
+    trace!(
+        "read_gpt: entered with block_size: {}, backup_lba: {:?}",
+        block_size,
+        backup_lba
+    );
+
+    let backup_lba = match read_header(image, 1, block_size) {
+        Ok(header) => match read_entries(image, &header, block_size) {
+            Ok(partitions) => {
+                return Ok(GptTable {
+                    disk_guid: Guid::from_bytes(header.disk_guid),
+                    partitions,
+                });
+            }
+            Err(why) => {
+                warn!(
+                    "Failed to read primary GPT partition entries, trying backup, error: {}",
+                    why
+                );
+                Some(header.alternate_lba)
+            }
+        },
+        Err(why) => {
+            warn!(
+                "Failed to read primary GPT header, trying backup, error: {}",
+                why
+            );
+            backup_lba
+        }
+    };
+
+    if let Some(backup_lba) = backup_lba {
+        let header = read_header(image, backup_lba, block_size)?;
+        let partitions = read_entries(image, &header, block_size)?;
+        Ok(GptTable {
+            disk_guid: Guid::from_bytes(header.disk_guid),
+            partitions,
+        })
+    } else {
+        Err(MigError::from_remark(
+            MigErrorKind::InvParam,
+            "No valid GPT header was found and the backup header location is unknown",
+        ))
+    }
+}
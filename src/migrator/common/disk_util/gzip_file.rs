@@ -1,23 +1,59 @@
-use flate2::read::GzDecoder;
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use std::fs::{File, OpenOptions};
-use std::io::Read;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
-const DEF_READ_BUFFER: usize = 1024 * 1024;
+// distance between access points in the uncompressed stream
+#[cfg(not(test))]
+const DEF_INDEX_SPAN: u64 = 8 * 1024 * 1024;
+#[cfg(test)]
+const DEF_INDEX_SPAN: u64 = 256 * 1024;
+const INDEX_FILE_EXT: &str = "gzidx";
+const INDEX_MAGIC: &[u8; 8] = b"BMGZIDX1";
 
-use crate::common::{disk_util::image_file::ImageFile, MigError, MigErrorKind};
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const GZIP_METHOD_DEFLATE: u8 = 8;
+const GZIP_FHCRC: u8 = 0x02;
+const GZIP_FEXTRA: u8 = 0x04;
+const GZIP_FNAME: u8 = 0x08;
+const GZIP_FCOMMENT: u8 = 0x10;
+
+use crate::common::{
+    disk_util::{
+        image_file::ImageFile,
+        inflate::{Checkpoint, Inflater, WINDOW_SIZE},
+    },
+    MigError, MigErrorKind,
+};
+
+/******************************************************************
+ * Random access to gzipped images.
+ * While decompressing, an access point (the input bit position and the
+ * 32KiB inflate window) is recorded every DEF_INDEX_SPAN bytes.
+ * Seeks resume from the closest access point instead of decompressing
+ * from the start. The index can optionally be cached next to the image
+ * in <image>.gzidx, where it is reused as long as the image is unchanged.
+ * CRC32 and size from the gzip trailer are verified when the image was
+ * decompressed sequentially up to its end.
+ ******************************************************************/
 
 pub(crate) struct GZipFile {
     path: PathBuf,
-    decoder: GzDecoder<File>,
-    bytes_read: u64,
+    inflater: Inflater<File>,
+    index_path: Option<PathBuf>,
+    index_loaded: usize,
 }
 
 impl GZipFile {
-    pub fn new(path: &Path) -> Result<GZipFile, MigError> {
-        trace!("new: entered with '{}'", path.display());
-        let file = match OpenOptions::new()
+    // with cache_index set the access point index is loaded from / stored to <image>.gzidx
+    pub fn new(path: &Path, cache_index: bool) -> Result<GZipFile, MigError> {
+        trace!(
+            "new: entered with '{}', cache_index: {}",
+            path.display(),
+            cache_index
+        );
+        let mut file = match OpenOptions::new()
             .write(false)
             .read(true)
             .create(false)
@@ -36,102 +72,146 @@ impl GZipFile {
             }
         };
 
-        Ok(GZipFile {
-            path: path.to_path_buf(),
-            decoder: GzDecoder::new(file),
-            bytes_read: 0,
-        })
-    }
+        let header_len = GZipFile::read_header(path, &mut file)?;
+        debug!(
+            "new: '{}' deflate stream starts at offset {}",
+            path.display(),
+            header_len
+        );
 
-    fn reset(&mut self) -> Result<(), MigError> {
-        trace!("reset: entered");
-        let file = match OpenOptions::new()
-            .write(false)
-            .read(true)
-            .create(false)
-            .open(&self.path)
-        {
-            Ok(file) => file,
+        let mut inflater = match Inflater::new(file, header_len, DEF_INDEX_SPAN) {
+            Ok(inflater) => inflater,
             Err(why) => {
                 return Err(MigError::from_remark(
                     MigErrorKind::Upstream,
                     &format!(
-                        "failed to reopen file for reading: '{}', error {:?}",
-                        self.path.display(),
+                        "failed to initialize decompression for file: '{}', error {:?}",
+                        path.display(),
                         why
                     ),
                 ));
             }
         };
 
-        self.decoder = GzDecoder::new(file);
-        self.bytes_read = 0;
-        Ok(())
-    }
+        inflater.set_gzip_trailer();
 
-    fn seek(&mut self, offset: u64) -> Result<(), MigError> {
-        trace!(
-            "seek: entered with offset {}, bytes_read: {}",
-            offset,
-            self.bytes_read
-        );
-        let mut to_read = if offset < self.bytes_read {
-            self.reset()?;
-            offset
+        let (index_path, index_loaded) = if cache_index {
+            let mut index_path = path.to_path_buf().into_os_string();
+            index_path.push(".");
+            index_path.push(INDEX_FILE_EXT);
+            let index_path = PathBuf::from(index_path);
+
+            let checkpoints = match read_index(&index_path, path) {
+                Ok(checkpoints) => checkpoints,
+                Err(why) => {
+                    debug!(
+                        "new: not using index file '{}', {:?}",
+                        index_path.display(),
+                        why
+                    );
+                    Vec::new()
+                }
+            };
+            let index_loaded = checkpoints.len();
+            debug!(
+                "new: loaded {} access points from '{}'",
+                index_loaded,
+                index_path.display()
+            );
+            inflater.set_checkpoints(checkpoints);
+            (Some(index_path), index_loaded)
         } else {
-            offset - self.bytes_read
+            (None, 0)
         };
 
-        trace!("to_read: {}", to_read);
+        Ok(GZipFile {
+            path: path.to_path_buf(),
+            inflater,
+            index_path,
+            index_loaded,
+        })
+    }
 
-        if to_read == 0 {
-            Ok(())
-        } else {
-            let mut buffer: [u8; DEF_READ_BUFFER] = [0; DEF_READ_BUFFER];
-            if to_read > (DEF_READ_BUFFER as u64) {
-                loop {
-                    match self.decoder.read(&mut buffer) {
-                        Ok(bytes_read) => {
-                            to_read -= bytes_read as u64;
-                            // debug!("bytes_read: {}, to_read:{}", bytes_read, to_read);
-                            if to_read < DEF_READ_BUFFER as u64 {
-                                debug!("done with DEF_BUFFER, to_read: {}", to_read);
-                                break;
-                            }
-                        }
-                        Err(why) => {
-                            return Err(MigError::from_remark(
-                                MigErrorKind::Upstream,
-                                &format!(
-                                    "failed to reopen file for reading: '{}', error {:?}",
-                                    self.path.display(),
-                                    why
-                                ),
-                            ));
+    // parse the gzip member header and return the offset of the deflate stream
+    fn read_header(path: &Path, file: &mut File) -> Result<u64, MigError> {
+        let mut reader = BufReader::new(file);
+        let mut header: [u8; 10] = [0; 10];
+        let mut header_len: u64 = header.len() as u64;
+
+        let res: Result<(), std::io::Error> = (|| {
+            reader.read_exact(&mut header)?;
+            if header[0..2] != GZIP_MAGIC || header[2] != GZIP_METHOD_DEFLATE {
+                return Ok(());
+            }
+
+            let flags = header[3];
+            if flags & GZIP_FEXTRA != 0 {
+                let mut xlen: [u8; 2] = [0; 2];
+                reader.read_exact(&mut xlen)?;
+                let xlen = u16::from_le_bytes(xlen) as usize;
+                let mut extra: Vec<u8> = vec![0; xlen];
+                reader.read_exact(&mut extra)?;
+                header_len += 2 + xlen as u64;
+            }
+
+            for flag in &[GZIP_FNAME, GZIP_FCOMMENT] {
+                if flags & flag != 0 {
+                    let mut byte: [u8; 1] = [0; 1];
+                    loop {
+                        reader.read_exact(&mut byte)?;
+                        header_len += 1;
+                        if byte[0] == 0 {
+                            break;
                         }
                     }
                 }
             }
 
-            if to_read > 0 {
-                debug!("last buffer, to_read: {}", to_read);
-                match self.decoder.read_exact(&mut buffer[0..to_read as usize]) {
-                    Ok(_) => {
-                        self.bytes_read = offset;
-                        Ok(())
-                    }
-                    Err(why) => Err(MigError::from_remark(
-                        MigErrorKind::Upstream,
-                        &format!(
-                            "failed to reopen file for reading: '{}', error {:?}",
-                            self.path.display(),
-                            why
-                        ),
-                    )),
+            if flags & GZIP_FHCRC != 0 {
+                header_len += 2;
+            }
+            Ok(())
+        })();
+
+        if let Err(why) = res {
+            return Err(MigError::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "failed to read gzip header from file: '{}', error {:?}",
+                    path.display(),
+                    why
+                ),
+            ));
+        }
+
+        if header[0..2] != GZIP_MAGIC || header[2] != GZIP_METHOD_DEFLATE {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("file '{}' is not a gzip file", path.display()),
+            ));
+        }
+
+        Ok(header_len)
+    }
+}
+
+impl Drop for GZipFile {
+    fn drop(&mut self) {
+        if let Some(ref index_path) = self.index_path {
+            let checkpoints = self.inflater.get_checkpoints();
+            if checkpoints.len() > self.index_loaded {
+                debug!(
+                    "drop: writing {} access points to '{}'",
+                    checkpoints.len(),
+                    index_path.display()
+                );
+                if let Err(why) = write_index(index_path, &self.path, checkpoints) {
+                    warn!(
+                        "Failed to write gzip index file '{}', error: {:?}",
+                        index_path.display(),
+                        why
+                    );
                 }
-            } else {
-                self.bytes_read = offset;
-                Ok(())
             }
         }
     }
@@ -144,15 +224,26 @@ impl ImageFile for GZipFile {
             offset,
             buffer.len()
         );
-        self.seek(offset)?;
 
-        trace!("fill: bytes_read after seek {}", self.bytes_read);
+        if let Err(why) = self.inflater.seek(offset) {
+            return Err(MigError::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "failed to seek to offset {} in file: '{}', error {:?}",
+                    offset,
+                    self.path.display(),
+                    why
+                ),
+            ));
+        }
+
+        trace!(
+            "fill: bytes_read after seek {}",
+            self.inflater.get_out_pos()
+        );
 
-        match self.decoder.read_exact(buffer) {
-            Ok(_) => {
-                self.bytes_read = offset + buffer.len() as u64;
-                Ok(())
-            }
+        match self.inflater.read_exact(buffer) {
+            Ok(_) => Ok(()),
             Err(why) => Err(MigError::from_remark(
                 MigErrorKind::Upstream,
                 &format!(
@@ -167,3 +258,238 @@ impl ImageFile for GZipFile {
         self.path.clone()
     }
 }
+
+// size & modification time of the image, used to detect stale index files
+fn image_stamp(image_path: &Path) -> Result<(u64, u64), std::io::Error> {
+    let metadata = image_path.metadata()?;
+    let mtime = match metadata.modified()?.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0,
+    };
+    Ok((metadata.len(), mtime))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, std::io::Error> {
+    let mut buffer: [u8; 8] = [0; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+fn read_index(index_path: &Path, image_path: &Path) -> Result<Vec<Checkpoint>, MigError> {
+    let res: Result<Option<Vec<Checkpoint>>, std::io::Error> = (|| {
+        let mut reader = BufReader::new(File::open(index_path)?);
+        let mut magic: [u8; 8] = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC {
+            return Ok(None);
+        }
+
+        let (image_size, image_mtime) = image_stamp(image_path)?;
+        if read_u64(&mut reader)? != image_size
+            || read_u64(&mut reader)? != image_mtime
+            || read_u64(&mut reader)? != DEF_INDEX_SPAN
+        {
+            return Ok(None);
+        }
+
+        let count = read_u64(&mut reader)?;
+        let mut checkpoints: Vec<Checkpoint> = Vec::new();
+        for _ in 0..count {
+            let out_pos = read_u64(&mut reader)?;
+            let bit_pos = read_u64(&mut reader)?;
+            let win_len = read_u64(&mut reader)?;
+            if win_len > WINDOW_SIZE as u64 || win_len > out_pos {
+                return Ok(None);
+            }
+            let mut window: Vec<u8> = vec![0; win_len as usize];
+            reader.read_exact(&mut window)?;
+            if let Some(last) = checkpoints.last() {
+                if last.out_pos >= out_pos {
+                    return Ok(None);
+                }
+            }
+            checkpoints.push(Checkpoint {
+                out_pos,
+                bit_pos,
+                window,
+            });
+        }
+        Ok(Some(checkpoints))
+    })();
+
+    match res {
+        Ok(Some(checkpoints)) => Ok(checkpoints),
+        Ok(None) => Err(MigError::from_remark(
+            MigErrorKind::InvState,
+            &format!(
+                "index file '{}' is invalid or does not match the image",
+                index_path.display()
+            ),
+        )),
+        Err(why) => Err(MigError::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "failed to read index file '{}', error {:?}",
+                index_path.display(),
+                why
+            ),
+        )),
+    }
+}
+
+fn write_index(
+    index_path: &Path,
+    image_path: &Path,
+    checkpoints: &[Checkpoint],
+) -> Result<(), MigError> {
+    let res: Result<(), std::io::Error> = (|| {
+        let (image_size, image_mtime) = image_stamp(image_path)?;
+        let mut writer = BufWriter::new(File::create(index_path)?);
+        writer.write_all(INDEX_MAGIC)?;
+        writer.write_all(&image_size.to_le_bytes())?;
+        writer.write_all(&image_mtime.to_le_bytes())?;
+        writer.write_all(&DEF_INDEX_SPAN.to_le_bytes())?;
+        writer.write_all(&(checkpoints.len() as u64).to_le_bytes())?;
+        for checkpoint in checkpoints {
+            writer.write_all(&checkpoint.out_pos.to_le_bytes())?;
+            writer.write_all(&checkpoint.bit_pos.to_le_bytes())?;
+            writer.write_all(&(checkpoint.window.len() as u64).to_le_bytes())?;
+            writer.write_all(&checkpoint.window)?;
+        }
+        writer.flush()
+    })();
+
+    if let Err(why) = res {
+        Err(MigError::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "failed to write index file '{}', error {:?}",
+                index_path.display(),
+                why
+            ),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{GZipFile, DEF_INDEX_SPAN};
+    use crate::common::disk_util::image_file::ImageFile;
+    use flate2::{write::GzEncoder, Compression};
+    use std::env::temp_dir;
+    use std::fs::{read, remove_file, write, File};
+    use std::io::{copy, sink, Write};
+    use std::path::PathBuf;
+
+    // pseudo random but compressible content, large enough for several access points
+    fn test_data(size: usize) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::with_capacity(size);
+        let mut seed: u32 = 0x1234_5678;
+        while data.len() < size {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let run = (seed >> 24) as usize % 64 + 1;
+            let byte = (seed >> 16) as u8 % 16;
+            for idx in 0..run {
+                data.push(byte.wrapping_add((idx % 3) as u8));
+            }
+        }
+        data.truncate(size);
+        data
+    }
+
+    fn write_gzip(name: &str, data: &[u8], level: u32) -> PathBuf {
+        let path = temp_dir().join(name);
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::new(level));
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap();
+        path
+    }
+
+    fn check_reads(gzip_file: &mut GZipFile, data: &[u8]) {
+        let span = DEF_INDEX_SPAN as usize;
+        let offsets = [
+            data.len() - 4096,
+            0,
+            span + 17,
+            2 * span - 100,
+            10,
+            3 * span + 5,
+            11 * span + 3,
+            span - 1,
+        ];
+        for offset in offsets.iter() {
+            let mut buffer: Vec<u8> = vec![0; 4096];
+            gzip_file.fill(*offset as u64, &mut buffer).unwrap();
+            assert_eq!(&buffer[..], &data[*offset..*offset + 4096]);
+        }
+    }
+
+    #[test]
+    fn gzip_random_access() {
+        let data = test_data(16 * DEF_INDEX_SPAN as usize + 1234);
+        for level in &[0, 1, 6, 9] {
+            let path = write_gzip(&format!("bm-gzip-test-{}.img.gz", level), &data, *level);
+            let mut gzip_file = GZipFile::new(&path, false).unwrap();
+            check_reads(&mut gzip_file, &data);
+            assert!(gzip_file.inflater.get_checkpoints().len() >= 3);
+
+            let mut buffer: Vec<u8> = vec![0; 4096];
+            assert!(gzip_file
+                .fill(data.len() as u64 - 1024, &mut buffer)
+                .is_err());
+            remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn gzip_index_cache() {
+        let data = test_data(16 * DEF_INDEX_SPAN as usize + 1234);
+        let path = write_gzip("bm-gzip-idx-test.img.gz", &data, 6);
+        let index_path = PathBuf::from(format!("{}.gzidx", path.display()));
+        if index_path.exists() {
+            remove_file(&index_path).unwrap();
+        }
+
+        let checkpoints = {
+            let mut gzip_file = GZipFile::new(&path, true).unwrap();
+            check_reads(&mut gzip_file, &data);
+            gzip_file.inflater.get_checkpoints().len()
+        };
+        assert!(index_path.exists());
+
+        let mut gzip_file = GZipFile::new(&path, true).unwrap();
+        assert_eq!(gzip_file.inflater.get_checkpoints().len(), checkpoints);
+        check_reads(&mut gzip_file, &data);
+
+        remove_file(&index_path).unwrap();
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn gzip_trailer() {
+        let data = test_data(4 * DEF_INDEX_SPAN as usize + 1234);
+        let path = write_gzip("bm-gzip-trailer-test.img.gz", &data, 6);
+
+        let mut gzip_file = GZipFile::new(&path, false).unwrap();
+        assert_eq!(
+            copy(&mut gzip_file.inflater, &mut sink()).unwrap(),
+            data.len() as u64
+        );
+
+        // corrupt the CRC32, then the size in the trailer
+        let compressed = read(&path).unwrap();
+        for offset in &[8, 4] {
+            let mut corrupt = compressed.clone();
+            let len = corrupt.len();
+            corrupt[len - offset] ^= 0xff;
+            write(&path, &corrupt).unwrap();
+
+            let mut gzip_file = GZipFile::new(&path, false).unwrap();
+            assert!(copy(&mut gzip_file.inflater, &mut sink()).is_err());
+        }
+
+        remove_file(&path).unwrap();
+    }
+}
@@ -1,8 +1,111 @@
-use std::path::PathBuf;
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
-use crate::common::MigError;
+use crate::common::{
+    disk_util::{
+        stream_file::{open_decoder, StreamType},
+        zip_file::ZipFile,
+    },
+    MigError, MigErrorKind,
+};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZIP_MAGIC: &[u8] = &[0x50, 0x4b, 0x03, 0x04];
+const XZ_MAGIC: &[u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
 
 pub(crate) trait ImageFile {
     fn fill(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), MigError>;
     fn get_path(&self) -> PathBuf;
 }
+
+// container format of an OS image file, determined from its magic bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ImageFormat {
+    Plain,
+    GZip,
+    Zip,
+    Xz,
+    Zstd,
+}
+
+impl ImageFormat {
+    pub fn from_file(path: &Path) -> Result<ImageFormat, MigError> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(why) => {
+                return Err(MigError::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "failed to open file for reading: '{}', error {:?}",
+                        path.display(),
+                        why
+                    ),
+                ));
+            }
+        };
+
+        let mut magic: [u8; 6] = [0; 6];
+        let mut magic_len = 0;
+        while magic_len < magic.len() {
+            match file.read(&mut magic[magic_len..]) {
+                Ok(0) => break,
+                Ok(bytes_read) => magic_len += bytes_read,
+                Err(why) => {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::Upstream,
+                        &format!(
+                            "failed to read from file: '{}', error {:?}",
+                            path.display(),
+                            why
+                        ),
+                    ));
+                }
+            }
+        }
+
+        let magic = &magic[0..magic_len];
+        if magic.starts_with(GZIP_MAGIC) {
+            Ok(ImageFormat::GZip)
+        } else if magic.starts_with(ZIP_MAGIC) {
+            Ok(ImageFormat::Zip)
+        } else if magic.starts_with(XZ_MAGIC) {
+            Ok(ImageFormat::Xz)
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Ok(ImageFormat::Zstd)
+        } else {
+            Ok(ImageFormat::Plain)
+        }
+    }
+
+    // open a sequential reader on the uncompressed image contents
+    pub fn open_stream(self, path: &Path) -> Result<Box<dyn Read>, MigError> {
+        match self {
+            ImageFormat::Zip => ZipFile::open_stream(path),
+            ImageFormat::Xz => open_decoder(path, StreamType::Xz),
+            ImageFormat::Zstd => open_decoder(path, StreamType::Zstd),
+            ImageFormat::Plain | ImageFormat::GZip => {
+                let file = match File::open(path) {
+                    Ok(file) => file,
+                    Err(why) => {
+                        return Err(MigError::from_remark(
+                            MigErrorKind::Upstream,
+                            &format!(
+                                "failed to open file for reading: '{}', error {:?}",
+                                path.display(),
+                                why
+                            ),
+                        ));
+                    }
+                };
+                if self == ImageFormat::GZip {
+                    Ok(Box::new(GzDecoder::new(file)))
+                } else {
+                    Ok(Box::new(file))
+                }
+            }
+        }
+    }
+}
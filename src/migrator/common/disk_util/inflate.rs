@@ -0,0 +1,611 @@
+// A raw deflate (RFC 1951) decoder that can be suspended and resumed at block boundaries.
+// flate2 does not allow resuming a stream at an arbitrary bit offset, which is what is needed
+// to build a zran-style access point index for gzip images.
+
+use flate2::Crc;
+use std::cmp::min;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+
+pub(crate) const WINDOW_SIZE: usize = 32768;
+const WINDOW_MASK: usize = WINDOW_SIZE - 1;
+const INPUT_BUFFER: usize = 65536;
+const MAX_BITS: usize = 15;
+const FAST_BITS: u32 = 9;
+const FAST_MASK: u64 = (1 << FAST_BITS) - 1;
+const SYM_MASK: u16 = 0x1ff;
+const MAX_LIT_CODES: usize = 288;
+const MAX_DIST_CODES: usize = 30;
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const CL_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+// An access point into the compressed stream.
+// bit_pos is the absolute position in the input stream in bits, window holds the last
+// (up to WINDOW_SIZE) uncompressed bytes preceding out_pos.
+#[derive(Debug, Clone)]
+pub(crate) struct Checkpoint {
+    pub out_pos: u64,
+    pub bit_pos: u64,
+    pub window: Vec<u8>,
+}
+
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+    // lookup table for codes up to FAST_BITS, entries are (length << 9) | symbol, 0 if not found
+    fast: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, Error> {
+        let mut counts: [u16; MAX_BITS + 1] = [0; MAX_BITS + 1];
+        for length in lengths {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // reject over subscribed codes, incomplete codes are legal for single distance codes
+        let mut left: i32 = 1;
+        for count in counts.iter().skip(1) {
+            left <<= 1;
+            left -= i32::from(*count);
+            if left < 0 {
+                return Err(invalid_data("over subscribed huffman code"));
+            }
+        }
+
+        let mut offsets: [u16; MAX_BITS + 2] = [0; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+
+        let mut symbols: Vec<u16> = vec![0; lengths.len()];
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length != 0 {
+                symbols[offsets[*length as usize] as usize] = symbol as u16;
+                offsets[*length as usize] += 1;
+            }
+        }
+
+        let mut next_code: [u32; MAX_BITS + 1] = [0; MAX_BITS + 1];
+        let mut code: u32 = 0;
+        for len in 1..=MAX_BITS {
+            code = (code + u32::from(counts[len - 1])) << 1;
+            next_code[len] = code;
+        }
+
+        let mut fast: Vec<u16> = vec![0; 1 << FAST_BITS];
+        for (symbol, length) in lengths.iter().enumerate() {
+            let length = *length as usize;
+            if length == 0 {
+                continue;
+            }
+            let code = next_code[length];
+            next_code[length] += 1;
+            if length as u32 <= FAST_BITS {
+                // codes are stored most significant bit first
+                let mut index = (code.reverse_bits() >> (32 - length)) as usize;
+                while index < fast.len() {
+                    fast[index] = ((length as u16) << 9) | symbol as u16;
+                    index += 1 << length;
+                }
+            }
+        }
+
+        Ok(Huffman {
+            counts,
+            symbols,
+            fast,
+        })
+    }
+
+    fn fixed() -> (Huffman, Huffman) {
+        let mut lengths: [u8; MAX_LIT_CODES] = [0; MAX_LIT_CODES];
+        for (symbol, length) in lengths.iter_mut().enumerate() {
+            *length = match symbol {
+                0..=143 => 8,
+                144..=255 => 9,
+                256..=279 => 7,
+                _ => 8,
+            };
+        }
+        (
+            Huffman::new(&lengths).unwrap(),
+            Huffman::new(&[5; MAX_DIST_CODES]).unwrap(),
+        )
+    }
+
+    fn empty() -> Huffman {
+        Huffman {
+            counts: [0; MAX_BITS + 1],
+            symbols: Vec::new(),
+            fast: vec![0; 1 << FAST_BITS],
+        }
+    }
+}
+
+struct BitReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    buf_pos: usize,
+    buf_len: usize,
+    // absolute input offset of buffer[0]
+    buf_offset: u64,
+    bit_buf: u64,
+    bit_cnt: u32,
+}
+
+impl<R: Read + Seek> BitReader<R> {
+    fn new(reader: R) -> BitReader<R> {
+        BitReader {
+            reader,
+            buffer: vec![0; INPUT_BUFFER],
+            buf_pos: 0,
+            buf_len: 0,
+            buf_offset: 0,
+            bit_buf: 0,
+            bit_cnt: 0,
+        }
+    }
+
+    // absolute input position of the next unconsumed bit
+    fn bit_pos(&self) -> u64 {
+        (self.buf_offset + self.buf_pos as u64) * 8 - u64::from(self.bit_cnt)
+    }
+
+    fn seek_bit(&mut self, bit_pos: u64) -> Result<(), Error> {
+        self.buf_offset = self.reader.seek(SeekFrom::Start(bit_pos / 8))?;
+        self.buf_pos = 0;
+        self.buf_len = 0;
+        self.bit_buf = 0;
+        self.bit_cnt = 0;
+        let skip = (bit_pos % 8) as u32;
+        if skip != 0 {
+            self.need(skip)?;
+            self.consume(skip);
+        }
+        Ok(())
+    }
+
+    // try to fill the bit buffer with at least min_bits, tolerating end of input
+    fn fill(&mut self, min_bits: u32) -> Result<(), Error> {
+        while self.bit_cnt < min_bits {
+            if self.buf_pos >= self.buf_len {
+                self.buf_offset += self.buf_len as u64;
+                self.buf_pos = 0;
+                self.buf_len = self.reader.read(&mut self.buffer)?;
+                if self.buf_len == 0 {
+                    return Ok(());
+                }
+            }
+            while self.bit_cnt <= 56 && self.buf_pos < self.buf_len {
+                self.bit_buf |= u64::from(self.buffer[self.buf_pos]) << self.bit_cnt;
+                self.bit_cnt += 8;
+                self.buf_pos += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn need(&mut self, bits: u32) -> Result<(), Error> {
+        self.fill(bits)?;
+        if self.bit_cnt < bits {
+            Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "unexpected end of compressed stream",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn consume(&mut self, bits: u32) {
+        self.bit_buf >>= bits;
+        self.bit_cnt -= bits;
+    }
+
+    fn bits(&mut self, bits: u32) -> Result<u32, Error> {
+        if bits == 0 {
+            return Ok(0);
+        }
+        self.need(bits)?;
+        let value = (self.bit_buf & ((1 << bits) - 1)) as u32;
+        self.consume(bits);
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        let bits = self.bit_cnt % 8;
+        self.consume(bits);
+    }
+
+    fn decode(&mut self, huffman: &Huffman) -> Result<u16, Error> {
+        self.fill(FAST_BITS)?;
+        let entry = huffman.fast[(self.bit_buf & FAST_MASK) as usize];
+        if entry != 0 {
+            let length = u32::from(entry >> 9);
+            if length <= self.bit_cnt {
+                self.consume(length);
+                return Ok(entry & SYM_MASK);
+            }
+        }
+
+        // slow path for long codes, canonical decoding bit by bit
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for len in 1..=MAX_BITS {
+            code |= self.bits(1)? as i32;
+            let count = i32::from(huffman.counts[len]);
+            if code - count < first {
+                return Ok(huffman.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err(invalid_data("invalid huffman code"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockState {
+    Header,
+    Stored(usize),
+    Codes,
+    Done,
+}
+
+pub(crate) struct Inflater<R> {
+    input: BitReader<R>,
+    start_bit_pos: u64,
+    window: Vec<u8>,
+    out_pos: u64,
+    state: BlockState,
+    last_block: bool,
+    lit: Huffman,
+    dist: Huffman,
+    copy_len: usize,
+    copy_dist: usize,
+    span: u64,
+    checkpoints: Vec<Checkpoint>,
+    gzip_trailer: bool,
+    // CRC32 of the output, only valid while decompressing sequentially from the start
+    crc: Option<Crc>,
+}
+
+impl<R: Read + Seek> Inflater<R> {
+    // start is the absolute offset of the deflate stream in reader, a checkpoint is
+    // recorded at the first block boundary after every span uncompressed bytes, 0 disables
+    // checkpoints
+    pub fn new(reader: R, start: u64, span: u64) -> Result<Inflater<R>, Error> {
+        let mut inflater = Inflater {
+            input: BitReader::new(reader),
+            start_bit_pos: start * 8,
+            window: vec![0; WINDOW_SIZE],
+            out_pos: 0,
+            state: BlockState::Header,
+            last_block: false,
+            lit: Huffman::empty(),
+            dist: Huffman::empty(),
+            copy_len: 0,
+            copy_dist: 0,
+            span,
+            checkpoints: Vec::new(),
+            gzip_trailer: false,
+            crc: None,
+        };
+        inflater.restart()?;
+        Ok(inflater)
+    }
+
+    pub fn get_out_pos(&self) -> u64 {
+        self.out_pos
+    }
+
+    pub fn get_checkpoints(&self) -> &[Checkpoint] {
+        &self.checkpoints
+    }
+
+    pub fn set_checkpoints(&mut self, checkpoints: Vec<Checkpoint>) {
+        self.checkpoints = checkpoints;
+    }
+
+    // the deflate stream is followed by a gzip trailer, CRC32 and size of the uncompressed data
+    // are verified when the stream was decompressed sequentially from its start
+    pub fn set_gzip_trailer(&mut self) {
+        self.gzip_trailer = true;
+        self.crc = Some(Crc::new());
+    }
+
+    fn restart(&mut self) -> Result<(), Error> {
+        self.input.seek_bit(self.start_bit_pos)?;
+        self.out_pos = 0;
+        self.state = BlockState::Header;
+        self.last_block = false;
+        self.copy_len = 0;
+        if self.gzip_trailer {
+            self.crc = Some(Crc::new());
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, index: usize) -> Result<(), Error> {
+        let checkpoint = &self.checkpoints[index];
+        self.input.seek_bit(checkpoint.bit_pos)?;
+        let window_start = checkpoint.out_pos - checkpoint.window.len() as u64;
+        for (idx, byte) in checkpoint.window.iter().enumerate() {
+            self.window[(window_start as usize + idx) & WINDOW_MASK] = *byte;
+        }
+        self.out_pos = checkpoint.out_pos;
+        self.state = BlockState::Header;
+        self.last_block = false;
+        self.copy_len = 0;
+        // the output preceding the checkpoint was not seen, the CRC32 can not be verified
+        self.crc = None;
+        Ok(())
+    }
+
+    // position the decoder at uncompressed offset, resuming from the closest checkpoint
+    pub fn seek(&mut self, offset: u64) -> Result<(), Error> {
+        let index = match self
+            .checkpoints
+            .binary_search_by(|checkpoint| checkpoint.out_pos.cmp(&offset))
+        {
+            Ok(index) => Some(index),
+            Err(0) => None,
+            Err(index) => Some(index - 1),
+        };
+
+        if let Some(index) = index {
+            if offset < self.out_pos || self.checkpoints[index].out_pos > self.out_pos {
+                self.restore(index)?;
+            }
+        } else if offset < self.out_pos {
+            self.restart()?;
+        }
+
+        let mut buffer: Vec<u8> = vec![0; min(offset - self.out_pos, INPUT_BUFFER as u64) as usize];
+        while self.out_pos < offset {
+            let to_read = min(offset - self.out_pos, buffer.len() as u64) as usize;
+            if self.read(&mut buffer[0..to_read])? == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "seek beyond end of compressed stream",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) {
+        if self.span == 0 {
+            return;
+        }
+
+        let last_pos = if let Some(checkpoint) = self.checkpoints.last() {
+            checkpoint.out_pos
+        } else {
+            0
+        };
+
+        if self.out_pos >= last_pos + self.span {
+            let win_len = min(self.out_pos, WINDOW_SIZE as u64) as usize;
+            let mut window: Vec<u8> = Vec::with_capacity(win_len);
+            let win_start = self.out_pos as usize - win_len;
+            for idx in 0..win_len {
+                window.push(self.window[(win_start + idx) & WINDOW_MASK]);
+            }
+            self.checkpoints.push(Checkpoint {
+                out_pos: self.out_pos,
+                bit_pos: self.input.bit_pos(),
+                window,
+            });
+        }
+    }
+
+    fn read_header(&mut self) -> Result<(), Error> {
+        self.last_block = self.input.bits(1)? == 1;
+        match self.input.bits(2)? {
+            0 => {
+                self.input.align();
+                let len = self.input.bits(16)?;
+                let nlen = self.input.bits(16)?;
+                if len != !nlen & 0xffff {
+                    return Err(invalid_data("stored block length mismatch"));
+                }
+                self.state = BlockState::Stored(len as usize);
+            }
+            1 => {
+                let (lit, dist) = Huffman::fixed();
+                self.lit = lit;
+                self.dist = dist;
+                self.state = BlockState::Codes;
+            }
+            2 => {
+                self.read_dynamic()?;
+                self.state = BlockState::Codes;
+            }
+            _ => return Err(invalid_data("invalid block type")),
+        }
+        Ok(())
+    }
+
+    fn read_dynamic(&mut self) -> Result<(), Error> {
+        let num_lit = self.input.bits(5)? as usize + 257;
+        let num_dist = self.input.bits(5)? as usize + 1;
+        let num_code = self.input.bits(4)? as usize + 4;
+        if num_lit > 286 || num_dist > MAX_DIST_CODES {
+            return Err(invalid_data("invalid dynamic block code counts"));
+        }
+
+        let mut lengths: [u8; 19] = [0; 19];
+        for idx in CL_ORDER.iter().take(num_code) {
+            lengths[*idx] = self.input.bits(3)? as u8;
+        }
+        let code_huff = Huffman::new(&lengths)?;
+
+        let mut lengths: Vec<u8> = vec![0; num_lit + num_dist];
+        let mut idx = 0;
+        while idx < lengths.len() {
+            let symbol = self.input.decode(&code_huff)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    if idx == 0 {
+                        return Err(invalid_data("repeat with no previous length"));
+                    }
+                    (lengths[idx - 1], 3 + self.input.bits(2)? as usize)
+                }
+                17 => (0, 3 + self.input.bits(3)? as usize),
+                _ => (0, 11 + self.input.bits(7)? as usize),
+            };
+            if idx + repeat > lengths.len() {
+                return Err(invalid_data("too many code lengths"));
+            }
+            for length in &mut lengths[idx..idx + repeat] {
+                *length = value;
+            }
+            idx += repeat;
+        }
+
+        if lengths[256] == 0 {
+            return Err(invalid_data("missing end of block code"));
+        }
+
+        self.lit = Huffman::new(&lengths[0..num_lit])?;
+        self.dist = Huffman::new(&lengths[num_lit..])?;
+        Ok(())
+    }
+
+    fn update_crc(&mut self, data: &[u8]) {
+        if let Some(ref mut crc) = self.crc {
+            crc.update(data);
+        }
+    }
+
+    fn check_trailer(&mut self) -> Result<(), Error> {
+        let crc = if let Some(ref crc) = self.crc {
+            crc.sum()
+        } else {
+            return Ok(());
+        };
+
+        // the trailer starts at the next byte boundary, both values are little endian
+        self.input.align();
+        let trailer_crc = self.input.bits(32)?;
+        let trailer_size = self.input.bits(32)?;
+        if trailer_crc != crc {
+            return Err(invalid_data("gzip CRC32 mismatch"));
+        }
+        if trailer_size != self.out_pos as u32 {
+            return Err(invalid_data("gzip uncompressed size mismatch"));
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, byte: u8) {
+        self.window[self.out_pos as usize & WINDOW_MASK] = byte;
+        self.out_pos += 1;
+    }
+}
+
+impl<R: Read + Seek> Read for Inflater<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut written = 0;
+        while written < buffer.len() {
+            if self.copy_len > 0 {
+                let count = min(self.copy_len, buffer.len() - written);
+                for byte in &mut buffer[written..written + count] {
+                    let value = self.window
+                        [(self.out_pos as usize).wrapping_sub(self.copy_dist) & WINDOW_MASK];
+                    self.put(value);
+                    *byte = value;
+                }
+                self.copy_len -= count;
+                written += count;
+                continue;
+            }
+
+            match self.state {
+                BlockState::Header => {
+                    if self.last_block {
+                        self.state = BlockState::Done;
+                        self.update_crc(&buffer[0..written]);
+                        self.check_trailer()?;
+                        return Ok(written);
+                    } else {
+                        self.checkpoint();
+                        self.read_header()?;
+                    }
+                }
+                BlockState::Stored(remaining) => {
+                    if remaining == 0 {
+                        self.state = BlockState::Header;
+                    } else {
+                        let count = min(remaining, buffer.len() - written);
+                        for byte in &mut buffer[written..written + count] {
+                            let value = self.input.bits(8)? as u8;
+                            self.put(value);
+                            *byte = value;
+                        }
+                        written += count;
+                        self.state = BlockState::Stored(remaining - count);
+                    }
+                }
+                BlockState::Codes => {
+                    let symbol = self.input.decode(&self.lit)? as usize;
+                    if symbol < 256 {
+                        self.put(symbol as u8);
+                        buffer[written] = symbol as u8;
+                        written += 1;
+                    } else if symbol == 256 {
+                        self.state = BlockState::Header;
+                    } else {
+                        let symbol = symbol - 257;
+                        if symbol >= LEN_BASE.len() {
+                            return Err(invalid_data("invalid length symbol"));
+                        }
+                        let length = LEN_BASE[symbol] as usize
+                            + self.input.bits(u32::from(LEN_EXTRA[symbol]))? as usize;
+                        let symbol = self.input.decode(&self.dist)? as usize;
+                        if symbol >= DIST_BASE.len() {
+                            return Err(invalid_data("invalid distance symbol"));
+                        }
+                        let distance = DIST_BASE[symbol] as usize
+                            + self.input.bits(u32::from(DIST_EXTRA[symbol]))? as usize;
+                        if distance as u64 > self.out_pos {
+                            return Err(invalid_data("distance too far back"));
+                        }
+                        self.copy_len = length;
+                        self.copy_dist = distance;
+                    }
+                }
+                BlockState::Done => break,
+            }
+        }
+        self.update_crc(&buffer[0..written]);
+        Ok(written)
+    }
+}
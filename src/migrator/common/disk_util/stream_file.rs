@@ -0,0 +1,165 @@
+use log::{debug, trace};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use xz2::read::XzDecoder;
+
+const DEF_READ_BUFFER: usize = 1024 * 1024;
+
+use crate::common::{disk_util::image_file::ImageFile, MigError, MigErrorKind};
+
+/******************************************************************
+ * Sequential access to xz and zstd compressed images.
+ * These formats offer no cheap way to resume decompression at an
+ * arbitrary offset, so seeking backwards restarts the decoder.
+ * Partition tables & partitions are read in ascending order, so this
+ * is mostly a single pass.
+ ******************************************************************/
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum StreamType {
+    Xz,
+    Zstd,
+}
+
+pub(crate) fn open_decoder(
+    path: &Path,
+    stream_type: StreamType,
+) -> Result<Box<dyn Read>, MigError> {
+    trace!(
+        "open_decoder: entered with '{}', type: {:?}",
+        path.display(),
+        stream_type
+    );
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(why) => {
+            return Err(MigError::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "failed to open file for reading: '{}', error {:?}",
+                    path.display(),
+                    why
+                ),
+            ));
+        }
+    };
+
+    match stream_type {
+        // images might be compressed with multiple streams by parallel compressors
+        StreamType::Xz => Ok(Box::new(XzDecoder::new_multi_decoder(file))),
+        StreamType::Zstd => match zstd::stream::read::Decoder::new(file) {
+            Ok(decoder) => Ok(Box::new(decoder)),
+            Err(why) => Err(MigError::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "failed to create zstd decoder for file: '{}', error {:?}",
+                    path.display(),
+                    why
+                ),
+            )),
+        },
+    }
+}
+
+pub(crate) struct StreamFile {
+    path: PathBuf,
+    stream_type: StreamType,
+    decoder: Box<dyn Read>,
+    bytes_read: u64,
+}
+
+impl StreamFile {
+    pub fn new(path: &Path, stream_type: StreamType) -> Result<StreamFile, MigError> {
+        Ok(StreamFile {
+            path: path.to_path_buf(),
+            stream_type,
+            decoder: open_decoder(path, stream_type)?,
+            bytes_read: 0,
+        })
+    }
+
+    fn seek(&mut self, offset: u64) -> Result<(), MigError> {
+        trace!(
+            "seek: entered with offset {}, bytes_read: {}",
+            offset,
+            self.bytes_read
+        );
+
+        if offset < self.bytes_read {
+            debug!("seek: restarting decoder for '{}'", self.path.display());
+            self.decoder = open_decoder(&self.path, self.stream_type)?;
+            self.bytes_read = 0;
+        }
+
+        let mut buffer: Vec<u8> = vec![0; DEF_READ_BUFFER];
+        while self.bytes_read < offset {
+            let to_read = if offset - self.bytes_read < DEF_READ_BUFFER as u64 {
+                (offset - self.bytes_read) as usize
+            } else {
+                DEF_READ_BUFFER
+            };
+
+            match self.decoder.read(&mut buffer[0..to_read]) {
+                Ok(0) => {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::InvParam,
+                        &format!(
+                            "offset {} is beyond the end of file: '{}'",
+                            offset,
+                            self.path.display()
+                        ),
+                    ));
+                }
+                Ok(bytes_read) => {
+                    self.bytes_read += bytes_read as u64;
+                }
+                Err(why) => {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::Upstream,
+                        &format!(
+                            "failed to read from file: '{}', error {:?}",
+                            self.path.display(),
+                            why
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ImageFile for StreamFile {
+    fn fill(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), MigError> {
+        trace!(
+            "fill: entered with offset {}, size {}",
+            offset,
+            buffer.len()
+        );
+        self.seek(offset)?;
+
+        match self.decoder.read_exact(buffer) {
+            Ok(_) => {
+                self.bytes_read = offset + buffer.len() as u64;
+                Ok(())
+            }
+            Err(why) => {
+                // decoder position is unknown now, make sure the next seek restarts
+                self.bytes_read = u64::MAX;
+                Err(MigError::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "failed to read from file: '{}', error {:?}",
+                        self.path.display(),
+                        why
+                    ),
+                ))
+            }
+        }
+    }
+
+    fn get_path(&self) -> PathBuf {
+        self.path.clone()
+    }
+}
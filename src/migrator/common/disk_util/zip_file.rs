@@ -0,0 +1,364 @@
+use flate2::{read::DeflateDecoder, Crc};
+use log::{debug, trace};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::common::{
+    disk_util::{image_file::ImageFile, inflate::Inflater},
+    MigError, MigErrorKind,
+};
+
+// distance between access points in the uncompressed stream
+const DEF_INDEX_SPAN: u64 = 8 * 1024 * 1024;
+
+/******************************************************************
+ * Access to images shipped as zip archive, as delivered by the balena
+ * download endpoints. The archive is expected to contain exactly one
+ * file, the image. Deflated entries are read through the same
+ * checkpointing inflater as gzipped images.
+ ******************************************************************/
+
+#[derive(Debug)]
+struct ZipEntry {
+    name: String,
+    deflated: bool,
+    data_start: u64,
+    size: u64,
+    compressed_size: u64,
+    crc32: u32,
+}
+
+enum ZipData {
+    Stored(File),
+    Deflated(Box<Inflater<File>>),
+}
+
+// checks the CRC32 and size of the entry once the stream is exhausted
+struct CheckedReader<R: Read> {
+    inner: R,
+    crc: Crc,
+    size: u64,
+    exp_crc32: u32,
+    exp_size: u64,
+}
+
+impl<R: Read> Read for CheckedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let bytes_read = self.inner.read(buf)?;
+        if bytes_read > 0 {
+            self.crc.update(&buf[0..bytes_read]);
+            self.size += bytes_read as u64;
+        } else if !buf.is_empty() {
+            if self.size != self.exp_size {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "zip entry size mismatch: expected {} bytes, read {}",
+                        self.exp_size, self.size
+                    ),
+                ));
+            }
+            if self.crc.sum() != self.exp_crc32 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "zip entry crc32 mismatch: expected 0x{:08x}, got 0x{:08x}",
+                        self.exp_crc32,
+                        self.crc.sum()
+                    ),
+                ));
+            }
+        }
+        Ok(bytes_read)
+    }
+}
+
+pub(crate) struct ZipFile {
+    path: PathBuf,
+    entry: ZipEntry,
+    data: ZipData,
+}
+
+fn open_file(path: &Path) -> Result<File, MigError> {
+    match File::open(path) {
+        Ok(file) => Ok(file),
+        Err(why) => Err(MigError::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "failed to open file for reading: '{}', error {:?}",
+                path.display(),
+                why
+            ),
+        )),
+    }
+}
+
+impl ZipFile {
+    pub fn new(path: &Path) -> Result<ZipFile, MigError> {
+        trace!("new: entered with '{}'", path.display());
+        let entry = ZipFile::find_entry(path)?;
+        let file = open_file(path)?;
+
+        let data = if entry.deflated {
+            match Inflater::new(file, entry.data_start, DEF_INDEX_SPAN) {
+                Ok(inflater) => ZipData::Deflated(Box::new(inflater)),
+                Err(why) => {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::Upstream,
+                        &format!(
+                            "failed to initialize decompression for file: '{}', error {:?}",
+                            path.display(),
+                            why
+                        ),
+                    ));
+                }
+            }
+        } else {
+            ZipData::Stored(file)
+        };
+
+        Ok(ZipFile {
+            path: path.to_path_buf(),
+            entry,
+            data,
+        })
+    }
+
+    // open a sequential reader on the image contained in the archive
+    pub fn open_stream(path: &Path) -> Result<Box<dyn Read>, MigError> {
+        let entry = ZipFile::find_entry(path)?;
+        let mut file = open_file(path)?;
+        if let Err(why) = file.seek(SeekFrom::Start(entry.data_start)) {
+            return Err(MigError::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "failed to seek to offset {} in file: '{}', error {:?}",
+                    entry.data_start,
+                    path.display(),
+                    why
+                ),
+            ));
+        }
+
+        let data = file.take(entry.compressed_size);
+        let inner: Box<dyn Read> = if entry.deflated {
+            Box::new(DeflateDecoder::new(data))
+        } else {
+            Box::new(data)
+        };
+
+        Ok(Box::new(CheckedReader {
+            inner,
+            crc: Crc::new(),
+            size: 0,
+            exp_crc32: entry.crc32,
+            exp_size: entry.size,
+        }))
+    }
+
+    fn find_entry(path: &Path) -> Result<ZipEntry, MigError> {
+        let mut archive = match ZipArchive::new(open_file(path)?) {
+            Ok(archive) => archive,
+            Err(why) => {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!(
+                        "failed to read zip archive: '{}', error {:?}",
+                        path.display(),
+                        why
+                    ),
+                ));
+            }
+        };
+
+        let mut found: Option<ZipEntry> = None;
+        for index in 0..archive.len() {
+            let zip_file = match archive.by_index(index) {
+                Ok(zip_file) => zip_file,
+                Err(why) => {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::InvParam,
+                        &format!(
+                            "failed to read entry {} of zip archive: '{}', error {:?}",
+                            index,
+                            path.display(),
+                            why
+                        ),
+                    ));
+                }
+            };
+
+            if zip_file.is_dir() {
+                continue;
+            }
+
+            if let Some(ref entry) = found {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!(
+                        "the zip archive '{}' contains more than one file: '{}', '{}'",
+                        path.display(),
+                        entry.name,
+                        zip_file.name()
+                    ),
+                ));
+            }
+
+            let deflated = match zip_file.compression() {
+                CompressionMethod::Stored => false,
+                CompressionMethod::Deflated => true,
+                compression => {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::InvParam,
+                        &format!(
+                            "unsupported compression method {:?} for '{}' in zip archive: '{}'",
+                            compression,
+                            zip_file.name(),
+                            path.display()
+                        ),
+                    ));
+                }
+            };
+
+            found = Some(ZipEntry {
+                name: String::from(zip_file.name()),
+                deflated,
+                data_start: zip_file.data_start(),
+                size: zip_file.size(),
+                compressed_size: zip_file.compressed_size(),
+                crc32: zip_file.crc32(),
+            });
+        }
+
+        if let Some(entry) = found {
+            debug!("find_entry: using {:?} from '{}'", entry, path.display());
+            Ok(entry)
+        } else {
+            Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("the zip archive '{}' contains no files", path.display()),
+            ))
+        }
+    }
+}
+
+impl ImageFile for ZipFile {
+    fn fill(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), MigError> {
+        trace!(
+            "fill: entered with offset {}, size {}",
+            offset,
+            buffer.len()
+        );
+
+        if offset + buffer.len() as u64 > self.entry.size {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "read of {} bytes at offset {} exceeds the size of '{}' in '{}'",
+                    buffer.len(),
+                    offset,
+                    self.entry.name,
+                    self.path.display()
+                ),
+            ));
+        }
+
+        let res = match self.data {
+            ZipData::Stored(ref mut file) => file
+                .seek(SeekFrom::Start(self.entry.data_start + offset))
+                .and_then(|_| file.read_exact(buffer)),
+            ZipData::Deflated(ref mut inflater) => inflater
+                .seek(offset)
+                .and_then(|_| inflater.read_exact(buffer)),
+        };
+
+        match res {
+            Ok(_) => Ok(()),
+            Err(why) => Err(MigError::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "failed to read from file: '{}', error {:?}",
+                    self.path.display(),
+                    why
+                ),
+            )),
+        }
+    }
+
+    fn get_path(&self) -> PathBuf {
+        self.path.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ZipFile;
+    use std::env::temp_dir;
+    use std::fs::{read, remove_file, write, File};
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    fn write_zip(name: &str, data: &[u8], method: CompressionMethod) -> PathBuf {
+        let path = temp_dir().join(name);
+        let mut writer = ZipWriter::new(File::create(&path).unwrap());
+        writer
+            .start_file(
+                "image.img",
+                FileOptions::default().compression_method(method),
+            )
+            .unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap();
+        path
+    }
+
+    fn test_data() -> Vec<u8> {
+        (0..64 * 1024).map(|idx| (idx % 251) as u8).collect()
+    }
+
+    #[test]
+    fn zip_stream_checked() {
+        let data = test_data();
+        for (name, method) in &[
+            ("bm-zip-stream-stored.zip", CompressionMethod::Stored),
+            ("bm-zip-stream-deflated.zip", CompressionMethod::Deflated),
+        ] {
+            let path = write_zip(name, &data, *method);
+            let mut read_data: Vec<u8> = Vec::new();
+            ZipFile::open_stream(&path)
+                .unwrap()
+                .read_to_end(&mut read_data)
+                .unwrap();
+            assert_eq!(read_data, data);
+            remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn zip_stream_corrupted() {
+        let data = test_data();
+        let path = write_zip(
+            "bm-zip-stream-corrupt.zip",
+            &data,
+            CompressionMethod::Stored,
+        );
+        let mut archive = read(&path).unwrap();
+        // flip a byte in the stored data, past the local file header
+        let pos = archive
+            .windows(16)
+            .position(|window| window == &data[1000..1016])
+            .unwrap();
+        archive[pos] ^= 0xff;
+        write(&path, &archive).unwrap();
+
+        let mut read_data: Vec<u8> = Vec::new();
+        let res = ZipFile::open_stream(&path)
+            .unwrap()
+            .read_to_end(&mut read_data);
+        assert!(res.is_err());
+        remove_file(&path).unwrap();
+    }
+}
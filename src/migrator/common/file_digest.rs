@@ -4,8 +4,9 @@ use log::debug;
 use md5::Md5;
 use serde::{Deserialize, Serialize};
 use sha1::Sha1;
+use sha2::Sha256;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
 use std::path::Path;
 
 use crate::common::{MigErrCtx, MigError, MigErrorKind};
@@ -18,6 +19,8 @@ pub(crate) enum HashInfo {
     Sha1(String),
     #[serde(rename = "md5")]
     Md5(String),
+    #[serde(rename = "sha256")]
+    Sha256(String),
 }
 
 pub(crate) fn check_digest<P: AsRef<Path>>(path: P, digest: &HashInfo) -> Result<bool, MigError> {
@@ -25,6 +28,7 @@ pub(crate) fn check_digest<P: AsRef<Path>>(path: P, digest: &HashInfo) -> Result
     let computed = match digest {
         HashInfo::Sha1(_) => HashInfo::Sha1(process_digest::<Sha1, _>(path)?),
         HashInfo::Md5(_) => HashInfo::Md5(process_digest::<Md5, _>(path)?),
+        HashInfo::Sha256(_) => HashInfo::Sha256(process_digest::<Sha256, _>(path)?),
     };
 
     debug!("check_digest: provided digest is: {:?}", digest);
@@ -36,6 +40,52 @@ pub(crate) fn get_default_digest<P: AsRef<Path>>(path: P) -> Result<HashInfo, Mi
     Ok(HashInfo::Md5(process_digest::<Md5, _>(path)?))
 }
 
+pub(crate) fn get_sha256_digest<P: AsRef<Path>>(path: P) -> Result<HashInfo, MigError> {
+    Ok(HashInfo::Sha256(process_digest::<Sha256, _>(path)?))
+}
+
+fn to_hex(digest: &[u8]) -> String {
+    let mut res = String::from("");
+    for byte in digest {
+        res.push_str(&format!("{:02x}", byte));
+    }
+    res
+}
+
+// computes the sha256 digest and the size of the data read through it
+pub(crate) struct DigestReader<R: Read> {
+    reader: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> DigestReader<R> {
+    pub fn new(reader: R) -> DigestReader<R> {
+        DigestReader {
+            reader,
+            hasher: Sha256::default(),
+            size: 0,
+        }
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn get_digest(self) -> String {
+        to_hex(&self.hasher.result())
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.reader.read(buf)?;
+        self.hasher.input(&buf[..bytes_read]);
+        self.size += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
 fn process_digest<D: Digest + Default, P: AsRef<Path>>(path: P) -> Result<String, MigError> {
     let path = path.as_ref();
     let mut file = File::open(path).context(MigErrCtx::from_remark(
@@ -55,10 +105,5 @@ fn process_digest<D: Digest + Default, P: AsRef<Path>>(path: P) -> Result<String
             break;
         }
     }
-    let digest = sh.result();
-    let mut res = String::from("");
-    for byte in &digest {
-        res.push_str(&format!("{:02x}", byte));
-    }
-    Ok(res)
+    Ok(to_hex(&sh.result()))
 }
@@ -0,0 +1,593 @@
+use flate2::read::GzDecoder;
+use log::{debug, error};
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+use xz2::read::XzDecoder;
+
+use crate::{
+    common::{bmap::BlockMap, disk_util::ImageFormat, MigError, MigErrorKind},
+    defs::FileType,
+};
+
+// *************************************************************************************************
+// Detect the type of the files used by the migrator from their contents, see the linux boot
+// protocol (x86 & arm64), the zImage header, the devicetree specification, cpio(5) and tar(5) for
+// the magic numbers used below.
+// *************************************************************************************************
+
+// enough to cover the MBR and all kernel header fields checked
+const HEADER_SIZE: usize = 1024;
+// text files are checked up to this size
+const TEXT_CHECK_SIZE: usize = 0x10000;
+
+const MBR_SIG_OFFSET: usize = 0x1FE;
+const MBR_SIG: &[u8] = &[0x55, 0xAA];
+const MBR_PART_TABLE_OFFSET: usize = 0x1BE;
+const BZIMAGE_MAGIC_OFFSET: usize = 0x202;
+const BZIMAGE_MAGIC: &[u8] = b"HdrS";
+const BZIMAGE_VERSION_OFFSET: usize = 0x206;
+const BZIMAGE_XLOADFLAGS_OFFSET: usize = 0x236;
+const XLF_KERNEL_64: u8 = 0x01;
+const ZIMAGE_MAGIC_OFFSET: usize = 0x24;
+const ZIMAGE_MAGIC: &[u8] = &[0x18, 0x28, 0x6F, 0x01];
+const ARM64_MAGIC_OFFSET: usize = 0x38;
+const ARM64_MAGIC: &[u8] = b"ARM\x64";
+const FDT_MAGIC: &[u8] = &[0xD0, 0x0D, 0xFE, 0xED];
+const CPIO_NEWC_MAGIC: &[u8] = b"07070";
+const TAR_USTAR_OFFSET: usize = 257;
+const TAR_USTAR_MAGIC: &[u8] = b"ustar";
+
+fn has_magic(header: &[u8], offset: usize, magic: &[u8]) -> bool {
+    header.len() >= offset + magic.len() && &header[offset..offset + magic.len()] == magic
+}
+
+// fill buffer from reader, returns the number of bytes read - less than the buffer size on EOF
+fn read_header<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize, MigError> {
+    let mut bytes_read = 0;
+    while bytes_read < buffer.len() {
+        match reader.read(&mut buffer[bytes_read..]) {
+            Ok(0) => break,
+            Ok(count) => bytes_read += count,
+            Err(why) => {
+                return Err(MigError::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!("failed to read file header, error {:?}", why),
+                ));
+            }
+        }
+    }
+    Ok(bytes_read)
+}
+
+fn open_file(path: &Path) -> Result<File, MigError> {
+    match File::open(path) {
+        Ok(file) => Ok(file),
+        Err(why) => Err(MigError::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "failed to open file for reading: '{}', error {:?}",
+                path.display(),
+                why
+            ),
+        )),
+    }
+}
+
+// the header of the file, uncompressed if compressed with gzip
+fn get_header(path: &Path, gunzip: bool) -> Result<Vec<u8>, MigError> {
+    let mut header: Vec<u8> = vec![0; HEADER_SIZE];
+    let bytes_read = if gunzip {
+        read_header(&mut GzDecoder::new(open_file(path)?), &mut header)
+    } else {
+        read_header(&mut open_file(path)?, &mut header)
+    };
+
+    // corrupt compressed data just does not match
+    match bytes_read {
+        Ok(bytes_read) => {
+            header.truncate(bytes_read);
+            Ok(header)
+        }
+        Err(why) => {
+            debug!(
+                "get_header: failed to read from '{}', error: {:?}",
+                path.display(),
+                why
+            );
+            Ok(Vec::new())
+        }
+    }
+}
+
+// a boot sector with a partition table
+fn is_disk_image(header: &[u8]) -> bool {
+    if !has_magic(header, MBR_SIG_OFFSET, MBR_SIG)
+        || has_magic(header, BZIMAGE_MAGIC_OFFSET, BZIMAGE_MAGIC)
+    {
+        return false;
+    }
+
+    // boot indicators have to be valid and at least one partition has to be defined
+    let mut has_partition = false;
+    for idx in 0..4 {
+        let entry =
+            &header[MBR_PART_TABLE_OFFSET + idx * 16..MBR_PART_TABLE_OFFSET + (idx + 1) * 16];
+        if entry[0] != 0x00 && entry[0] != 0x80 {
+            return false;
+        }
+        if entry[4] != 0 {
+            has_partition = true;
+        }
+    }
+    has_partition
+}
+
+// an x86 bzImage, kernels using boot protocol 2.12 and up have to declare a 64 bit entry point
+fn is_kernel_amd64(header: &[u8]) -> bool {
+    if header.len() < BZIMAGE_VERSION_OFFSET + 2
+        || !has_magic(header, MBR_SIG_OFFSET, MBR_SIG)
+        || !has_magic(header, BZIMAGE_MAGIC_OFFSET, BZIMAGE_MAGIC)
+    {
+        return false;
+    }
+
+    let version = u16::from_le_bytes([
+        header[BZIMAGE_VERSION_OFFSET],
+        header[BZIMAGE_VERSION_OFFSET + 1],
+    ]);
+    version < 0x020C
+        || (header.len() > BZIMAGE_XLOADFLAGS_OFFSET
+            && header[BZIMAGE_XLOADFLAGS_OFFSET] & XLF_KERNEL_64 == XLF_KERNEL_64)
+}
+
+fn is_cpio(header: &[u8]) -> bool {
+    // newc (070701) or newc with checksum (070702)
+    header.len() > CPIO_NEWC_MAGIC.len()
+        && has_magic(header, 0, CPIO_NEWC_MAGIC)
+        && (header[5] == b'1' || header[5] == b'2')
+}
+
+// printable UTF-8 text, a multi byte sequence cut off at the end of the buffer is fine
+fn is_text(buffer: &[u8]) -> bool {
+    let text = match std::str::from_utf8(buffer) {
+        Ok(text) => text,
+        Err(why) => {
+            if why.error_len().is_some() {
+                return false;
+            }
+            // valid_up_to is on a char boundary
+            std::str::from_utf8(&buffer[..why.valid_up_to()]).unwrap_or("")
+        }
+    };
+
+    !text
+        .chars()
+        .any(|c| c.is_control() && c != '\n' && c != '\r' && c != '\t')
+}
+
+fn is_compressed_image(path: &Path, format: ImageFormat) -> Result<bool, MigError> {
+    if ImageFormat::from_file(path)? != format {
+        return Ok(false);
+    }
+
+    let mut stream = match format.open_stream(path) {
+        Ok(stream) => stream,
+        Err(why) => {
+            debug!(
+                "is_compressed_image: failed to open '{}', error: {:?}",
+                path.display(),
+                why
+            );
+            return Ok(false);
+        }
+    };
+
+    let mut header: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+    match read_header(&mut stream, &mut header) {
+        Ok(bytes_read) => Ok(is_disk_image(&header[..bytes_read])),
+        Err(why) => {
+            debug!(
+                "is_compressed_image: failed to read from '{}', error: {:?}",
+                path.display(),
+                why
+            );
+            Ok(false)
+        }
+    }
+}
+
+fn is_json(path: &Path) -> Result<bool, MigError> {
+    match serde_json::from_reader::<_, serde_json::Value>(open_file(path)?) {
+        Ok(_) => Ok(true),
+        Err(why) => {
+            debug!(
+                "is_json: failed to parse '{}', error: {:?}",
+                path.display(),
+                why
+            );
+            Ok(false)
+        }
+    }
+}
+
+// a bmap file is only accepted if it can be parsed and its checksum matches
+fn is_block_map(file: &Path) -> bool {
+    match BlockMap::from_file(file) {
+        Ok(_) => true,
+        Err(why) => {
+            debug!(
+                "is_block_map: failed to parse '{}', error: {:?}",
+                file.display(),
+                why
+            );
+            false
+        }
+    }
+}
+
+pub(crate) fn is_file_type<P: AsRef<Path>>(file: P, ftype: &FileType) -> Result<bool, MigError> {
+    let path = file.as_ref();
+
+    let is_type = match ftype {
+        FileType::GZipOSImage => is_compressed_image(path, ImageFormat::GZip)?,
+        FileType::ZipOSImage => is_compressed_image(path, ImageFormat::Zip)?,
+        FileType::XzOSImage => is_compressed_image(path, ImageFormat::Xz)?,
+        FileType::ZstdOSImage => is_compressed_image(path, ImageFormat::Zstd)?,
+        FileType::OSImage => is_disk_image(&get_header(path, false)?),
+        FileType::KernelAMD64 => is_kernel_amd64(&get_header(path, false)?),
+        FileType::KernelARMHF => {
+            has_magic(&get_header(path, false)?, ZIMAGE_MAGIC_OFFSET, ZIMAGE_MAGIC)
+        }
+        FileType::KernelAARCH64 => {
+            has_magic(&get_header(path, false)?, ARM64_MAGIC_OFFSET, ARM64_MAGIC)
+        }
+        FileType::InitRD => {
+            if ImageFormat::from_file(path)? == ImageFormat::GZip {
+                is_cpio(&get_header(path, true)?)
+            } else {
+                is_cpio(&get_header(path, false)?)
+            }
+        }
+        FileType::Json => is_json(path)?,
+        FileType::Text => {
+            let mut buffer: Vec<u8> = vec![0; TEXT_CHECK_SIZE];
+            let bytes_read = read_header(&mut open_file(path)?, &mut buffer)?;
+            is_text(&buffer[..bytes_read])
+        }
+        FileType::DTB => has_magic(&get_header(path, false)?, 0, FDT_MAGIC),
+        FileType::GZipTar => {
+            ImageFormat::from_file(path)? == ImageFormat::GZip
+                && has_magic(&get_header(path, true)?, TAR_USTAR_OFFSET, TAR_USTAR_MAGIC)
+        }
+        FileType::BlockMap => is_block_map(path),
+    };
+
+    debug!(
+        "is_file_type: '{}' is {}a {}",
+        path.display(),
+        if is_type { "" } else { "not " },
+        ftype.get_descr()
+    );
+
+    Ok(is_type)
+}
+
+pub(crate) fn expect_type<P: AsRef<Path>>(file: P, ftype: &FileType) -> Result<(), MigError> {
+    if !is_file_type(file.as_ref(), ftype)? {
+        error!(
+            "Could not determine expected file type '{}' for file '{}'",
+            ftype.get_descr(),
+            file.as_ref().display()
+        );
+        Err(MigError::displayed())
+    } else {
+        Ok(())
+    }
+}
+
+// *************************************************************************************************
+// The kernel release (uname -r) of a kernel image is taken from its banner
+// 'Linux version <release> (<builder>) ...', which is found uncompressed in arm64 Images and in the
+// compressed payload of zImage and bzImage kernels.
+// *************************************************************************************************
+
+const KERNEL_BANNER: &[u8] = b"Linux version ";
+// longer release strings are truncated
+const MAX_RELEASE_LEN: usize = 256;
+// upper limit for kernel images and their decompressed payload
+const MAX_KERNEL_SIZE: u64 = 0x1000_0000;
+// images and payloads are scanned in chunks of this size
+const SCAN_CHUNK_SIZE: usize = 0x10000;
+// kernel payload compression formats supported, gzip is expected to use deflate
+const PAYLOAD_MAGIC: &[(&[u8], ImageFormat)] = &[
+    (&[0x1F, 0x8B, 0x08], ImageFormat::GZip),
+    (&[0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00], ImageFormat::Xz),
+    (&[0x28, 0xB5, 0x2F, 0xFD], ImageFormat::Zstd),
+];
+
+fn find_bytes(buffer: &[u8], pattern: &[u8], start: usize) -> Option<usize> {
+    if start >= buffer.len() {
+        return None;
+    }
+    buffer[start..]
+        .windows(pattern.len())
+        .position(|window| window == pattern)
+        .map(|pos| pos + start)
+}
+
+// Scan a stream chunk by chunk. scan is called with a window holding the last match_size - 1
+// bytes of the previous window followed by the next chunk, the offset of the window in the
+// stream and a flag telling whether the stream has ended. A match of up to match_size bytes is
+// complete in the window if it starts at or before window.len() - match_size, otherwise it is
+// found in the next window. Data read before a read error is scanned as if the stream had ended.
+fn scan_stream<R: Read, T, F>(
+    reader: &mut R,
+    match_size: usize,
+    mut scan: F,
+) -> Result<Option<T>, Error>
+where
+    F: FnMut(&[u8], u64, bool) -> Option<T>,
+{
+    let mut window: Vec<u8> = Vec::with_capacity(match_size + SCAN_CHUNK_SIZE);
+    let mut offset: u64 = 0;
+    loop {
+        let filled = window.len();
+        window.resize(filled + SCAN_CHUNK_SIZE, 0);
+        let bytes_read = loop {
+            match reader.read(&mut window[filled..]) {
+                Ok(bytes_read) => break bytes_read,
+                Err(ref why) if why.kind() == ErrorKind::Interrupted => continue,
+                Err(why) => {
+                    window.truncate(filled);
+                    return match scan(&window, offset, true) {
+                        Some(found) => Ok(Some(found)),
+                        None => Err(why),
+                    };
+                }
+            }
+        };
+        window.truncate(filled + bytes_read);
+
+        let eof = bytes_read == 0;
+        if let Some(found) = scan(&window, offset, eof) {
+            return Ok(Some(found));
+        }
+        if eof {
+            return Ok(None);
+        }
+
+        let consumed = window.len().saturating_sub(match_size - 1);
+        window.drain(..consumed);
+        offset += consumed as u64;
+    }
+}
+
+// the first banner followed by a release, the banner text is also part of some log messages
+fn find_kernel_release(window: &[u8], eof: bool) -> Option<String> {
+    let match_size = KERNEL_BANNER.len() + MAX_RELEASE_LEN;
+    let mut start = 0;
+    while let Some(pos) = find_bytes(window, KERNEL_BANNER, start) {
+        if !eof && pos + match_size > window.len() {
+            break;
+        }
+        let release: String = window[pos + KERNEL_BANNER.len()..]
+            .iter()
+            .take(MAX_RELEASE_LEN)
+            .take_while(|byte| byte.is_ascii_graphic())
+            .map(|byte| *byte as char)
+            .collect();
+        if release.starts_with(|c: char| c.is_ascii_digit()) {
+            return Some(release);
+        }
+        start = pos + 1;
+    }
+    None
+}
+
+// scan the stream for the kernel banner, stops at the first release found
+fn read_kernel_release<R: Read>(reader: &mut R) -> Result<Option<String>, Error> {
+    scan_stream(
+        reader,
+        KERNEL_BANNER.len() + MAX_RELEASE_LEN,
+        |window, _offset, eof| find_kernel_release(window, eof),
+    )
+}
+
+// decompress and scan as much as possible, the payload is followed by data that is not part of
+// the stream
+fn read_payload_release<R: Read>(reader: R, format: ImageFormat) -> Option<String> {
+    let result = match format {
+        ImageFormat::GZip => read_kernel_release(&mut GzDecoder::new(reader).take(MAX_KERNEL_SIZE)),
+        ImageFormat::Xz => read_kernel_release(&mut XzDecoder::new(reader).take(MAX_KERNEL_SIZE)),
+        ImageFormat::Zstd => match zstd::stream::read::Decoder::new(reader) {
+            Ok(decoder) => read_kernel_release(&mut decoder.take(MAX_KERNEL_SIZE)),
+            Err(why) => Err(why),
+        },
+        _ => Ok(None),
+    };
+
+    match result {
+        Ok(release) => release,
+        Err(why) => {
+            debug!(
+                "read_payload_release: {:?} stream ended with error: {:?}",
+                format, why
+            );
+            None
+        }
+    }
+}
+
+// the kernel release of a kernel image, None if no banner was found
+pub(crate) fn get_kernel_release<P: AsRef<Path>>(file: P) -> Result<Option<String>, MigError> {
+    let path = file.as_ref();
+    let read_error = |why: Error| {
+        MigError::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "failed to read kernel image '{}', error {:?}",
+                path.display(),
+                why
+            ),
+        )
+    };
+
+    if let Some(release) =
+        read_kernel_release(&mut open_file(path)?.take(MAX_KERNEL_SIZE)).map_err(read_error)?
+    {
+        debug!(
+            "get_kernel_release: found release '{}' in uncompressed '{}'",
+            release,
+            path.display()
+        );
+        return Ok(Some(release));
+    }
+
+    // every payload magic found is tried in turn, decompressing from a second file handle
+    let payload_file = open_file(path)?;
+    let match_size = PAYLOAD_MAGIC
+        .iter()
+        .map(|(magic, _format)| magic.len())
+        .max()
+        .unwrap_or(1);
+    let found = scan_stream(
+        &mut open_file(path)?.take(MAX_KERNEL_SIZE),
+        match_size,
+        |window, offset, eof| {
+            let last = if eof {
+                window.len()
+            } else {
+                (window.len() + 1).saturating_sub(match_size)
+            };
+            for pos in 0..last {
+                for (magic, format) in PAYLOAD_MAGIC {
+                    if !has_magic(window, pos, magic) {
+                        continue;
+                    }
+                    let payload_offset = offset + pos as u64;
+                    if let Err(why) = (&payload_file).seek(SeekFrom::Start(payload_offset)) {
+                        debug!(
+                            "get_kernel_release: failed to seek to 0x{:x}, error: {:?}",
+                            payload_offset, why
+                        );
+                        continue;
+                    }
+                    if let Some(release) =
+                        read_payload_release(BufReader::new(&payload_file), *format)
+                    {
+                        return Some((release, *format, payload_offset));
+                    }
+                }
+            }
+            None
+        },
+    )
+    .map_err(read_error)?;
+
+    if let Some((release, format, payload_offset)) = found {
+        debug!(
+            "get_kernel_release: found release '{}' in {:?} payload at offset 0x{:x} of '{}'",
+            release,
+            format,
+            payload_offset,
+            path.display()
+        );
+        Ok(Some(release))
+    } else {
+        debug!(
+            "get_kernel_release: no kernel banner found in '{}'",
+            path.display()
+        );
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mbr_header() -> Vec<u8> {
+        let mut header = vec![0; 512];
+        header[MBR_SIG_OFFSET] = 0x55;
+        header[MBR_SIG_OFFSET + 1] = 0xAA;
+        header[MBR_PART_TABLE_OFFSET] = 0x80;
+        header[MBR_PART_TABLE_OFFSET + 4] = 0x0C;
+        header
+    }
+
+    #[test]
+    fn detect_headers() {
+        let mut header = mbr_header();
+        assert!(is_disk_image(&header));
+        assert!(!is_kernel_amd64(&header));
+        header[MBR_PART_TABLE_OFFSET] = 0x12;
+        assert!(!is_disk_image(&header));
+
+        // bzImage, boot protocol 2.15 with a 64 bit entry point
+        let mut header = mbr_header();
+        header.resize(HEADER_SIZE, 0);
+        header[BZIMAGE_MAGIC_OFFSET..BZIMAGE_MAGIC_OFFSET + 4].copy_from_slice(BZIMAGE_MAGIC);
+        header[BZIMAGE_VERSION_OFFSET] = 0x0F;
+        header[BZIMAGE_VERSION_OFFSET + 1] = 0x02;
+        assert!(!is_kernel_amd64(&header));
+        header[BZIMAGE_XLOADFLAGS_OFFSET] = 0x7F;
+        assert!(is_kernel_amd64(&header));
+        assert!(!is_disk_image(&header));
+
+        assert!(is_cpio(b"07070100000001000041ed"));
+        assert!(!is_cpio(b"070707"));
+        assert!(!has_magic(b"\xD0\x0D\xFE", 0, FDT_MAGIC));
+
+        assert!(is_text(b"[connection]\nid=wifi\tname\n"));
+        assert!(is_text("ssid=caf\u{e9}".as_bytes()));
+        assert!(is_text(&"ssid=caf\u{e9}".as_bytes()[..9]));
+        assert!(!is_text(b"abc\0def"));
+        assert!(!is_text(b"\xFF\xFE"));
+    }
+
+    #[test]
+    fn kernel_release() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let banner: &[u8] =
+            b"Linux version %s\0Linux version 5.4.83-v7l+ (dom@buildbot) (gcc) #1379 SMP\n";
+        assert_eq!(
+            find_kernel_release(banner, true),
+            Some(String::from("5.4.83-v7l+"))
+        );
+        // the release might continue in the next window
+        assert_eq!(find_kernel_release(banner, false), None);
+        assert_eq!(find_kernel_release(b"Linux version ", true), None);
+
+        // the banner spans two chunks
+        let mut image = vec![0; SCAN_CHUNK_SIZE - 20];
+        image.extend_from_slice(banner);
+        image.extend_from_slice(&[0; 1024]);
+        assert_eq!(
+            read_kernel_release(&mut &image[..]).unwrap(),
+            Some(String::from("5.4.83-v7l+"))
+        );
+
+        // a zImage like layout, decompressor code followed by the gzipped kernel and its size
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&vec![0; 3 * SCAN_CHUNK_SIZE]).unwrap();
+        encoder.write_all(banner).unwrap();
+        let mut image = vec![0x1F, 0x8B, 0x00, 0x13];
+        image.resize(SCAN_CHUNK_SIZE - 1, 0);
+        image.extend_from_slice(&encoder.finish().unwrap());
+        image.extend_from_slice(&[0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(read_kernel_release(&mut &image[..]).unwrap(), None);
+
+        let path = std::env::temp_dir().join("balena-migrate-test-zimage");
+        std::fs::write(&path, &image).unwrap();
+        assert_eq!(
+            get_kernel_release(&path).unwrap(),
+            Some(String::from("5.4.83-v7l+"))
+        );
+        std::fs::write(&path, &image[..SCAN_CHUNK_SIZE]).unwrap();
+        assert_eq!(get_kernel_release(&path).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -8,15 +8,17 @@ use crate::{
             MigrateWifis,
         },
         device_info::DeviceInfo,
+        disk_util::Guid,
         file_info::RelFileInfo,
         os_api::OSApi,
+        os_policy::OsIdent,
         path_info::PathInfo,
-        stage2_config::{CheckedFSDump, CheckedImageType, CheckedPartDump},
+        stage2_config::{CheckedFSDump, CheckedFlasherImage, CheckedImageType, CheckedPartDump},
         wifi_config::WifiConfig,
         Config, FileInfo, MigError, MigErrorKind,
     },
-    defs::FileType,
     defs::OSArch,
+    defs::{FileType, COMPRESSED_OS_IMAGE_TYPES},
 };
 
 // *************************************************************************************************
@@ -31,7 +33,7 @@ pub(crate) use balena_cfg_json::BalenaCfgJson;
 
 #[derive(Debug)]
 pub(crate) struct MigrateInfo {
-    pub os_name: String,
+    pub os_ident: OsIdent,
     pub os_arch: OSArch,
 
     pub work_path: PathInfo,
@@ -84,14 +86,26 @@ impl MigrateInfo {
 
         let os_image = match config.balena.get_image_path() {
             ImageType::Flasher(ref flasher_img) => {
-                let checked_ref = MigrateInfo::check_file(
-                    &flasher_img,
-                    &FileType::GZipOSImage,
+                let image = MigrateInfo::check_file(
+                    &flasher_img.image,
+                    COMPRESSED_OS_IMAGE_TYPES,
                     &work_path,
                     os_api,
                 )?;
 
-                CheckedImageType::Flasher(checked_ref)
+                let bmap = if let Some(ref bmap) = flasher_img.bmap {
+                    Some(MigrateInfo::check_file(
+                        bmap,
+                        &[FileType::BlockMap],
+                        &work_path,
+                        os_api,
+                    )?)
+                } else {
+                    info!("No block map was configured for the balena OS image, unmapped blocks will be detected while flashing");
+                    None
+                };
+
+                CheckedImageType::Flasher(CheckedFlasherImage { image, bmap })
             }
             ImageType::FileSystems(ref fs_dump) => {
                 // make sure all files are present and in /workdir, generate total size and partitioning config in miginfo
@@ -101,26 +115,12 @@ impl MigrateInfo {
                     max_data: fs_dump.max_data,
                     mkfs_direct: fs_dump.mkfs_direct,
                     extended_blocks: fs_dump.extended_blocks,
-                    boot: CheckedPartDump {
-                        archive: MigrateInfo::check_dump(&fs_dump.boot, &work_path, os_api)?,
-                        blocks: fs_dump.boot.blocks,
-                    },
-                    root_a: CheckedPartDump {
-                        archive: MigrateInfo::check_dump(&fs_dump.root_a, &work_path, os_api)?,
-                        blocks: fs_dump.root_a.blocks,
-                    },
-                    root_b: CheckedPartDump {
-                        archive: MigrateInfo::check_dump(&fs_dump.root_b, &work_path, os_api)?,
-                        blocks: fs_dump.root_b.blocks,
-                    },
-                    state: CheckedPartDump {
-                        archive: MigrateInfo::check_dump(&fs_dump.state, &work_path, os_api)?,
-                        blocks: fs_dump.state.blocks,
-                    },
-                    data: CheckedPartDump {
-                        archive: MigrateInfo::check_dump(&fs_dump.data, &work_path, os_api)?,
-                        blocks: fs_dump.data.blocks,
-                    },
+                    part_table: fs_dump.part_table.clone(),
+                    boot: MigrateInfo::check_dump(&fs_dump.boot, &work_path, os_api)?,
+                    root_a: MigrateInfo::check_dump(&fs_dump.root_a, &work_path, os_api)?,
+                    root_b: MigrateInfo::check_dump(&fs_dump.root_b, &work_path, os_api)?,
+                    state: MigrateInfo::check_dump(&fs_dump.state, &work_path, os_api)?,
+                    data: MigrateInfo::check_dump(&fs_dump.data, &work_path, os_api)?,
                 })
             }
         };
@@ -274,7 +274,7 @@ impl MigrateInfo {
         }
 
         let result = MigrateInfo {
-            os_name: os_api.get_os_name()?,
+            os_ident: os_api.get_os_ident()?,
             os_arch,
             work_path,
             log_path,
@@ -296,18 +296,33 @@ impl MigrateInfo {
         dump: &PartDump,
         work_path: &PathInfo,
         os_api: &impl OSApi,
-    ) -> Result<RelFileInfo, MigError> {
-        Ok(MigrateInfo::check_file(
-            &dump.archive,
-            &FileType::GZipTar,
-            work_path,
-            os_api,
-        )?)
+    ) -> Result<CheckedPartDump, MigError> {
+        if let Some(ref type_guid) = dump.type_guid {
+            if Guid::from_str(type_guid).is_none() {
+                error!(
+                    "Invalid partition type GUID '{}' configured for '{}'",
+                    type_guid,
+                    dump.archive.path.display()
+                );
+                return Err(MigError::displayed());
+            }
+        }
+
+        Ok(CheckedPartDump {
+            archive: MigrateInfo::check_file(
+                &dump.archive,
+                &[FileType::GZipTar],
+                work_path,
+                os_api,
+            )?,
+            blocks: dump.blocks,
+            type_guid: dump.type_guid.clone(),
+        })
     }
 
     fn check_file(
         file_ref: &FileRef,
-        expected_type: &FileType,
+        expected_types: &[FileType],
         work_path: &PathInfo,
         os_api: &impl OSApi,
     ) -> Result<RelFileInfo, MigError> {
@@ -327,21 +342,32 @@ impl MigrateInfo {
             }
 
             // ensure expected type
-            match os_api.expect_type(&file_info.path, expected_type) {
-                Ok(_) => {
-                    info!("The file '{}' image looks ok", file_info.path.display());
-                }
-                Err(_why) => {
-                    // TODO: try gzip non compressed OS image
-                    error!(
-                        "The file '{}' does not match the expected type: '{:?}'",
-                        file_ref.path.display(),
-                        expected_type
-                    );
-                    return Err(MigError::displayed());
+            let mut found_type: Option<&FileType> = None;
+            for expected_type in expected_types {
+                if os_api.is_file_type(&file_info.path, expected_type)? {
+                    found_type = Some(expected_type);
+                    break;
                 }
             }
 
+            if let Some(found_type) = found_type {
+                info!(
+                    "The file '{}' looks ok, found: '{}'",
+                    file_info.path.display(),
+                    found_type.get_descr()
+                );
+            } else {
+                error!(
+                    "The file '{}' does not match any of the expected types: {:?}",
+                    file_ref.path.display(),
+                    expected_types
+                        .iter()
+                        .map(|ftype| ftype.get_descr())
+                        .collect::<Vec<&str>>()
+                );
+                return Err(MigError::displayed());
+            }
+
             Ok(RelFileInfo {
                 rel_path,
                 size: file_info.size,
@@ -0,0 +1,331 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+
+use crate::common::{MigError, MigErrorKind};
+
+// ******************************************************************
+// OS support policy - the OS is identified by the ID, ID_LIKE and
+// VERSION_ID fields of /etc/os-release and matched against the
+// os_policy rules of the device catalog entry.
+// The first matching rule decides, OSses without a matching rule
+// are untested.
+// ******************************************************************
+
+#[derive(Debug, Clone)]
+pub(crate) struct OsIdent {
+    pub name: String,
+    pub id: String,
+    pub id_like: Vec<String>,
+    pub version_id: Option<String>,
+}
+
+impl OsIdent {
+    // parse the contents of an os-release file
+    pub fn from_os_release(content: &str) -> OsIdent {
+        let mut pretty_name: Option<String> = None;
+        let mut name: Option<String> = None;
+        let mut version: Option<String> = None;
+        let mut id: Option<String> = None;
+        let mut id_like: Vec<String> = Vec::new();
+        let mut version_id: Option<String> = None;
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(pos) = line.find('=') {
+                let value = line[pos + 1..]
+                    .trim()
+                    .trim_matches(|c| c == '"' || c == '\'');
+                match &line[..pos] {
+                    "PRETTY_NAME" => pretty_name = Some(String::from(value)),
+                    "NAME" => name = Some(String::from(value)),
+                    "VERSION" => version = Some(String::from(value)),
+                    "ID" => id = Some(value.to_lowercase()),
+                    "ID_LIKE" => {
+                        id_like = value
+                            .split_whitespace()
+                            .map(|id| id.to_lowercase())
+                            .collect()
+                    }
+                    "VERSION_ID" => version_id = Some(String::from(value)),
+                    _ => (),
+                }
+            }
+        }
+
+        // defaults as defined in os-release(5)
+        let name = if let Some(pretty_name) = pretty_name {
+            pretty_name
+        } else {
+            match (name, version) {
+                (Some(name), Some(version)) => format!("{} {}", name, version),
+                (Some(name), None) => name,
+                (None, _) => String::from("Linux"),
+            }
+        };
+
+        OsIdent {
+            name,
+            id: id.unwrap_or_else(|| String::from("linux")),
+            id_like,
+            version_id,
+        }
+    }
+}
+
+impl Display for OsIdent {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub(crate) enum OsSupport {
+    #[serde(rename = "supported")]
+    Supported,
+    // requires migrate.allow_untested_os
+    #[serde(rename = "untested")]
+    Untested,
+    // known not to work
+    #[serde(rename = "blocked")]
+    Blocked,
+}
+
+impl Display for OsSupport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            OsSupport::Supported => write!(f, "supported"),
+            OsSupport::Untested => write!(f, "untested"),
+            OsSupport::Blocked => write!(f, "blocked"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct OsRule {
+    // matches ID
+    pub id: Option<String>,
+    // matches ID or any of ID_LIKE
+    pub id_like: Option<String>,
+    // comma separated requirements on VERSION_ID, eg. '>=9, <13' or '20.04'
+    pub version: Option<String>,
+    pub support: OsSupport,
+    pub reason: Option<String>,
+}
+
+impl OsRule {
+    fn is_match(&self, os: &OsIdent) -> Result<bool, MigError> {
+        if let Some(ref id) = self.id {
+            if *id != os.id {
+                return Ok(false);
+            }
+        }
+
+        if let Some(ref id_like) = self.id_like {
+            if *id_like != os.id && !os.id_like.contains(id_like) {
+                return Ok(false);
+            }
+        }
+
+        if let Some(ref version) = self.version {
+            if let Some(ref version_id) = os.version_id {
+                if !is_version_match(version, version_id)? {
+                    return Ok(false);
+                }
+            } else {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl Display for OsRule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut conditions: Vec<String> = Vec::new();
+        if let Some(ref id) = self.id {
+            conditions.push(format!("id: {}", id));
+        }
+        if let Some(ref id_like) = self.id_like {
+            conditions.push(format!("id_like: {}", id_like));
+        }
+        if let Some(ref version) = self.version {
+            conditions.push(format!("version: '{}'", version));
+        }
+        if conditions.is_empty() {
+            write!(f, "any OS")
+        } else {
+            write!(f, "{}", conditions.join(", "))
+        }
+    }
+}
+
+// evaluate the rules for the given OS, returns the outcome and the reason for it
+pub(crate) fn check_os_policy(
+    rules: &[OsRule],
+    os: &OsIdent,
+) -> Result<(OsSupport, String), MigError> {
+    for rule in rules {
+        if rule.is_match(os)? {
+            debug!("check_os_policy: '{}' matches rule '{}'", os, rule);
+            let reason = if let Some(ref reason) = rule.reason {
+                format!("{} (rule '{}')", reason, rule)
+            } else {
+                format!("matched rule '{}'", rule)
+            };
+            return Ok((rule.support, reason));
+        }
+    }
+
+    Ok((
+        OsSupport::Untested,
+        format!(
+            "no rule for ID '{}', ID_LIKE '{}', VERSION_ID '{}'",
+            os.id,
+            os.id_like.join(" "),
+            os.version_id.as_deref().unwrap_or("")
+        ),
+    ))
+}
+
+// compare dot separated versions numerically, missing parts count as 0
+fn cmp_version(left: &str, right: &str) -> Ordering {
+    let parse = |version: &str| -> Vec<u64> {
+        version
+            .split('.')
+            .map(|part| {
+                let digits: String = part.chars().take_while(|c| c.is_ascii_digit()).collect();
+                digits.parse::<u64>().unwrap_or(0)
+            })
+            .collect()
+    };
+
+    let left = parse(left);
+    let right = parse(right);
+    for idx in 0..left.len().max(right.len()) {
+        let ordering = left
+            .get(idx)
+            .unwrap_or(&0)
+            .cmp(right.get(idx).unwrap_or(&0));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+fn is_version_match(requirements: &str, version: &str) -> Result<bool, MigError> {
+    for requirement in requirements.split(',') {
+        let requirement = requirement.trim();
+        let (operator, req_version) =
+            if requirement.starts_with(">=") || requirement.starts_with("<=") {
+                requirement.split_at(2)
+            } else if requirement.starts_with('>')
+                || requirement.starts_with('<')
+                || requirement.starts_with('=')
+            {
+                requirement.split_at(1)
+            } else {
+                ("=", requirement)
+            };
+
+        let req_version = req_version.trim();
+        if req_version.is_empty() {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("Invalid version requirement '{}'", requirements),
+            ));
+        }
+
+        let ordering = cmp_version(version, req_version);
+        let is_match = match operator {
+            ">=" => ordering != Ordering::Less,
+            "<=" => ordering != Ordering::Greater,
+            ">" => ordering == Ordering::Greater,
+            "<" => ordering == Ordering::Less,
+            _ => ordering == Ordering::Equal,
+        };
+
+        if !is_match {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_os_policy, OsIdent, OsRule, OsSupport};
+
+    const RASPBIAN_OS_RELEASE: &str = r##"PRETTY_NAME="Raspbian GNU/Linux 10 (buster)"
+NAME="Raspbian GNU/Linux"
+VERSION_ID="10"
+VERSION="10 (buster)"
+VERSION_CODENAME=buster
+ID=raspbian
+ID_LIKE=debian
+HOME_URL="http://www.raspbian.org/"
+"##;
+
+    const RULES: &str = r##"
+- id: ubuntu-core
+  support: blocked
+  reason: the boot configuration is read only
+- id: raspbian
+  version: '>=9, <13'
+  support: supported
+- id: ubuntu
+  version: '18.04'
+  support: supported
+- id_like: debian
+  support: untested
+  reason: debian derivative
+"##;
+
+    fn get_support(rules: &[OsRule], os_release: &str) -> OsSupport {
+        check_os_policy(rules, &OsIdent::from_os_release(os_release))
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn check_policy() {
+        let os = OsIdent::from_os_release(RASPBIAN_OS_RELEASE);
+        assert_eq!(os.name, "Raspbian GNU/Linux 10 (buster)");
+        assert_eq!(os.id, "raspbian");
+        assert_eq!(os.id_like, vec!["debian"]);
+        assert_eq!(os.version_id.as_deref(), Some("10"));
+
+        let rules: Vec<OsRule> = serde_yaml::from_str(RULES).unwrap();
+        assert_eq!(
+            get_support(&rules, RASPBIAN_OS_RELEASE),
+            OsSupport::Supported
+        );
+        assert_eq!(
+            get_support(&rules, "ID=raspbian\nID_LIKE=debian\nVERSION_ID=\"8\"\n"),
+            OsSupport::Untested
+        );
+        assert_eq!(
+            get_support(&rules, "ID=ubuntu\nID_LIKE=debian\nVERSION_ID=\"18.04\"\n"),
+            OsSupport::Supported
+        );
+        assert_eq!(
+            get_support(&rules, "ID=ubuntu\nID_LIKE=debian\nVERSION_ID=\"18.10\"\n"),
+            OsSupport::Untested
+        );
+        assert_eq!(
+            get_support(&rules, "ID=ubuntu-core\nVERSION_ID=\"20\"\n"),
+            OsSupport::Blocked
+        );
+
+        let (support, reason) = check_os_policy(&rules, &OsIdent::from_os_release("")).unwrap();
+        assert_eq!(support, OsSupport::Untested);
+        assert!(reason.contains("'linux'"));
+    }
+}
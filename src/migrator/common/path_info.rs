@@ -2,7 +2,9 @@ use failure::ResultExt;
 use log::error;
 use std::path::{Path, PathBuf};
 
-use crate::common::{device_info::DeviceInfo, MigErrCtx, MigError, MigErrorKind};
+use crate::common::{
+    device_info::DeviceInfo, stage2_config::MountConfig, MigErrCtx, MigError, MigErrorKind,
+};
 
 #[cfg(target_os = "linux")]
 use crate::linux::{
@@ -90,4 +92,25 @@ impl PathInfo {
             fs_free,
         })
     }
+
+    // describe the path by its partition and the path relative to the partition root,
+    // so it can be found in stage2
+    pub fn get_mount_config(&self) -> Result<MountConfig, MigError> {
+        let rel_path = self
+            .path
+            .strip_prefix(&self.mountpoint)
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "failed to create relative path for '{}'",
+                    self.path.display()
+                ),
+            ))?;
+
+        Ok(MountConfig::new(
+            &self.device_info.get_alt_path(),
+            &self.device_info.fs_type,
+            rel_path,
+        ))
+    }
 }
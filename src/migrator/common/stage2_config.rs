@@ -9,22 +9,31 @@ use serde::{Deserialize, Serialize};
 use serde_yaml;
 
 pub const EMPTY_BACKUPS: &[(String, String)] = &[];
+pub const EMPTY_BOOT_FILES: &[MountConfig] = &[];
+pub const EMPTY_BOOT_DIRS: &[BootDirState] = &[];
+pub const EMPTY_RPI_CONFIG: &[String] = &[];
 
 const MODULE: &str = "stage2::stage2:config";
 
 use crate::{
     common::{
-        config::{balena_config::PartCheck, migrate_config::WatchdogCfg},
+        config::{
+            balena_config::{PartCheck, PartTable},
+            migrate_config::WatchdogCfg,
+        },
+        device_catalog::DeviceEntry,
+        file_digest::HashInfo,
         file_info::RelFileInfo,
         MigErrCtx, MigError, MigErrorKind,
     },
-    defs::{BootType, DeviceType, FailMode},
+    defs::{BootType, FailMode},
 };
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) struct CheckedPartDump {
     pub blocks: u64,
     pub archive: RelFileInfo,
+    pub type_guid: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -34,6 +43,7 @@ pub(crate) struct CheckedFSDump {
     pub check: Option<PartCheck>,
     pub max_data: Option<bool>,
     pub mkfs_direct: Option<bool>,
+    pub part_table: Option<PartTable>,
     pub boot: CheckedPartDump,
     pub root_a: CheckedPartDump,
     pub root_b: CheckedPartDump,
@@ -41,17 +51,30 @@ pub(crate) struct CheckedFSDump {
     pub data: CheckedPartDump,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct CheckedFlasherImage {
+    #[serde(flatten)]
+    pub image: RelFileInfo,
+    pub bmap: Option<RelFileInfo>,
+}
+
 #[allow(clippy::large_enum_variant)] //TODO refactor to remove clippy warning
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) enum CheckedImageType {
-    Flasher(RelFileInfo),
+    Flasher(CheckedFlasherImage),
     FileSystems(CheckedFSDump),
 }
 
 impl CheckedImageType {
     pub fn get_required_space(&self) -> u64 {
         match self {
-            CheckedImageType::Flasher(ref flasher) => flasher.size,
+            CheckedImageType::Flasher(ref flasher) => {
+                if let Some(ref bmap) = flasher.bmap {
+                    flasher.image.size + bmap.size
+                } else {
+                    flasher.image.size
+                }
+            }
             CheckedImageType::FileSystems(ref file_systems) => {
                 file_systems.boot.archive.size
                     + file_systems.root_a.archive.size
@@ -99,6 +122,22 @@ impl<'a> MountConfig {
     }
 }
 
+// an entry of a directory boot files are installed to, digest is only set for regular files
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub(crate) struct DirEntryInfo {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub digest: Option<HashInfo>,
+}
+
+// contents of a directory before boot files were installed to it
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct BootDirState {
+    pub dir: MountConfig,
+    pub entries: Vec<DirEntryInfo>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) enum PathType {
     Path(PathBuf),
@@ -121,18 +160,36 @@ pub(crate) struct Stage2Config {
     work_path: PathType,
     // backed up former boot configuration (from , to) expected in boot manager
     boot_bckup: Option<Vec<(String, String)>>,
+    // EFI boot entry (Boot####) created for the migration
+    efi_boot_entry: Option<u16>,
+    // files installed for the migrate boot, removed when restoring the former boot configuration
+    boot_files: Option<Vec<MountConfig>>,
+    // former contents of the directories boot files were installed to, checked after restoring
+    boot_dirs: Option<Vec<BootDirState>>,
+    // grub environment block holding the one-shot boot entry
+    grub_env: Option<MountConfig>,
+    // grub config containing the migrate menu entry
+    grub_cfg: Option<MountConfig>,
+    // raspberry pi config.txt directives to merge into the balena config.txt
+    rpi_config: Option<Vec<String>>,
+    // release of the migrate kernel (uname -r) as found in the kernel image
+    kernel_release: Option<String>,
     // backup present in work_dir/backup.tgz
     has_backup: bool,
+    // digest of the backup, verified after copying it
+    backup_digest: Option<HashInfo>,
     // use rust internal gzip
     gzip_internal: bool,
+    // read back and compare the flashed image
+    flash_verify: bool,
     // stage 2 log level
     log_level: String,
     // stage 2 log destination
     log_to: Option<Stage2LogConfig>,
     // log also to console
     log_console: bool,
-    // device type
-    device_type: DeviceType,
+    // device catalog entry
+    device: DeviceEntry,
     // boot type
     boot_type: BootType,
     // delay migration in stage 2
@@ -196,6 +253,10 @@ impl<'a> Stage2Config {
         self.has_backup
     }
 
+    pub fn get_backup_digest(&'a self) -> Option<&'a HashInfo> {
+        self.backup_digest.as_ref()
+    }
+
     pub fn is_no_flash(&self) -> bool {
         self.no_flash
     }
@@ -204,6 +265,10 @@ impl<'a> Stage2Config {
         self.gzip_internal
     }
 
+    pub fn is_flash_verify(&self) -> bool {
+        self.flash_verify
+    }
+
     pub fn get_force_flash_device(&'a self) -> Option<&'a PathBuf> {
         if let Some(ref flash_device) = self.force_flash_device {
             Some(flash_device)
@@ -232,8 +297,8 @@ impl<'a> Stage2Config {
         &self.boot_type
     }
 
-    pub fn get_device_type(&'a self) -> &'a DeviceType {
-        &self.device_type
+    pub fn get_device(&'a self) -> &'a DeviceEntry {
+        &self.device
     }
 
     pub fn get_balena_image(&'a self) -> &'a CheckedImageType {
@@ -252,6 +317,46 @@ impl<'a> Stage2Config {
         }
     }
 
+    pub fn get_efi_boot_entry(&self) -> Option<u16> {
+        self.efi_boot_entry
+    }
+
+    pub fn get_boot_files(&'a self) -> &'a [MountConfig] {
+        if let Some(ref boot_files) = self.boot_files {
+            boot_files.as_slice()
+        } else {
+            EMPTY_BOOT_FILES
+        }
+    }
+
+    pub fn get_boot_dirs(&'a self) -> &'a [BootDirState] {
+        if let Some(ref boot_dirs) = self.boot_dirs {
+            boot_dirs.as_slice()
+        } else {
+            EMPTY_BOOT_DIRS
+        }
+    }
+
+    pub fn get_grub_env(&'a self) -> Option<&'a MountConfig> {
+        self.grub_env.as_ref()
+    }
+
+    pub fn get_grub_cfg(&'a self) -> Option<&'a MountConfig> {
+        self.grub_cfg.as_ref()
+    }
+
+    pub fn get_rpi_config(&'a self) -> &'a [String] {
+        if let Some(ref rpi_config) = self.rpi_config {
+            rpi_config.as_slice()
+        } else {
+            EMPTY_RPI_CONFIG
+        }
+    }
+
+    pub fn get_kernel_release(&'a self) -> Option<&'a str> {
+        self.kernel_release.as_deref()
+    }
+
     pub fn get_work_path(&'a self) -> &'a PathType {
         &self.work_path
     }
@@ -333,12 +438,21 @@ pub(crate) struct Stage2ConfigBuilder {
     balena_image: Required<CheckedImageType>,
     work_path: Required<PathType>,
     boot_bckup: Optional<Vec<(String, String)>>,
+    efi_boot_entry: Optional<u16>,
+    boot_files: Optional<Vec<MountConfig>>,
+    boot_dirs: Optional<Vec<BootDirState>>,
+    grub_env: Optional<MountConfig>,
+    grub_cfg: Optional<MountConfig>,
+    rpi_config: Optional<Vec<String>>,
+    kernel_release: Optional<String>,
     has_backup: Required<bool>,
+    backup_digest: Optional<HashInfo>,
     gzip_internal: Required<bool>,
+    flash_verify: Required<bool>,
     log_level: Required<String>,
     log_to: Optional<Stage2LogConfig>,
     log_console: Required<bool>,
-    device_type: Required<DeviceType>,
+    device: Required<DeviceEntry>,
     boot_type: Required<BootType>,
     migrate_delay: Optional<u64>,
     watchdogs: Optional<Vec<WatchdogCfg>>,
@@ -354,12 +468,21 @@ impl<'a> Stage2ConfigBuilder {
             balena_image: Required::new("balena_image", None),
             work_path: Required::new("work_path", None),
             boot_bckup: Optional::new(None),
+            efi_boot_entry: Optional::new(None),
+            boot_files: Optional::new(None),
+            boot_dirs: Optional::new(None),
+            grub_env: Optional::new(None),
+            grub_cfg: Optional::new(None),
+            rpi_config: Optional::new(None),
+            kernel_release: Optional::new(None),
             has_backup: Required::new("has_backup", None),
+            backup_digest: Optional::new(None),
             gzip_internal: Required::new("gzip_internal", Some(&true)),
+            flash_verify: Required::new("flash_verify", Some(&false)),
             log_level: Required::new("log_level", Some(&String::from("warn"))),
             log_to: Optional::new(None),
             log_console: Required::new("log_console", Some(&false)),
-            device_type: Required::new("device_type", None),
+            device: Required::new("device", None),
             boot_type: Required::new("boot_type", None),
             migrate_delay: Optional::new(None),
             watchdogs: Optional::new(None),
@@ -375,12 +498,21 @@ impl<'a> Stage2ConfigBuilder {
             balena_image: self.balena_image.get()?.clone(),
             work_path: self.work_path.get()?.clone(),
             boot_bckup: self.boot_bckup.get().clone(),
+            efi_boot_entry: *self.efi_boot_entry.get(),
+            boot_files: self.boot_files.get().clone(),
+            boot_dirs: self.boot_dirs.get().clone(),
+            grub_env: self.grub_env.get().clone(),
+            grub_cfg: self.grub_cfg.get().clone(),
+            rpi_config: self.rpi_config.get().clone(),
+            kernel_release: self.kernel_release.get().clone(),
             has_backup: *self.has_backup.get()?,
+            backup_digest: self.backup_digest.get().clone(),
             gzip_internal: *self.gzip_internal.get()?,
+            flash_verify: *self.flash_verify.get()?,
             log_level: self.log_level.get()?.clone(),
             log_to: self.log_to.get().clone(),
             log_console: *self.log_console.get()?,
-            device_type: *self.device_type.get()?,
+            device: self.device.get()?.clone(),
             boot_type: *self.boot_type.get()?,
             migrate_delay: *self.migrate_delay.get(),
             watchdogs: self.watchdogs.get().clone(),
@@ -448,17 +580,53 @@ impl<'a> Stage2ConfigBuilder {
         self.boot_bckup.set(boot_backup);
     }
 
+    pub fn set_efi_boot_entry(&mut self, val: u16) {
+        self.efi_boot_entry.set(val);
+    }
+
+    pub fn set_boot_files(&mut self, val: Vec<MountConfig>) {
+        self.boot_files.set(val);
+    }
+
+    pub fn set_boot_dirs(&mut self, val: Vec<BootDirState>) {
+        self.boot_dirs.set(val);
+    }
+
+    pub fn set_grub_env(&mut self, val: MountConfig) {
+        self.grub_env.set(val);
+    }
+
+    pub fn set_grub_cfg(&mut self, val: MountConfig) {
+        self.grub_cfg.set(val);
+    }
+
+    pub fn set_rpi_config(&mut self, val: Vec<String>) {
+        self.rpi_config.set(val);
+    }
+
+    pub fn set_kernel_release(&mut self, val: String) {
+        self.kernel_release.set(val);
+    }
+
     pub fn set_has_backup(&mut self, val: bool) -> bool {
         self.has_backup.set(val);
         val
     }
 
+    pub fn set_backup_digest(&mut self, val: HashInfo) {
+        self.backup_digest.set(val);
+    }
+
     pub fn set_gzip_internal(&mut self, val: bool) {
         self.gzip_internal.set(val);
     }
 
-    pub fn set_device_type(&mut self, dev_type: DeviceType) {
-        self.device_type.set(dev_type);
+    pub fn set_flash_verify(&mut self, val: bool) {
+        self.flash_verify.set(val);
+    }
+
+    pub fn set_device(&mut self, device: DeviceEntry) {
+        self.device.set(device);
     }
 
     pub fn set_log_level(&mut self, val: String) {
@@ -508,12 +676,24 @@ work_path:
 boot_bckup: ~
 has_backup: false
 gzip_internal: true
+flash_verify: false
 log_level: debug
 log_to:
   device: /dev/sdb1
   fstype: vfat
 log_console: false
-device_type: IntelNuc
+device:
+  name: Intel NUC
+  slug: intel-nuc
+  arch: amd64
+  model: ~
+  compatible: ~
+  boot_manager:
+    grub:
+      prefer_efi: true
+  kernel_opts: ~
+  supported_os: []
+  emmc_boot: ~
 boot_type: Grub
 migrate_delay: 0
 watchdogs: ~'
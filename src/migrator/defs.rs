@@ -75,23 +75,13 @@ pub(crate) enum BootType {
     MSWBootMgr,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
-pub(crate) enum DeviceType {
-    BeagleboneGreen,
-    BeagleboneBlack,
-    BeagleboardXM,
-    IntelNuc,
-    RaspberryPi3,
-    RaspberryPi4_64,
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum OSArch {
     AMD64,
     ARMHF,
+    AARCH64,
     I386,
     /*
-        ARM64,
         ARMEL,
         MIPS,
         MIPSEL,
@@ -122,6 +112,9 @@ impl FailMode {
 #[derive(Debug, Clone)]
 pub(crate) enum FileType {
     GZipOSImage,
+    ZipOSImage,
+    XzOSImage,
+    ZstdOSImage,
     OSImage,
     KernelAMD64,
     KernelARMHF,
@@ -132,12 +125,24 @@ pub(crate) enum FileType {
     Text,
     DTB,
     GZipTar,
+    BlockMap,
 }
 
+// compressed OS image types accepted for flashing
+pub(crate) const COMPRESSED_OS_IMAGE_TYPES: &[FileType] = &[
+    FileType::GZipOSImage,
+    FileType::ZipOSImage,
+    FileType::XzOSImage,
+    FileType::ZstdOSImage,
+];
+
 impl FileType {
     pub fn get_descr(&self) -> &str {
         match self {
             FileType::GZipOSImage => "gzipped balena OS image",
+            FileType::ZipOSImage => "zipped balena OS image",
+            FileType::XzOSImage => "xz compressed balena OS image",
+            FileType::ZstdOSImage => "zstd compressed balena OS image",
             FileType::OSImage => "balena OS image",
             FileType::KernelAMD64 => "balena migrate kernel image for AMD64",
             FileType::KernelARMHF => "balena migrate kernel image for ARMHF",
@@ -148,6 +153,7 @@ impl FileType {
             FileType::Json => "balena config.json file",
             FileType::Text => "Text file",
             FileType::GZipTar => "Gzipped Tar file",
+            FileType::BlockMap => "bmap block map file",
         }
     }
 }
@@ -18,20 +18,21 @@ use crate::{
     common::disk_util::PartitionType,
     common::{
         call,
-        config::balena_config::{FSDump, FileRef, ImageType, PartDump},
+        config::balena_config::{FSDump, FileRef, ImageType, PartDump, PartTable},
         disk_util::{Disk, PartitionIterator, PartitionReader}, //  , ImageFile, GZipFile, PlainFile },
         file_digest::get_default_digest,
+        file_type::is_file_type,
         path_append,
         MigErrCtx,
         MigError,
         MigErrorKind,
     },
-    defs::FileType,
     defs::PART_INFO,
+    defs::{FileType, COMPRESSED_OS_IMAGE_TYPES},
     linux::{
-        linux_common::{is_admin, is_file_type, mktemp, whereis},
+        linux_common::{is_admin, mktemp, whereis},
         linux_defs::NIX_NONE,
-        linux_defs::{FILE_CMD, LOSETUP_CMD, MKTEMP_CMD, TAR_CMD},
+        linux_defs::{LOSETUP_CMD, MKTEMP_CMD, TAR_CMD},
     },
 };
 
@@ -44,7 +45,7 @@ use crate::{
 // mod plain_file;
 // use plain_file::PlainFile;
 
-const REQUIRED_CMDS: &[&str] = &[FILE_CMD, MKTEMP_CMD, TAR_CMD, LOSETUP_CMD];
+const REQUIRED_CMDS: &[&str] = &[MKTEMP_CMD, TAR_CMD, LOSETUP_CMD];
 const DEF_BUFFER_SIZE: usize = 1024 * 1024;
 
 const EXTRACT_FILE_TEMPLATE: &str = "extract.XXXXXXXXXX";
@@ -57,6 +58,7 @@ pub(crate) struct Partition {
     pub status: u8,
     pub start_lba: u64,
     pub num_sectors: u64,
+    pub type_guid: Option<String>,
     pub archive: Option<FileRef>,
 }
 
@@ -183,19 +185,29 @@ impl Extractor {
         }
 
         debug!("new: working with file '{}'", image_file.display());
-        if is_file_type(&image_file, &FileType::GZipOSImage)? {
-            match Disk::from_gzip_img(&image_file) {
-                Ok(gzip_img) => {
-                    debug!("new: is gzipped image '{}'", image_file.display());
-                    Ok(Extractor {
-                        work_dir,
-                        disk: gzip_img,
-                        device_slug: extract_device,
-                    })
-                }
+        let mut is_compressed = false;
+        for image_type in COMPRESSED_OS_IMAGE_TYPES {
+            if is_file_type(&image_file, image_type)? {
+                debug!(
+                    "new: '{}' is a {}",
+                    image_file.display(),
+                    image_type.get_descr()
+                );
+                is_compressed = true;
+                break;
+            }
+        }
+
+        if is_compressed {
+            match Disk::from_image(&image_file, true) {
+                Ok(compressed_img) => Ok(Extractor {
+                    work_dir,
+                    disk: compressed_img,
+                    device_slug: extract_device,
+                }),
                 Err(why) => {
                     error!(
-                        "Unable to open the gzipped image file '{}', error: {:?}",
+                        "Unable to open the compressed image file '{}', error: {:?}",
                         image_file.display(),
                         why
                     );
@@ -265,25 +277,58 @@ impl Extractor {
         let mut part_iterator = PartitionIterator::new(&mut self.disk)?;
 
         let mut extended_blocks: u64 = 0;
+        let mut part_table = PartTable::Dos;
 
         while let Some(raw_part) = part_iterator.next() {
             let part_idx = partitions.len();
 
-            match PartitionType::from_ptype(raw_part.ptype) {
+            match raw_part.get_part_type() {
                 PartitionType::Container => {
                     extended_blocks = raw_part.num_sectors;
                     continue;
                 } // skip extended partition
                 PartitionType::Fat | PartitionType::Linux => (), // expected partition
                 _ => {
-                    return Err(MigError::from_remark(
-                        MigErrorKind::InvParam,
-                        &format!("Encountered unexpected partition type {:x}", raw_part.ptype),
-                    ));
+                    if let Some(ref type_guid) = raw_part.type_guid {
+                        return Err(MigError::from_remark(
+                            MigErrorKind::InvParam,
+                            &format!("Encountered unexpected partition type {}", type_guid),
+                        ));
+                    } else {
+                        return Err(MigError::from_remark(
+                            MigErrorKind::InvParam,
+                            &format!("Encountered unexpected partition type {:x}", raw_part.ptype),
+                        ));
+                    }
                 }
             }
 
+            if part_idx >= PART_INFO.len() {
+                error!(
+                    "Unexpected number of partitions found in image: '{}'",
+                    self.disk.get_image_file().display(),
+                );
+                return Err(MigError::displayed());
+            }
+
             let (part_label, part_fs_type) = PART_INFO[part_idx];
+
+            if let Some(ref part_guid) = raw_part.part_guid {
+                debug!(
+                    "GPT partition {} '{}' has the partition guid {}",
+                    raw_part.index, part_label, part_guid
+                );
+            }
+
+            if let Some(ref part_name) = raw_part.name {
+                if part_name != part_label {
+                    warn!(
+                        "GPT partition {} is named '{}', expected '{}'",
+                        raw_part.index, part_name, part_label
+                    );
+                }
+            }
+
             let mut partition = Partition {
                 name: part_label,
                 fstype: part_fs_type,
@@ -291,6 +336,12 @@ impl Extractor {
                 ptype: raw_part.ptype,
                 start_lba: raw_part.start_lba,
                 num_sectors: raw_part.num_sectors,
+                type_guid: if let Some(ref type_guid) = raw_part.type_guid {
+                    part_table = PartTable::Gpt;
+                    Some(type_guid.to_string())
+                } else {
+                    None
+                },
                 archive: None,
             };
 
@@ -362,25 +413,31 @@ impl Extractor {
                 max_data: None,
                 mkfs_direct: None,
                 extended_blocks,
+                part_table: Some(part_table),
                 boot: PartDump {
                     archive: partitions[0].archive.as_ref().unwrap().clone(),
                     blocks: partitions[0].num_sectors,
+                    type_guid: partitions[0].type_guid.clone(),
                 },
                 root_a: PartDump {
                     archive: partitions[1].archive.as_ref().unwrap().clone(),
                     blocks: partitions[1].num_sectors,
+                    type_guid: partitions[1].type_guid.clone(),
                 },
                 root_b: PartDump {
                     archive: partitions[2].archive.as_ref().unwrap().clone(),
                     blocks: partitions[2].num_sectors,
+                    type_guid: partitions[2].type_guid.clone(),
                 },
                 state: PartDump {
                     archive: partitions[3].archive.as_ref().unwrap().clone(),
                     blocks: partitions[3].num_sectors,
+                    type_guid: partitions[3].type_guid.clone(),
                 },
                 data: PartDump {
                     archive: partitions[4].archive.as_ref().unwrap().clone(),
                     blocks: partitions[4].num_sectors,
+                    type_guid: partitions[4].type_guid.clone(),
                 },
             });
 
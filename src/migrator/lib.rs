@@ -43,6 +43,10 @@ pub fn extract() -> Result<(), MigError> {
     extract::extract()
 }
 
+pub fn list_devices() -> Result<(), MigError> {
+    common::device_catalog::list_devices()
+}
+
 // TODO: move to stage 2 - leave only wrapper as above
 #[cfg(target_os = "linux")]
 pub fn stage2() -> Result<(), MigError> {
@@ -12,10 +12,13 @@ use crate::{
         backup, call,
         config::balena_config::ImageType,
         device::Device,
-        dir_exists, format_size_with_unit,
+        dir_exists,
+        disk_util::{Disk, LabelType},
+        file_digest::get_sha256_digest,
+        format_size_with_unit,
         migrate_info::MigrateInfo,
         path_append,
-        stage2_config::{PathType, Stage2ConfigBuilder, Stage2LogConfig},
+        stage2_config::{CheckedImageType, PathType, Stage2ConfigBuilder, Stage2LogConfig},
         Config, MigErrCtx, MigError, MigErrorKind, MigMode,
     },
     defs::{
@@ -25,7 +28,7 @@ use crate::{
 
 pub(crate) mod linux_defs;
 use linux_defs::{
-    CHMOD_CMD, DF_CMD, FILE_CMD, LSBLK_CMD, MKTEMP_CMD, MOUNT_CMD, REBOOT_CMD, TAR_CMD, UNAME_CMD,
+    CHMOD_CMD, DF_CMD, LSBLK_CMD, MKTEMP_CMD, MOUNT_CMD, REBOOT_CMD, TAR_CMD, UNAME_CMD,
 };
 
 pub(crate) mod device_impl;
@@ -41,6 +44,14 @@ pub(crate) mod lsblk_info;
 //pub(crate) use lsblk_info::LsblkInfo;
 
 pub(crate) mod linux_common;
+
+pub(crate) mod uboot_env;
+
+pub(crate) mod efi_vars;
+
+pub(crate) mod grub_env;
+
+pub(crate) mod rpi_config_txt;
 use crate::common::file_size;
 use crate::common::stage2_config::MountConfig;
 use crate::defs::VERSION;
@@ -51,7 +62,7 @@ use mod_logger::{LogDestination, Logger};
 
 const REQUIRED_CMDS: &[&str] = &[
     // TODO: check this
-    DF_CMD, LSBLK_CMD, FILE_CMD, UNAME_CMD, MOUNT_CMD, REBOOT_CMD, CHMOD_CMD, MKTEMP_CMD, TAR_CMD,
+    DF_CMD, LSBLK_CMD, UNAME_CMD, MOUNT_CMD, REBOOT_CMD, CHMOD_CMD, MKTEMP_CMD, TAR_CMD,
 ];
 
 pub(crate) struct LinuxMigrator {
@@ -80,8 +91,9 @@ impl<'a> LinuxMigrator {
                 let mut migrator = LinuxMigrator::try_init(config)?;
                 let res = match migrator.config.migrate.get_mig_mode() {
                     MigMode::Immediate => migrator.do_migrate(),
-                    MigMode::Pretend => Ok(()),
-                    //MigMode::Agent => Err(MigError::from(MigErrorKind::NotImpl)),
+                    MigMode::Pretend => {
+                        backup::sample(migrator.config.migrate.get_backup_volumes()).map(|_| ())
+                    } //MigMode::Agent => Err(MigError::from(MigErrorKind::NotImpl)),
                 };
                 Logger::flush();
                 res
@@ -122,7 +134,7 @@ impl<'a> LinuxMigrator {
             Ok(mig_info) => {
                 info!(
                     "OS Architecture is {}, OS Name is '{}'",
-                    mig_info.os_arch, mig_info.os_name
+                    mig_info.os_arch, mig_info.os_ident
                 );
                 mig_info
             }
@@ -147,11 +159,14 @@ impl<'a> LinuxMigrator {
 
         let device = match device_impl::get_device(&mig_info, &config, &mut stage2_config) {
             Ok(device) => {
-                let dev_type = device.get_device_type();
                 let boot_type = device.get_boot_type();
-                info!("Device Type is {:?}", device.get_device_type());
+                info!(
+                    "Device Type is {} ({})",
+                    device.get_device_entry().name,
+                    device.get_device_slug()
+                );
                 info!("Boot mode is {:?}", boot_type);
-                stage2_config.set_device_type(dev_type);
+                stage2_config.set_device(device.get_device_entry().clone());
                 stage2_config.set_boot_type(boot_type);
                 device
             }
@@ -204,14 +219,57 @@ impl<'a> LinuxMigrator {
             format_size_with_unit(flash_dev_size)
         );
 
-        if let ImageType::FileSystems(ref fs_dump) = config.balena.get_image_path() {
-            if fs_dump.device_slug != device.get_device_slug() {
-                error!(
-                    "The device-slug of the image dump configuration differs from the detect device slug '{}' != '{}'",
-                    fs_dump.device_slug,
-                    device.get_device_slug()
-                );
-                return Err(MigError::from(MigErrorKind::Displayed));
+        match config.balena.get_image_path() {
+            ImageType::FileSystems(ref fs_dump) => {
+                if fs_dump.device_slug != device.get_device_slug() {
+                    error!(
+                        "The device-slug of the image dump configuration differs from the detect device slug '{}' != '{}'",
+                        fs_dump.device_slug,
+                        device.get_device_slug()
+                    );
+                    return Err(MigError::from(MigErrorKind::Displayed));
+                }
+            }
+            ImageType::Flasher(ref flasher) => {
+                if let Some(ref image_slug) = flasher.device_slug {
+                    if image_slug != device.get_device_slug() {
+                        error!(
+                            "The device-slug of the image differs from the detected device slug '{}' != '{}'",
+                            image_slug,
+                            device.get_device_slug()
+                        );
+                        return Err(MigError::from(MigErrorKind::Displayed));
+                    }
+                } else if let Some(true) = device.get_device_entry().gpt_image {
+                    error!(
+                        "The device-slug of the image is required for device type '{}', please set it in the balena image configuration",
+                        device.get_device_slug()
+                    );
+                    return Err(MigError::from(MigErrorKind::Displayed));
+                }
+            }
+        }
+
+        // GPT / UEFI device types need a GPT partitioned image
+        if let Some(true) = device.get_device_entry().gpt_image {
+            if let CheckedImageType::Flasher(ref flasher) = mig_info.image_file {
+                let image_path = path_append(&mig_info.work_path.path, &flasher.image.rel_path);
+                match Disk::from_image(&image_path, false)?.get_label()? {
+                    LabelType::GPT => info!(
+                        "The image '{}' is GPT partitioned as required for device type '{}'",
+                        image_path.display(),
+                        device.get_device_slug()
+                    ),
+                    label => {
+                        error!(
+                            "The image '{}' does not match device type '{}', expected a GPT partition table, found {:?}",
+                            image_path.display(),
+                            device.get_device_slug(),
+                            label
+                        );
+                        return Err(MigError::from(MigErrorKind::Displayed));
+                    }
+                }
             }
         }
 
@@ -286,6 +344,17 @@ impl<'a> LinuxMigrator {
                     backup::create_ext(&backup_path, self.config.migrate.get_backup_volumes())?
                 });
 
+        if has_backup {
+            // stage2 verifies the backup after copying it
+            let digest = get_sha256_digest(&backup_path)?;
+            info!(
+                "Created backup '{}', digest: {:?}",
+                backup_path.display(),
+                digest
+            );
+            self.stage2_config.set_backup_digest(digest);
+        }
+
         // TODO: this might not be a smart place to put things, everything in system-connections
         // will end up in /mnt/boot/system-connections
         trace!("nwmgr_files");
@@ -416,6 +485,9 @@ impl<'a> LinuxMigrator {
         self.stage2_config
             .set_gzip_internal(self.config.migrate.is_gzip_internal());
 
+        self.stage2_config
+            .set_flash_verify(self.config.migrate.is_flash_verify());
+
         self.stage2_config
             .set_log_console(self.config.migrate.get_log_console());
 
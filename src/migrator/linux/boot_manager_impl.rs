@@ -1,78 +1,303 @@
+use failure::ResultExt;
+use log::{debug, error, info, warn};
+use std::fs::{read_dir, remove_dir, remove_file};
+use std::path::{Path, PathBuf};
+
 use crate::{
     common::{
         boot_manager::BootManager,
         config::migrate_config::UEnvStrategy,
-        migrate_info::MigrateInfo,
+        dir_exists,
+        file_digest::get_sha256_digest,
+        path_append,
         path_info::PathInfo,
-        stage2_config::{Stage2Config, Stage2ConfigBuilder},
-        Config, MigError, MigErrorKind,
+        stage2_config::{BootDirState, DirEntryInfo, MountConfig},
+        MigErrCtx, MigError, MigErrorKind,
     },
     defs::BootType,
-    linux::stage2::mounts::Mounts,
+    linux::{lsblk_info::LsblkInfo, stage2::mounts::Mounts},
 };
 
 pub(crate) mod u_boot_manager;
 pub(crate) use u_boot_manager::UBootManager;
 pub(crate) mod grub_boot_manager;
 pub(crate) use grub_boot_manager::GrubBootManager;
+pub(crate) mod efi_boot_manager;
 pub(crate) mod raspi_boot_manager;
-use crate::linux::linux_defs::DEFAULT_UNAME_STR;
+pub(crate) use efi_boot_manager::EfiBootManager;
 pub(crate) use raspi_boot_manager::RaspiBootManager;
 
 pub(crate) fn from_boot_type(boot_type: BootType) -> Box<dyn BootManager> {
     match boot_type {
-        BootType::UBoot => Box::new(UBootManager::new(
-            1,
-            UEnvStrategy::UName(String::from(DEFAULT_UNAME_STR)),
-            String::from(""),
-        )),
+        // the strategy is not used to restore the former boot configuration
+        BootType::UBoot => Box::new(UBootManager::new(1, UEnvStrategy::Manual, String::from(""))),
         BootType::Grub => Box::new(GrubBootManager::new()),
         BootType::Efi => Box::new(EfiBootManager::new(false)),
         BootType::MSWEfi => Box::new(EfiBootManager::new(true)),
-        BootType::Raspi => Box::new(RaspiBootManager::new(boot_type).unwrap()),
-        BootType::Raspi64 => Box::new(RaspiBootManager::new(boot_type).unwrap()),
+        BootType::Raspi => Box::new(RaspiBootManager::new(boot_type, Vec::new()).unwrap()),
+        BootType::Raspi64 => Box::new(RaspiBootManager::new(boot_type, Vec::new()).unwrap()),
         BootType::MSWBootMgr => panic!("BootType::MSWBootMgr is not implemented"),
     }
 }
 
-// TODO: support configured / device specific command line options
+// *************************************************************************************************
+// Helpers to record files installed for the migrate boot in stage1 and remove them in stage2
+
+// describe a file by its partition and its path within the partition
+pub(crate) fn get_mount_config<P: AsRef<Path>>(
+    path: P,
+    lsblk_info: &LsblkInfo,
+) -> Result<MountConfig, MigError> {
+    let path = path.as_ref();
+    if let Some(path_info) = PathInfo::from_path(path, lsblk_info)? {
+        path_info.get_mount_config()
+    } else {
+        Err(MigError::from_remark(
+            MigErrorKind::NotFound,
+            &format!("Could not find path '{}'", path.display()),
+        ))
+    }
+}
+
+// call func with the path of a file of the former system, mounting its partition if required
+pub(crate) fn on_former_file<F: FnOnce(&Path) -> bool>(
+    mounts: &Mounts,
+    file_cfg: &MountConfig,
+    func: F,
+) -> bool {
+    let (mountpoint, mounted) =
+        match mounts.mount_former(file_cfg.get_device(), file_cfg.get_fstype()) {
+            Ok(res) => res,
+            Err(why) => {
+                error!(
+                    "Failed to mount former device '{}', error: {:?}",
+                    file_cfg.get_device().display(),
+                    why
+                );
+                return false;
+            }
+        };
+
+    let res = func(&path_append(&mountpoint, file_cfg.get_path()));
+
+    if mounted {
+        if let Err(why) = mounts.unmount_former(&mountpoint) {
+            warn!(
+                "Failed to unmount former device '{}', error: {:?}",
+                file_cfg.get_device().display(),
+                why
+            );
+        }
+    }
 
-pub(crate) struct EfiBootManager {
-    #[allow(dead_code)]
-    msw_device: bool,
+    res
 }
 
-impl EfiBootManager {
-    pub fn new(msw_device: bool) -> EfiBootManager {
-        EfiBootManager { msw_device }
+// remove a file or an empty directory created for the migrate boot
+fn remove_boot_file(path: &Path) -> bool {
+    let res = match dir_exists(path) {
+        Ok(true) => remove_dir(path),
+        Ok(false) => {
+            if !path.exists() {
+                warn!("Migrate boot file '{}' not found", path.display());
+                return true;
+            }
+            remove_file(path)
+        }
+        Err(why) => {
+            error!(
+                "Failed to get file status for '{}', error: {:?}",
+                path.display(),
+                why
+            );
+            return false;
+        }
+    };
+
+    if let Err(why) = res {
+        error!(
+            "Failed to remove migrate boot file '{}', error: {:?}",
+            path.display(),
+            why
+        );
+        false
+    } else {
+        info!("Removed migrate boot file '{}'", path.display());
+        true
     }
 }
 
-impl BootManager for EfiBootManager {
-    fn get_boot_type(&self) -> BootType {
-        BootType::Efi
+// remove the recorded boot files in the given order, directories must follow their contents
+pub(crate) fn remove_boot_files(mounts: &Mounts, boot_files: &[MountConfig]) -> bool {
+    let mut res = true;
+    for boot_file in boot_files {
+        if !on_former_file(mounts, boot_file, remove_boot_file) {
+            res = false;
+        }
     }
-    fn get_bootmgr_path(&self) -> PathInfo {
-        unimplemented!()
+    res
+}
+
+// read name, size and digest of the entries of a directory, sorted by name
+fn read_dir_entries(dir: &Path) -> Result<Vec<DirEntryInfo>, MigError> {
+    let mut entries: Vec<DirEntryInfo> = Vec::new();
+    for entry in read_dir(dir).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to read directory '{}'", dir.display()),
+    ))? {
+        let entry = entry.context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to read entry of directory '{}'", dir.display()),
+        ))?;
+        let path = entry.path();
+        let metadata = path.symlink_metadata().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to get file status for '{}'", path.display()),
+        ))?;
+
+        let file_type = metadata.file_type();
+        entries.push(DirEntryInfo {
+            name: String::from(&*entry.file_name().to_string_lossy()),
+            is_dir: file_type.is_dir(),
+            size: if file_type.is_dir() {
+                0
+            } else {
+                metadata.len()
+            },
+            digest: if file_type.is_file() {
+                Some(get_sha256_digest(&path)?)
+            } else {
+                None
+            },
+        });
     }
+    entries.sort_by(|entry1, entry2| entry1.name.cmp(&entry2.name));
+    Ok(entries)
+}
 
-    fn can_migrate(
-        &mut self,
-        _dev_info: &MigrateInfo,
-        _config: &Config,
-        _s2_cfg: &mut Stage2ConfigBuilder,
-    ) -> Result<bool, MigError> {
-        Err(MigError::from(MigErrorKind::NotImpl))
+// record the contents of the directories boot files are about to be installed to,
+// directories that do not exist yet are covered by their closest existing ancestor
+pub(crate) fn get_boot_dir_states(boot_files: &[PathBuf]) -> Result<Vec<BootDirState>, MigError> {
+    let mut dirs: Vec<&Path> = Vec::new();
+    for boot_file in boot_files {
+        if let Some(dir) = boot_file.ancestors().skip(1).find(|dir| dir.is_dir()) {
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
     }
-    fn setup(
-        &mut self,
-        _dev_info: &MigrateInfo,
-        _s2_cfg: &mut Stage2ConfigBuilder,
-        _kernel_opts: &str,
-    ) -> Result<(), MigError> {
-        Err(MigError::from(MigErrorKind::NotImpl))
+
+    let lsblk_info = LsblkInfo::all()?;
+    let mut dir_states: Vec<BootDirState> = Vec::new();
+    for dir in dirs {
+        debug!("get_boot_dir_states: recording '{}'", dir.display());
+        dir_states.push(BootDirState {
+            dir: get_mount_config(dir, &lsblk_info)?,
+            entries: read_dir_entries(dir)?,
+        });
     }
-    fn restore(&self, _mounts: &Mounts, _config: &Stage2Config) -> bool {
-        unimplemented!()
+    Ok(dir_states)
+}
+
+// compare the contents of a directory to its former contents
+fn check_dir_state(dir: &Path, former: &[DirEntryInfo]) -> bool {
+    let entries = match read_dir_entries(dir) {
+        Ok(entries) => entries,
+        Err(why) => {
+            error!(
+                "Failed to read directory '{}', error: {:?}",
+                dir.display(),
+                why
+            );
+            return false;
+        }
+    };
+
+    let mut res = true;
+    for former_entry in former {
+        match entries.iter().find(|entry| entry.name == former_entry.name) {
+            Some(entry) if entry == former_entry => (),
+            Some(_) => {
+                error!(
+                    "'{}' in '{}' differs from its former contents",
+                    former_entry.name,
+                    dir.display()
+                );
+                res = false;
+            }
+            None => {
+                error!("'{}' in '{}' is missing", former_entry.name, dir.display());
+                res = false;
+            }
+        }
+    }
+
+    for entry in &entries {
+        if !former
+            .iter()
+            .any(|former_entry| former_entry.name == entry.name)
+        {
+            error!(
+                "'{}' in '{}' was not there before the migration",
+                entry.name,
+                dir.display()
+            );
+            res = false;
+        }
+    }
+    res
+}
+
+// make sure the directories boot files were installed to are back to their former contents
+pub(crate) fn check_boot_dirs(mounts: &Mounts, dir_states: &[BootDirState]) -> bool {
+    let mut res = true;
+    for dir_state in dir_states {
+        if !on_former_file(mounts, &dir_state.dir, |dir| {
+            check_dir_state(dir, &dir_state.entries)
+        }) {
+            res = false;
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_dir_state, read_dir_entries, remove_boot_file};
+    use std::fs::{create_dir, create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn boot_dir_state() {
+        let dir = std::env::temp_dir().join("balena-migrate-test-boot-dir");
+        let _res = remove_dir_all(&dir);
+        create_dir_all(dir.join("dtbs")).unwrap();
+        write(dir.join("uEnv.txt"), "uenvcmd=boot\n").unwrap();
+        write(dir.join("vmlinuz"), "kernel").unwrap();
+
+        let former = read_dir_entries(&dir).unwrap();
+        assert_eq!(former.len(), 3);
+        assert!(former[0].is_dir);
+        assert!(former[1].digest.is_some());
+
+        // install migrate boot files, then remove them in reverse order
+        write(dir.join("uEnv.txt"), "balena").unwrap();
+        create_dir(dir.join("dtbs/4.19")).unwrap();
+        write(dir.join("dtbs/4.19/balena.dtb"), "dtb").unwrap();
+        write(dir.join("balena.zImage"), "kernel").unwrap();
+        assert!(!check_dir_state(&dir, &former));
+
+        assert!(remove_boot_file(&dir.join("balena.zImage")));
+        assert!(remove_boot_file(&dir.join("dtbs/4.19/balena.dtb")));
+        assert!(remove_boot_file(&dir.join("dtbs/4.19")));
+        // already gone
+        assert!(remove_boot_file(&dir.join("balena.zImage")));
+
+        // uEnv.txt has not been restored yet
+        assert!(!check_dir_state(&dir, &former));
+        write(dir.join("uEnv.txt"), "uenvcmd=boot\n").unwrap();
+        assert!(check_dir_state(&dir, &former));
+
+        remove_dir_all(&dir).unwrap();
+        assert!(!check_dir_state(&dir, &former));
     }
 }
@@ -0,0 +1,369 @@
+use failure::ResultExt;
+use log::{debug, error, info, trace, warn};
+use std::fs::create_dir_all;
+use std::path::Path;
+
+use crate::{
+    common::{
+        boot_manager::BootManager,
+        dir_exists,
+        file_digest::check_digest,
+        file_exists, format_size_with_unit,
+        migrate_info::MigrateInfo,
+        path_append,
+        path_info::PathInfo,
+        stage2_config::{Stage2Config, Stage2ConfigBuilder},
+        Config, FileInfo, MigErrCtx, MigError, MigErrorKind,
+    },
+    defs::{BootType, MIG_INITRD_NAME, MIG_KERNEL_NAME},
+    linux::{
+        boot_manager_impl::{get_mount_config, remove_boot_files},
+        efi_vars::{
+            delete_boot_next, delete_var, encode_load_option, get_boot_entry_name, get_boot_next,
+            get_free_boot_entry, mount_efivars, set_boot_next, write_var, HardDriveNode,
+            EFI_SYSTEM_PART_GUID, EFI_SYSTEM_PART_MBR,
+        },
+        linux_common::is_efi_boot,
+        linux_defs::{BALENA_EFI_DIR, BOOT_PATH, EFIVARS_DIR},
+        lsblk_info::LsblkInfo,
+        stage2::mounts::Mounts,
+    },
+};
+
+const EFI_BOOT_ENTRY_DESC: &str = "balena-migrate";
+
+// *************************************************************************************************
+// Boot the migrate kernel through the UEFI firmware:
+// the kernel (built with EFI stub) and initramfs are copied to /EFI/balena-migrate on the EFI
+// system partition, a Boot#### load option pointing to the kernel is created and BootNext is set
+// to it. BootNext is consumed by the firmware, so any later boot falls back to the old OS.
+
+pub(crate) struct EfiBootManager {
+    #[allow(dead_code)]
+    msw_device: bool,
+    // where the stage2 config goes, the root for the migrate kernel
+    bootmgr_path: Option<PathInfo>,
+    // the mounted EFI system partition
+    efi_path: Option<PathInfo>,
+}
+
+impl EfiBootManager {
+    pub fn new(msw_device: bool) -> EfiBootManager {
+        EfiBootManager {
+            msw_device,
+            bootmgr_path: None,
+            efi_path: None,
+        }
+    }
+
+    // find a mounted EFI system partition, prefer one on the drive given
+    pub fn find_efi_path(
+        lsblk_info: &LsblkInfo,
+        drive: &Path,
+    ) -> Result<Option<PathInfo>, MigError> {
+        let mut found: Option<PathInfo> = None;
+        for blk_device in lsblk_info.get_blk_devices() {
+            if let Some(ref partitions) = blk_device.children {
+                for partition in partitions {
+                    let is_esp = if let Some(ref parttype) = partition.parttype {
+                        let parttype = parttype.to_lowercase();
+                        parttype == EFI_SYSTEM_PART_GUID || parttype == EFI_SYSTEM_PART_MBR
+                    } else {
+                        false
+                    };
+
+                    if !is_esp {
+                        continue;
+                    }
+
+                    if let Some(ref mountpoint) = partition.mountpoint {
+                        debug!(
+                            "find_efi_path: found EFI partition '{}' mounted on '{}'",
+                            partition.get_path().display(),
+                            mountpoint.display()
+                        );
+                        if let Some(path_info) = PathInfo::from_path(mountpoint, lsblk_info)? {
+                            if path_info.device_info.drive == drive {
+                                return Ok(Some(path_info));
+                            } else if found.is_none() {
+                                found = Some(path_info);
+                            }
+                        }
+                    } else {
+                        warn!(
+                            "The EFI partition '{}' is not mounted",
+                            partition.get_path().display()
+                        );
+                    }
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    fn copy_and_check<P: AsRef<Path>>(source: &FileInfo, dest: P) -> Result<(), MigError> {
+        let dest = dest.as_ref();
+        std::fs::copy(&source.path, dest).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "failed to copy file '{}' to '{}'",
+                source.path.display(),
+                dest.display()
+            ),
+        ))?;
+
+        if !check_digest(dest, &source.hash_info)? {
+            return Err(MigError::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "Failed to check digest on copied file '{}' to {:?}",
+                    dest.display(),
+                    source.hash_info
+                ),
+            ));
+        }
+
+        info!("copied '{}' -> '{}'", source.path.display(), dest.display());
+        Ok(())
+    }
+}
+
+impl BootManager for EfiBootManager {
+    fn get_boot_type(&self) -> BootType {
+        BootType::Efi
+    }
+
+    fn get_bootmgr_path(&self) -> PathInfo {
+        self.bootmgr_path.as_ref().unwrap().clone()
+    }
+
+    fn can_migrate(
+        &mut self,
+        mig_info: &MigrateInfo,
+        _config: &Config,
+        _s2_cfg: &mut Stage2ConfigBuilder,
+    ) -> Result<bool, MigError> {
+        trace!("can_migrate: entered");
+
+        if !is_efi_boot()? {
+            error!("The system was not booted through UEFI");
+            return Ok(false);
+        }
+
+        if !dir_exists(EFIVARS_DIR)? {
+            error!(
+                "The EFI variables directory '{}' could not be found",
+                EFIVARS_DIR
+            );
+            return Ok(false);
+        }
+
+        let lsblk_info = LsblkInfo::all()?;
+
+        let boot_path = if let Some(boot_path) = PathInfo::from_path(BOOT_PATH, &lsblk_info)? {
+            boot_path
+        } else {
+            error!("Could not find boot path '{}'", BOOT_PATH);
+            return Err(MigError::displayed());
+        };
+
+        let efi_path = if let Some(efi_path) =
+            EfiBootManager::find_efi_path(&lsblk_info, &boot_path.device_info.drive)?
+        {
+            efi_path
+        } else {
+            error!("Could not find a mounted EFI system partition");
+            return Ok(false);
+        };
+
+        if efi_path.device_info.part_uuid.is_none() {
+            error!(
+                "Could not determine the partuuid of the EFI system partition '{}'",
+                efi_path.device_info.device.display()
+            );
+            return Ok(false);
+        }
+
+        info!(
+            "Using EFI system partition '{}' mounted on '{}'",
+            efi_path.device_info.device.display(),
+            efi_path.mountpoint.display()
+        );
+
+        let balena_efi_dir = path_append(&efi_path.mountpoint, BALENA_EFI_DIR);
+        let mut efi_req_space = if !file_exists(path_append(&balena_efi_dir, MIG_KERNEL_NAME)) {
+            mig_info.kernel_file.size
+        } else {
+            0
+        };
+
+        efi_req_space += if !file_exists(path_append(&balena_efi_dir, MIG_INITRD_NAME)) {
+            mig_info.initrd_file.size
+        } else {
+            0
+        };
+
+        if efi_path.fs_free < efi_req_space {
+            error!("The EFI partition '{}' does not have enough space to store the migrate kernel and initramfs. Required space is {}",
+                   efi_path.mountpoint.display(), format_size_with_unit(efi_req_space));
+            return Ok(false);
+        }
+
+        self.bootmgr_path = Some(boot_path);
+        self.efi_path = Some(efi_path);
+
+        Ok(true)
+    }
+
+    fn setup(
+        &mut self,
+        mig_info: &MigrateInfo,
+        s2_cfg: &mut Stage2ConfigBuilder,
+        kernel_opts: &str,
+    ) -> Result<(), MigError> {
+        trace!("setup: entered");
+
+        let (boot_path, efi_path) = if let (Some(boot_path), Some(efi_path)) =
+            (self.bootmgr_path.as_ref(), self.efi_path.as_ref())
+        {
+            (boot_path, efi_path)
+        } else {
+            error!("setup: boot manager paths are not set");
+            return Err(MigError::displayed());
+        };
+
+        // **********************************************************************
+        // ** copy new kernel & iniramfs to the EFI partition
+
+        let balena_efi_dir = path_append(&efi_path.mountpoint, BALENA_EFI_DIR);
+        let created_dir = !dir_exists(&balena_efi_dir)?;
+        if created_dir {
+            create_dir_all(&balena_efi_dir).context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "failed to create EFI directory '{}'",
+                    balena_efi_dir.display()
+                ),
+            ))?;
+        }
+
+        let kernel_path = path_append(&balena_efi_dir, MIG_KERNEL_NAME);
+        let initrd_path = path_append(&balena_efi_dir, MIG_INITRD_NAME);
+        EfiBootManager::copy_and_check(&mig_info.kernel_file, &kernel_path)?;
+        EfiBootManager::copy_and_check(&mig_info.initrd_file, &initrd_path)?;
+
+        // **********************************************************************
+        // ** record the installed files for removal in stage2
+
+        let lsblk_info = LsblkInfo::all()?;
+        let mut boot_files = vec![
+            get_mount_config(&kernel_path, &lsblk_info)?,
+            get_mount_config(&initrd_path, &lsblk_info)?,
+        ];
+        if created_dir {
+            boot_files.push(get_mount_config(&balena_efi_dir, &lsblk_info)?);
+        }
+        s2_cfg.set_boot_files(boot_files);
+
+        // **********************************************************************
+        // ** create the boot entry, paths are relative to the EFI partition
+
+        let loader = path_append(BALENA_EFI_DIR, MIG_KERNEL_NAME);
+        let initrd = path_append(BALENA_EFI_DIR, MIG_INITRD_NAME);
+
+        let mut options = format!(
+            "initrd={} root={} rootfstype={} console=tty0 debug",
+            initrd.to_string_lossy().replace('/', "\\"),
+            boot_path.device_info.get_kernel_cmd(),
+            boot_path.device_info.fs_type
+        );
+
+        if !kernel_opts.is_empty() {
+            options.push(' ');
+            options.push_str(kernel_opts);
+        }
+
+        debug!("EFI loader: '{}', options: '{}'", loader.display(), options);
+
+        let hd_node = HardDriveNode::from_partition(
+            &efi_path.device_info.device,
+            efi_path.device_info.part_uuid.as_ref().unwrap(),
+        )?;
+        debug!("EFI hard drive node: {:?}", hd_node);
+
+        let boot_entry = get_free_boot_entry()?;
+        let entry_name = get_boot_entry_name(boot_entry);
+
+        write_var(
+            &entry_name,
+            &encode_load_option(
+                EFI_BOOT_ENTRY_DESC,
+                &hd_node,
+                &loader.to_string_lossy(),
+                &options,
+            ),
+        )?;
+        info!("Created EFI boot entry {}", entry_name);
+
+        // record the entry first, so stage2 can remove it even if setting BootNext fails
+        s2_cfg.set_efi_boot_entry(boot_entry);
+
+        set_boot_next(boot_entry)?;
+        info!("Set EFI BootNext to {}", entry_name);
+
+        Ok(())
+    }
+
+    fn restore(&self, mounts: &Mounts, config: &Stage2Config) -> bool {
+        info!("restoring boot configuration",);
+
+        // remove kernel, initramfs and the balena-migrate directory from the EFI partition
+        let mut res = remove_boot_files(mounts, config.get_boot_files());
+
+        let boot_entry = if let Some(boot_entry) = config.get_efi_boot_entry() {
+            boot_entry
+        } else {
+            warn!("No EFI boot entry found in stage2 config");
+            return false;
+        };
+
+        if let Err(why) = mount_efivars() {
+            error!("Failed to access EFI variables, error: {:?}", why);
+            return false;
+        }
+
+        // BootNext should have been consumed by the firmware, remove it if it still points to us
+        match get_boot_next() {
+            Ok(Some(boot_next)) => {
+                if boot_next == boot_entry {
+                    if let Err(why) = delete_boot_next() {
+                        error!("Failed to delete EFI BootNext, error: {:?}", why);
+                        res = false;
+                    }
+                }
+            }
+            Ok(None) => (),
+            Err(why) => {
+                warn!("Failed to read EFI BootNext, error: {:?}", why);
+            }
+        }
+
+        let entry_name = get_boot_entry_name(boot_entry);
+        match delete_var(&entry_name) {
+            Ok(true) => info!("Removed EFI boot entry {}", entry_name),
+            Ok(false) => {
+                warn!("EFI boot entry {} not found", entry_name);
+                res = false;
+            }
+            Err(why) => {
+                error!(
+                    "Failed to remove EFI boot entry {}, error: {:?}",
+                    entry_name, why
+                );
+                res = false;
+            }
+        }
+
+        res
+    }
+}
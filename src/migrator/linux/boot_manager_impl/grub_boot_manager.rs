@@ -1,9 +1,9 @@
 use failure::ResultExt;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use regex::Regex;
-use std::fs::{read_to_string, File};
+use std::fs::{read_dir, read_to_string, File};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crate::{
     common::{
@@ -18,20 +18,36 @@ use crate::{
         stage2_config::{Stage2Config, Stage2ConfigBuilder},
         Config, MigErrCtx, MigError, MigErrorKind,
     },
-    defs::{BootType, MIG_INITRD_NAME, MIG_KERNEL_NAME},
+    defs::{BootType, BALENA_FILE_TAG, MIG_INITRD_NAME, MIG_KERNEL_NAME},
     linux::{
+        boot_manager_impl::{get_mount_config, on_former_file, remove_boot_files},
+        grub_env::GrubEnv,
+        linux_common::{restore_backups, whereis},
         linux_defs::{
-            BOOT_PATH, GRUB_CONFIG_DIR, GRUB_CONFIG_FILE, GRUB_MIN_VERSION, KERNEL_CMDLINE_PATH,
-            ROOT_PATH,
+            BLS_ENTRIES_DIR, BOOT_PATH, GRUB2_DIR, GRUB_CFG_NAME, GRUB_CONFIG_DIR,
+            GRUB_CONFIG_FILE, GRUB_DEFAULT_FILE, GRUB_DIR, GRUB_EFI_DIR, GRUB_ENV_NAME,
+            GRUB_MIN_VERSION, KERNEL_CMDLINE_PATH, ROOT_PATH,
+        },
+        linux_defs::{
+            CHMOD_CMD, GRUB2_MKCONFIG_CMD, GRUB2_REBOOT_CMD, GRUB_MKCONFIG_CMD, GRUB_REBOOT_CMD,
+            GRUB_UPDT_CMD,
         },
-        linux_defs::{CHMOD_CMD, GRUB_REBOOT_CMD, GRUB_UPDT_CMD},
         lsblk_info::LsblkInfo,
         stage2::mounts::Mounts,
     },
 };
 
 const GRUB_UPDT_VERSION_ARGS: [&str; 1] = ["--version"];
-const GRUB_UPDT_VERSION_RE: &str = r#"^.*\s+\(GRUB\)\s+([0-9]+)\.([0-9]+)[^0-9].*$"#;
+const GRUB_UPDT_VERSION_RE: &str = r#"^.*\s+\(GRUB2?\)\s+([0-9]+)\.([0-9]+)[^0-9].*$"#;
+const GRUB_BLSCFG_RE: &str = r#"(?m)^\s*GRUB_ENABLE_BLSCFG\s*=\s*"?true"?\s*$"#;
+
+// menu entry title and BLS entry id
+const GRUB_ENTRY_NAME: &str = "balena-migrate";
+const BLS_ENTRY_FILE: &str = "balena-migrate.conf";
+
+// grub-mkconfig encloses the output of each grub.d script in these markers
+const GRUB_CFG_SECTION_BEGIN: &str = "### BEGIN /etc/grub.d/43_balena-migrate ###";
+const GRUB_CFG_SECTION_END: &str = "### END /etc/grub.d/43_balena-migrate ###";
 
 const GRUB_CFG_TEMPLATE: &str = r##"
 #!/bin/sh
@@ -51,14 +67,135 @@ menuentry "balena-migrate" {
 }
 "##;
 
+// boot loader spec entry, used instead of a grub.d script where grub reads BLS entries
+const BLS_ENTRY_TEMPLATE: &str = r##"
+title balena-migrate
+version balena-migrate
+linux __LINUX__
+initrd __INITRD_NAME__
+options __OPTIONS__
+"##;
+
+// the grub flavour and its config locations
+#[derive(Debug, Clone)]
+struct GrubInstall {
+    // command creating grub.cfg: update-grub (debian) or grub(2)-mkconfig
+    mkconfig_cmd: String,
+    mkconfig_args: Vec<String>,
+    // grub-reboot or grub2-reboot, if installed
+    reboot_cmd: Option<String>,
+    // the grub config file
+    grub_cfg: PathBuf,
+    // the grub environment block
+    grubenv: PathBuf,
+    // boot loader spec entries in /boot/loader/entries are used (fedora, rhel 8+)
+    bls: bool,
+}
+
+impl GrubInstall {
+    fn detect() -> Result<Option<GrubInstall>, MigError> {
+        trace!("detect: entered");
+
+        let grub_dir = if dir_exists(GRUB2_DIR)? {
+            GRUB2_DIR
+        } else if dir_exists(GRUB_DIR)? {
+            GRUB_DIR
+        } else {
+            error!(
+                "No grub directory found in '{}' or '{}'",
+                GRUB_DIR, GRUB2_DIR
+            );
+            return Ok(None);
+        };
+
+        let mkconfig_cmd = if let Some(cmd) = [GRUB_UPDT_CMD, GRUB2_MKCONFIG_CMD, GRUB_MKCONFIG_CMD]
+            .iter()
+            .find_map(|cmd| whereis(cmd).ok())
+        {
+            cmd
+        } else {
+            error!(
+                "None of the grub config commands {}, {}, {} could be found",
+                GRUB_UPDT_CMD, GRUB2_MKCONFIG_CMD, GRUB_MKCONFIG_CMD
+            );
+            return Ok(None);
+        };
+
+        let grub_cfg = GrubInstall::find_grub_cfg(grub_dir)?;
+
+        let mkconfig_args = if mkconfig_cmd.ends_with(GRUB_UPDT_CMD) {
+            Vec::new()
+        } else {
+            vec![
+                String::from("-o"),
+                String::from(&*grub_cfg.to_string_lossy()),
+            ]
+        };
+
+        let reboot_cmd = [GRUB_REBOOT_CMD, GRUB2_REBOOT_CMD]
+            .iter()
+            .find_map(|cmd| whereis(cmd).ok());
+
+        let bls = if dir_exists(BLS_ENTRIES_DIR)? && file_exists(GRUB_DEFAULT_FILE) {
+            let grub_default =
+                read_to_string(GRUB_DEFAULT_FILE).context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!("Failed to read '{}'", GRUB_DEFAULT_FILE),
+                ))?;
+            Regex::new(GRUB_BLSCFG_RE).unwrap().is_match(&grub_default)
+        } else {
+            false
+        };
+
+        let grub_install = GrubInstall {
+            mkconfig_cmd,
+            mkconfig_args,
+            reboot_cmd,
+            grub_cfg,
+            grubenv: path_append(grub_dir, GRUB_ENV_NAME),
+            bls,
+        };
+
+        debug!("detect: found {:?}", grub_install);
+        Ok(Some(grub_install))
+    }
+
+    // grub.cfg lives in the grub directory or, on older EFI installs, in the distribution
+    // directory on the EFI partition
+    fn find_grub_cfg(grub_dir: &str) -> Result<PathBuf, MigError> {
+        let grub_cfg = path_append(grub_dir, GRUB_CFG_NAME);
+        if file_exists(&grub_cfg) || !dir_exists(GRUB_EFI_DIR)? {
+            return Ok(grub_cfg);
+        }
+
+        let entries = read_dir(GRUB_EFI_DIR).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to list directory '{}'", GRUB_EFI_DIR),
+        ))?;
+
+        for entry in entries.flatten() {
+            let efi_grub_cfg = path_append(entry.path(), GRUB_CFG_NAME);
+            if file_exists(&efi_grub_cfg) {
+                return Ok(efi_grub_cfg);
+            }
+        }
+
+        Ok(grub_cfg)
+    }
+}
+
 pub(crate) struct GrubBootManager {
     // valid is just used to enforce the use of new
     bootmgr_path: Option<PathInfo>,
+    grub_install: Option<GrubInstall>,
 }
 
 impl<'a> GrubBootManager {
     pub fn new() -> GrubBootManager {
-        GrubBootManager { bootmgr_path: None }
+        GrubBootManager {
+            bootmgr_path: None,
+            grub_install: None,
+        }
     }
 
     /******************************************************************
@@ -66,15 +203,15 @@ impl<'a> GrubBootManager {
      * as (major,minor)
      ******************************************************************/
 
-    fn get_grub_version() -> Result<(String, String), MigError> {
+    fn get_grub_version(mkconfig_cmd: &str) -> Result<(String, String), MigError> {
         trace!("get_grub_version: entered");
 
         let cmd_res =
-            call(GRUB_UPDT_CMD, &GRUB_UPDT_VERSION_ARGS, true).context(MigErrCtx::from_remark(
+            call(mkconfig_cmd, &GRUB_UPDT_VERSION_ARGS, true).context(MigErrCtx::from_remark(
                 MigErrorKind::Upstream,
                 &format!(
                     "get_grub_version: call '{} {:?}'",
-                    GRUB_UPDT_CMD, GRUB_UPDT_VERSION_ARGS
+                    mkconfig_cmd, GRUB_UPDT_VERSION_ARGS
                 ),
             ))?;
 
@@ -104,6 +241,103 @@ impl<'a> GrubBootManager {
             ))
         }
     }
+
+    fn reset_grub_env(path: &Path) -> bool {
+        let mut grub_env = match GrubEnv::read(path) {
+            Ok(grub_env) => grub_env,
+            Err(why) => {
+                error!(
+                    "Failed to read grub environment '{}', error: {:?}",
+                    path.display(),
+                    why
+                );
+                return false;
+            }
+        };
+
+        // grub clears next_entry when booting it, it is only left if the migrate entry was not used
+        if grub_env.get("next_entry") != Some(GRUB_ENTRY_NAME) {
+            debug!("next_entry is not set in '{}'", path.display());
+            return true;
+        }
+
+        grub_env.unset("next_entry");
+        if let Err(why) = grub_env.write() {
+            error!(
+                "Failed to write grub environment '{}', error: {:?}",
+                path.display(),
+                why
+            );
+            false
+        } else {
+            info!("Removed next_entry from '{}'", path.display());
+            true
+        }
+    }
+
+    // remove the section created from 43_balena-migrate, returns None if there is none
+    fn strip_grub_cfg(grub_cfg: &str) -> Option<String> {
+        let mut stripped = String::new();
+        let mut in_section = false;
+        let mut found = false;
+        for line in grub_cfg.lines() {
+            if in_section {
+                if line.trim() == GRUB_CFG_SECTION_END {
+                    in_section = false;
+                }
+            } else if line.trim() == GRUB_CFG_SECTION_BEGIN {
+                in_section = true;
+                found = true;
+            } else {
+                stripped.push_str(line);
+                stripped.push('\n');
+            }
+        }
+
+        if found {
+            Some(stripped)
+        } else {
+            None
+        }
+    }
+
+    fn restore_grub_cfg(path: &Path) -> bool {
+        let grub_cfg = match read_to_string(path) {
+            Ok(grub_cfg) => grub_cfg,
+            Err(why) => {
+                error!(
+                    "Failed to read grub config '{}', error: {:?}",
+                    path.display(),
+                    why
+                );
+                return false;
+            }
+        };
+
+        let stripped = if let Some(stripped) = GrubBootManager::strip_grub_cfg(&grub_cfg) {
+            stripped
+        } else {
+            warn!("No migrate menu entry found in '{}'", path.display());
+            return true;
+        };
+
+        let res = File::create(path).and_then(|mut file| {
+            file.write_all(stripped.as_bytes())?;
+            file.sync_all()
+        });
+
+        if let Err(why) = res {
+            error!(
+                "Failed to write grub config '{}', error: {:?}",
+                path.display(),
+                why
+            );
+            false
+        } else {
+            info!("Removed migrate menu entry from '{}'", path.display());
+            true
+        }
+    }
 }
 
 impl BootManager for GrubBootManager {
@@ -141,7 +375,14 @@ impl BootManager for GrubBootManager {
             return Err(MigError::displayed());
         };
 
-        let grub_version = GrubBootManager::get_grub_version()?;
+        let grub_install = if let Some(grub_install) = GrubInstall::detect()? {
+            grub_install
+        } else {
+            error!("Could not determine the grub installation");
+            return Ok(false);
+        };
+
+        let grub_version = GrubBootManager::get_grub_version(&grub_install.mkconfig_cmd)?;
         info!(
             "grub-install version is {}.{}",
             grub_version.0, grub_version.1
@@ -181,7 +422,13 @@ impl BootManager for GrubBootManager {
             return Ok(false);
         }
 
+        info!(
+            "Using grub config command '{}', reboot command: {:?}, BLS entries: {}",
+            grub_install.mkconfig_cmd, grub_install.reboot_cmd, grub_install.bls
+        );
+
         self.bootmgr_path = Some(boot_path);
+        self.grub_install = Some(grub_install);
 
         Ok(true)
     }
@@ -189,7 +436,7 @@ impl BootManager for GrubBootManager {
     fn setup(
         &mut self,
         mig_info: &MigrateInfo,
-        _s2_cfg: &mut Stage2ConfigBuilder,
+        s2_cfg: &mut Stage2ConfigBuilder,
         kernel_opts: &str,
     ) -> Result<(), MigError> {
         trace!("setup: entered");
@@ -200,6 +447,7 @@ impl BootManager for GrubBootManager {
 
         // let install_drive = mig_info.get_installPath().drive;
         let boot_path = self.bootmgr_path.as_ref().unwrap();
+        let grub_install = self.grub_install.as_ref().unwrap();
 
         // path to kernel & initramfs at boot time depends on how /boot is mounted
         // either / (for a /boot mount or /boot for a directory of /root file system)
@@ -269,7 +517,8 @@ impl BootManager for GrubBootManager {
             String::from(&*boot_path.device_info.device.to_string_lossy())
         };
 
-        let mut linux = String::from(path_append(&grub_boot, MIG_KERNEL_NAME).to_string_lossy());
+        let kernel = String::from(path_append(grub_boot, MIG_KERNEL_NAME).to_string_lossy());
+        let mut options = String::new();
 
         // filter some bullshit out of commandline, else leave it as is
 
@@ -305,53 +554,78 @@ impl BootManager for GrubBootManager {
                 continue;
             }
 
-            linux.push_str(&format!(" {}", word));
+            options.push_str(&format!("{} ", word));
         }
 
-        linux.push_str(&format!(
-            " root={} rootfstype={} console=tty0 debug ",
+        options.push_str(&format!(
+            "root={} rootfstype={} console=tty0 debug",
             root_dev, boot_path.device_info.fs_type,
         ));
 
         if !kernel_opts.is_empty() {
-            linux.push(' ');
-            linux.push_str(kernel_opts);
+            options.push(' ');
+            options.push_str(kernel_opts);
         }
 
-        let mut grub_cfg = String::from(GRUB_CFG_TEMPLATE);
-
-        grub_cfg = grub_cfg.replace("__PART_MOD__", &part_mod);
-        grub_cfg = grub_cfg.replace("__FSTYPE_MOD__", &fstype_mod);
-        grub_cfg = grub_cfg.replace("__ROOT_CMD__", &root_cmd);
-        grub_cfg = grub_cfg.replace("__LINUX__", &linux);
-        grub_cfg = grub_cfg.replace(
-            "__INITRD_NAME__",
-            &path_append(&grub_boot, MIG_INITRD_NAME).to_string_lossy(),
-        );
-
-        debug!("grub config: {}", grub_cfg);
-
-        // let mut grub_cfg_file =
-        File::create(GRUB_CONFIG_FILE)
-            .context(MigErrCtx::from_remark(
-                MigErrorKind::Upstream,
-                &format!("Failed to create grub config file '{}'", GRUB_CONFIG_FILE),
-            ))?
-            .write(grub_cfg.as_bytes())
-            .context(MigErrCtx::from_remark(
-                MigErrorKind::Upstream,
-                &format!("Failed to write to grub config file '{}'", GRUB_CONFIG_FILE),
-            ))?;
-
-        let cmd_res = call(CHMOD_CMD, &["+x", GRUB_CONFIG_FILE], true)?;
-        if !cmd_res.status.success() {
-            return Err(MigError::from_remark(
-                MigErrorKind::ExecProcess,
-                &format!("Failure from '{}': {:?}", CHMOD_CMD, cmd_res),
-            ));
-        }
+        let initrd = path_append(grub_boot, MIG_INITRD_NAME);
+
+        let boot_cfg_path = if grub_install.bls {
+            let mut bls_entry = String::from(BALENA_FILE_TAG);
+            bls_entry.push_str(BLS_ENTRY_TEMPLATE);
+            bls_entry = bls_entry.replace("__LINUX__", &kernel);
+            bls_entry = bls_entry.replace("__INITRD_NAME__", &initrd.to_string_lossy());
+            bls_entry = bls_entry.replace("__OPTIONS__", &options);
+
+            debug!("BLS entry: {}", bls_entry);
+
+            let bls_path = path_append(BLS_ENTRIES_DIR, BLS_ENTRY_FILE);
+            File::create(&bls_path)
+                .context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!("Failed to create BLS entry '{}'", bls_path.display()),
+                ))?
+                .write_all(bls_entry.as_bytes())
+                .context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!("Failed to write to BLS entry '{}'", bls_path.display()),
+                ))?;
+
+            info!("BLS entry written to '{}'", bls_path.display());
+            bls_path
+        } else {
+            let mut grub_cfg = String::from(GRUB_CFG_TEMPLATE);
+
+            grub_cfg = grub_cfg.replace("__PART_MOD__", &part_mod);
+            grub_cfg = grub_cfg.replace("__FSTYPE_MOD__", fstype_mod);
+            grub_cfg = grub_cfg.replace("__ROOT_CMD__", &root_cmd);
+            grub_cfg = grub_cfg.replace("__LINUX__", &format!("{} {}", kernel, options));
+            grub_cfg = grub_cfg.replace("__INITRD_NAME__", &initrd.to_string_lossy());
+
+            debug!("grub config: {}", grub_cfg);
+
+            // let mut grub_cfg_file =
+            File::create(GRUB_CONFIG_FILE)
+                .context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!("Failed to create grub config file '{}'", GRUB_CONFIG_FILE),
+                ))?
+                .write(grub_cfg.as_bytes())
+                .context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!("Failed to write to grub config file '{}'", GRUB_CONFIG_FILE),
+                ))?;
+
+            let cmd_res = call(CHMOD_CMD, &["+x", GRUB_CONFIG_FILE], true)?;
+            if !cmd_res.status.success() {
+                return Err(MigError::from_remark(
+                    MigErrorKind::ExecProcess,
+                    &format!("Failure from '{}': {:?}", CHMOD_CMD, cmd_res),
+                ));
+            }
 
-        info!("Grub config written to '{}'", GRUB_CONFIG_FILE);
+            info!("Grub config written to '{}'", GRUB_CONFIG_FILE);
+            PathBuf::from(GRUB_CONFIG_FILE)
+        };
 
         // **********************************************************************
         // ** copy new kernel & iniramfs
@@ -413,50 +687,119 @@ impl BootManager for GrubBootManager {
             initrd_path.display()
         );
 
-        info!("calling '{}'", GRUB_UPDT_CMD);
-
-        let cmd_res = call(GRUB_UPDT_CMD, &[], true).context(MigErrCtx::from_remark(
-            MigErrorKind::Upstream,
-            "Failed to set up boot configuration'",
-        ))?;
+        // **********************************************************************
+        // ** record the installed files for removal in stage2
 
-        if !cmd_res.status.success() {
-            return Err(MigError::from_remark(
-                MigErrorKind::ExecProcess,
-                &format!("Failure from '{}': {:?}", GRUB_UPDT_CMD, cmd_res),
-            ));
+        let lsblk_info = LsblkInfo::all()?;
+        s2_cfg.set_boot_files(vec![
+            get_mount_config(&kernel_path, &lsblk_info)?,
+            get_mount_config(&initrd_path, &lsblk_info)?,
+            get_mount_config(&boot_cfg_path, &lsblk_info)?,
+        ]);
+
+        // BLS entries are read by grub at boot time, no need to recreate grub.cfg
+        if !grub_install.bls {
+            info!("calling '{}'", grub_install.mkconfig_cmd);
+
+            let args: Vec<&str> = grub_install
+                .mkconfig_args
+                .iter()
+                .map(|arg| arg.as_str())
+                .collect();
+            let cmd_res =
+                call(&grub_install.mkconfig_cmd, &args, true).context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    "Failed to set up boot configuration'",
+                ))?;
+
+            if !cmd_res.status.success() {
+                return Err(MigError::from_remark(
+                    MigErrorKind::ExecProcess,
+                    &format!(
+                        "Failure from '{}': {:?}",
+                        grub_install.mkconfig_cmd, cmd_res
+                    ),
+                ));
+            }
         }
 
-        info!("calling '{}'", GRUB_REBOOT_CMD);
+        if let Some(ref reboot_cmd) = grub_install.reboot_cmd {
+            info!("calling '{}'", reboot_cmd);
 
-        let cmd_res =
-            call(GRUB_REBOOT_CMD, &["balena-migrate"], true).context(MigErrCtx::from_remark(
-                MigErrorKind::Upstream,
-                &format!(
-                    "Failed to activate boot configuration using '{}'",
-                    GRUB_REBOOT_CMD,
-                ),
-            ))?;
+            let cmd_res =
+                call(reboot_cmd, &[GRUB_ENTRY_NAME], true).context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "Failed to activate boot configuration using '{}'",
+                        reboot_cmd,
+                    ),
+                ))?;
 
-        if !cmd_res.status.success() {
-            return Err(MigError::from_remark(
-                MigErrorKind::ExecProcess,
-                &format!(
-                    "Failed to activate boot configuration using '{}': {:?}",
-                    GRUB_REBOOT_CMD, cmd_res
-                ),
-            ));
+            if !cmd_res.status.success() {
+                return Err(MigError::from_remark(
+                    MigErrorKind::ExecProcess,
+                    &format!(
+                        "Failed to activate boot configuration using '{}': {:?}",
+                        reboot_cmd, cmd_res
+                    ),
+                ));
+            }
+        } else {
+            // no grub-reboot, set the one-shot entry in grubenv
+            let mut grub_env = GrubEnv::read(&grub_install.grubenv)?;
+            if let Some(next_entry) = grub_env.get("next_entry") {
+                warn!("Replacing grub next_entry '{}'", next_entry);
+            }
+            grub_env.set("next_entry", GRUB_ENTRY_NAME);
+            grub_env.write()?;
+            info!(
+                "Set next_entry to '{}' in '{}'",
+                GRUB_ENTRY_NAME,
+                grub_install.grubenv.display()
+            );
+        }
+
+        // the path is recorded resolved, so stage2 edits the target of a symlinked grubenv
+        // on its own partition, eg. the EFI partition on RHEL / CentOS
+        if file_exists(&grub_install.grubenv) {
+            s2_cfg.set_grub_env(get_mount_config(&grub_install.grubenv, &lsblk_info)?);
+        }
+
+        if !grub_install.bls && file_exists(&grub_install.grub_cfg) {
+            s2_cfg.set_grub_cfg(get_mount_config(&grub_install.grub_cfg, &lsblk_info)?);
         }
 
         Ok(())
     }
 
-    fn restore(&self, _mounts: &Mounts, _config: &Stage2Config) -> bool {
-        trace!("restore: entered");
-        // Nothing to restore with grub-reboot
-        // TODO: might be worthwhile to remove kernel / initramfs and grub config
-        // TODO: restore backups anyway if they exist
-        true
+    fn restore(&self, mounts: &Mounts, config: &Stage2Config) -> bool {
+        info!("restoring boot configuration",);
+
+        let mut res = true;
+
+        // remove kernel, initramfs and the grub.d script or BLS entry
+        if !remove_boot_files(mounts, config.get_boot_files()) {
+            res = false;
+        }
+
+        // grub.cfg still contains the menu entry as grub-mkconfig cannot be run here
+        if let Some(grub_cfg) = config.get_grub_cfg() {
+            if !on_former_file(mounts, grub_cfg, GrubBootManager::restore_grub_cfg) {
+                res = false;
+            }
+        }
+
+        if let Some(grub_env) = config.get_grub_env() {
+            if !on_former_file(mounts, grub_env, GrubBootManager::reset_grub_env) {
+                res = false;
+            }
+        }
+
+        if !restore_backups(mounts.get_boot_mountpoint(), config.get_boot_backups()) {
+            res = false;
+        }
+
+        res
     }
     /*
         fn set_bootmgr_path(&self,dev_info:& DeviceInfo, config: &Config, s2_cfg: &mut Stage2ConfigBuilder) -> Result<bool, MigError> {
@@ -497,3 +840,28 @@ pub(crate) fn grub_valid(_config: &Config, _mig_info: &MigrateInfo) -> Result<bo
 pub(crate) fn grub_install(_config: &Config, mig_info: &mut MigrateInfo) -> Result<(), MigError> {
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::GrubBootManager;
+
+    #[test]
+    fn strip_grub_cfg() {
+        let grub_cfg = "### BEGIN /etc/grub.d/41_custom ###\n\
+                        ### END /etc/grub.d/41_custom ###\n\
+                        \n\
+                        ### BEGIN /etc/grub.d/43_balena-migrate ###\n\
+                        menuentry \"balena-migrate\" {\n\
+                        }\n\
+                        ### END /etc/grub.d/43_balena-migrate ###\n\
+                        \n";
+
+        assert_eq!(
+            GrubBootManager::strip_grub_cfg(grub_cfg),
+            Some(String::from(
+                "### BEGIN /etc/grub.d/41_custom ###\n### END /etc/grub.d/41_custom ###\n\n\n"
+            ))
+        );
+        assert_eq!(GrubBootManager::strip_grub_cfg("set default=0\n"), None);
+    }
+}
@@ -1,17 +1,17 @@
-use failure::{Fail, ResultExt};
+use failure::ResultExt;
 use log::{debug, error, info, trace, warn};
 use regex::Regex;
 use std::fs::{copy, read_to_string, File};
-use std::io::{BufRead, BufReader, Write};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use std::time::SystemTime;
 
-use crate::linux::lsblk_info::LsblkInfo;
+use crate::linux::lsblk_info::{LsblkInfo, LsblkPartition};
 use crate::{
     common::{
         boot_manager::BootManager,
-        call, dir_exists,
+        call,
         file_digest::check_digest,
         file_exists, is_balena_file,
         migrate_info::MigrateInfo,
@@ -20,46 +20,56 @@ use crate::{
         stage2_config::{Stage2Config, Stage2ConfigBuilder},
         Config, MigErrCtx, MigError, MigErrorKind,
     },
-    defs::{BootType, BALENA_FILE_TAG},
-    linux::{linux_defs::BOOT_PATH, linux_defs::CHMOD_CMD, stage2::mounts::Mounts},
+    defs::BootType,
+    linux::{
+        linux_common::restore_backups,
+        linux_defs::CHMOD_CMD,
+        rpi_config_txt::{ConfigTxt, RPI_CONFIG_TXT},
+        stage2::mounts::Mounts,
+    },
 };
 
 // TODO: copy rpi dtb's , backup orig dtbs
 
-const RPI_MIG_KERNEL_PATH: &str = "/boot/balena.zImage";
 const RPI_MIG_KERNEL_NAME: &str = "balena.zImage";
-
-const RPI_MIG_INITRD_PATH: &str = "/boot/balena.initramfs.cpio.gz";
 const RPI_MIG_INITRD_NAME: &str = "balena.initramfs.cpio.gz";
 
-const RPI_CONFIG_TXT: &str = "config.txt";
 const RPI_CMDLINE_TXT: &str = "cmdline.txt";
-const RPI_BOOT_PATH: &str = "/boot";
-
-// TODO: more specific lists for PRI types ?
-
-const RPI3_DTB_FILES: &[&str] = &["bcm2710-rpi-3-b.dtb", "bcm2710-rpi-3-b-plus.dtb"];
 
-const RPI4_64_DTB_FILES: &[&str] = &["bcm2711-rpi-4-b.dtb"];
-
-pub(crate) struct RaspiBootManager<'a> {
+// where the firmware partition is mounted, Raspberry Pi OS bookworm and ubuntu use /boot/firmware
+const RPI_FIRMWARE_PATHS: &[&str] = &["/boot/firmware", "/boot"];
+// MBR partition types used for FAT file systems
+const FAT_PART_TYPES: &[&str] = &["0x1", "0x4", "0x6", "0xb", "0xc", "0xe"];
+
+// config.txt settings disabled to boot the migrate kernel, kernel also covers ubuntu's
+// kernel=uboot_rpi_*.bin, device_tree_address is only used when chain loading u-boot and
+// os_prefix would make the firmware look for our kernel & initramfs in a sub directory
+const RPI_CONFIG_DISABLE: &[&str] = &[
+    "initramfs",
+    "ramfsfile",
+    "ramfsaddr",
+    "kernel",
+    "kernel_address",
+    "enable_uart",
+    "arm_64bit",
+    "device_tree_address",
+    "os_prefix",
+];
+
+pub(crate) struct RaspiBootManager {
     bootmgr_path: Option<PathInfo>,
     boot_type: BootType,
-    dtb_files: &'a [&'a str],
+    dtb_files: Vec<String>,
 }
 
 #[allow(clippy::new_ret_no_self)] //TODO refactor this to fix cluppy warning
-impl RaspiBootManager<'_> {
-    pub fn new(boot_type: BootType) -> Result<impl BootManager + 'static, MigError> {
+impl RaspiBootManager {
+    // dtb_files are the device trees to install for the device, they are not needed in stage2
+    pub fn new(boot_type: BootType, dtb_files: Vec<String>) -> Result<impl BootManager, MigError> {
         match boot_type {
-            BootType::Raspi => Ok(RaspiBootManager {
-                bootmgr_path: None,
-                dtb_files: RPI3_DTB_FILES,
-                boot_type,
-            }),
-            BootType::Raspi64 => Ok(RaspiBootManager {
+            BootType::Raspi | BootType::Raspi64 => Ok(RaspiBootManager {
                 bootmgr_path: None,
-                dtb_files: RPI4_64_DTB_FILES,
+                dtb_files,
                 boot_type,
             }),
             _ => {
@@ -71,9 +81,100 @@ impl RaspiBootManager<'_> {
             }
         }
     }
+
+    fn is_fat_partition(partition: &LsblkPartition) -> bool {
+        if let Some(ref parttype) = partition.parttype {
+            if FAT_PART_TYPES.contains(&parttype.to_lowercase().as_str()) {
+                return true;
+            }
+        }
+
+        if let Some(ref fstype) = partition.fstype {
+            fstype == "vfat"
+        } else {
+            false
+        }
+    }
+
+    // find the mounted FAT partition holding the firmware and config.txt
+    fn find_firmware_path(lsblk_info: &LsblkInfo) -> Result<Option<PathInfo>, MigError> {
+        for fw_path in RPI_FIRMWARE_PATHS {
+            for blk_device in lsblk_info.get_blk_devices() {
+                if let Some(ref partitions) = blk_device.children {
+                    for partition in partitions {
+                        if partition.mountpoint.as_deref() != Some(Path::new(fw_path)) {
+                            continue;
+                        }
+
+                        if !RaspiBootManager::is_fat_partition(partition) {
+                            debug!(
+                                "find_firmware_path: '{}' mounted on '{}' is not a FAT partition",
+                                partition.get_path().display(),
+                                fw_path
+                            );
+                            continue;
+                        }
+
+                        if !file_exists(path_append(fw_path, RPI_CONFIG_TXT)) {
+                            warn!(
+                                "No '{}' found in FAT partition mounted on '{}'",
+                                RPI_CONFIG_TXT, fw_path
+                            );
+                            continue;
+                        }
+
+                        info!(
+                            "Found firmware partition '{}' mounted on '{}'",
+                            partition.get_path().display(),
+                            fw_path
+                        );
+                        return PathInfo::from_path(fw_path, lsblk_info);
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
 }
 
-impl BootManager for RaspiBootManager<'_> {
+// comment out settings that interfere with booting the migrate kernel and append ours,
+// returns the name of the cmdline file and the os_prefix config.txt used
+fn modify_config_txt(
+    config: &mut ConfigTxt,
+    boot_type: BootType,
+    add_tag: bool,
+) -> (String, Option<String>) {
+    for (name, value) in config.get_directives() {
+        if name == "kernel" && value.starts_with("uboot_") {
+            info!("Disabling u-boot chain loading: '{}={}'", name, value);
+        }
+    }
+
+    let cmdline_file = String::from(config.get("cmdline").unwrap_or(RPI_CMDLINE_TXT));
+    let os_prefix = config.get("os_prefix").map(String::from);
+
+    config.disable(RPI_CONFIG_DISABLE);
+
+    if add_tag {
+        config.add_tag();
+    }
+
+    if let BootType::Raspi64 = boot_type {
+        config.append("arm_64bit", "1");
+    }
+
+    config.append("enable_uart", "1");
+    config.append(
+        "initramfs",
+        &format!("{} followkernel", RPI_MIG_INITRD_NAME),
+    );
+    config.append("kernel", RPI_MIG_KERNEL_NAME);
+
+    (cmdline_file, os_prefix)
+}
+
+impl BootManager for RaspiBootManager {
     fn get_boot_type(&self) -> BootType {
         self.boot_type
     }
@@ -90,18 +191,17 @@ impl BootManager for RaspiBootManager<'_> {
     ) -> Result<bool, MigError> {
         // TODO: calculate/ensure  required space on /boot /bootmgr
 
-        if !dir_exists(BOOT_PATH)? {
-            error!("The /boot directory required for the raspi boot manager could not be found");
-            return Ok(false);
-        }
-
         let lsblk_info = LsblkInfo::all()?;
-        self.bootmgr_path = if let Some(boot_path) = PathInfo::from_path(BOOT_PATH, &lsblk_info)? {
-            Some(boot_path)
-        } else {
-            error!("Could not get path info from '{}'", BOOT_PATH);
-            return Err(MigError::displayed());
-        };
+        self.bootmgr_path =
+            if let Some(boot_path) = RaspiBootManager::find_firmware_path(&lsblk_info)? {
+                Some(boot_path)
+            } else {
+                error!(
+                    "Could not find a mounted firmware partition in any of {:?}",
+                    RPI_FIRMWARE_PATHS
+                );
+                return Ok(false);
+            };
 
         // TODO: provide a way to supply digests for DTB files
 
@@ -109,7 +209,7 @@ impl BootManager for RaspiBootManager<'_> {
 
         #[allow(clippy::redundant_pattern_matching)]
         //TODO refactor this function to fix the clippy warning
-        for file in self.dtb_files {
+        for file in &self.dtb_files {
             if let None = mig_info.dtb_file.iter().find(|file_info| {
                 debug!(
                     "looking for: '{}' - cmp: '{}'",
@@ -149,25 +249,34 @@ impl BootManager for RaspiBootManager<'_> {
     ) -> Result<(), MigError> {
         debug!("setup: entered with type: {:?}", self.boot_type);
 
+        let boot_path = if let Some(ref boot_path) = self.bootmgr_path {
+            boot_path
+        } else {
+            return Err(MigError::from_remark(
+                MigErrorKind::NotFound,
+                "bootmgr_path is not configured",
+            ));
+        };
+
         // **********************************************************************
         // ** copy new kernel
-        std::fs::copy(&mig_info.kernel_file.path, RPI_MIG_KERNEL_PATH).context(
-            MigErrCtx::from_remark(
-                MigErrorKind::Upstream,
-                &format!(
-                    "failed to copy kernel file '{}' to '{}'",
-                    mig_info.kernel_file.path.display(),
-                    RPI_MIG_KERNEL_PATH
-                ),
+        let kernel_path = path_append(&boot_path.path, RPI_MIG_KERNEL_NAME);
+        std::fs::copy(&mig_info.kernel_file.path, &kernel_path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "failed to copy kernel file '{}' to '{}'",
+                mig_info.kernel_file.path.display(),
+                kernel_path.display()
             ),
-        )?;
+        ))?;
 
-        if !check_digest(RPI_MIG_KERNEL_PATH, &mig_info.kernel_file.hash_info)? {
+        if !check_digest(&kernel_path, &mig_info.kernel_file.hash_info)? {
             return Err(MigError::from_remark(
                 MigErrorKind::Upstream,
                 &format!(
                     "Failed to check digest on copied kernel file '{}' to {:?}",
-                    RPI_MIG_KERNEL_PATH, mig_info.kernel_file.hash_info
+                    kernel_path.display(),
+                    mig_info.kernel_file.hash_info
                 ),
             ));
         }
@@ -175,30 +284,30 @@ impl BootManager for RaspiBootManager<'_> {
         info!(
             "copied kernel: '{}' -> '{}'",
             mig_info.kernel_file.path.display(),
-            RPI_MIG_KERNEL_PATH
+            kernel_path.display()
         );
 
-        call(CHMOD_CMD, &["+x", RPI_MIG_KERNEL_PATH], false)?;
+        call(CHMOD_CMD, &["+x", &kernel_path.to_string_lossy()], false)?;
 
         // **********************************************************************
         // ** copy new iniramfs
-        std::fs::copy(&mig_info.initrd_file.path, RPI_MIG_INITRD_PATH).context(
-            MigErrCtx::from_remark(
-                MigErrorKind::Upstream,
-                &format!(
-                    "failed to copy initrd file '{}' to '{}'",
-                    mig_info.initrd_file.path.display(),
-                    RPI_MIG_INITRD_PATH
-                ),
+        let initrd_path = path_append(&boot_path.path, RPI_MIG_INITRD_NAME);
+        std::fs::copy(&mig_info.initrd_file.path, &initrd_path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "failed to copy initrd file '{}' to '{}'",
+                mig_info.initrd_file.path.display(),
+                initrd_path.display()
             ),
-        )?;
+        ))?;
 
-        if !check_digest(RPI_MIG_INITRD_PATH, &mig_info.initrd_file.hash_info)? {
+        if !check_digest(&initrd_path, &mig_info.initrd_file.hash_info)? {
             return Err(MigError::from_remark(
                 MigErrorKind::Upstream,
                 &format!(
                     "Failed to check digest on copied initrd file '{}' to {:?}",
-                    RPI_MIG_INITRD_PATH, mig_info.initrd_file.hash_info
+                    initrd_path.display(),
+                    mig_info.initrd_file.hash_info
                 ),
             ));
         }
@@ -206,18 +315,9 @@ impl BootManager for RaspiBootManager<'_> {
         info!(
             "copied initramfs: '{}' -> '{}'",
             mig_info.initrd_file.path.display(),
-            RPI_MIG_INITRD_PATH
+            initrd_path.display()
         );
 
-        let boot_path = if let Some(ref boot_path) = self.bootmgr_path {
-            boot_path
-        } else {
-            return Err(MigError::from_remark(
-                MigErrorKind::NotFound,
-                "bootmgr_path is not configured",
-            ));
-        };
-
         // create backup of config.txt
 
         let system_time = SystemTime::now()
@@ -229,13 +329,13 @@ impl BootManager for RaspiBootManager<'_> {
 
         let mut boot_cfg_bckup: Vec<(String, String)> = Vec::new();
 
-        for file in self.dtb_files {
+        for file in &self.dtb_files {
             let src_path = path_append(&mig_info.work_path.path, file);
-            let tgt_path = path_append(&RPI_BOOT_PATH, file);
+            let tgt_path = path_append(&boot_path.path, file);
 
             if file_exists(&tgt_path) {
                 let backup_file = format!("{}-{}", file, system_time.as_secs());
-                let backup_path = path_append(RPI_BOOT_PATH, &backup_file);
+                let backup_path = path_append(&boot_path.path, &backup_file);
                 copy(&tgt_path, &backup_path).context(MigErrCtx::from_remark(
                     MigErrorKind::Upstream,
                     &format!(
@@ -258,7 +358,7 @@ impl BootManager for RaspiBootManager<'_> {
 
             if let Some(file_info) = mig_info.dtb_file.iter().find(|&file_info| {
                 if let Some(ref rel_path) = file_info.rel_path {
-                    &&*rel_path.to_string_lossy() == file
+                    rel_path.to_string_lossy() == file.as_str()
                 } else {
                     false
                 }
@@ -315,69 +415,65 @@ impl BootManager for RaspiBootManager<'_> {
             warn!("We appear to be modifying a '{}' that has been created by balena-migrate. No original config backup will be available as fallback.", &config_path.display());
         }
 
-        let initrd_re = Regex::new(r#"^\s*initramfs"#).unwrap();
-        let kernel_re = Regex::new(r#"^\s*kernel"#).unwrap();
-        let uart_re = Regex::new(r#"^\s*enable_uart"#).unwrap();
-        let sixty4_bits_re = Regex::new(r#"^\s*arm_64bit"#).unwrap();
-
-        let mut config_str = String::new();
+        let mut config_txt = ConfigTxt::read(&config_path)?;
+        let (cmdline_name, os_prefix) =
+            modify_config_txt(&mut config_txt, self.boot_type, !balena_config);
+
+        // backup included files that we modify, config.txt has been saved above
+        for include_path in config_txt.get_modified_includes() {
+            let rel_path = match include_path.strip_prefix(&boot_path.path) {
+                Ok(rel_path) => rel_path.to_string_lossy().to_string(),
+                Err(_) => {
+                    error!(
+                        "Included file '{}' is not located in '{}'",
+                        include_path.display(),
+                        boot_path.path.display()
+                    );
+                    return Err(MigError::displayed());
+                }
+            };
 
-        if !balena_config {
-            config_str += &format!("{}\n", BALENA_FILE_TAG);
-        }
+            let backup_file = format!("{}.{}", rel_path, system_time.as_secs());
+            let backup_path = path_append(&boot_path.path, &backup_file);
 
-        {
-            let config_file = File::open(&config_path).context(MigErrCtx::from_remark(
+            copy(include_path, &backup_path).context(MigErrCtx::from_remark(
                 MigErrorKind::Upstream,
-                &format!("Failed to open file '{}'", config_path.display()),
+                &format!(
+                    "Failed to copy '{}' to '{}'",
+                    include_path.display(),
+                    backup_path.display()
+                ),
             ))?;
-            for line in BufReader::new(config_file).lines() {
-                match line {
-                    Ok(line) => {
-                        // TODO: more modifications to /boot/config.txt
-                        if initrd_re.is_match(&line)
-                            || kernel_re.is_match(&line)
-                            || uart_re.is_match(&line)
-                        {
-                            config_str.push_str(&format!("# {}\n", line));
-                        } else if let BootType::Raspi64 = self.boot_type {
-                            if sixty4_bits_re.is_match(&line) {
-                                config_str.push_str(&format!("# {}\n", line));
-                            } else {
-                                config_str.push_str(&format!("{}\n", &line));
-                            }
-                        } else {
-                            config_str.push_str(&format!("{}\n", &line));
-                        }
-                    }
-                    Err(why) => {
-                        return Err(MigError::from(why.context(MigErrCtx::from_remark(
-                            MigErrorKind::Upstream,
-                            &format!("Failed to read line from file '{}'", config_path.display()),
-                        ))));
-                    }
-                }
-            }
-        }
 
-        if let BootType::Raspi64 = self.boot_type {
-            config_str.push_str("arm_64bit=1\n");
-        }
+            info!(
+                "Created backup of '{}' in '{}'",
+                include_path.display(),
+                backup_path.display()
+            );
 
-        config_str.push_str("enable_uart=1\n");
-        config_str.push_str(&format!("initramfs {} followkernel\n", RPI_MIG_INITRD_NAME));
-        config_str.push_str(&format!("kernel {}\n", RPI_MIG_KERNEL_NAME));
+            boot_cfg_bckup.push((rel_path, backup_file));
+        }
 
-        info!(
-            "Modified '{}' to boot migrate environment",
-            config_path.display()
-        );
+        let cmdline_path = path_append(&boot_path.path, &cmdline_name);
+        // with os_prefix disabled the firmware reads cmdline from the firmware directory,
+        // start from the prefixed cmdline the current OS boots with if there is one
+        let cmdline_src = match os_prefix {
+            Some(os_prefix) => {
+                let prefixed =
+                    path_append(&boot_path.path, format!("{}{}", os_prefix, cmdline_name));
+                if file_exists(&prefixed) {
+                    prefixed
+                } else {
+                    cmdline_path.clone()
+                }
+            }
+            None => cmdline_path.clone(),
+        };
 
-        let cmdline_path = path_append(&boot_path.path, RPI_CMDLINE_TXT);
         // Assume we have to backup cmdline.txt if we had to backup config.txt
-        if !balena_config {
+        if !balena_config && file_exists(&cmdline_path) {
             // backup cmdline.txt
-            let backup_file = format!("{}.{}", RPI_CMDLINE_TXT, system_time.as_secs());
+            let backup_file = format!("{}.{}", cmdline_name, system_time.as_secs());
             let backup_path = path_append(&boot_path.path, &backup_file);
 
             copy(&cmdline_path, &backup_path).context(MigErrCtx::from_remark(
@@ -389,10 +485,10 @@ impl BootManager for RaspiBootManager<'_> {
                 ),
             ))?;
 
-            boot_cfg_bckup.push((String::from(RPI_CMDLINE_TXT), backup_file.clone()));
+            boot_cfg_bckup.push((cmdline_name.clone(), backup_file.clone()));
         }
 
-        let cmdline_str = match read_to_string(&cmdline_path) {
+        let cmdline_str = match read_to_string(&cmdline_src) {
             Ok(cmdline) => {
                 let cmdline = cmdline.trim_end();
 
@@ -450,7 +546,7 @@ impl BootManager for RaspiBootManager<'_> {
             Err(why) => {
                 error!(
                     "failed to read boot file '{}', error: {:?}",
-                    cmdline_path.display(),
+                    cmdline_src.display(),
                     why
                 );
                 return Err(MigError::displayed());
@@ -464,20 +560,12 @@ impl BootManager for RaspiBootManager<'_> {
 
         // Finally write stuff
 
-        let mut config_file = File::create(&config_path).context(MigErrCtx::from_remark(
-            MigErrorKind::Upstream,
-            &format!(
-                "Failed to open file '{}' for writing",
-                config_path.display()
-            ),
-        ))?;
+        config_txt.write()?;
 
-        config_file
-            .write(config_str.as_bytes())
-            .context(MigErrCtx::from_remark(
-                MigErrorKind::Upstream,
-                &format!("Failed write to file '{}'", config_path.display()),
-            ))?;
+        info!(
+            "Modified '{}' to boot migrate environment",
+            config_path.display()
+        );
 
         let mut cmdline_file = File::create(&cmdline_path).context(MigErrCtx::from_remark(
             MigErrorKind::Upstream,
@@ -499,8 +587,53 @@ impl BootManager for RaspiBootManager<'_> {
         Ok(())
     }
 
-    fn restore(&self, _mounts: &Mounts, _config: &Stage2Config) -> bool {
+    fn restore(&self, mounts: &Mounts, config: &Stage2Config) -> bool {
         // TODO: remove kernel & initramfs, dtb  too
-        false
+        restore_backups(mounts.get_boot_mountpoint(), config.get_boot_backups())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{modify_config_txt, BootType};
+    use crate::linux::rpi_config_txt::ConfigTxt;
+
+    #[test]
+    fn modify_ubuntu_config_txt() {
+        let config_txt = "[pi4]\n\
+                          kernel=uboot_rpi_4.bin\n\
+                          max_framebuffers=2\n\
+                          [pi3]\n\
+                          kernel=uboot_rpi_3.bin\n\
+                          [all]\n\
+                          arm_64bit=1\n\
+                          device_tree_address=0x03000000\n\
+                          cmdline=nobtcmd.txt\n\
+                          [pi4]\n\
+                          enable_uart=1\n";
+
+        let mut config = ConfigTxt::from_str("/boot/firmware/config.txt", config_txt);
+        let (cmdline_file, os_prefix) = modify_config_txt(&mut config, BootType::Raspi64, false);
+        assert_eq!(cmdline_file, "nobtcmd.txt");
+        assert_eq!(os_prefix, None);
+        assert_eq!(
+            config.to_string(),
+            "[pi4]\n\
+             # kernel=uboot_rpi_4.bin\n\
+             max_framebuffers=2\n\
+             [pi3]\n\
+             # kernel=uboot_rpi_3.bin\n\
+             [all]\n\
+             # arm_64bit=1\n\
+             # device_tree_address=0x03000000\n\
+             cmdline=nobtcmd.txt\n\
+             [pi4]\n\
+             # enable_uart=1\n\
+             [all]\n\
+             arm_64bit=1\n\
+             enable_uart=1\n\
+             initramfs balena.initramfs.cpio.gz followkernel\n\
+             kernel=balena.zImage\n"
+        );
     }
 }
@@ -15,28 +15,49 @@ use crate::{
     common::{
         boot_manager::BootManager,
         call,
-        config::migrate_config::UEnvStrategy,
+        config::migrate_config::{UBootEnvMode, UEnvStrategy},
         file_exists, is_balena_file,
         migrate_info::MigrateInfo,
         path_append,
         path_info::PathInfo,
-        stage2_config::{Stage2Config, Stage2ConfigBuilder},
+        stage2_config::{MountConfig, Stage2Config, Stage2ConfigBuilder},
         Config, FileInfo, MigErrCtx, MigError, MigErrorKind,
     },
     defs::{BootType, BALENA_FILE_TAG, MIG_DTB_NAME, MIG_INITRD_NAME, MIG_KERNEL_NAME},
     linux::{
+        boot_manager_impl::{
+            check_boot_dirs, get_boot_dir_states, get_mount_config, remove_boot_files,
+        },
         linux_common::restore_backups,
         linux_defs::{
-            BOOT_PATH, MLO_FILE_NAME, NIX_NONE, ROOT_PATH, UBOOT_FILE_NAME, UENV_FILE_NAME,
+            BOOT_PATH, FW_ENV_CONFIG_PATH, MLO_FILE_NAME, NIX_NONE, ROOT_PATH, UBOOT_ENV_FILE_NAME,
+            UBOOT_FILE_NAME, UBOOT_REDUND_ENV_FILE_NAME, UENV_FILE_NAME,
         },
         linux_defs::{CHMOD_CMD, MKTEMP_CMD},
         stage2::mounts::Mounts,
+        uboot_env::{UBootEnv, UBootEnvLocation},
     },
 };
 
 // TODO: this might be a bit of a tight fit, allow (s|h)d([a-z])(\d+) too ?
 const UBOOT_DRIVE_FILTER_REGEX: &str = r#"^mmcblk\d+$"#;
 const UBOOT_DRIVE_REGEX: &str = r#"^/dev/mmcblk\d+p(\d+)$"#;
+
+// *************************************************************************************************
+// u-boot environment variables used for a one-shot boot of the migrate kernel.
+// bootcmd (or altbootcmd) is replaced with 'run balena_migrate_restore; run balena_migrate_boot'.
+// balena_migrate_restore puts back the original values saved in balena_migrate_orig_<name> and
+// saves the environment before the migrate kernel is loaded, so the next boot is a regular one
+// and stage2 has no u-boot environment to restore.
+
+const ENV_BOOT_VAR: &str = "balena_migrate_boot";
+const ENV_RESTORE_VAR: &str = "balena_migrate_restore";
+const ENV_ORIG_PREFIX: &str = "balena_migrate_orig_";
+
+// load addresses used if the environment does not define them, same as in UENV_TXT2
+const ENV_DEFAULT_LOADADDR: &str = "0x82000000";
+const ENV_DEFAULT_FDTADDR: &str = "0x88000000";
+const ENV_DEFAULT_RDADDR: &str = "0x88080000";
 #[derive(Debug, Clone)]
 enum BootFileType {
     KernelFile,
@@ -136,6 +157,10 @@ pub(crate) struct UBootManager {
     initrd_dest: Option<PathBuf>,
     dtb_dest: Option<PathBuf>,
     uenv_dest: Option<PathBuf>,
+    // how u-boot is made to boot the migrate kernel, resolved in can_migrate
+    env_mode: UBootEnvMode,
+    // the u-boot environment, if used
+    env: Option<UBootEnv>,
 }
 
 impl UBootManager {
@@ -150,6 +175,8 @@ impl UBootManager {
             initrd_dest: None,
             dtb_dest: None,
             uenv_dest: None,
+            env_mode: UBootEnvMode::UEnv,
+            env: None,
         }
     }
 
@@ -509,32 +536,11 @@ impl UBootManager {
         Ok(boot_req_space < bootmgr_path.fs_free)
     }
 
-    // uname setup strategy for fn setup
-    fn strategy_uname(
-        &mut self,
-        mig_info: &MigrateInfo,
-        s2_cfg: &mut Stage2ConfigBuilder,
-        kernel_opts: &str,
-        uname: &str,
-    ) -> Result<(), MigError> {
-        // **********************************************************************
-        // copy new kernel & iniramfs
-        // save under the corresponding names
-        // - vmlinuz-<uname_r>
-        // - initrd.img-<uname_r>
-        // - config-<uname_r> kernel config parameters
-        // - dtbs/<uname_r>/*.dtb
-        // __ROOT_DEV_UUID__ needs to be replaced with the root partition UUID
-        // __KERNEL_CMDLINE__ needs to be replaced with additional kernel cmdline parameters
-
-        let uenv_path = if let Some(ref bootmgr_path) = self.bootmgr_path {
-            bootmgr_path.clone()
-        } else {
-            self.bootmgr_alt_path.as_ref().unwrap().clone()
-        };
-
-        let kernel_dest =
-            self.get_target_file_name(&BootFileType::KernelFile, None, MIG_KERNEL_NAME)?;
+    // copy kernel, initramfs & dtb, returns the files followed by the directories created for them
+    fn copy_boot_files(&mut self, mig_info: &MigrateInfo) -> Result<Vec<PathBuf>, MigError> {
+        let kernel_dest = self
+            .get_target_file_name(&BootFileType::KernelFile, None, MIG_KERNEL_NAME)?
+            .to_path_buf();
         UBootManager::copy_and_check(&mig_info.kernel_file, &kernel_dest)?;
 
         info!(
@@ -545,8 +551,9 @@ impl UBootManager {
 
         call(CHMOD_CMD, &["+x", &kernel_dest.to_string_lossy()], false)?;
 
-        let initrd_dest =
-            self.get_target_file_name(&BootFileType::Initramfs, None, MIG_INITRD_NAME)?;
+        let initrd_dest = self
+            .get_target_file_name(&BootFileType::Initramfs, None, MIG_INITRD_NAME)?
+            .to_path_buf();
         UBootManager::copy_and_check(&mig_info.initrd_file, &initrd_dest)?;
 
         info!(
@@ -556,7 +563,9 @@ impl UBootManager {
         );
 
         if let Some(dtb_src) = &mig_info.dtb_file.get(0) {
-            let dtb_dest = self.get_target_file_name(&BootFileType::DtbFile, None, MIG_DTB_NAME)?;
+            let dtb_dest = self
+                .get_target_file_name(&BootFileType::DtbFile, None, MIG_DTB_NAME)?
+                .to_path_buf();
             let dtb_dir = if let Some(parent) = dtb_dest.parent() {
                 parent
             } else {
@@ -567,7 +576,16 @@ impl UBootManager {
                 return Err(MigError::displayed());
             };
 
-            if !dir_exists(&dtb_dir)? {
+            // remember created directories innermost first, so they can be removed in order
+            let mut created_dirs: Vec<PathBuf> = Vec::new();
+            for dir in dtb_dir.ancestors() {
+                if dir_exists(dir)? {
+                    break;
+                }
+                created_dirs.push(dir.to_path_buf());
+            }
+
+            if !created_dirs.is_empty() {
                 create_dir_all(&dtb_dir).context(MigErrCtx::from_remark(
                     MigErrorKind::Upstream,
                     &format!("Failed to create dtb directory: '{}", dtb_dir.display()),
@@ -581,15 +599,463 @@ impl UBootManager {
                 dtb_src.path.display(),
                 dtb_dest.display()
             );
+            let mut boot_files = vec![kernel_dest, initrd_dest, dtb_dest];
+            boot_files.append(&mut created_dirs);
+            Ok(boot_files)
         } else {
-            return Err(MigError::from_remark(
+            Err(MigError::from_remark(
                 MigErrorKind::NotFound,
                 &"The device tree blob (dtb_file) could not be found".to_string(),
-            ));
+            ))
+        }
+    }
+
+    // find the paths for uEnv.txt and kernel / initramfs / dtb
+    fn find_boot_paths(&mut self, mig_info: &MigrateInfo) -> Result<bool, MigError> {
+        // TODO: calculate/ensure  required space on /boot /bootmgr
+        trace!("find_boot_paths: entered");
+
+        // find the u-boot boot device
+        // this is where uEnv.txt has to go
+
+        let lsblk_info = LsblkInfo::all()?;
+        if let Some(path) = UBootManager::find_bootmgr_path(mig_info, &lsblk_info)? {
+            info!(
+                "Found uboot boot manager files in '{}', device: '{}', mountpoint: '{}', fs type: {}",
+                path.path.display(),
+                path.device_info.device.display(),
+                path.mountpoint.display(),
+                path.device_info.fs_type,
+            );
+
+            if self.check_bootmgr_path(&path, mig_info)? {
+                info!(
+                    "Using boot manager path '{}', device: '{}', mountpoint: '{}', fs type: {}",
+                    path.path.display(),
+                    path.device_info.device.display(),
+                    path.mountpoint.display(),
+                    path.device_info.fs_type,
+                );
+
+                self.bootmgr_path = Some(path.clone());
+                self.bootmgr_alt_path = Some(path);
+                return Ok(true);
+            } else {
+                // Not enough space for kernel / initamfs etc where boot files where found
+                match &self.strategy {
+                    UEnvStrategy::UName(_uname) => {
+                        // Uname strategy need kerne etc right there can't do this
+                        error!(
+                            "Can't_migrate with boot manager path {}",
+                            path.path.display()
+                        );
+                        // save this anyway, gotta figure out in setup
+                        return Ok(false);
+                    }
+                    UEnvStrategy::Manual => {
+                        // manual strategy can work out alt dest
+                        warn!(
+                            "Can't_migrate with boot manager path {} : checking for space elsewhere",
+                            path.path.display()
+                        );
+                        // save this anyway, gotta figure out in setup
+                        self.bootmgr_path = Some(path);
+                    }
+                }
+            }
+        }
+
+        // no uboot files found or not enough space there, try (again) in / or /boot
+        if let Some(path) = PathInfo::from_path(BOOT_PATH, &lsblk_info)? {
+            if self.check_bootmgr_path(&path, mig_info)? {
+                info!(
+                    "Using boot manager path '{}', device: '{}', mountpoint: '{}', fs type: {}",
+                    path.path.display(),
+                    path.device_info.device.display(),
+                    path.mountpoint.display(),
+                    path.device_info.fs_type,
+                );
+
+                // if no uboot files were found - this is the path for all files
+                if self.bootmgr_path.is_none() {
+                    self.bootmgr_path = Some(path.clone())
+                }
+
+                self.bootmgr_alt_path = Some(path);
+                return Ok(true);
+            }
+
+            match &self.strategy {
+                UEnvStrategy::UName(_uname) => {
+                    error!(
+                        "Can't_migrate with boot manager path {}",
+                        path.path.display()
+                    );
+                    // save this anyway, gotta figure out in setup
+                    return Ok(false);
+                }
+                UEnvStrategy::Manual => {
+                    warn!(
+                        "Can't_migrate with boot manager path {} : checking for space elsewhere",
+                        path.path.display()
+                    );
+                }
+            }
+        }
+
+        if let Some(path) = PathInfo::from_path(ROOT_PATH, &lsblk_info)? {
+            if self.check_bootmgr_path(&path, mig_info)? {
+                info!(
+                    "Using boot manager path '{}', device: '{}, mountpoint: '{}', fs type: {}",
+                    path.path.display(),
+                    path.device_info.device.display(),
+                    path.mountpoint.display(),
+                    path.device_info.fs_type,
+                );
+
+                // if no uboot files were found - this is the path for all files
+                if self.bootmgr_path.is_none() {
+                    self.bootmgr_path = Some(path.clone())
+                }
+
+                self.bootmgr_alt_path = Some(path);
+                return Ok(true);
+            }
+        }
+
+        error!("Could not find a directory with sufficient space to store the migrate kernel, initramfs and dtb file.");
+        Ok(false)
+    }
+
+    // find the locations of the u-boot environment:
+    // a configured fw_env.config style file, /etc/fw_env.config or a uboot.env file
+    // next to the u-boot files
+    fn get_env_locations(
+        &self,
+        env_config: Option<&Path>,
+    ) -> Result<Option<Vec<UBootEnvLocation>>, MigError> {
+        if let Some(env_config) = env_config {
+            return Ok(Some(UBootEnvLocation::from_fw_env_config(env_config)?));
+        }
+
+        if file_exists(FW_ENV_CONFIG_PATH) {
+            match UBootEnvLocation::from_fw_env_config(FW_ENV_CONFIG_PATH) {
+                Ok(locations) => return Ok(Some(locations)),
+                Err(why) => warn!(
+                    "Failed to read u-boot env config from '{}', error: {:?}",
+                    FW_ENV_CONFIG_PATH, why
+                ),
+            }
+        }
+
+        let bootmgr_path = self.get_bootmgr_path();
+        for search_path in &[&bootmgr_path.path, &bootmgr_path.mountpoint] {
+            let env_path = path_append(search_path, UBOOT_ENV_FILE_NAME);
+            if file_exists(&env_path) {
+                let mut locations: Vec<UBootEnvLocation> = Vec::new();
+                for env_path in &[
+                    env_path,
+                    path_append(search_path, UBOOT_REDUND_ENV_FILE_NAME),
+                ] {
+                    if file_exists(env_path) {
+                        let size = env_path
+                            .metadata()
+                            .context(MigErrCtx::from_remark(
+                                MigErrorKind::Upstream,
+                                &format!(
+                                    "Failed to retrieve metadata for '{}'",
+                                    env_path.display()
+                                ),
+                            ))?
+                            .len();
+                        locations.push(UBootEnvLocation {
+                            device: env_path.clone(),
+                            offset: 0,
+                            size,
+                        });
+                    }
+                }
+                return Ok(Some(locations));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // determine the u-boot environment mode, read the environment if it is going to be used
+    fn check_env(&mut self, config: &Config) -> Result<bool, MigError> {
+        let (env_mode, env_config) = if let Some(uboot_cfg) = config.migrate.get_uboot_cfg() {
+            (
+                uboot_cfg.env_mode.clone().unwrap_or(UBootEnvMode::Auto),
+                uboot_cfg.env_config.as_deref(),
+            )
+        } else {
+            (UBootEnvMode::Auto, None)
+        };
+
+        if env_mode == UBootEnvMode::UEnv {
+            self.env_mode = env_mode;
+            return Ok(true);
+        }
+
+        let env = match self.get_env_locations(env_config) {
+            Ok(Some(locations)) => match UBootEnv::read(&locations) {
+                Ok(env) => {
+                    info!(
+                        "Found u-boot environment in {:?}, redundant: {}",
+                        env.get_locations(),
+                        env.is_redundant()
+                    );
+                    Some(env)
+                }
+                Err(why) => {
+                    warn!("Failed to read u-boot environment, error: {:?}", why);
+                    None
+                }
+            },
+            Ok(None) => {
+                info!("No u-boot environment found");
+                None
+            }
+            Err(why) => {
+                warn!("Failed to locate u-boot environment, error: {:?}", why);
+                None
+            }
         };
 
-        let uenv_file_path =
-            self.get_target_file_name(&BootFileType::UEnvFile, None, UENV_FILE_NAME)?;
+        match env_mode {
+            UBootEnvMode::Auto => {
+                if let Some(env) = env {
+                    let uses_uenv = env.get_vars().iter().any(|(name, value)| {
+                        name == "uenvcmd"
+                            || value.contains(UENV_FILE_NAME)
+                            || value.contains("uenvcmd")
+                    });
+                    if uses_uenv {
+                        info!("The u-boot environment loads {}, using it", UENV_FILE_NAME);
+                        self.env_mode = UBootEnvMode::UEnv;
+                    } else {
+                        info!(
+                            "The u-boot environment does not load {}, using a one-shot bootcmd",
+                            UENV_FILE_NAME
+                        );
+                        self.env_mode = UBootEnvMode::BootCmd;
+                        self.env = Some(env);
+                    }
+                } else {
+                    self.env_mode = UBootEnvMode::UEnv;
+                }
+                Ok(true)
+            }
+            _ => {
+                if env.is_some() {
+                    self.env_mode = env_mode;
+                    self.env = env;
+                    Ok(true)
+                } else {
+                    error!(
+                        "A u-boot environment is required for u-boot env mode {:?}",
+                        env_mode
+                    );
+                    Ok(false)
+                }
+            }
+        }
+    }
+
+    // record the files installed for the migrate boot with their partitions for removal in stage2
+    fn set_boot_files(
+        s2_cfg: &mut Stage2ConfigBuilder,
+        boot_files: &[PathBuf],
+    ) -> Result<(), MigError> {
+        let lsblk_info = LsblkInfo::all()?;
+        let mut mount_cfgs: Vec<MountConfig> = Vec::new();
+        for boot_file in boot_files {
+            mount_cfgs.push(get_mount_config(boot_file, &lsblk_info)?);
+        }
+        s2_cfg.set_boot_files(mount_cfgs);
+        Ok(())
+    }
+
+    // boot config backups are restored relative to the boot mountpoint in stage2
+    fn get_boot_rel_path<P: AsRef<Path>>(&self, path: P) -> Result<String, MigError> {
+        let path = path.as_ref();
+        let boot_path = self.get_bootmgr_path();
+        let rel_path = path
+            .strip_prefix(&boot_path.mountpoint)
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "failed to make '{}' relative to '{}'",
+                    path.display(),
+                    boot_path.mountpoint.display()
+                ),
+            ))?;
+        Ok(String::from(
+            &*path_append(ROOT_PATH, rel_path).to_string_lossy(),
+        ))
+    }
+
+    // record the former contents of the directories kernel, initramfs, dtb & uEnv.txt go to
+    fn set_boot_dirs(
+        &mut self,
+        s2_cfg: &mut Stage2ConfigBuilder,
+        with_uenv: bool,
+    ) -> Result<(), MigError> {
+        let mut boot_files = vec![
+            self.get_target_file_name(&BootFileType::KernelFile, None, MIG_KERNEL_NAME)?
+                .to_path_buf(),
+            self.get_target_file_name(&BootFileType::Initramfs, None, MIG_INITRD_NAME)?
+                .to_path_buf(),
+            self.get_target_file_name(&BootFileType::DtbFile, None, MIG_DTB_NAME)?
+                .to_path_buf(),
+        ];
+        if with_uenv {
+            boot_files.push(
+                self.get_target_file_name(&BootFileType::UEnvFile, None, UENV_FILE_NAME)?
+                    .to_path_buf(),
+            );
+        }
+        s2_cfg.set_boot_dirs(get_boot_dir_states(&boot_files)?);
+        Ok(())
+    }
+
+    // one-shot setup through the u-boot environment for fn setup
+    fn strategy_env(
+        &mut self,
+        mig_info: &MigrateInfo,
+        s2_cfg: &mut Stage2ConfigBuilder,
+        kernel_opts: &str,
+    ) -> Result<(), MigError> {
+        self.set_boot_dirs(s2_cfg, false)?;
+        let paths = self.copy_boot_files(mig_info)?;
+        UBootManager::set_boot_files(s2_cfg, &paths)?;
+
+        // files are on bootmgr_alt_path, make paths relative to its partition
+        let alt_path = self.bootmgr_alt_path.as_ref().unwrap().clone();
+        let mut rel_paths: Vec<String> = Vec::new();
+        for path in &paths[0..3] {
+            match path.strip_prefix(&alt_path.mountpoint) {
+                Ok(rel_path) => rel_paths.push(String::from(
+                    path_append(ROOT_PATH, rel_path).to_string_lossy(),
+                )),
+                Err(why) => {
+                    error!(
+                        "cannot remove prefix '{}' from '{}', error: {:?}",
+                        alt_path.mountpoint.display(),
+                        path.display(),
+                        why
+                    );
+                    return Err(MigError::displayed());
+                }
+            }
+        }
+
+        let part_num = UBootManager::get_part_num(&alt_path.device_info.device)?;
+        let boot_path = self.get_bootmgr_path();
+        let env_mode = self.env_mode.clone();
+        let env = if let Some(ref mut env) = self.env {
+            env
+        } else {
+            error!("strategy_env: the u-boot environment was not read");
+            return Err(MigError::displayed());
+        };
+
+        let loadaddr = env
+            .get("loadaddr")
+            .unwrap_or(ENV_DEFAULT_LOADADDR)
+            .to_string();
+        let fdtaddr = env
+            .get("fdtaddr")
+            .unwrap_or(ENV_DEFAULT_FDTADDR)
+            .to_string();
+        let rdaddr = env.get("rdaddr").unwrap_or(ENV_DEFAULT_RDADDR).to_string();
+        let load = format!("load mmc {}:{}", self.mmc_index, part_num);
+
+        let boot_cmd = format!(
+            "{load} {loadaddr} {kernel}; {load} {fdtaddr} {dtb}; {load} {rdaddr} {initrd}; \
+             setenv rdsize ${{filesize}}; \
+             setenv bootargs console=tty0 console=${{console}} root={root} rootfstype={fstype} {opts}; \
+             bootz {loadaddr} {rdaddr}:${{rdsize}} {fdtaddr}",
+            load = load,
+            loadaddr = loadaddr,
+            fdtaddr = fdtaddr,
+            rdaddr = rdaddr,
+            kernel = rel_paths[0],
+            initrd = rel_paths[1],
+            dtb = rel_paths[2],
+            root = boot_path.device_info.get_kernel_cmd(),
+            fstype = boot_path.device_info.fs_type,
+            opts = kernel_opts
+        );
+
+        let (new_vars, restore_cmd) = one_shot_env(&env_mode, env.get_vars());
+        for (name, value) in &new_vars {
+            debug!("u-boot env {}='{}'", name, value);
+            env.set(name, value);
+        }
+
+        debug!("u-boot env {}='{}'", ENV_BOOT_VAR, boot_cmd);
+        debug!("u-boot env {}='{}'", ENV_RESTORE_VAR, restore_cmd);
+
+        env.set(ENV_BOOT_VAR, &boot_cmd);
+        env.set(ENV_RESTORE_VAR, &restore_cmd);
+        env.write()?;
+
+        info!(
+            "set up one-shot boot in the u-boot environment using {:?}",
+            env_mode
+        );
+        Ok(())
+    }
+
+    fn get_part_num(device: &Path) -> Result<String, MigError> {
+        if let Some(captures) = Regex::new(UBOOT_DRIVE_REGEX)
+            .unwrap()
+            .captures(&device.to_string_lossy())
+        {
+            Ok(String::from(captures.get(1).unwrap().as_str()))
+        } else {
+            Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "failed to parse partition numbers from boot device name '{}'",
+                    device.display()
+                ),
+            ))
+        }
+    }
+
+    // uname setup strategy for fn setup
+    fn strategy_uname(
+        &mut self,
+        mig_info: &MigrateInfo,
+        s2_cfg: &mut Stage2ConfigBuilder,
+        kernel_opts: &str,
+        uname: &str,
+    ) -> Result<(), MigError> {
+        // **********************************************************************
+        // copy new kernel & iniramfs
+        // save under the corresponding names
+        // - vmlinuz-<uname_r>
+        // - initrd.img-<uname_r>
+        // - config-<uname_r> kernel config parameters
+        // - dtbs/<uname_r>/*.dtb
+        // __ROOT_DEV_UUID__ needs to be replaced with the root partition UUID
+        // __KERNEL_CMDLINE__ needs to be replaced with additional kernel cmdline parameters
+
+        let uenv_path = if let Some(ref bootmgr_path) = self.bootmgr_path {
+            bootmgr_path.clone()
+        } else {
+            self.bootmgr_alt_path.as_ref().unwrap().clone()
+        };
+
+        self.set_boot_dirs(s2_cfg, true)?;
+        let mut boot_files = self.copy_boot_files(mig_info)?;
+
+        let uenv_file_path = self
+            .get_target_file_name(&BootFileType::UEnvFile, None, UENV_FILE_NAME)?
+            .to_path_buf();
 
         if file_exists(&uenv_file_path) {
             // **********************************************************************
@@ -617,8 +1083,8 @@ impl UBootManager {
 
                 let mut boot_cfg_bckup: Vec<(String, String)> = Vec::new();
                 boot_cfg_bckup.push((
-                    String::from(&*uenv_file_path.to_string_lossy()),
-                    backup_uenv,
+                    self.get_boot_rel_path(&uenv_file_path)?,
+                    self.get_boot_rel_path(&backup_uenv)?,
                 ));
 
                 s2_cfg.set_boot_bckup(boot_cfg_bckup);
@@ -653,6 +1119,10 @@ impl UBootManager {
                 &format!("failed to write new '{}'", uenv_file_path.display()),
             ))?;
         info!("created new file in '{}'", uenv_file_path.display());
+
+        // uEnv.txt goes before the directories created for the dtb
+        boot_files.insert(3, uenv_file_path);
+        UBootManager::set_boot_files(s2_cfg, &boot_files)?;
         Ok(())
     }
 
@@ -664,6 +1134,8 @@ impl UBootManager {
         kernel_opts: &str,
         part_num: &str,
     ) -> Result<(), MigError> {
+        self.set_boot_dirs(s2_cfg, true)?;
+
         // **********************************************************************
         // ** copy new kernel & iniramfs
         let kernel_dest = self
@@ -738,7 +1210,10 @@ impl UBootManager {
                 );
 
                 let mut boot_cfg_bckup: Vec<(String, String)> = Vec::new();
-                boot_cfg_bckup.push((String::from(&*uenv_dest.to_string_lossy()), backup_uenv));
+                boot_cfg_bckup.push((
+                    self.get_boot_rel_path(&uenv_dest)?,
+                    self.get_boot_rel_path(&backup_uenv)?,
+                ));
 
                 s2_cfg.set_boot_bckup(boot_cfg_bckup);
             }
@@ -748,7 +1223,8 @@ impl UBootManager {
         // ** create new /uEnv.txt
         // convert kernel / initrd / dtb paths to mountpoint relative paths for uEnv.txt
         let mut paths: Vec<PathBuf> = Vec::new();
-        let result = [kernel_dest, initrd_dest, dtb_dest].iter().all(|path| {
+        let boot_files = vec![kernel_dest, initrd_dest, dtb_dest, uenv_dest.clone()];
+        let result = boot_files[0..3].iter().all(|path| {
             let mut done = false;
             if let Some(ref boot_path) = self.bootmgr_path {
                 if (boot_path.mountpoint != PathBuf::from(ROOT_PATH))
@@ -833,6 +1309,8 @@ impl UBootManager {
                 &format!("failed to write new '{}'", uenv_dest.display()),
             ))?;
         info!("created new file in '{}'", uenv_dest.display());
+
+        UBootManager::set_boot_files(s2_cfg, &boot_files)?;
         Ok(())
     }
 }
@@ -855,122 +1333,15 @@ impl BootManager for UBootManager {
     fn can_migrate(
         &mut self,
         mig_info: &MigrateInfo,
-        _config: &Config,
+        config: &Config,
         _s2_cfg: &mut Stage2ConfigBuilder,
     ) -> Result<bool, MigError> {
-        // TODO: calculate/ensure  required space on /boot /bootmgr
         trace!("can_migrate: entered");
-
-        // find the u-boot boot device
-        // this is where uEnv.txt has to go
-
-        let lsblk_info = LsblkInfo::all()?;
-        if let Some(path) = UBootManager::find_bootmgr_path(mig_info, &lsblk_info)? {
-            info!(
-                "Found uboot boot manager files in '{}', device: '{}', mountpoint: '{}', fs type: {}",
-                path.path.display(),
-                path.device_info.device.display(),
-                path.mountpoint.display(),
-                path.device_info.fs_type,
-            );
-
-            if self.check_bootmgr_path(&path, mig_info)? {
-                info!(
-                    "Using boot manager path '{}', device: '{}', mountpoint: '{}', fs type: {}",
-                    path.path.display(),
-                    path.device_info.device.display(),
-                    path.mountpoint.display(),
-                    path.device_info.fs_type,
-                );
-
-                self.bootmgr_path = Some(path.clone());
-                self.bootmgr_alt_path = Some(path);
-                return Ok(true);
-            } else {
-                // Not enough space for kernel / initamfs etc where boot files where found
-                match &self.strategy {
-                    UEnvStrategy::UName(_uname) => {
-                        // Uname strategy need kerne etc right there can't do this
-                        error!(
-                            "Can't_migrate with boot manager path {}",
-                            path.path.display()
-                        );
-                        // save this anyway, gotta figure out in setup
-                        return Ok(false);
-                    }
-                    UEnvStrategy::Manual => {
-                        // manual strategy can work out alt dest
-                        warn!(
-                            "Can't_migrate with boot manager path {} : checking for space elsewhere",
-                            path.path.display()
-                        );
-                        // save this anyway, gotta figure out in setup
-                        self.bootmgr_path = Some(path);
-                    }
-                }
-            }
-        }
-
-        // no uboot files found or not enough space there, try (again) in / or /boot
-        if let Some(path) = PathInfo::from_path(BOOT_PATH, &lsblk_info)? {
-            if self.check_bootmgr_path(&path, mig_info)? {
-                info!(
-                    "Using boot manager path '{}', device: '{}', mountpoint: '{}', fs type: {}",
-                    path.path.display(),
-                    path.device_info.device.display(),
-                    path.mountpoint.display(),
-                    path.device_info.fs_type,
-                );
-
-                // if no uboot files were found - this is the path for all files
-                if self.bootmgr_path.is_none() {
-                    self.bootmgr_path = Some(path.clone())
-                }
-
-                self.bootmgr_alt_path = Some(path);
-                return Ok(true);
-            }
-
-            match &self.strategy {
-                UEnvStrategy::UName(_uname) => {
-                    error!(
-                        "Can't_migrate with boot manager path {}",
-                        path.path.display()
-                    );
-                    // save this anyway, gotta figure out in setup
-                    return Ok(false);
-                }
-                UEnvStrategy::Manual => {
-                    warn!(
-                        "Can't_migrate with boot manager path {} : checking for space elsewhere",
-                        path.path.display()
-                    );
-                }
-            }
-        }
-
-        if let Some(path) = PathInfo::from_path(ROOT_PATH, &lsblk_info)? {
-            if self.check_bootmgr_path(&path, mig_info)? {
-                info!(
-                    "Using boot manager path '{}', device: '{}, mountpoint: '{}', fs type: {}",
-                    path.path.display(),
-                    path.device_info.device.display(),
-                    path.mountpoint.display(),
-                    path.device_info.fs_type,
-                );
-
-                // if no uboot files were found - this is the path for all files
-                if self.bootmgr_path.is_none() {
-                    self.bootmgr_path = Some(path.clone())
-                }
-
-                self.bootmgr_alt_path = Some(path);
-                return Ok(true);
-            }
+        if self.find_boot_paths(mig_info)? {
+            self.check_env(config)
+        } else {
+            Ok(false)
         }
-
-        error!("Could not find a directory with sufficient space to store the migrate kernel, initramfs and dtb file.");
-        Ok(false)
     }
 
     fn setup(
@@ -1001,24 +1372,12 @@ impl BootManager for UBootManager {
         //     - beagleboardXM - no emmc use mmc 0
         //     - beaglebone-green - has emc - use mmc 1 by default
 
-        let part_num = {
-            let dev_name = &self.bootmgr_path.as_ref().unwrap().device_info.device;
+        if self.env_mode != UBootEnvMode::UEnv {
+            return self.strategy_env(mig_info, s2_cfg, kernel_opts);
+        }
 
-            if let Some(captures) = Regex::new(UBOOT_DRIVE_REGEX)
-                .unwrap()
-                .captures(&dev_name.to_string_lossy())
-            {
-                String::from(captures.get(1).unwrap().as_str())
-            } else {
-                return Err(MigError::from_remark(
-                    MigErrorKind::InvParam,
-                    &format!(
-                        "failed to parse partition numbers from boot device name '{}'",
-                        dev_name.display()
-                    ),
-                ));
-            }
-        };
+        let part_num =
+            UBootManager::get_part_num(&self.bootmgr_path.as_ref().unwrap().device_info.device)?;
 
         match self.strategy {
             UEnvStrategy::UName(ref uname) => {
@@ -1032,46 +1391,146 @@ impl BootManager for UBootManager {
     fn restore(&self, mounts: &Mounts, config: &Stage2Config) -> bool {
         info!("restoring boot configuration",);
 
-        // TODO: restore on bootmgr device
         let mut res = true;
 
-        let uenv_file = path_append(mounts.get_boot_mountpoint(), UENV_FILE_NAME);
-
-        let balena_file = match is_balena_file(&uenv_file) {
-            Ok(res) => res,
-            Err(why) => {
-                warn!(
-                    "Failed to get file status for '{}', error: {:?}",
-                    uenv_file.display(),
-                    why
-                );
-                false
-            }
-        };
+        // remove kernel, initramfs, dtb and uEnv.txt from the partitions they were installed to
+        let boot_files = config.get_boot_files();
+        if boot_files.is_empty() {
+            warn!("No migrate boot files found in stage2 config");
+            res = false;
+        } else if !remove_boot_files(mounts, boot_files) {
+            res = false;
+        }
 
-        if file_exists(&uenv_file) && balena_file {
-            if let Err(why) = remove_file(&uenv_file) {
-                error!(
-                    "failed to remove migrate boot config file '{}' error: {:?}",
-                    uenv_file.display(),
-                    why
-                )
-            } else {
-                info!("Removed balena boot config file '{}'", &uenv_file.display());
+        if restore_backups(mounts.get_boot_mountpoint(), config.get_boot_backups()) {
+            // the backups have been copied back, they are not needed anymore
+            for (_, backup) in config.get_boot_backups() {
+                let backup_path = path_append(mounts.get_boot_mountpoint(), backup);
+                if let Err(why) = remove_file(&backup_path) {
+                    error!(
+                        "Failed to remove backup '{}', error: {:?}",
+                        backup_path.display(),
+                        why
+                    );
+                    res = false;
+                }
             }
         } else {
-            warn!(
-                "balena boot config file not found in '{}'",
-                &uenv_file.display()
-            );
             res = false;
         }
 
-        if !restore_backups(mounts.get_boot_mountpoint(), config.get_boot_backups()) {
+        // make sure the boot directories are back to what they were before the migration
+        if !check_boot_dirs(mounts, config.get_boot_dirs()) {
             res = false;
         }
 
-        // TODO: remove kernel & initramfs, dtb  too
+        // a one-shot boot through the u-boot environment has restored the environment before
+        // booting the migrate kernel, so there is nothing to do for it here
+
         res
     }
 }
+
+// variables to set for a one-shot boot in env_mode and the command restoring the original
+// environment, original values are saved with ENV_ORIG_PREFIX before they are replaced
+fn one_shot_env(
+    env_mode: &UBootEnvMode,
+    curr_vars: &[(String, String)],
+) -> (Vec<(String, String)>, String) {
+    let run_cmd = format!("run {}; run {}", ENV_RESTORE_VAR, ENV_BOOT_VAR);
+    let one_shot_vars: Vec<(&str, &str)> = match env_mode {
+        UBootEnvMode::AltBootCmd => vec![
+            ("altbootcmd", &run_cmd),
+            ("bootlimit", "1"),
+            ("bootcount", "1"),
+            ("upgrade_available", "1"),
+        ],
+        _ => vec![("bootcmd", &run_cmd)],
+    };
+
+    let mut new_vars: Vec<(String, String)> = Vec::new();
+    let mut restore_cmd = String::new();
+    for (name, value) in one_shot_vars {
+        let orig_name = format!("{}{}", ENV_ORIG_PREFIX, name);
+        if let Some((_, orig_value)) = curr_vars.iter().find(|(curr_name, _)| curr_name == name) {
+            info!(
+                "saving u-boot env {}='{}' as {}",
+                name, orig_value, orig_name
+            );
+            new_vars.push((orig_name.clone(), orig_value.clone()));
+            // quoted, the original value might contain spaces or ';'
+            restore_cmd.push_str(&format!(
+                "setenv {} \"${{{}}}\"; setenv {}; ",
+                name, orig_name, orig_name
+            ));
+        } else {
+            restore_cmd.push_str(&format!("setenv {}; ", name));
+        }
+        new_vars.push((String::from(name), String::from(value)));
+    }
+    restore_cmd.push_str("saveenv");
+
+    (new_vars, restore_cmd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{one_shot_env, ENV_BOOT_VAR, ENV_RESTORE_VAR};
+    use crate::common::config::migrate_config::UBootEnvMode;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (String::from(*name), String::from(*value)))
+            .collect()
+    }
+
+    #[test]
+    fn one_shot_bootcmd() {
+        let run_cmd = format!("run {}; run {}", ENV_RESTORE_VAR, ENV_BOOT_VAR);
+        let curr_vars = vars(&[("bootcmd", "run findfdt; run mmcboot"), ("bootdelay", "1")]);
+        let (new_vars, restore_cmd) = one_shot_env(&UBootEnvMode::BootCmd, &curr_vars);
+        assert_eq!(
+            new_vars,
+            vars(&[
+                ("balena_migrate_orig_bootcmd", "run findfdt; run mmcboot"),
+                ("bootcmd", &run_cmd),
+            ])
+        );
+        assert_eq!(
+            restore_cmd,
+            "setenv bootcmd \"${balena_migrate_orig_bootcmd}\"; \
+             setenv balena_migrate_orig_bootcmd; saveenv"
+        );
+
+        let (new_vars, restore_cmd) = one_shot_env(&UBootEnvMode::Auto, &[]);
+        assert_eq!(new_vars, vars(&[("bootcmd", &run_cmd)]));
+        assert_eq!(restore_cmd, "setenv bootcmd; saveenv");
+    }
+
+    #[test]
+    fn one_shot_altbootcmd() {
+        let curr_vars = vars(&[("altbootcmd", "run altboot"), ("bootlimit", "3")]);
+        let (new_vars, restore_cmd) = one_shot_env(&UBootEnvMode::AltBootCmd, &curr_vars);
+        let names: Vec<&str> = new_vars.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "balena_migrate_orig_altbootcmd",
+                "altbootcmd",
+                "balena_migrate_orig_bootlimit",
+                "bootlimit",
+                "bootcount",
+                "upgrade_available"
+            ]
+        );
+        assert_eq!(new_vars[3].1, "1");
+        assert_eq!(
+            restore_cmd,
+            "setenv altbootcmd \"${balena_migrate_orig_altbootcmd}\"; \
+             setenv balena_migrate_orig_altbootcmd; \
+             setenv bootlimit \"${balena_migrate_orig_bootlimit}\"; \
+             setenv balena_migrate_orig_bootlimit; \
+             setenv bootcount; setenv upgrade_available; saveenv"
+        );
+    }
+}
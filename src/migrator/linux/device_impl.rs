@@ -1,58 +1,92 @@
 use failure::ResultExt;
-use log::error;
+use log::{debug, error, info};
 use std::fs::read_to_string;
 
 use crate::{
     common::{
-        device::Device, migrate_info::MigrateInfo, stage2_config::Stage2ConfigBuilder, Config,
-        MigErrCtx, MigError, MigErrorKind,
+        device::Device,
+        device_catalog::{DeviceArch, DeviceCatalog, DeviceEntry, DmiInfo},
+        file_type::is_file_type,
+        migrate_info::MigrateInfo,
+        path_append,
+        stage2_config::Stage2ConfigBuilder,
+        Config, MigErrCtx, MigError, MigErrorKind,
     },
-    defs::{BootType, DeviceType, OSArch},
+    defs::{BootType, OSArch},
 };
 
-mod beaglebone;
-mod intel_nuc;
-mod raspberrypi;
+mod catalog_device;
+use catalog_device::CatalogDevice;
 
 const DEVICE_TREE_MODEL: &str = "/proc/device-tree/model";
+const DEVICE_TREE_COMPATIBLE: &str = "/proc/device-tree/compatible";
+const DMI_ID_DIR: &str = "/sys/class/dmi/id";
 
 pub(crate) fn from_config(
-    device_type: DeviceType,
+    device: &DeviceEntry,
     boot_type: BootType,
 ) -> Result<Box<dyn Device>, MigError> {
-    match device_type {
-        DeviceType::BeagleboneGreen => Ok(Box::new(beaglebone::BeagleboneGreen::from_boot_type(
-            boot_type,
-        ))),
-        DeviceType::BeagleboneBlack => Ok(Box::new(beaglebone::BeagleboneBlack::from_boot_type(
-            boot_type,
-        ))),
-        DeviceType::BeagleboardXM => Ok(Box::new(beaglebone::BeagleboardXM::from_boot_type(
-            boot_type,
-        ))),
-        DeviceType::RaspberryPi3 => Ok(Box::new(raspberrypi::RaspberryPi3::from_boot_type(
-            boot_type,
-        ))),
-        DeviceType::RaspberryPi4_64 => Ok(Box::new(raspberrypi::RaspberryPi4_64::from_boot_type(
-            boot_type,
-        ))),
-        DeviceType::IntelNuc => Ok(Box::new(intel_nuc::IntelNuc::from_boot_type(boot_type))),
-        /*        _ => {
-                    let message = format!("unexpected device type: {}", &slug);
-                    error!("{}", &message);
-                    Err(MigError::from_remark(MigErrorKind::InvState, &message))
-                }
-        */
+    Ok(Box::new(CatalogDevice::from_boot_type(
+        device.clone(),
+        boot_type,
+    )))
+}
+
+// the device tree compatible list is optional, the model string is required
+fn get_dt_compatible() -> Vec<String> {
+    match read_to_string(DEVICE_TREE_COMPATIBLE) {
+        Ok(compatible) => compatible
+            .split('\0')
+            .filter(|entry| !entry.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(why) => {
+            debug!(
+                "get_dt_compatible: failed to read '{}', error: {:?}",
+                DEVICE_TREE_COMPATIBLE, why
+            );
+            Vec::new()
+        }
     }
 }
 
+// missing DMI strings are left empty, they do not match any device
+fn get_dmi_info() -> DmiInfo {
+    let read_dmi = |name: &str| -> String {
+        let dmi_path = path_append(DMI_ID_DIR, name);
+        match read_to_string(&dmi_path) {
+            Ok(value) => String::from(value.trim()),
+            Err(why) => {
+                debug!(
+                    "get_dmi_info: failed to read '{}', error: {:?}",
+                    dmi_path.display(),
+                    why
+                );
+                String::new()
+            }
+        }
+    };
+
+    let dmi_info = DmiInfo {
+        sys_vendor: read_dmi("sys_vendor"),
+        product_name: read_dmi("product_name"),
+        board_vendor: read_dmi("board_vendor"),
+        board_name: read_dmi("board_name"),
+    };
+    debug!("get_dmi_info: {:?}", dmi_info);
+    dmi_info
+}
+
 pub(crate) fn get_device(
     mig_info: &MigrateInfo,
     config: &Config,
     s2_cfg: &mut Stage2ConfigBuilder,
 ) -> Result<Box<dyn Device>, MigError> {
-    match mig_info.os_arch {
-        OSArch::ARMHF => {
+    let catalog = DeviceCatalog::load(Some(config.migrate.get_work_dir()))?;
+
+    let devices = match mig_info.os_arch {
+        // 32 and 64 bit ARM devices are identified through the device tree
+        OSArch::ARMHF | OSArch::AARCH64 => {
             let dev_tree_model = String::from(
                 read_to_string(DEVICE_TREE_MODEL)
                     .context(MigErrCtx::from_remark(
@@ -66,36 +100,74 @@ pub(crate) fn get_device(
                     .trim_end(),
             );
 
-            if let Some(device) = raspberrypi::is_rpi(mig_info, config, s2_cfg, &dev_tree_model)? {
-                return Ok(device);
+            let devices = catalog.find_by_model(&dev_tree_model, &get_dt_compatible())?;
+            if devices.is_empty() {
+                let message = format!(
+                    "Your device type: '{}' is not supported by balena-migrate.",
+                    dev_tree_model
+                );
+                error!("{}", message);
+                return Err(MigError::from_remark(MigErrorKind::InvState, &message));
             }
-
-            if let Some(device) = beaglebone::is_bb(mig_info, config, s2_cfg, &dev_tree_model)? {
-                return Ok(device);
+            devices
+        }
+        OSArch::AMD64 => {
+            // devices identified through DMI take precedence over the generic ones
+            let mut devices = catalog.find_by_dmi(DeviceArch::AMD64, &get_dmi_info())?;
+            devices.append(&mut catalog.find_by_arch(DeviceArch::AMD64));
+            if devices.is_empty() {
+                error!(
+                    "No {} device was found in the device catalog",
+                    DeviceArch::AMD64
+                );
+                return Err(MigError::displayed());
             }
-
-            let message = format!(
-                "Your device type: '{}' is not supported by balena-migrate.",
-                dev_tree_model
-            );
-            error!("{}", message);
-            Err(MigError::from_remark(MigErrorKind::InvState, &message))
+            devices
         }
-        OSArch::AMD64 => Ok(Box::new(intel_nuc::IntelNuc::from_config(
-            mig_info, config, s2_cfg,
-        )?)),
         /*            OSArch::I386 => {
                     migrator.init_i386()?;
                 },
         */
-        _ => Err(MigError::from_remark(
-            MigErrorKind::InvParam,
-            &format!(
-                "get_device: unexpected OsArch encountered: {}",
-                mig_info.os_arch
-            ),
-        )),
+        _ => {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "get_device: unexpected OsArch encountered: {}",
+                    mig_info.os_arch
+                ),
+            ))
+        }
+    };
+
+    // devices sharing a model differ in the balena OS architecture, go by the migrate kernel
+    for device in &devices {
+        if is_file_type(&mig_info.kernel_file.path, &device.arch.get_kernel_type())? {
+            info!("Identified {} ({})", device.name, device.slug);
+            return Ok(Box::new(CatalogDevice::from_config(
+                (*device).clone(),
+                mig_info,
+                config,
+                s2_cfg,
+            )?));
+        }
     }
+
+    let expected: Vec<String> = devices
+        .iter()
+        .map(|device| {
+            format!(
+                "{} for the {}",
+                device.arch.get_kernel_type().get_descr(),
+                device.name
+            )
+        })
+        .collect();
+    error!(
+        "The migrate kernel '{}' is not a {}",
+        mig_info.kernel_file.path.display(),
+        expected.join(" or a ")
+    );
+    Err(MigError::displayed())
 }
 
 /*
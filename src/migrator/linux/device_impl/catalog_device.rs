@@ -0,0 +1,470 @@
+use failure::ResultExt;
+use log::{debug, error, info, warn};
+use std::fs::{read_dir, read_to_string};
+
+use crate::{
+    common::{
+        boot_manager::BootManager,
+        config::migrate_config::UEnvStrategy,
+        device_catalog::{BootManagerCfg, DeviceArch, DeviceEntry},
+        disk_util::LabelType,
+        file_type::get_kernel_release,
+        migrate_info::MigrateInfo,
+        os_policy::OsSupport,
+        path_append,
+        path_info::PathInfo,
+        stage2_config::{Stage2Config, Stage2ConfigBuilder},
+        Config, MigErrCtx, MigError, MigErrorKind,
+    },
+    defs::BootType,
+    linux::{
+        boot_manager_impl::{
+            from_boot_type, EfiBootManager, GrubBootManager, RaspiBootManager, UBootManager,
+        },
+        device_impl::Device,
+        linux_common::{is_efi_boot, is_secure_boot},
+        linux_defs::ROOT_PATH,
+        lsblk_info::LsblkInfo,
+        rpi_config_txt::{ConfigTxt, RPI_CONFIG_TXT},
+        stage2::mounts::Mounts,
+    },
+};
+
+const SYS_BLOCK_DIR: &str = "/sys/block";
+
+// The uname strategy needs the kernel release of the migrate kernel, it is taken from the kernel
+// image, a release configured with the uname strategy overrides it
+fn get_uboot_cfg(
+    config: &Config,
+    mig_info: &MigrateInfo,
+    def_mmc_index: u8,
+    s2_cfg: &mut Stage2ConfigBuilder,
+) -> Result<(u8, UEnvStrategy), MigError> {
+    let (mmc_index, strategy) = if let Some(uboot_cfg) = config.migrate.get_uboot_cfg() {
+        let mmc_index = if let Some(mmc_index) = uboot_cfg.mmc_index {
+            mmc_index
+        } else {
+            def_mmc_index
+        };
+        (mmc_index, uboot_cfg.strategy.clone())
+    } else {
+        (def_mmc_index, None)
+    };
+
+    let kernel_path = &mig_info.kernel_file.path;
+    let kernel_release = get_kernel_release(kernel_path)?;
+    if let Some(ref kernel_release) = kernel_release {
+        info!(
+            "The migrate kernel '{}' has release '{}'",
+            kernel_path.display(),
+            kernel_release
+        );
+        s2_cfg.set_kernel_release(kernel_release.clone());
+    }
+
+    let strategy = match (strategy, kernel_release) {
+        (Some(UEnvStrategy::UName(uname)), Some(kernel_release)) => {
+            if uname != kernel_release {
+                warn!(
+                    "The configured kernel release '{}' overrides the release '{}' of the migrate kernel",
+                    uname, kernel_release
+                );
+            }
+            UEnvStrategy::UName(uname)
+        }
+        (Some(strategy), _) => strategy,
+        (None, Some(kernel_release)) => UEnvStrategy::UName(kernel_release),
+        (None, None) => {
+            error!(
+                "Could not determine the kernel release of the migrate kernel '{}', please configure it using the uname strategy",
+                kernel_path.display()
+            );
+            return Err(MigError::displayed());
+        }
+    };
+
+    Ok((mmc_index, strategy))
+}
+
+// Some devices (eg. the raspberry pi compute module 4) boot from their eMMC if they have one,
+// an SD slot on the carrier board is only used without eMMC. Make sure the boot partition we
+// modify is on the device that is booted.
+fn check_emmc_boot_device(boot_path: &PathInfo) -> Result<bool, MigError> {
+    let boot_drive = &boot_path.device_info.drive;
+
+    let entries = read_dir(SYS_BLOCK_DIR).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to list directory '{}'", SYS_BLOCK_DIR),
+    ))?;
+
+    let mut emmc_drive = None;
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.starts_with("mmcblk") {
+            continue;
+        }
+
+        let type_path = path_append(entry.path(), "device/type");
+        match read_to_string(&type_path) {
+            Ok(mmc_type) => {
+                debug!(
+                    "check_emmc_boot_device: '{}' is type {}",
+                    name,
+                    mmc_type.trim()
+                );
+                if mmc_type.trim() == "MMC" {
+                    emmc_drive = Some(path_append("/dev", name.as_ref()));
+                }
+            }
+            Err(why) => warn!(
+                "Failed to read mmc type from '{}', error: {:?}",
+                type_path.display(),
+                why
+            ),
+        }
+    }
+
+    if let Some(emmc_drive) = emmc_drive {
+        if boot_drive == &emmc_drive {
+            info!("The device boots from eMMC '{}'", emmc_drive.display());
+            Ok(true)
+        } else {
+            error!(
+                "The device has an eMMC '{}' which it boots from, but the boot partition is on '{}'",
+                emmc_drive.display(),
+                boot_drive.display()
+            );
+            Ok(false)
+        }
+    } else {
+        info!(
+            "The device without eMMC boots from '{}'",
+            boot_drive.display()
+        );
+        Ok(true)
+    }
+}
+
+// GPT / UEFI images are installed to the drive holding the EFI system partition and the root
+// file system, the boot partition has to be on the same drive
+fn is_install_drive(root_path: &PathInfo, efi_path: &PathInfo, boot_path: &PathInfo) -> bool {
+    let root_drive = &root_path.device_info.drive;
+
+    if efi_path.device_info.drive != *root_drive {
+        error!(
+            "The EFI system partition '{}' is not on the root drive '{}', unable to determine the install drive",
+            efi_path.device_info.device.display(),
+            root_drive.display()
+        );
+        return false;
+    }
+
+    if boot_path.device_info.drive != *root_drive {
+        error!(
+            "The boot partition '{}' is not on the root drive '{}', unable to determine the install drive",
+            boot_path.device_info.device.display(),
+            root_drive.display()
+        );
+        return false;
+    }
+
+    true
+}
+
+// find the install drive and make sure that it is GPT partitioned
+fn check_install_disk(boot_path: &PathInfo) -> Result<bool, MigError> {
+    let lsblk_info = LsblkInfo::all()?;
+
+    let root_path = if let Some(root_path) = PathInfo::from_path(ROOT_PATH, &lsblk_info)? {
+        root_path
+    } else {
+        error!("Could not find root path '{}'", ROOT_PATH);
+        return Err(MigError::displayed());
+    };
+    let root_drive = &root_path.device_info.drive;
+
+    let efi_path = if let Some(efi_path) = EfiBootManager::find_efi_path(&lsblk_info, root_drive)? {
+        efi_path
+    } else {
+        error!("Could not find a mounted EFI system partition");
+        return Ok(false);
+    };
+
+    if !is_install_drive(&root_path, &efi_path, boot_path) {
+        return Ok(false);
+    }
+
+    match LabelType::from_device(root_drive)? {
+        LabelType::GPT => {
+            info!(
+                "The install drive '{}' holds the EFI system partition '{}' and the root file system",
+                root_drive.display(),
+                efi_path.device_info.device.display()
+            );
+            Ok(true)
+        }
+        label => {
+            error!(
+                "The install drive '{}' is not GPT partitioned, found {:?}",
+                root_drive.display(),
+                label
+            );
+            Ok(false)
+        }
+    }
+}
+
+// create the boot manager configured for the device and check that it can set up the device
+fn get_boot_manager(
+    device: &DeviceEntry,
+    mig_info: &MigrateInfo,
+    config: &Config,
+    s2_cfg: &mut Stage2ConfigBuilder,
+) -> Result<Box<dyn BootManager>, MigError> {
+    let mut boot_manager: Box<dyn BootManager> = match device.boot_manager {
+        BootManagerCfg::Raspi { ref dtb_files } => {
+            let boot_type = if let DeviceArch::AARCH64 = device.arch {
+                BootType::Raspi64
+            } else {
+                BootType::Raspi
+            };
+            Box::new(RaspiBootManager::new(boot_type, dtb_files.clone())?)
+        }
+        BootManagerCfg::UBoot {
+            mmc_index,
+            ref dtb_file,
+        } => {
+            let (mmc_index, strategy) = get_uboot_cfg(config, mig_info, mmc_index, s2_cfg)?;
+            info!(
+                "Using uboot device index: {}, strategy is {:?}",
+                mmc_index, strategy
+            );
+            Box::new(UBootManager::new(mmc_index, strategy, dtb_file.clone()))
+        }
+        BootManagerCfg::Grub { prefer_efi } => {
+            let secure_boot = is_secure_boot()?;
+            info!(
+                "Secure boot is {}enabled",
+                if secure_boot { "" } else { "not " }
+            );
+
+            if secure_boot {
+                let message =
+                    "balena-migrate does not currently support systems with secure boot enabled."
+                        .to_string();
+                error!("{}", &message);
+                return Err(MigError::from_remark(MigErrorKind::InvParam, &message));
+            }
+
+            // prefer a one-shot EFI boot entry, the firmware falls back to the old OS on failure
+            if prefer_efi && is_efi_boot()? {
+                let mut boot_manager = EfiBootManager::new(false);
+                if boot_manager.can_migrate(mig_info, config, s2_cfg)? {
+                    return Ok(Box::new(boot_manager));
+                }
+                warn!("The EFI boot manager is not able to set up your device, trying grub");
+            }
+
+            Box::new(GrubBootManager::new())
+        }
+    };
+
+    if boot_manager.can_migrate(mig_info, config, s2_cfg)? {
+        Ok(boot_manager)
+    } else {
+        let message = format!(
+            "The boot manager '{:?}' is not able to set up your device",
+            boot_manager.get_boot_type()
+        );
+        error!("{}", &message);
+        Err(MigError::from_remark(MigErrorKind::InvState, &message))
+    }
+}
+
+// A device described by its entry in the device catalog
+pub(crate) struct CatalogDevice {
+    device: DeviceEntry,
+    boot_manager: Box<dyn BootManager>,
+}
+
+impl CatalogDevice {
+    // this is used in stage1
+    pub fn from_config(
+        device: DeviceEntry,
+        mig_info: &MigrateInfo,
+        config: &Config,
+        s2_cfg: &mut Stage2ConfigBuilder,
+    ) -> Result<CatalogDevice, MigError> {
+        let os_ident = &mig_info.os_ident;
+
+        let (os_support, reason) = device.get_os_support(os_ident)?;
+        match os_support {
+            OsSupport::Supported => info!(
+                "The OS '{}' is supported on {}: {}",
+                os_ident, device.name, reason
+            ),
+            OsSupport::Untested => {
+                if config.migrate.allow_untested_os() {
+                    warn!(
+                        "The OS '{}' is untested on {}: {}, migrating anyway as allow_untested_os is set",
+                        os_ident, device.name, reason
+                    );
+                } else {
+                    error!(
+                        "The OS '{}' is untested on {}: {}, set allow_untested_os to migrate anyway",
+                        os_ident, device.name, reason
+                    );
+                    return Err(MigError::displayed());
+                }
+            }
+            OsSupport::Blocked => {
+                error!(
+                    "The OS '{}' can not be migrated on {}: {}",
+                    os_ident, device.name, reason
+                );
+                return Err(MigError::displayed());
+            }
+        }
+
+        let boot_manager = get_boot_manager(&device, mig_info, config, s2_cfg)?;
+
+        if let Some(true) = device.emmc_boot {
+            if !check_emmc_boot_device(&boot_manager.get_bootmgr_path())? {
+                return Err(MigError::displayed());
+            }
+        }
+
+        if let Some(true) = device.gpt_image {
+            if !check_install_disk(&boot_manager.get_bootmgr_path())? {
+                return Err(MigError::displayed());
+            }
+        }
+
+        Ok(CatalogDevice {
+            device,
+            boot_manager,
+        })
+    }
+
+    // this is used in stage2
+    pub fn from_boot_type(device: DeviceEntry, boot_type: BootType) -> CatalogDevice {
+        CatalogDevice {
+            device,
+            boot_manager: from_boot_type(boot_type),
+        }
+    }
+}
+
+impl Device for CatalogDevice {
+    fn get_device_slug(&self) -> &str {
+        &self.device.slug
+    }
+
+    fn get_device_entry(&self) -> &DeviceEntry {
+        &self.device
+    }
+
+    fn get_boot_type(&self) -> BootType {
+        self.boot_manager.get_boot_type()
+    }
+
+    fn setup(
+        &mut self,
+        mig_info: &mut MigrateInfo,
+        config: &Config,
+        s2_cfg: &mut Stage2ConfigBuilder,
+    ) -> Result<(), MigError> {
+        let mut kernel_opts = if let Some(ref kernel_opts) = config.migrate.get_kernel_opts() {
+            kernel_opts.clone()
+        } else {
+            String::from("")
+        };
+
+        let device_opts = self.device.get_kernel_opts();
+        if !device_opts.is_empty() {
+            if !kernel_opts.is_empty() {
+                kernel_opts.push(' ');
+            }
+            kernel_opts.push_str(device_opts);
+        }
+
+        if let BootManagerCfg::Raspi { .. } = self.device.boot_manager {
+            // collect the directives to carry over before the boot manager modifies config.txt
+            let rpi_config = config.migrate.get_rpi_config();
+            if !rpi_config.is_empty() {
+                let config_path =
+                    path_append(&self.boot_manager.get_bootmgr_path().path, RPI_CONFIG_TXT);
+                let lines = ConfigTxt::read(&config_path)?.collect(rpi_config);
+                info!(
+                    "Migrating {} lines from '{}'",
+                    lines.len(),
+                    config_path.display()
+                );
+                s2_cfg.set_rpi_config(lines);
+            }
+        }
+
+        self.boot_manager.setup(mig_info, s2_cfg, &kernel_opts)
+    }
+
+    fn restore_boot(&self, mounts: &Mounts, config: &Stage2Config) -> bool {
+        info!("restoring boot configuration for {}", self.device.name);
+        self.boot_manager.restore(mounts, config)
+    }
+
+    fn get_boot_device(&self) -> PathInfo {
+        self.boot_manager.get_bootmgr_path()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_install_drive;
+    use crate::common::{device_info::DeviceInfo, path_info::PathInfo};
+    use std::path::PathBuf;
+
+    fn path_info(path: &str, drive: &str, index: u16) -> PathInfo {
+        PathInfo {
+            device_info: DeviceInfo {
+                drive: PathBuf::from(drive),
+                drive_size: 0,
+                device: PathBuf::from(format!("{}p{}", drive, index)),
+                index,
+                fs_type: String::from("ext4"),
+                uuid: None,
+                part_uuid: None,
+                part_label: None,
+                part_size: 0,
+            },
+            path: PathBuf::from(path),
+            mountpoint: PathBuf::from(path),
+            fs_size: 0,
+            fs_free: 0,
+        }
+    }
+
+    #[test]
+    fn install_drive() {
+        let root = path_info("/", "/dev/nvme0n1", 2);
+        let efi = path_info("/boot/efi", "/dev/nvme0n1", 1);
+        assert!(is_install_drive(&root, &efi, &root));
+        assert!(is_install_drive(
+            &root,
+            &efi,
+            &path_info("/boot", "/dev/nvme0n1", 3)
+        ));
+
+        // EFI system partition or boot partition on a different drive
+        assert!(!is_install_drive(
+            &root,
+            &path_info("/boot/efi", "/dev/sda", 1),
+            &root
+        ));
+        assert!(!is_install_drive(
+            &root,
+            &efi,
+            &path_info("/boot", "/dev/sdb", 1)
+        ));
+    }
+}
@@ -0,0 +1,416 @@
+use failure::ResultExt;
+use log::{debug, info, trace, warn};
+use nix::mount::{mount, MsFlags};
+use regex::Regex;
+use std::fs::{read, read_dir, read_to_string, remove_file, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    common::{dir_exists, file_exists, path_append, MigErrCtx, MigError, MigErrorKind},
+    linux::linux_defs::{EFIVARS_DIR, NIX_NONE},
+};
+
+// ******************************************************************
+// Access to UEFI variables through efivarfs, replaces efibootmgr.
+// Each variable is a file named <name>-<vendor guid>, its content is a
+// 4 byte attribute field followed by the variable data.
+// Boot#### variables hold an EFI_LOAD_OPTION, BootNext selects the
+// load option used for the next boot only.
+// ******************************************************************
+
+pub const EFI_GLOBAL_VARIABLE: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+pub const EFI_SYSTEM_PART_GUID: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+pub const EFI_SYSTEM_PART_MBR: &str = "0xef";
+
+const EFI_VARIABLE_NON_VOLATILE: u32 = 0x1;
+const EFI_VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x2;
+const EFI_VARIABLE_RUNTIME_ACCESS: u32 = 0x4;
+const EFI_VARIABLE_DEFAULT_ATTRS: u32 =
+    EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS | EFI_VARIABLE_RUNTIME_ACCESS;
+
+const LOAD_OPTION_ACTIVE: u32 = 0x1;
+
+const BOOT_NEXT_VAR: &str = "BootNext";
+const BOOT_ENTRY_REGEX: &str = r#"^Boot([0-9A-F]{4})-8be4df61-93ca-11d2-aa0d-00e098032b8c$"#;
+
+const SYS_BLOCK_DIR: &str = "/sys/class/block";
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum PartSignature {
+    Mbr(u32),
+    Gpt([u8; 16]),
+}
+
+// hard drive media device path node, identifies the partition containing the loader
+#[derive(Debug, Clone)]
+pub(crate) struct HardDriveNode {
+    pub part_num: u32,
+    // start & size in logical blocks of the drive
+    pub part_start: u64,
+    pub part_size: u64,
+    pub signature: PartSignature,
+}
+
+impl HardDriveNode {
+    // create from partition device and PARTUUID, read start & size from sysfs
+    pub fn from_partition(device: &Path, part_uuid: &str) -> Result<HardDriveNode, MigError> {
+        trace!(
+            "from_partition: entered with '{}', partuuid: {}",
+            device.display(),
+            part_uuid
+        );
+
+        let part_name = if let Some(name) = device.file_name() {
+            name
+        } else {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("Invalid partition device '{}'", device.display()),
+            ));
+        };
+
+        let sys_part_dir = path_append(SYS_BLOCK_DIR, part_name);
+        let part_num = read_sys_num(&path_append(&sys_part_dir, "partition"))?;
+        // sysfs start & size are always in 512 byte sectors
+        let part_start = read_sys_num(&path_append(&sys_part_dir, "start"))?;
+        let part_size = read_sys_num(&path_append(&sys_part_dir, "size"))?;
+
+        let block_size = match sys_part_dir.canonicalize() {
+            Ok(sys_path) => {
+                if let Some(drive_dir) = sys_path.parent() {
+                    read_sys_num(&path_append(drive_dir, "queue/logical_block_size"))?
+                } else {
+                    512
+                }
+            }
+            Err(why) => {
+                return Err(MigError::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "Failed to canonicalize '{}', error: {:?}",
+                        sys_part_dir.display(),
+                        why
+                    ),
+                ));
+            }
+        };
+
+        let signature = if let Some(pos) = part_uuid.find('-') {
+            if part_uuid.len() == 36 {
+                PartSignature::Gpt(guid_to_bytes(part_uuid)?)
+            } else {
+                // MBR partuuid is <disk signature>-<partition number>
+                match u32::from_str_radix(&part_uuid[0..pos], 16) {
+                    Ok(sig) => PartSignature::Mbr(sig),
+                    Err(why) => {
+                        return Err(MigError::from_remark(
+                            MigErrorKind::InvParam,
+                            &format!(
+                                "Failed to parse disk signature from partuuid '{}', error: {:?}",
+                                part_uuid, why
+                            ),
+                        ));
+                    }
+                }
+            }
+        } else {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("Invalid partuuid '{}'", part_uuid),
+            ));
+        };
+
+        Ok(HardDriveNode {
+            part_num: part_num as u32,
+            part_start: part_start * 512 / block_size,
+            part_size: part_size * 512 / block_size,
+            signature,
+        })
+    }
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        // media device path, hard drive, length 42
+        buffer.extend_from_slice(&[0x04, 0x01, 42, 0]);
+        buffer.extend_from_slice(&self.part_num.to_le_bytes());
+        buffer.extend_from_slice(&self.part_start.to_le_bytes());
+        buffer.extend_from_slice(&self.part_size.to_le_bytes());
+        match self.signature {
+            PartSignature::Mbr(sig) => {
+                buffer.extend_from_slice(&sig.to_le_bytes());
+                buffer.extend_from_slice(&[0; 12]);
+                buffer.extend_from_slice(&[0x01, 0x01]);
+            }
+            PartSignature::Gpt(ref guid) => {
+                buffer.extend_from_slice(guid);
+                buffer.extend_from_slice(&[0x02, 0x02]);
+            }
+        }
+    }
+}
+
+fn read_sys_num(path: &Path) -> Result<u64, MigError> {
+    let content = read_to_string(path).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to read '{}'", path.display()),
+    ))?;
+    Ok(content
+        .trim()
+        .parse::<u64>()
+        .context(MigErrCtx::from_remark(
+            MigErrorKind::InvParam,
+            &format!("Failed to parse number from '{}'", path.display()),
+        ))?)
+}
+
+// convert a textual GUID to its mixed endian binary representation
+pub(crate) fn guid_to_bytes(guid: &str) -> Result<[u8; 16], MigError> {
+    let hex: String = guid.chars().filter(|c| *c != '-').collect();
+    if guid.len() != 36 || hex.len() != 32 {
+        return Err(MigError::from_remark(
+            MigErrorKind::InvParam,
+            &format!("Invalid GUID '{}'", guid),
+        ));
+    }
+
+    let mut bytes: [u8; 16] = [0; 16];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).context(
+            MigErrCtx::from_remark(MigErrorKind::InvParam, &format!("Invalid GUID '{}'", guid)),
+        )?;
+    }
+
+    // the first three fields are little endian
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    Ok(bytes)
+}
+
+fn to_ucs2(text: &str, buffer: &mut Vec<u8>) {
+    for unit in text.encode_utf16() {
+        buffer.extend_from_slice(&unit.to_le_bytes());
+    }
+    buffer.extend_from_slice(&[0, 0]);
+}
+
+// encode an EFI_LOAD_OPTION starting loader from the given partition, options are passed to the
+// loader as UCS-2 string
+pub(crate) fn encode_load_option(
+    description: &str,
+    hd_node: &HardDriveNode,
+    loader: &str,
+    options: &str,
+) -> Vec<u8> {
+    let mut file_path: Vec<u8> = Vec::new();
+    hd_node.encode(&mut file_path);
+
+    // media device path, file path
+    let mut loader_path: Vec<u8> = Vec::new();
+    to_ucs2(&loader.replace('/', "\\"), &mut loader_path);
+    file_path.extend_from_slice(&[0x04, 0x04]);
+    file_path.extend_from_slice(&(loader_path.len() as u16 + 4).to_le_bytes());
+    file_path.extend_from_slice(&loader_path);
+
+    // end of device path
+    file_path.extend_from_slice(&[0x7F, 0xFF, 0x04, 0x00]);
+
+    let mut buffer: Vec<u8> = Vec::new();
+    buffer.extend_from_slice(&LOAD_OPTION_ACTIVE.to_le_bytes());
+    buffer.extend_from_slice(&(file_path.len() as u16).to_le_bytes());
+    to_ucs2(description, &mut buffer);
+    buffer.extend_from_slice(&file_path);
+    if !options.is_empty() {
+        to_ucs2(options, &mut buffer);
+    }
+    buffer
+}
+
+fn get_var_path(name: &str) -> PathBuf {
+    path_append(EFIVARS_DIR, format!("{}-{}", name, EFI_GLOBAL_VARIABLE))
+}
+
+// make sure efivarfs is mounted, it might not be in the stage2 environment
+pub(crate) fn mount_efivars() -> Result<(), MigError> {
+    if dir_exists(EFIVARS_DIR)? {
+        let populated = match read_dir(EFIVARS_DIR) {
+            Ok(mut dir) => dir.next().is_some(),
+            Err(_) => false,
+        };
+        if populated {
+            return Ok(());
+        }
+    }
+
+    info!("mounting efivarfs on '{}'", EFIVARS_DIR);
+    mount(
+        Some("efivarfs"),
+        EFIVARS_DIR,
+        Some("efivarfs"),
+        MsFlags::empty(),
+        NIX_NONE,
+    )
+    .context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to mount efivarfs on '{}'", EFIVARS_DIR),
+    ))?;
+    Ok(())
+}
+
+pub(crate) fn read_var(name: &str) -> Result<Option<Vec<u8>>, MigError> {
+    let var_path = get_var_path(name);
+    if !file_exists(&var_path) {
+        return Ok(None);
+    }
+
+    let content = read(&var_path).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to read EFI variable '{}'", var_path.display()),
+    ))?;
+
+    if content.len() < 4 {
+        return Err(MigError::from_remark(
+            MigErrorKind::InvState,
+            &format!("Invalid EFI variable '{}'", var_path.display()),
+        ));
+    }
+
+    Ok(Some(content[4..].to_vec()))
+}
+
+pub(crate) fn write_var(name: &str, data: &[u8]) -> Result<(), MigError> {
+    let var_path = get_var_path(name);
+    debug!(
+        "write_var: writing {} bytes to '{}'",
+        data.len(),
+        var_path.display()
+    );
+
+    // efivarfs requires attributes & data in a single write
+    let mut buffer: Vec<u8> = Vec::from(&EFI_VARIABLE_DEFAULT_ATTRS.to_le_bytes()[..]);
+    buffer.extend_from_slice(data);
+
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&var_path)
+        .context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to open EFI variable '{}'", var_path.display()),
+        ))?
+        .write_all(&buffer)
+        .context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to write EFI variable '{}'", var_path.display()),
+        ))?;
+    Ok(())
+}
+
+pub(crate) fn delete_var(name: &str) -> Result<bool, MigError> {
+    let var_path = get_var_path(name);
+    if file_exists(&var_path) {
+        remove_file(&var_path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to delete EFI variable '{}'", var_path.display()),
+        ))?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+pub(crate) fn get_boot_entry_name(entry: u16) -> String {
+    format!("Boot{:04X}", entry)
+}
+
+// find the lowest Boot#### number not in use
+pub(crate) fn get_free_boot_entry() -> Result<u16, MigError> {
+    let entry_re = Regex::new(BOOT_ENTRY_REGEX).unwrap();
+    let mut used: Vec<u16> = Vec::new();
+    for dir_entry in read_dir(EFIVARS_DIR).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to list EFI variables in '{}'", EFIVARS_DIR),
+    ))? {
+        match dir_entry {
+            Ok(dir_entry) => {
+                if let Some(captures) = entry_re.captures(&dir_entry.file_name().to_string_lossy())
+                {
+                    used.push(u16::from_str_radix(captures.get(1).unwrap().as_str(), 16).unwrap());
+                }
+            }
+            Err(why) => warn!("Failed to read EFI variable directory entry: {:?}", why),
+        }
+    }
+
+    if let Some(entry) = (0..=0xFFFFu16).find(|num| !used.contains(num)) {
+        Ok(entry)
+    } else {
+        Err(MigError::from_remark(
+            MigErrorKind::InvState,
+            "No free EFI boot entry found",
+        ))
+    }
+}
+
+pub(crate) fn get_boot_next() -> Result<Option<u16>, MigError> {
+    if let Some(data) = read_var(BOOT_NEXT_VAR)? {
+        if data.len() == 2 {
+            return Ok(Some(u16::from_le_bytes([data[0], data[1]])));
+        }
+    }
+    Ok(None)
+}
+
+pub(crate) fn set_boot_next(entry: u16) -> Result<(), MigError> {
+    write_var(BOOT_NEXT_VAR, &entry.to_le_bytes())
+}
+
+pub(crate) fn delete_boot_next() -> Result<bool, MigError> {
+    delete_var(BOOT_NEXT_VAR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_load_option, guid_to_bytes, HardDriveNode, PartSignature};
+
+    #[test]
+    fn load_option() {
+        let guid = guid_to_bytes("c12a7328-f81f-11d2-ba4b-00a0c93ec93b").unwrap();
+        assert_eq!(
+            guid,
+            [
+                0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e,
+                0xc9, 0x3b
+            ]
+        );
+        assert!(guid_to_bytes("c12a7328-f81f-11d2-ba4b").is_err());
+
+        let hd_node = HardDriveNode {
+            part_num: 1,
+            part_start: 2048,
+            part_size: 1_048_576,
+            signature: PartSignature::Gpt(guid),
+        };
+
+        let option = encode_load_option("bm", &hd_node, "/EFI/b.efi", "");
+        // attributes, file path list length
+        assert_eq!(&option[0..4], &[1, 0, 0, 0]);
+        let path_len = u16::from_le_bytes([option[4], option[5]]) as usize;
+        // hd node + file path node with "\EFI\b.efi" + end node
+        assert_eq!(path_len, 42 + 4 + 11 * 2 + 4);
+        // description
+        assert_eq!(&option[6..12], &[b'b', 0, b'm', 0, 0, 0]);
+        let path = &option[12..];
+        assert_eq!(path.len(), path_len);
+        assert_eq!(&path[0..4], &[0x04, 0x01, 42, 0]);
+        assert_eq!(&path[8..16], &2048u64.to_le_bytes());
+        assert_eq!(&path[24..40], &guid);
+        assert_eq!(&path[42..46], &[0x04, 0x04, 26, 0]);
+        assert_eq!(&path[46..48], &[b'\\', 0]);
+        assert_eq!(&path[path_len - 4..], &[0x7F, 0xFF, 0x04, 0x00]);
+
+        let option = encode_load_option("bm", &hd_node, "/EFI/b.efi", "a");
+        assert_eq!(&option[12 + path_len..], &[b'a', 0, 0, 0]);
+    }
+}
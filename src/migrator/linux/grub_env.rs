@@ -0,0 +1,288 @@
+use failure::ResultExt;
+use log::{debug, trace, warn};
+use std::fs::{canonicalize, read_to_string, rename, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::common::{file_exists, MigErrCtx, MigError, MigErrorKind};
+
+// ******************************************************************
+// Native access to the grub environment block (grubenv), replaces
+// grub-editenv. The block is a file of exactly 1024 bytes starting
+// with a header line followed by 'name=value' lines, padded with '#'.
+// Backslashes and newlines in values are escaped with a backslash.
+// Like grub-editenv the block is written to a temporary file that
+// replaces grubenv once it is synced. If grubenv is a symlink, eg.
+// into the EFI partition on RHEL / CentOS, its target is replaced.
+// ******************************************************************
+
+const GRUBENV_SIZE: usize = 1024;
+const GRUBENV_HEADER: &str = "# GRUB Environment Block\n";
+const GRUBENV_TMP_EXT: &str = "new";
+
+#[derive(Debug)]
+pub(crate) struct GrubEnv {
+    path: PathBuf,
+    vars: Vec<(String, String)>,
+}
+
+impl GrubEnv {
+    // read the environment block, a missing file results in an empty environment
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<GrubEnv, MigError> {
+        let path = path.as_ref();
+        trace!("read: entered with '{}'", path.display());
+
+        let mut vars: Vec<(String, String)> = Vec::new();
+        if !file_exists(path) {
+            debug!(
+                "read: '{}' not found, using empty environment",
+                path.display()
+            );
+            return Ok(GrubEnv {
+                path: path.to_path_buf(),
+                vars,
+            });
+        }
+
+        let content = read_to_string(path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to read grub environment from '{}'", path.display()),
+        ))?;
+
+        if !content.starts_with(GRUBENV_HEADER) {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("Invalid grub environment block in '{}'", path.display()),
+            ));
+        }
+
+        let mut lines: Vec<String> = Vec::new();
+        let mut line = String::new();
+        let mut chars = content[GRUBENV_HEADER.len()..].chars();
+        while let Some(curr) = chars.next() {
+            match curr {
+                '\\' => {
+                    if let Some(next) = chars.next() {
+                        line.push(next);
+                    }
+                }
+                '\n' => {
+                    lines.push(line);
+                    line = String::new();
+                }
+                _ => line.push(curr),
+            }
+        }
+
+        for line in lines {
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+            if let Some(pos) = line.find('=') {
+                vars.push((String::from(&line[0..pos]), String::from(&line[pos + 1..])));
+            } else {
+                warn!("read: ignoring invalid grub env entry: '{}'", line);
+            }
+        }
+
+        Ok(GrubEnv {
+            path: path.to_path_buf(),
+            vars,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        if let Some((_, value)) = self.vars.iter().find(|(var, _)| var == name) {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        if let Some(entry) = self.vars.iter_mut().find(|(var, _)| var == name) {
+            entry.1 = String::from(value);
+        } else {
+            self.vars.push((String::from(name), String::from(value)));
+        }
+    }
+
+    // remove a variable, returns false if it was not set
+    pub fn unset(&mut self, name: &str) -> bool {
+        let count = self.vars.len();
+        self.vars.retain(|(var, _)| var != name);
+        self.vars.len() != count
+    }
+
+    pub fn write(&self) -> Result<(), MigError> {
+        let mut content = String::from(GRUBENV_HEADER);
+        for (name, value) in &self.vars {
+            content.push_str(name);
+            content.push('=');
+            for curr in value.chars() {
+                if curr == '\\' || curr == '\n' {
+                    content.push('\\');
+                }
+                content.push(curr);
+            }
+            content.push('\n');
+        }
+
+        if content.len() > GRUBENV_SIZE {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "The grub environment exceeds {} bytes, '{}'",
+                    GRUBENV_SIZE,
+                    self.path.display()
+                ),
+            ));
+        }
+
+        let mut buffer = content.into_bytes();
+        buffer.resize(GRUBENV_SIZE, b'#');
+
+        // replace the file grub reads, not the symlink pointing to it
+        let path = if self.path.symlink_metadata().is_ok() {
+            canonicalize(&self.path).context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to resolve path '{}'", self.path.display()),
+            ))?
+        } else {
+            self.path.clone()
+        };
+
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".");
+        tmp_path.push(GRUBENV_TMP_EXT);
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "Failed to open grub environment '{}' for writing",
+                    tmp_path.display()
+                ),
+            ))?;
+
+        file.write_all(&buffer).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "Failed to write grub environment to '{}'",
+                tmp_path.display()
+            ),
+        ))?;
+
+        file.sync_all().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to sync grub environment '{}'", tmp_path.display()),
+        ))?;
+
+        rename(&tmp_path, &path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "Failed to rename '{}' to '{}'",
+                tmp_path.display(),
+                path.display()
+            ),
+        ))?;
+
+        // make the rename persistent
+        if let Some(parent) = path.parent() {
+            if let Err(why) = File::open(parent).and_then(|dir| dir.sync_all()) {
+                warn!(
+                    "Failed to sync directory '{}', error: {:?}",
+                    parent.display(),
+                    why
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GrubEnv, GRUBENV_SIZE};
+    use std::fs::{create_dir_all, read, remove_dir_all, remove_file};
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn read_write_grubenv() {
+        let path = std::env::temp_dir().join("balena-migrate-test-grubenv");
+        let _res = remove_file(&path);
+
+        let mut env = GrubEnv::read(&path).unwrap();
+        assert_eq!(env.get("saved_entry"), None);
+        env.set("saved_entry", "Ubuntu");
+        env.set("kernelopts", "root=/dev/sda1 a\\b\nc");
+        env.write().unwrap();
+
+        let content = read(&path).unwrap();
+        assert_eq!(content.len(), GRUBENV_SIZE);
+        assert_eq!(content[GRUBENV_SIZE - 1], b'#');
+        // the temporary file has replaced grubenv
+        assert!(!std::env::temp_dir()
+            .join("balena-migrate-test-grubenv.new")
+            .exists());
+
+        let mut env = GrubEnv::read(&path).unwrap();
+        assert_eq!(env.get("saved_entry"), Some("Ubuntu"));
+        assert_eq!(env.get("kernelopts"), Some("root=/dev/sda1 a\\b\nc"));
+        env.set("next_entry", "balena-migrate");
+        env.write().unwrap();
+
+        let mut env = GrubEnv::read(&path).unwrap();
+        assert_eq!(env.get("next_entry"), Some("balena-migrate"));
+        assert_eq!(env.get("saved_entry"), Some("Ubuntu"));
+        assert!(env.unset("next_entry"));
+        assert!(!env.unset("next_entry"));
+        env.write().unwrap();
+
+        let env = GrubEnv::read(&path).unwrap();
+        assert_eq!(env.get("next_entry"), None);
+        assert_eq!(env.get("saved_entry"), Some("Ubuntu"));
+
+        let _res = remove_file(&path);
+    }
+    #[test]
+    fn write_symlinked_grubenv() {
+        let base_dir = std::env::temp_dir().join("balena-migrate-test-grubenv-link");
+        let _res = remove_dir_all(&base_dir);
+        let grub_dir = base_dir.join("grub2");
+        let efi_dir = base_dir.join("efi/EFI/centos");
+        create_dir_all(&grub_dir).unwrap();
+        create_dir_all(&efi_dir).unwrap();
+
+        let target = efi_dir.join("grubenv");
+        let mut env = GrubEnv::read(&target).unwrap();
+        env.set("saved_entry", "CentOS");
+        env.write().unwrap();
+
+        // relative link like /boot/grub2/grubenv -> ../efi/EFI/centos/grubenv
+        let link = grub_dir.join("grubenv");
+        symlink("../efi/EFI/centos/grubenv", &link).unwrap();
+
+        let mut env = GrubEnv::read(&link).unwrap();
+        assert_eq!(env.get("saved_entry"), Some("CentOS"));
+        env.set("next_entry", "balena-migrate");
+        env.write().unwrap();
+
+        // the link is kept, its target has been updated
+        assert!(link.symlink_metadata().unwrap().file_type().is_symlink());
+        assert!(!grub_dir.join("grubenv.new").exists());
+        assert!(!efi_dir.join("grubenv.new").exists());
+        let env = GrubEnv::read(&target).unwrap();
+        assert_eq!(env.get("next_entry"), Some("balena-migrate"));
+        assert_eq!(env.get("saved_entry"), Some("CentOS"));
+        assert_eq!(read(&link).unwrap().len(), GRUBENV_SIZE);
+
+        let _res = remove_dir_all(&base_dir);
+    }
+}
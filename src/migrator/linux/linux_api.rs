@@ -2,10 +2,17 @@ use log::error;
 use std::path::Path;
 
 use crate::{
-    common::{device_info::DeviceInfo, os_api::OSApi, path_info::PathInfo, MigError},
+    common::{
+        device_info::DeviceInfo,
+        file_type::{expect_type, is_file_type},
+        os_api::OSApi,
+        os_policy::OsIdent,
+        path_info::PathInfo,
+        MigError,
+    },
     defs::{FileType, OSArch},
     linux::{
-        linux_common::{expect_type, get_os_arch, get_os_name},
+        linux_common::{get_os_arch, get_os_ident},
         lsblk_info::LsblkInfo,
     },
 };
@@ -25,8 +32,8 @@ impl OSApi for LinuxAPI<'_> {
         get_os_arch()
     }
 
-    fn get_os_name(&self) -> Result<String, MigError> {
-        get_os_name()
+    fn get_os_ident(&self) -> Result<OsIdent, MigError> {
+        get_os_ident()
     }
 
     fn path_info_from_path<P: AsRef<Path>>(&self, path: P) -> Result<PathInfo, MigError> {
@@ -46,6 +53,10 @@ impl OSApi for LinuxAPI<'_> {
         Ok(DeviceInfo::new(drive, partition)?)
     }
 
+    fn is_file_type<P: AsRef<Path>>(&self, file: P, ftype: &FileType) -> Result<bool, MigError> {
+        is_file_type(file.as_ref(), ftype)
+    }
+
     fn expect_type<P: AsRef<Path>>(&self, file: P, ftype: &FileType) -> Result<(), MigError> {
         expect_type(file.as_ref(), ftype)
     }
@@ -3,18 +3,19 @@ use failure::ResultExt;
 use lazy_static::lazy_static;
 use log::{debug, error, info, trace, warn};
 use regex::{Regex, RegexBuilder};
-use std::fs::{copy, read_link, read_to_string};
+use std::fs::{copy, read_link, read_to_string, File};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
 use libc::getuid;
 
 use crate::{
-    common::{call, file_exists, parse_file, path_append, MigErrCtx, MigError, MigErrorKind},
-    defs::FileType,
+    common::{
+        call, file_exists, os_policy::OsIdent, path_append, MigErrCtx, MigError, MigErrorKind,
+    },
     defs::{OSArch, DISK_BY_LABEL_PATH, DISK_BY_PARTUUID_PATH, DISK_BY_UUID_PATH},
     linux::linux_defs::{
-        DF_CMD, FILE_CMD, KERNEL_CMDLINE_PATH, MKTEMP_CMD, MOKUTIL_CMD, SYS_UEFI_DIR, UNAME_CMD,
-        WHEREIS_CMD,
+        DF_CMD, KERNEL_CMDLINE_PATH, MKTEMP_CMD, MOKUTIL_CMD, SYS_UEFI_DIR, UNAME_CMD, WHEREIS_CMD,
     },
 };
 
@@ -27,27 +28,10 @@ const UNAME_ARGS_OS_ARCH: [&str; 1] = ["-m"];
 const BIN_DIRS: &[&str] = &["/bin", "/usr/bin", "/sbin", "/usr/sbin"];
 
 const OS_RELEASE_FILE: &str = "/etc/os-release";
-const OS_NAME_REGEX: &str = r#"^PRETTY_NAME="([^"]+)"$"#;
-
-// file on ubuntu-14.04 reports x86 boot sector for image and kernel files
-
-const OS_IMG_FTYPE_REGEX: &str = r#"^(DOS/MBR boot sector|x86 boot sector)$"#;
-const GZIP_OS_IMG_FTYPE_REGEX: &str =
-    r#"^(DOS/MBR boot sector|x86 boot sector).*\(gzip compressed data.*\)$"#;
-
-const INITRD_FTYPE_REGEX: &str = r#"^ASCII cpio archive.*\(gzip compressed data.*\)$"#;
-const OS_CFG_FTYPE_REGEX: &str = r#"^(ASCII text|JSON data).*$"#;
-const KERNEL_AMD64_FTYPE_REGEX: &str =
-    r#"^(Linux kernel x86 boot executable bzImage|x86 boot sector).*$"#;
-const KERNEL_ARMHF_FTYPE_REGEX: &str = r#"^Linux kernel ARM boot executable zImage.*$"#;
-//const KERNEL_I386_FTYPE_REGEX: &str = r#"^Linux kernel i386 boot executable bzImage.*$"#;
-const KERNEL_AARCH64_FTYPE_REGEX: &str = r#"^MS-DOS executable.*$"#;
 
-const TEXT_FTYPE_REGEX: &str = r#"^ASCII text.*$"#;
-
-const DTB_FTYPE_REGEX: &str = r#"^(Device Tree Blob|data).*$"#;
-
-const GZIP_TAR_FTYPE_REGEX: &str = r#"^(POSIX tar archive \(GNU\)).*\(gzip compressed data.*\)$"#;
+// the userland architecture is taken from the ELF header of this binary
+const USERLAND_BINARY: &str = "/bin/sh";
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
 
 pub(crate) fn is_admin() -> Result<bool, MigError> {
     trace!("LinuxMigrator::is_admin: entered");
@@ -124,6 +108,9 @@ pub(crate) fn get_os_arch() -> Result<OSArch, MigError> {
         } else if cmd_res.stdout.to_lowercase() == "armv7l" {
             // TODO: try to determine the CPU Architecture
             Ok(OSArch::ARMHF)
+        } else if cmd_res.stdout.to_lowercase() == "aarch64" {
+            // a 64 bit kernel might be running a 32 bit userland, eg. raspbian on a pi 4
+            get_arm_userland_arch()
         } else {
             Err(MigError::from_remark(
                 MigErrorKind::InvParam,
@@ -138,6 +125,55 @@ pub(crate) fn get_os_arch() -> Result<OSArch, MigError> {
     }
 }
 
+// the machine type of the ELF header: 0x28 ARM, 0xB7 AArch64
+fn get_elf_arch(header: &[u8]) -> Option<OSArch> {
+    if header.len() < 20 || header[0..4] != ELF_MAGIC[..] {
+        return None;
+    }
+
+    // EI_DATA: 1 little endian, 2 big endian
+    let machine = if header[5] == 2 {
+        u16::from_be_bytes([header[18], header[19]])
+    } else {
+        u16::from_le_bytes([header[18], header[19]])
+    };
+
+    match machine {
+        0x28 => Some(OSArch::ARMHF),
+        0xB7 => Some(OSArch::AARCH64),
+        _ => None,
+    }
+}
+
+fn get_arm_userland_arch() -> Result<OSArch, MigError> {
+    let mut header: [u8; 20] = [0; 20];
+    File::open(USERLAND_BINARY)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "get_arm_userland_arch: failed to read ELF header from '{}'",
+                USERLAND_BINARY
+            ),
+        ))?;
+
+    if let Some(os_arch) = get_elf_arch(&header) {
+        debug!(
+            "get_arm_userland_arch: '{}' is {}",
+            USERLAND_BINARY, os_arch
+        );
+        Ok(os_arch)
+    } else {
+        Err(MigError::from_remark(
+            MigErrorKind::InvParam,
+            &format!(
+                "get_arm_userland_arch: unsupported userland architecture in '{}'",
+                USERLAND_BINARY
+            ),
+        ))
+    }
+}
+
 pub(crate) fn get_mem_info() -> Result<(u64, u64), MigError> {
     trace!("get_mem_info: entered");
     // TODO: could add loads, uptime if needed
@@ -151,21 +187,19 @@ pub(crate) fn get_mem_info() -> Result<(u64, u64), MigError> {
     }
 }
 
-/*
 pub(crate) fn is_efi_boot() -> Result<bool, MigError> {
     trace!("is_efi_boot: entered");
     match std::fs::metadata(SYS_UEFI_DIR) {
         Ok(metadata) => Ok(metadata.file_type().is_dir()),
         Err(why) => match why.kind() {
             std::io::ErrorKind::NotFound => Ok(false),
-            _ => Err(MigError::from(why.context(MigErrCtx::from_remark(
+            _ => Err(MigError::from_remark(
                 MigErrorKind::Upstream,
-                &format!("is_uefi_boot: access {}", SYS_UEFI_DIR),
-            )))),
+                &format!("is_efi_boot: access {}, error: {:?}", SYS_UEFI_DIR, why),
+            )),
         },
     }
 }
-*/
 
 pub(crate) fn mktemp<P: AsRef<Path>>(
     dir: bool,
@@ -205,31 +239,24 @@ pub(crate) fn mktemp<P: AsRef<Path>>(
 }
 
 /******************************************************************
- * Get OS name from /etc/os-release
+ * Get OS identification from /etc/os-release
  ******************************************************************/
 
-pub(crate) fn get_os_name() -> Result<String, MigError> {
-    trace!("get_os_name: entered");
+pub(crate) fn get_os_ident() -> Result<OsIdent, MigError> {
+    trace!("get_os_ident: entered");
 
     // TODO: implement other source as fallback
 
     if file_exists(OS_RELEASE_FILE) {
-        // TODO: ensure availabilty of method / file exists
-        if let Some(os_name) = parse_file(OS_RELEASE_FILE, &Regex::new(OS_NAME_REGEX).unwrap())? {
-            Ok(os_name[1].clone())
-        } else {
-            Err(MigError::from_remark(
-                MigErrorKind::NotFound,
-                &format!(
-                    "get_os_name: could not be located in file {}",
-                    OS_RELEASE_FILE
-                ),
-            ))
-        }
+        let os_release = read_to_string(OS_RELEASE_FILE).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("get_os_ident: failed to read file {}", OS_RELEASE_FILE),
+        ))?;
+        Ok(OsIdent::from_os_release(&os_release))
     } else {
         Err(MigError::from_remark(
             MigErrorKind::NotFound,
-            &format!("get_os_name: could not locate file {}", OS_RELEASE_FILE),
+            &format!("get_os_ident: could not locate file {}", OS_RELEASE_FILE),
         ))
     }
 }
@@ -591,61 +618,22 @@ pub(crate) fn get_kernel_root_info() -> Result<(PathBuf, Option<String>), MigErr
     Ok((root_device, root_fs_type))
 }
 
-pub(crate) fn expect_type<P: AsRef<Path>>(file: P, ftype: &FileType) -> Result<(), MigError> {
-    if !is_file_type(file.as_ref(), ftype)? {
-        error!(
-            "Could not determine expected file type '{}' for file '{}'",
-            ftype.get_descr(),
-            file.as_ref().display()
-        );
-        Err(MigError::displayed())
-    } else {
-        Ok(())
-    }
-}
-
-pub(crate) fn is_file_type<P: AsRef<Path>>(file: P, ftype: &FileType) -> Result<bool, MigError> {
-    let path_str = file.as_ref().to_string_lossy();
-    let args: Vec<&str> = vec!["-bz", &path_str];
-
-    let cmd_res = call(FILE_CMD, &args, true)?;
-    if !cmd_res.status.success() || cmd_res.stdout.is_empty() {
-        return Err(MigError::from_remark(
-            MigErrorKind::InvParam,
-            &format!("new: failed determine type for file {}", path_str),
-        ));
-    }
-
-    lazy_static! {
-        static ref OS_IMG_FTYPE_RE: Regex = Regex::new(OS_IMG_FTYPE_REGEX).unwrap();
-        static ref GZIP_OS_IMG_FTYPE_RE: Regex = Regex::new(GZIP_OS_IMG_FTYPE_REGEX).unwrap();
-        static ref INITRD_FTYPE_RE: Regex = Regex::new(INITRD_FTYPE_REGEX).unwrap();
-        static ref OS_CFG_FTYPE_RE: Regex = Regex::new(OS_CFG_FTYPE_REGEX).unwrap();
-        static ref TEXT_FTYPE_RE: Regex = Regex::new(TEXT_FTYPE_REGEX).unwrap();
-        static ref KERNEL_AMD64_FTYPE_RE: Regex = Regex::new(KERNEL_AMD64_FTYPE_REGEX).unwrap();
-        static ref KERNEL_ARMHF_FTYPE_RE: Regex = Regex::new(KERNEL_ARMHF_FTYPE_REGEX).unwrap();
-        static ref KERNEL_AARCH64_FTYPE_RE: Regex = Regex::new(KERNEL_AARCH64_FTYPE_REGEX).unwrap();
-        //static ref KERNEL_I386_FTYPE_RE: Regex = Regex::new(KERNEL_I386_FTYPE_REGEX).unwrap();
-        static ref DTB_FTYPE_RE: Regex = Regex::new(DTB_FTYPE_REGEX).unwrap();
-        static ref GZIP_TAR_FTYPE_RE: Regex = Regex::new(GZIP_TAR_FTYPE_REGEX).unwrap();
-    }
-
-    debug!(
-        "FileInfo::is_type: looking for: {}, found {}",
-        ftype.get_descr(),
-        cmd_res.stdout
-    );
-    match ftype {
-        FileType::GZipOSImage => Ok(GZIP_OS_IMG_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::OSImage => Ok(OS_IMG_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::InitRD => Ok(INITRD_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::KernelARMHF => Ok(KERNEL_ARMHF_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::KernelAMD64 => Ok(KERNEL_AMD64_FTYPE_RE.is_match(&cmd_res.stdout)),
-        //FileType::KernelI386 => Ok(KERNEL_I386_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::KernelAARCH64 => Ok(KERNEL_AARCH64_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::Json => Ok(OS_CFG_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::Text => Ok(TEXT_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::DTB => Ok(DTB_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::GZipTar => Ok(GZIP_TAR_FTYPE_RE.is_match(&cmd_res.stdout)),
+#[cfg(test)]
+mod tests {
+    use super::get_elf_arch;
+    use crate::defs::OSArch;
+
+    #[test]
+    fn elf_arch() {
+        let mut header: [u8; 20] = [0; 20];
+        header[0..6].copy_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1]);
+        header[18] = 0xB7;
+        assert_eq!(get_elf_arch(&header), Some(OSArch::AARCH64));
+        header[4] = 1;
+        header[18] = 0x28;
+        assert_eq!(get_elf_arch(&header), Some(OSArch::ARMHF));
+        header[18] = 0x3E;
+        assert_eq!(get_elf_arch(&header), None);
+        assert_eq!(get_elf_arch(b"#!/bin/sh\necho"), None);
     }
 }
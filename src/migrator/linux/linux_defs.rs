@@ -4,19 +4,30 @@ pub const ROOT_PATH: &str = "/";
 
 pub const MIGRATE_LOG_FILE: &str = "migrate.log";
 
-pub const DEFAULT_UNAME_STR: &str = "4.1.4.53-balenaOS-2.38.0+rev1";
 pub const MLO_FILE_NAME: &str = "MLO";
 pub const UENV_FILE_NAME: &str = "uEnv.txt";
 pub const UBOOT_FILE_NAME: &str = "u-boot.img";
+pub const UBOOT_ENV_FILE_NAME: &str = "uboot.env";
+pub const UBOOT_REDUND_ENV_FILE_NAME: &str = "uboot-redund.env";
+pub const FW_ENV_CONFIG_PATH: &str = "/etc/fw_env.config";
 
 pub const KERNEL_CMDLINE_PATH: &str = "/proc/cmdline";
 pub const KERNEL_OSRELEASE_PATH: &str = "/proc/sys/kernel/osrelease";
+pub const DROP_CACHES_PATH: &str = "/proc/sys/vm/drop_caches";
 
 pub const GRUB_CONFIG_DIR: &str = "/etc/grub.d";
 pub const GRUB_CONFIG_FILE: &str = "/etc/grub.d/43_balena-migrate";
+pub const GRUB_DEFAULT_FILE: &str = "/etc/default/grub";
 pub const GRUB_MIN_VERSION: &str = "2";
+pub const GRUB_DIR: &str = "/boot/grub";
+pub const GRUB2_DIR: &str = "/boot/grub2";
+pub const GRUB_EFI_DIR: &str = "/boot/efi/EFI";
+pub const GRUB_CFG_NAME: &str = "grub.cfg";
+pub const GRUB_ENV_NAME: &str = "grubenv";
+pub const BLS_ENTRIES_DIR: &str = "/boot/loader/entries";
 
 pub const SYS_UEFI_DIR: &str = "/sys/firmware/efi";
+pub const EFIVARS_DIR: &str = "/sys/firmware/efi/efivars";
 
 pub const NIX_NONE: Option<&'static [u8]> = None;
 
@@ -30,11 +41,13 @@ pub const CHMOD_CMD: &str = "chmod";
 pub const DD_CMD: &str = "dd";
 pub const DF_CMD: &str = "df";
 pub const SFDISK_CMD: &str = "sfdisk";
-pub const FILE_CMD: &str = "file";
 pub const LSBLK_CMD: &str = "lsblk";
 // pub const BLKID_CMD: &str = "blkid";
 pub const GRUB_REBOOT_CMD: &str = "grub-reboot";
+pub const GRUB2_REBOOT_CMD: &str = "grub2-reboot";
 pub const GRUB_UPDT_CMD: &str = "update-grub";
+pub const GRUB_MKCONFIG_CMD: &str = "grub-mkconfig";
+pub const GRUB2_MKCONFIG_CMD: &str = "grub2-mkconfig";
 pub const GZIP_CMD: &str = "gzip";
 pub const MKTEMP_CMD: &str = "mktemp";
 pub const MOKUTIL_CMD: &str = "mokutil";
@@ -44,6 +57,9 @@ pub const LOSETUP_CMD: &str = "losetup";
 pub const PARTPROBE_CMD: &str = "partprobe";
 pub const REBOOT_CMD: &str = "reboot";
 pub const TAR_CMD: &str = "tar";
+pub const SYSTEMCTL_CMD: &str = "systemctl";
+pub const DOCKER_CMD: &str = "docker";
+pub const PODMAN_CMD: &str = "podman";
 pub const UDEVADM_CMD: &str = "udevadm";
 pub const UNAME_CMD: &str = "uname";
 pub const EXT_FMT_CMD: &str = "mkfs.ext4";
@@ -51,8 +67,8 @@ pub const FAT_FMT_CMD: &str = "mkfs.vfat";
 
 pub const FAT_CHK_CMD: &str = "fsck.vfat";
 
-// TODO: EFI support in Linux
-/*pub const BALENA_EFI_DIR: &str = r#"/EFI/balena-migrate"#;
+pub const BALENA_EFI_DIR: &str = r#"/EFI/balena-migrate"#;
+/*
 pub const EFI_DEFAULT_BOOTMGR32: &str = r#"/EFI/Boot/bootx32.efi"#;
 pub const EFI_DEFAULT_BOOTMGR64: &str = r#"/EFI/Boot/bootx64.efi"#;
 pub const EFI_BOOT_DIR: &str = r#"/EFI/Boot"#;
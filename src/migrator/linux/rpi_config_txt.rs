@@ -0,0 +1,590 @@
+use failure::ResultExt;
+use log::{debug, trace, warn};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{read_to_string, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    common::{file_exists, path_append, MigErrCtx, MigError, MigErrorKind},
+    defs::BALENA_FILE_TAG,
+};
+
+// ******************************************************************
+// A lossless model of the raspberry pi firmware config.txt.
+// Lines are kept as read, only directives that are disabled or added
+// are changed. Conditional sections ([pi4], [all], ..) are tracked and
+// include files are parsed as part of the config, as the firmware
+// processes them inline.
+// ******************************************************************
+
+pub(crate) const RPI_CONFIG_TXT: &str = "config.txt";
+
+// directives that take their value separated by whitespace instead of '='
+const SPACE_DIRECTIVES: &[&str] = &["initramfs", "include"];
+// the firmware does not nest includes deeper than this
+const MAX_INCLUDE_DEPTH: usize = 8;
+// selecting 'all' migrates all device tree overlays and parameters
+const RPI_CONFIG_ALL: &[&str] = &["dtoverlay", "dtparam"];
+
+#[derive(Debug)]
+enum ConfigLine {
+    // comments, blank lines and anything not understood, kept as is
+    Other(String),
+    Section {
+        raw: String,
+        filter: String,
+    },
+    Directive {
+        raw: String,
+        name: String,
+        value: String,
+    },
+    Include {
+        raw: String,
+        file: String,
+        config: Option<ConfigTxt>,
+    },
+}
+
+#[derive(Debug)]
+pub(crate) struct ConfigTxt {
+    path: PathBuf,
+    lines: Vec<ConfigLine>,
+    modified: bool,
+}
+
+impl ConfigTxt {
+    // read config.txt and the files it includes from the same directory
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<ConfigTxt, MigError> {
+        ConfigTxt::read_file(path.as_ref(), 0)
+    }
+
+    fn read_file(path: &Path, depth: usize) -> Result<ConfigTxt, MigError> {
+        trace!("read_file: entered with '{}'", path.display());
+        let content = read_to_string(path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to read '{}'", path.display()),
+        ))?;
+
+        let mut config = ConfigTxt::from_str(path, &content);
+
+        if depth < MAX_INCLUDE_DEPTH {
+            let base_dir = if let Some(parent) = path.parent() {
+                parent.to_path_buf()
+            } else {
+                PathBuf::from("/")
+            };
+
+            for line in &mut config.lines {
+                if let ConfigLine::Include {
+                    ref file,
+                    ref mut config,
+                    ..
+                } = line
+                {
+                    let include_path = path_append(&base_dir, file);
+                    if file_exists(&include_path) {
+                        *config = Some(ConfigTxt::read_file(&include_path, depth + 1)?);
+                    } else {
+                        debug!(
+                            "read_file: included file '{}' not found",
+                            include_path.display()
+                        );
+                    }
+                }
+            }
+        } else {
+            warn!(
+                "Not following includes in '{}', too deeply nested",
+                path.display()
+            );
+        }
+
+        Ok(config)
+    }
+
+    pub fn from_str<P: AsRef<Path>>(path: P, content: &str) -> ConfigTxt {
+        let lines = content.lines().map(ConfigTxt::parse_line).collect();
+
+        ConfigTxt {
+            path: path.as_ref().to_path_buf(),
+            lines,
+            modified: false,
+        }
+    }
+
+    fn parse_line(line: &str) -> ConfigLine {
+        let raw = String::from(line);
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return ConfigLine::Other(raw);
+        }
+
+        if trimmed.starts_with('[') {
+            if let Some(end) = trimmed.find(']') {
+                let filter = String::from(trimmed[1..end].trim());
+                return ConfigLine::Section { raw, filter };
+            }
+            return ConfigLine::Other(raw);
+        }
+
+        let name_end = trimmed
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(trimmed.len());
+        let name = String::from(&trimmed[0..name_end]);
+        let value = String::from(
+            trimmed[name_end..]
+                .trim_start()
+                .trim_start_matches('=')
+                .trim(),
+        );
+
+        if name == "include" {
+            ConfigLine::Include {
+                raw,
+                file: value,
+                config: None,
+            }
+        } else {
+            ConfigLine::Directive { raw, name, value }
+        }
+    }
+
+    // all directives in the order the firmware reads them, regardless of sections
+    pub fn get_directives(&self) -> Vec<(&str, &str)> {
+        let mut values: Vec<(&str, &str)> = Vec::new();
+        for line in &self.lines {
+            match line {
+                ConfigLine::Directive {
+                    ref name,
+                    ref value,
+                    ..
+                } => values.push((name, value)),
+                ConfigLine::Include {
+                    config: Some(ref config),
+                    ..
+                } => values.append(&mut config.get_directives()),
+                _ => (),
+            }
+        }
+        values
+    }
+
+    // the last value set for name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_directives()
+            .iter()
+            .rev()
+            .find(|(curr, _)| *curr == name)
+            .map(|(_, value)| *value)
+    }
+
+    // the section filter in effect at the end of the file, includes can change it
+    fn get_end_filter(&self) -> Option<&str> {
+        let mut filter: Option<&str> = None;
+        for line in &self.lines {
+            match line {
+                ConfigLine::Section {
+                    filter: ref curr, ..
+                } => filter = Some(curr),
+                ConfigLine::Include {
+                    config: Some(ref config),
+                    ..
+                } => {
+                    if let Some(curr) = config.get_end_filter() {
+                        filter = Some(curr)
+                    }
+                }
+                _ => (),
+            }
+        }
+        filter
+    }
+
+    // collect the selected directives in the order the firmware reads them, each preceded by
+    // the section filters it appears under, so they can be merged into another config.txt.
+    // A selection is a directive name, 'name=value' or 'all' for overlays and parameters
+    pub fn collect(&self, selection: &[String]) -> Vec<String> {
+        let mut lines: Vec<String> = Vec::new();
+        let mut curr_filters: Vec<String> = Vec::new();
+        let mut out_filters: Vec<String> = Vec::new();
+        self.collect_into(selection, &mut curr_filters, &mut out_filters, &mut lines);
+        if !out_filters.is_empty() {
+            lines.push(String::from("[all]"));
+        }
+        lines
+    }
+
+    fn collect_into(
+        &self,
+        selection: &[String],
+        curr_filters: &mut Vec<String>,
+        out_filters: &mut Vec<String>,
+        lines: &mut Vec<String>,
+    ) {
+        for line in &self.lines {
+            match line {
+                // filters accumulate until [all] resets them
+                ConfigLine::Section { ref filter, .. } => {
+                    if filter == "all" {
+                        curr_filters.clear();
+                    } else {
+                        curr_filters.push(filter.clone());
+                    }
+                }
+                ConfigLine::Directive {
+                    ref name,
+                    ref value,
+                    ..
+                } if is_selected(selection, name, value) => {
+                    if curr_filters != out_filters {
+                        if !out_filters.is_empty() {
+                            lines.push(String::from("[all]"));
+                        }
+                        for filter in curr_filters.iter() {
+                            lines.push(format!("[{}]", filter));
+                        }
+                        *out_filters = curr_filters.clone();
+                    }
+                    lines.push(format!("{}={}", name, value));
+                }
+                ConfigLine::Include {
+                    config: Some(ref config),
+                    ..
+                } => config.collect_into(selection, curr_filters, out_filters, lines),
+                _ => (),
+            }
+        }
+    }
+
+    // directives as 'name=value' that apply to all models, at top level or in [all] sections
+    fn get_unconditional(&self, conditional: &mut bool, directives: &mut Vec<String>) {
+        for line in &self.lines {
+            match line {
+                ConfigLine::Section { ref filter, .. } => *conditional = filter != "all",
+                ConfigLine::Directive {
+                    ref name,
+                    ref value,
+                    ..
+                } if !*conditional => directives.push(format!("{}={}", name, value)),
+                ConfigLine::Include {
+                    config: Some(ref config),
+                    ..
+                } => config.get_unconditional(conditional, directives),
+                _ => (),
+            }
+        }
+    }
+
+    // append lines created by collect, unconditional directives that are already present
+    // unconditionally are skipped. Returns the number of directives added
+    pub fn merge(&mut self, lines: &[String]) -> usize {
+        let mut existing: Vec<String> = Vec::new();
+        self.get_unconditional(&mut false, &mut existing);
+
+        let mut merged: Vec<ConfigLine> = Vec::new();
+        let mut conditional = false;
+        let mut count = 0;
+
+        for line in lines {
+            match ConfigTxt::parse_line(line) {
+                ConfigLine::Section { raw, filter } => {
+                    conditional = filter != "all";
+                    merged.push(ConfigLine::Section { raw, filter });
+                }
+                ConfigLine::Directive { raw, name, value } => {
+                    if conditional || !existing.contains(&format!("{}={}", name, value)) {
+                        merged.push(ConfigLine::Directive { raw, name, value });
+                        count += 1;
+                    } else {
+                        debug!("merge: skipping existing directive '{}'", raw);
+                    }
+                }
+                _ => warn!("merge: ignoring unexpected line '{}'", line),
+            }
+        }
+
+        if count > 0 {
+            self.open_all_section();
+            self.lines.append(&mut merged);
+            self.modified = true;
+        }
+
+        count
+    }
+
+    // comment out all occurrences of the given directives, in included files too
+    // returns the number of lines disabled
+    pub fn disable(&mut self, names: &[&str]) -> usize {
+        let mut count = 0;
+        for line in &mut self.lines {
+            let disabled = match line {
+                ConfigLine::Directive {
+                    ref raw, ref name, ..
+                } => {
+                    if names.contains(&name.as_str()) {
+                        Some(format!("# {}", raw))
+                    } else {
+                        None
+                    }
+                }
+                ConfigLine::Include {
+                    config: Some(ref mut config),
+                    ..
+                } => {
+                    count += config.disable(names);
+                    None
+                }
+                _ => None,
+            };
+
+            if let Some(disabled) = disabled {
+                debug!("disable: '{}' in '{}'", disabled, self.path.display());
+                *line = ConfigLine::Other(disabled);
+                self.modified = true;
+                count += 1;
+            }
+        }
+        count
+    }
+
+    // append a directive so it applies to all models, opening an [all] section if required
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.open_all_section();
+
+        let raw = if SPACE_DIRECTIVES.contains(&name) {
+            format!("{} {}", name, value)
+        } else {
+            format!("{}={}", name, value)
+        };
+
+        self.lines.push(ConfigLine::Directive {
+            raw,
+            name: String::from(name),
+            value: String::from(value),
+        });
+        self.modified = true;
+    }
+
+    fn open_all_section(&mut self) {
+        match self.get_end_filter() {
+            None => (),
+            Some("all") => (),
+            Some(_) => self.lines.push(ConfigLine::Section {
+                raw: String::from("[all]"),
+                filter: String::from("all"),
+            }),
+        }
+    }
+
+    // mark the file as created by balena-migrate
+    pub fn add_tag(&mut self) {
+        self.lines
+            .insert(0, ConfigLine::Other(String::from(BALENA_FILE_TAG)));
+        self.modified = true;
+    }
+
+    // the included files that have been modified and will be written
+    pub fn get_modified_includes(&self) -> Vec<&Path> {
+        let mut modified: Vec<&Path> = Vec::new();
+        for line in &self.lines {
+            if let ConfigLine::Include {
+                config: Some(ref config),
+                ..
+            } = line
+            {
+                if config.modified {
+                    modified.push(&config.path);
+                }
+                modified.append(&mut config.get_modified_includes());
+            }
+        }
+        modified
+    }
+
+    // write the file and all modified includes
+    pub fn write(&self) -> Result<(), MigError> {
+        for line in &self.lines {
+            if let ConfigLine::Include {
+                config: Some(ref config),
+                ..
+            } = line
+            {
+                config.write()?;
+            }
+        }
+
+        if !self.modified {
+            return Ok(());
+        }
+
+        let mut file = File::create(&self.path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to open file '{}' for writing", self.path.display()),
+        ))?;
+
+        file.write_all(self.to_string().as_bytes())
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed write to file '{}'", self.path.display()),
+            ))?;
+
+        Ok(())
+    }
+}
+
+fn is_selected(selection: &[String], name: &str, value: &str) -> bool {
+    selection.iter().any(|selected| {
+        if selected == "all" {
+            RPI_CONFIG_ALL.contains(&name)
+        } else if let Some(pos) = selected.find('=') {
+            // dtoverlay=w1-gpio also selects dtoverlay=w1-gpio,gpiopin=4
+            name == &selected[..pos]
+                && (value == &selected[pos + 1..]
+                    || value.starts_with(&format!("{},", &selected[pos + 1..])))
+        } else {
+            name == selected
+        }
+    })
+}
+
+impl Display for ConfigTxt {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for line in &self.lines {
+            match line {
+                ConfigLine::Other(ref raw)
+                | ConfigLine::Section { ref raw, .. }
+                | ConfigLine::Directive { ref raw, .. }
+                | ConfigLine::Include { ref raw, .. } => writeln!(f, "{}", raw)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigTxt;
+    use std::fs::{create_dir_all, read_to_string, remove_dir_all, write};
+
+    #[test]
+    fn edit_config_txt() {
+        let dir = std::env::temp_dir().join("balena-migrate-test-config-txt");
+        let _res = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+
+        write(
+            dir.join("config.txt"),
+            "# comment\n\
+             dtoverlay=vc4-kms-v3d\n\
+             os_prefix=current/\n\
+             include syscfg.txt\n\
+             initramfs initrd.img followkernel\n",
+        )
+        .unwrap();
+        write(
+            dir.join("syscfg.txt"),
+            "[pi4]\n\
+             kernel=uboot_rpi_4.bin\n\
+             cmdline=nobtcmd.txt\n",
+        )
+        .unwrap();
+
+        let mut config = ConfigTxt::read(dir.join("config.txt")).unwrap();
+        assert_eq!(config.get("cmdline"), Some("nobtcmd.txt"));
+        assert_eq!(config.get("initramfs"), Some("initrd.img followkernel"));
+
+        assert_eq!(config.disable(&["kernel", "initramfs", "os_prefix"]), 3);
+        config.add_tag();
+        config.append("kernel", "balena.zImage");
+        config.append("initramfs", "balena.initramfs.cpio.gz followkernel");
+        assert_eq!(config.get_modified_includes(), vec![dir.join("syscfg.txt")]);
+        config.write().unwrap();
+
+        assert_eq!(
+            read_to_string(dir.join("config.txt")).unwrap(),
+            "## created by balena-migrate\n\
+             # comment\n\
+             dtoverlay=vc4-kms-v3d\n\
+             # os_prefix=current/\n\
+             include syscfg.txt\n\
+             # initramfs initrd.img followkernel\n\
+             [all]\n\
+             kernel=balena.zImage\n\
+             initramfs balena.initramfs.cpio.gz followkernel\n"
+        );
+        assert_eq!(
+            read_to_string(dir.join("syscfg.txt")).unwrap(),
+            "[pi4]\n\
+             # kernel=uboot_rpi_4.bin\n\
+             cmdline=nobtcmd.txt\n"
+        );
+
+        let _res = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn collect_and_merge() {
+        let config = ConfigTxt::from_str(
+            "config.txt",
+            "dtparam=i2c_arm=on\n\
+             dtparam=audio=on\n\
+             gpu_mem=128\n\
+             [pi4]\n\
+             dtoverlay=vc4-kms-v3d\n\
+             [all]\n\
+             dtoverlay=w1-gpio,gpiopin=4\n\
+             dtoverlay=disable-bt\n",
+        );
+
+        let lines = config.collect(&[String::from("all")]);
+        assert_eq!(
+            lines,
+            vec![
+                "dtparam=i2c_arm=on",
+                "dtparam=audio=on",
+                "[pi4]",
+                "dtoverlay=vc4-kms-v3d",
+                "[all]",
+                "dtoverlay=w1-gpio,gpiopin=4",
+                "dtoverlay=disable-bt",
+            ]
+        );
+
+        assert_eq!(
+            config.collect(&[String::from("gpu_mem"), String::from("dtoverlay=w1-gpio")]),
+            vec!["gpu_mem=128", "dtoverlay=w1-gpio,gpiopin=4"]
+        );
+
+        // i2c is only enabled for the pi4, it still has to be merged
+        let mut balena_config = ConfigTxt::from_str(
+            "config.txt",
+            "[pi4]\n\
+             arm_64bit=1\n\
+             dtparam=i2c_arm=on\n\
+             [all]\n\
+             dtparam=audio=on\n\
+             [none]\n",
+        );
+
+        assert_eq!(balena_config.merge(&lines), 4);
+        assert_eq!(
+            balena_config.to_string(),
+            "[pi4]\n\
+             arm_64bit=1\n\
+             dtparam=i2c_arm=on\n\
+             [all]\n\
+             dtparam=audio=on\n\
+             [none]\n\
+             [all]\n\
+             dtparam=i2c_arm=on\n\
+             [pi4]\n\
+             dtoverlay=vc4-kms-v3d\n\
+             [all]\n\
+             dtoverlay=w1-gpio,gpiopin=4\n\
+             dtoverlay=disable-bt\n"
+        );
+    }
+}
@@ -11,6 +11,7 @@ use std::time::{Duration, Instant};
 
 use crate::{
     common::{
+        backup::verify_backup,
         call, dir_exists,
         file_digest::check_digest,
         file_exists,
@@ -25,6 +26,7 @@ use crate::{
         linux_common::{get_mem_info, whereis},
         linux_defs::{KERNEL_OSRELEASE_PATH, REBOOT_CMD},
         linux_defs::{MIGRATE_LOG_FILE, STAGE2_MEM_THRESHOLD},
+        rpi_config_txt::{ConfigTxt, RPI_CONFIG_TXT},
     },
 };
 
@@ -56,6 +58,7 @@ const MIGRATE_TEMP_DIR: &str = "/migrate_tmp";
 //const MIG_REQUIRED_CMDS: &[&str] = &[REBOOT_CMD, UDEVADM_CMD, FAT_CHK_CMD];
 
 const BALENA_IMAGE_FILE: &str = "balenaOS.img.gz";
+const BALENA_BMAP_FILE: &str = "balenaOS.bmap";
 const BALENA_CONFIG_FILE: &str = "config.json";
 
 const BALENA_BOOT_FS_FILE: &str = "resin-boot.tgz";
@@ -106,11 +109,13 @@ impl<'a> Stage2 {
             }
         }
 
-        if let Ok(krelease) = read_to_string(KERNEL_OSRELEASE_PATH) {
+        let krelease = if let Ok(krelease) = read_to_string(KERNEL_OSRELEASE_PATH) {
             info!("Running stage2 on kernel version: '{}'", krelease.trim());
+            Some(String::from(krelease.trim()))
         } else {
             warn!("Failed to retrieve kernel release");
-        }
+            None
+        };
 
         // TODO: create replacement for ensured commands
 
@@ -152,6 +157,16 @@ impl<'a> Stage2 {
             }
         };
 
+        // the migrate kernel release was taken from the kernel image in stage1
+        if let (Some(krelease), Some(expected)) = (&krelease, stage2_cfg.get_kernel_release()) {
+            if krelease != expected {
+                warn!(
+                    "The running kernel release '{}' differs from the migrate kernel release '{}'",
+                    krelease, expected
+                );
+            }
+        }
+
         if let Some(device) = stage2_cfg.get_force_flash_device() {
             if device != mounts.get_flash_device() {
                 warn!("Forcibly setting flash device to '{}'", device.display());
@@ -222,7 +237,7 @@ impl<'a> Stage2 {
     pub fn migrate(&mut self) -> Result<(), MigError> {
         trace!("migrate: entered");
 
-        let device_type = self.config.get_device_type();
+        let device = self.config.get_device();
         let boot_type = self.config.get_boot_type();
 
         // Recover device type and restore original boot configuration
@@ -259,7 +274,7 @@ impl<'a> Stage2 {
             info!("Done waiting, continuing now");
         }
 
-        let device = device_impl::from_config(*device_type, *boot_type)?;
+        let device = device_impl::from_config(device, *boot_type)?;
         if device.restore_boot(&self.mounts.borrow(), &self.config) {
             info!("Boot configuration was restored sucessfully");
             // boot config restored can reboot
@@ -272,7 +287,11 @@ impl<'a> Stage2 {
         // TODO: debug, remove this
         thread::sleep(Duration::from_secs(3));
 
-        info!("migrating {:?} boot type: {:?}", device_type, &boot_type);
+        info!(
+            "migrating {} boot type: {:?}",
+            self.config.get_device().name,
+            &boot_type
+        );
 
         let work_path = if let Some(work_path) = self.mounts.borrow().get_work_path() {
             work_path.to_path_buf()
@@ -347,7 +366,8 @@ impl<'a> Stage2 {
             }
 
             match self.config.get_balena_image() {
-                CheckedImageType::Flasher(ref image_file) => {
+                CheckedImageType::Flasher(ref flasher_img) => {
+                    let image_file = &flasher_img.image;
                     let src = path_append(&work_path, &image_file.rel_path);
                     let tgt = path_append(mig_tmp_dir, BALENA_IMAGE_FILE);
                     copy(&src, &tgt).context(MigErrCtx::from_remark(
@@ -372,6 +392,16 @@ impl<'a> Stage2 {
 
                     info!("copied balena OS image to '{}'", tgt.display());
                     // check digest
+
+                    if let Some(ref bmap_file) = flasher_img.bmap {
+                        self.copy_and_check(
+                            &work_path,
+                            bmap_file,
+                            mig_tmp_dir,
+                            "bmap",
+                            BALENA_BMAP_FILE,
+                        )?;
+                    }
                 }
                 CheckedImageType::FileSystems(ref fs_dump) => {
                     self.copy_and_check(
@@ -480,6 +510,7 @@ impl<'a> Stage2 {
                     ),
                 ))?;
                 info!("copied backup  to '{}'", target_path.display());
+                self.verify_backup(&target_path)?;
             }
 
             info!("Files copied to RAMFS");
@@ -487,7 +518,9 @@ impl<'a> Stage2 {
         } else {
             info!("Files were not copied, work dir is on a separate drive");
             // TODO: adapt path for no copy mode
-            // TODO: check digest anyway ?
+            if self.config.has_backup() {
+                self.verify_backup(&path_append(&work_path, BACKUP_FILE))?;
+            }
             &work_path
         };
 
@@ -537,18 +570,36 @@ impl<'a> Stage2 {
         // Call external script
 
         match self.config.get_balena_image() {
-            CheckedImageType::Flasher(ref image_file) => {
+            CheckedImageType::Flasher(ref flasher_img) => {
                 // TODO: move some, if not most of this into flasher
 
-                let image_path = if self.mounts.borrow().is_work_no_copy() {
+                let (image_path, bmap_path) = if self.mounts.borrow().is_work_no_copy() {
                     if let Some(work_dir) = self.mounts.borrow().get_work_path() {
-                        path_append(work_dir, &image_file.rel_path)
+                        (
+                            path_append(work_dir, &flasher_img.image.rel_path),
+                            flasher_img
+                                .bmap
+                                .as_ref()
+                                .map(|bmap| path_append(work_dir, &bmap.rel_path)),
+                        )
                     } else {
                         warn!("Work path not found in no_copy mode, trying mig temp");
-                        path_append(mig_tmp_dir, BALENA_IMAGE_FILE)
+                        (
+                            path_append(mig_tmp_dir, BALENA_IMAGE_FILE),
+                            flasher_img
+                                .bmap
+                                .as_ref()
+                                .map(|_| path_append(mig_tmp_dir, BALENA_BMAP_FILE)),
+                        )
                     }
                 } else {
-                    path_append(mig_tmp_dir, BALENA_IMAGE_FILE)
+                    (
+                        path_append(mig_tmp_dir, BALENA_IMAGE_FILE),
+                        flasher_img
+                            .bmap
+                            .as_ref()
+                            .map(|_| path_append(mig_tmp_dir, BALENA_BMAP_FILE)),
+                    )
                 };
 
                 info!(
@@ -569,6 +620,7 @@ impl<'a> Stage2 {
                     &mut self.mounts.borrow_mut(),
                     &self.config,
                     &image_path,
+                    bmap_path.as_deref(),
                 ) {
                     FlashResult::Ok => {}
                     FlashResult::FailRecoverable => {
@@ -648,6 +700,35 @@ impl<'a> Stage2 {
 
         info!("copied balena OS config to '{}'", tgt.display());
 
+        let rpi_config = self.config.get_rpi_config();
+        if !rpi_config.is_empty() {
+            // not fatal, the device will come up without the carried over hardware config
+            let config_path = path_append(&boot_mountpoint, RPI_CONFIG_TXT);
+            match ConfigTxt::read(&config_path) {
+                Ok(mut config_txt) => {
+                    let count = config_txt.merge(rpi_config);
+                    if let Err(why) = config_txt.write() {
+                        warn!(
+                            "Failed to write '{}', error: {:?}",
+                            config_path.display(),
+                            why
+                        );
+                    } else {
+                        info!(
+                            "merged {} former config.txt directives into '{}'",
+                            count,
+                            config_path.display()
+                        );
+                    }
+                }
+                Err(why) => warn!(
+                    "Failed to read '{}', error: {:?}",
+                    config_path.display(),
+                    why
+                ),
+            }
+        }
+
         // copy system connections
         let nwmgr_dir = path_append(mig_tmp_dir, SYSTEM_CONNECTIONS_DIR);
         if dir_exists(&nwmgr_dir)? {
@@ -694,6 +775,7 @@ impl<'a> Stage2 {
                     ),
                 ))?;
                 info!("copied backup  to '{}'", target_path.display());
+                self.verify_backup(&target_path)?;
             }
 
             if Logger::get_log_dest().is_buffer_dest() {
@@ -782,6 +864,20 @@ impl<'a> Stage2 {
         }
     }
 
+    // a corrupt backup blocks the migration
+    fn verify_backup(&self, path: &Path) -> Result<(), MigError> {
+        if let Some(digest) = self.config.get_backup_digest() {
+            info!("Verifying backup '{}' - {:?}", path.display(), digest);
+            verify_backup(path, digest)
+        } else {
+            error!(
+                "No digest was recorded for the backup, unable to verify '{}'",
+                path.display()
+            );
+            Err(MigError::displayed())
+        }
+    }
+
     fn copy_and_check(
         &self,
         source_dir: &Path,
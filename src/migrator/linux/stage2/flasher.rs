@@ -1,11 +1,12 @@
 // TODO: flash image using DD
 
-use flate2::read::GzDecoder;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use mod_logger::Logger;
 use nix::unistd::sync;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use std::cmp::{max, min};
+use std::fs::{write, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::process::{Command, Stdio};
 use std::str;
@@ -13,9 +14,15 @@ use std::thread;
 use std::time::{Duration, Instant};
 
 use crate::{
-    common::{call, format_size_with_unit, stage2_config::Stage2Config},
+    common::{
+        bmap::{BlockMap, ChecksumType, RangeHasher},
+        call,
+        disk_util::ImageFormat,
+        format_size_with_unit,
+        stage2_config::Stage2Config,
+    },
     linux::{
-        linux_defs::{DD_CMD, GZIP_CMD, PARTPROBE_CMD, UDEVADM_CMD},
+        linux_defs::{DD_CMD, DROP_CACHES_PATH, GZIP_CMD, PARTPROBE_CMD, UDEVADM_CMD},
         linux_defs::{POST_PARTPROBE_WAIT_SECS, PRE_PARTPROBE_WAIT_SECS},
         stage2::{mounts::Mounts, FlashResult},
     },
@@ -23,8 +30,20 @@ use crate::{
 
 // TODO: minimum recommended size 128K
 const DD_BLOCK_SIZE: usize = 128 * 1024; // 4_194_304;
+
+// granularity of zero block detection when flashing without block map
+const ZERO_BLOCK_SIZE: usize = 4096;
 const UDEVADM_PARAMS: &[&str] = &["settle", "-t", "10"];
 
+// BLKZEROOUT, zeroes the byte range [offset, length] of a block device
+const BLK_IOC_MAGIC: u8 = 0x12;
+const BLK_IOC_ZEROOUT: u8 = 127;
+ioctl_write_ptr_bad!(
+    blk_zero_out,
+    request_code_none!(BLK_IOC_MAGIC, BLK_IOC_ZEROOUT),
+    [u64; 2]
+);
+
 // TODO: replace removed command checks ?
 //const REQUIRED_CMDS: &[&str] = &[DD_CMD, PARTPROBE_CMD, UDEVADM_CMD];
 
@@ -35,9 +54,63 @@ pub(crate) fn flash_balena_os(
     mounts: &mut Mounts,
     config: &Stage2Config,
     image_path: &Path,
+    bmap_path: Option<&Path>,
 ) -> FlashResult {
-    let res = if config.is_gzip_internal() {
-        flash_gzip_internal(DD_CMD, target_path, image_path)
+    let image_format = match ImageFormat::from_file(image_path) {
+        Ok(image_format) => image_format,
+        Err(why) => {
+            error!(
+                "Failed to determine the format of image file '{}', error: {:?}",
+                image_path.display(),
+                why
+            );
+            return FlashResult::FailRecoverable;
+        }
+    };
+
+    debug!(
+        "flash_balena_os: image '{}' has format {:?}",
+        image_path.display(),
+        image_format
+    );
+
+    let block_map = if let Some(bmap_path) = bmap_path {
+        match BlockMap::from_file(bmap_path) {
+            Ok(block_map) => {
+                info!(
+                    "Using block map '{}', {} of {} are mapped",
+                    bmap_path.display(),
+                    format_size_with_unit(block_map.get_mapped_size()),
+                    format_size_with_unit(block_map.get_image_size())
+                );
+                Some(block_map)
+            }
+            Err(why) => {
+                error!(
+                    "Failed to read block map '{}', error: {:?}",
+                    bmap_path.display(),
+                    why
+                );
+                return FlashResult::FailRecoverable;
+            }
+        }
+    } else {
+        None
+    };
+
+    // only gzip without block map or verification can be flashed using external commands
+    let res = if image_format != ImageFormat::GZip
+        || config.is_gzip_internal()
+        || config.is_flash_verify()
+        || block_map.is_some()
+    {
+        flash_internal(
+            target_path,
+            image_path,
+            image_format,
+            block_map.as_ref(),
+            config.is_flash_verify(),
+        )
     } else {
         flash_gzip_external(DD_CMD, target_path, image_path)
     };
@@ -65,11 +138,291 @@ pub(crate) fn flash_balena_os(
     res
 }
 
-fn flash_gzip_internal(_dd_cmd: &str, target_path: &Path, image_path: &Path) -> FlashResult {
+// digest of a contiguous range written to the target
+struct WrittenExtent {
+    offset: u64,
+    length: u64,
+    digest: String,
+}
+
+// records digests of everything written, so it can be read back & compared after flashing
+// zeroed ranges are recorded as zeros, so without a block map the whole image is verified
+struct WriteVerifier {
+    extents: Vec<WrittenExtent>,
+    current: Option<(u64, u64, RangeHasher)>,
+}
+
+impl WriteVerifier {
+    fn new() -> WriteVerifier {
+        WriteVerifier {
+            extents: Vec::new(),
+            current: None,
+        }
+    }
+
+    fn add(&mut self, offset: u64, data: &[u8]) {
+        if let Some((start, ref mut length, ref mut hasher)) = self.current {
+            if start + *length == offset {
+                hasher.input(data);
+                *length += data.len() as u64;
+                return;
+            }
+        }
+
+        self.finish();
+        let mut hasher = RangeHasher::new(ChecksumType::Sha256);
+        hasher.input(data);
+        self.current = Some((offset, data.len() as u64, hasher));
+    }
+
+    fn add_zeros(&mut self, offset: u64, length: u64) {
+        let zeros: Vec<u8> = vec![0; min(length, DD_BLOCK_SIZE as u64) as usize];
+        let mut remaining = length;
+        while remaining > 0 {
+            let count = min(remaining, zeros.len() as u64) as usize;
+            self.add(offset + length - remaining, &zeros[0..count]);
+            remaining -= count as u64;
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some((offset, length, hasher)) = self.current.take() {
+            self.extents.push(WrittenExtent {
+                offset,
+                length,
+                digest: hasher.result_str(),
+            });
+        }
+    }
+
+    fn verify(&mut self, target_path: &Path) -> FlashResult {
+        self.finish();
+
+        let mut in_file = match File::open(target_path) {
+            Ok(file) => file,
+            Err(why) => {
+                error!(
+                    "Failed to open '{}' for read back verification, error: {:?}",
+                    target_path.display(),
+                    why
+                );
+                return FlashResult::FailNonRecoverable;
+            }
+        };
+
+        let mut buffer: Vec<u8> = vec![0; DD_BLOCK_SIZE];
+        let mut verified: u64 = 0;
+        for extent in &self.extents {
+            if let Err(why) = in_file.seek(SeekFrom::Start(extent.offset)) {
+                error!(
+                    "Failed to seek to offset {} on '{}' for read back verification, error: {:?}",
+                    extent.offset,
+                    target_path.display(),
+                    why
+                );
+                return FlashResult::FailNonRecoverable;
+            }
+
+            let mut hasher = RangeHasher::new(ChecksumType::Sha256);
+            let mut remaining = extent.length;
+            while remaining > 0 {
+                let to_read = min(remaining, buffer.len() as u64) as usize;
+                match read_fill(&mut in_file, &mut buffer[0..to_read]) {
+                    Ok(bytes_read) if bytes_read == to_read => {
+                        hasher.input(&buffer[0..to_read]);
+                        remaining -= to_read as u64;
+                    }
+                    Ok(_) => {
+                        error!(
+                            "Unexpected end of device '{}' in read back verification at offset {}",
+                            target_path.display(),
+                            extent.offset + extent.length - remaining
+                        );
+                        return FlashResult::FailNonRecoverable;
+                    }
+                    Err(why) => {
+                        error!(
+                            "Failed to read from '{}' for read back verification, error: {:?}",
+                            target_path.display(),
+                            why
+                        );
+                        return FlashResult::FailNonRecoverable;
+                    }
+                }
+            }
+
+            let digest = hasher.result_str();
+            if digest != extent.digest {
+                error!(
+                    "Read back verification failed on '{}': {} at offset {} do not match the data written, expected digest: {}, found: {}",
+                    target_path.display(),
+                    format_size_with_unit(extent.length),
+                    extent.offset,
+                    extent.digest,
+                    digest
+                );
+                return FlashResult::FailNonRecoverable;
+            }
+            verified += extent.length;
+        }
+
+        info!(
+            "Read back verification succeeded, {} in {} ranges verified on '{}'",
+            format_size_with_unit(verified),
+            self.extents.len(),
+            target_path.display()
+        );
+        FlashResult::Ok
+    }
+}
+
+// keeps track of logical (image) bytes processed vs bytes actually written to the target
+struct FlashProgress {
+    start_time: Instant,
+    last_elapsed: Duration,
+    logical: u64,
+    written: u64,
+    // bytes of zero blocks zeroed on the target, by BLKZEROOUT or by writing zeros
+    zeroed: u64,
+    verifier: Option<WriteVerifier>,
+    // zero range (offset, length) that has not been written to the target yet
+    zeros: Option<(u64, u64)>,
+    // BLKZEROOUT is not used again once it failed, eg. on regular files
+    zero_ioctl: bool,
+}
+
+impl FlashProgress {
+    fn new(verify: bool) -> FlashProgress {
+        FlashProgress {
+            start_time: Instant::now(),
+            last_elapsed: Duration::new(0, 0),
+            logical: 0,
+            written: 0,
+            zeroed: 0,
+            verifier: if verify {
+                Some(WriteVerifier::new())
+            } else {
+                None
+            },
+            zeros: None,
+            zero_ioctl: true,
+        }
+    }
+
+    // adjacent zero ranges are collected and zeroed on the next write or flush
+    fn add_zeros(&mut self, offset: u64, length: u64) {
+        if let Some((start, ref mut zero_len)) = self.zeros {
+            if start + *zero_len == offset {
+                *zero_len += length;
+                return;
+            }
+        }
+        self.zeros = Some((offset, length));
+    }
+
+    // zero the collected range, skipped blocks must not keep the data of the previous OS
+    fn flush_zeros(&mut self, out_file: &mut File) -> Result<(), io::Error> {
+        let (offset, length) = if let Some(zeros) = self.zeros.take() {
+            zeros
+        } else {
+            return Ok(());
+        };
+
+        if let Some(ref mut verifier) = self.verifier {
+            verifier.add_zeros(offset, length);
+        }
+        self.zeroed += length;
+
+        // page aligned ranges only, so BLKZEROOUT does not touch cached data around the range
+        let block_size = ZERO_BLOCK_SIZE as u64;
+        if self.zero_ioctl && offset.is_multiple_of(block_size) && length.is_multiple_of(block_size)
+        {
+            let range: [u64; 2] = [offset, length];
+            match unsafe { blk_zero_out(out_file.as_raw_fd(), &range) } {
+                Ok(_) => return Ok(()),
+                Err(why) => {
+                    debug!(
+                        "flush_zeros: BLKZEROOUT failed, writing zeros instead, error: {:?}",
+                        why
+                    );
+                    self.zero_ioctl = false;
+                }
+            }
+        }
+
+        let zeros: Vec<u8> = vec![0; min(length, DD_BLOCK_SIZE as u64) as usize];
+        out_file.seek(SeekFrom::Start(offset))?;
+        let mut remaining = length;
+        while remaining > 0 {
+            let count = min(remaining, zeros.len() as u64) as usize;
+            out_file.write_all(&zeros[0..count])?;
+            remaining -= count as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, out_file: &mut File, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        self.flush_zeros(out_file)?;
+        out_file.seek(SeekFrom::Start(offset))?;
+        out_file.write_all(data)?;
+        self.written += data.len() as u64;
+        if let Some(ref mut verifier) = self.verifier {
+            verifier.add(offset, data);
+        }
+        Ok(())
+    }
+
+    fn log(&self) {
+        let secs_elapsed = self.start_time.elapsed().as_secs();
+        info!(
+            "{} of {} written, {} zeroed @ {}/sec in {} seconds",
+            format_size_with_unit(self.written),
+            format_size_with_unit(self.logical),
+            format_size_with_unit(self.zeroed),
+            format_size_with_unit(self.logical / max(secs_elapsed, 1)),
+            secs_elapsed
+        );
+    }
+
+    fn update(&mut self) {
+        let curr_elapsed = self.start_time.elapsed();
+        let since_last = match curr_elapsed.checked_sub(self.last_elapsed) {
+            Some(dur) => dur,
+            None => Duration::from_secs(0),
+        };
+
+        if since_last.as_secs() >= 10 {
+            self.last_elapsed = curr_elapsed;
+            self.log();
+            Logger::flush();
+        }
+    }
+}
+
+// read until buffer is full or end of stream is reached
+fn read_fill(reader: &mut dyn Read, buffer: &mut [u8]) -> Result<usize, io::Error> {
+    let mut buff_fill: usize = 0;
+    while buff_fill < buffer.len() {
+        let bytes_read = reader.read(&mut buffer[buff_fill..])?;
+        if bytes_read == 0 {
+            break;
+        }
+        buff_fill += bytes_read;
+    }
+    Ok(buff_fill)
+}
+
+fn flash_internal(
+    target_path: &Path,
+    image_path: &Path,
+    image_format: ImageFormat,
+    block_map: Option<&BlockMap>,
+    verify: bool,
+) -> FlashResult {
     debug!("opening: '{}'", image_path.display());
 
-    let mut decoder = GzDecoder::new(match File::open(&image_path) {
-        Ok(file) => file,
+    let mut decoder = match image_format.open_stream(image_path) {
+        Ok(decoder) => decoder,
         Err(why) => {
             error!(
                 "Failed to open image file '{}', error: {:?}",
@@ -78,30 +431,7 @@ fn flash_gzip_internal(_dd_cmd: &str, target_path: &Path, image_path: &Path) ->
             );
             return FlashResult::FailRecoverable;
         }
-    });
-
-    /* debug!("invoking dd");
-
-    let mut dd_child = match Command::new(dd_cmd)
-        .args(&[
-            // "conv=fsync", sadly not supported on busybox dd
-            // "oflag=direct",
-            &format!("of={}", &target_path.to_string_lossy()),
-            &format!("bs={}", DD_BLOCK_SIZE),
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::inherit()) // test
-        .stderr(Stdio::inherit())
-        .spawn()
-    {
-        Ok(dd_child) => dd_child,
-        Err(why) => {
-            error!("failed to execute command {}, error: {:?}", dd_cmd, why);
-            Logger::flush();
-            return FlashResult::FailRecoverable;
-        }
     };
-    */
 
     debug!("opening output file '{}", target_path.display());
     let mut out_file = match OpenOptions::new()
@@ -121,19 +451,79 @@ fn flash_gzip_internal(_dd_cmd: &str, target_path: &Path, image_path: &Path) ->
         }
     };
 
-    let start_time = Instant::now();
-    let mut last_elapsed = Duration::new(0, 0);
-    let mut write_count: usize = 0;
+    let mut progress = FlashProgress::new(verify);
+
+    let res = if let Some(block_map) = block_map {
+        flash_mapped(
+            decoder.as_mut(),
+            &mut out_file,
+            block_map,
+            image_path,
+            &mut progress,
+        )
+    } else {
+        flash_sparse(decoder.as_mut(), &mut out_file, image_path, &mut progress)
+    };
+
+    if let FlashResult::Ok = res {
+        if let Err(why) = out_file.flush() {
+            error!(
+                "Failed to flush output file '{}', error: {:?}",
+                target_path.display(),
+                why
+            );
+            return FlashResult::FailNonRecoverable;
+        }
+        progress.log();
+
+        if let Some(ref mut verifier) = progress.verifier {
+            // make sure data is read back from the device, not from the page cache
+            sync();
+            if let Err(why) = write(DROP_CACHES_PATH, "3") {
+                warn!(
+                    "Failed to drop caches before read back verification, error: {:?}",
+                    why
+                );
+            }
+            return verifier.verify(target_path);
+        }
+    }
+
+    res
+}
+
+// write only the ranges listed in the block map, verifying each range checksum
+fn flash_mapped(
+    decoder: &mut dyn Read,
+    out_file: &mut File,
+    block_map: &BlockMap,
+    image_path: &Path,
+    progress: &mut FlashProgress,
+) -> FlashResult {
+    let block_size = block_map.get_block_size();
+    let image_size = block_map.get_image_size();
 
     let mut fail_res = FlashResult::FailRecoverable;
-    // TODO: might pay to put buffer on page boundary
-    let mut buffer: [u8; DD_BLOCK_SIZE] = [0; DD_BLOCK_SIZE];
-    loop {
-        // fill buffer
-        let mut buff_fill: usize = 0;
-        loop {
-            let bytes_read = match decoder.read(&mut buffer[buff_fill..]) {
-                Ok(bytes_read) => bytes_read,
+    let mut buffer: Vec<u8> = vec![0; DD_BLOCK_SIZE];
+
+    for range in block_map.get_ranges() {
+        let (start, end) = range.get_byte_range(block_size, image_size);
+
+        // skip unmapped data in the stream
+        while progress.logical < start {
+            let to_read = min((start - progress.logical) as usize, buffer.len());
+            match read_fill(decoder, &mut buffer[0..to_read]) {
+                Ok(bytes_read) if bytes_read == to_read => {
+                    progress.logical += bytes_read as u64;
+                }
+                Ok(_) => {
+                    error!(
+                        "Unexpected end of image '{}' at offset {}",
+                        image_path.display(),
+                        progress.logical
+                    );
+                    return fail_res;
+                }
                 Err(why) => {
                     error!(
                         "Failed to read uncompressed data from '{}', error: {:?}",
@@ -142,95 +532,131 @@ fn flash_gzip_internal(_dd_cmd: &str, target_path: &Path, image_path: &Path) ->
                     );
                     return fail_res;
                 }
-            };
-
-            if bytes_read > 0 {
-                buff_fill += bytes_read;
-                if buff_fill < buffer.len() {
-                    continue;
-                }
             }
-            break;
         }
 
-        if buff_fill > 0 {
-            fail_res = FlashResult::FailNonRecoverable;
-
-            let bytes_written = match out_file.write(&buffer[0..buff_fill]) {
-                Ok(bytes_written) => bytes_written,
+        let mut hasher = RangeHasher::new(block_map.get_checksum_type());
+        while progress.logical < end {
+            let to_read = min((end - progress.logical) as usize, buffer.len());
+            match read_fill(decoder, &mut buffer[0..to_read]) {
+                Ok(bytes_read) if bytes_read == to_read => (),
+                Ok(_) => {
+                    error!(
+                        "Unexpected end of image '{}' at offset {}",
+                        image_path.display(),
+                        progress.logical
+                    );
+                    return fail_res;
+                }
                 Err(why) => {
-                    error!("Failed to write uncompressed data to dd, error {:?}", why);
+                    error!(
+                        "Failed to read uncompressed data from '{}', error: {:?}",
+                        image_path.display(),
+                        why
+                    );
                     return fail_res;
                 }
-            };
+            }
 
-            write_count += bytes_written;
+            hasher.input(&buffer[0..to_read]);
 
-            if buff_fill != bytes_written {
+            fail_res = FlashResult::FailNonRecoverable;
+            let out_offset = progress.logical;
+            if let Err(why) = progress.write(out_file, out_offset, &buffer[0..to_read]) {
                 error!(
-                    "Read/write count mismatch, read {}, wrote {}",
-                    buff_fill, bytes_written
+                    "Failed to write uncompressed data at offset {}, error {:?}",
+                    progress.logical, why
                 );
                 return fail_res;
             }
 
-            let curr_elapsed = start_time.elapsed();
-            let since_last = match curr_elapsed.checked_sub(last_elapsed) {
-                Some(dur) => dur,
-                None => Duration::from_secs(0),
-            };
+            progress.logical += to_read as u64;
+            progress.update();
+        }
 
-            if since_last.as_secs() >= 10 {
-                last_elapsed = curr_elapsed;
-                let secs_elapsed = curr_elapsed.as_secs();
-                info!(
-                    "{} written @ {}/sec in {} seconds",
-                    format_size_with_unit(write_count as u64),
-                    format_size_with_unit(write_count as u64 / secs_elapsed),
-                    secs_elapsed
+        if let Some(ref checksum) = range.checksum {
+            let computed = hasher.result_str();
+            if computed != *checksum {
+                error!(
+                    "Checksum mismatch on block range {}-{}, expected: {}, computed: {}",
+                    range.first, range.last, checksum, computed
                 );
-                Logger::flush();
+                return fail_res;
             }
+        }
+    }
 
-            if buff_fill < buffer.len() {
-                break;
+    // unmapped blocks at the end of the image are not read at all
+    progress.logical = image_size;
+    FlashResult::Ok
+}
+
+// without block map blocks that contain only zeros are not written but zeroed on the target
+fn flash_sparse(
+    decoder: &mut dyn Read,
+    out_file: &mut File,
+    image_path: &Path,
+    progress: &mut FlashProgress,
+) -> FlashResult {
+    let mut fail_res = FlashResult::FailRecoverable;
+    let mut buffer: Vec<u8> = vec![0; DD_BLOCK_SIZE];
+
+    loop {
+        let buff_fill = match read_fill(decoder, &mut buffer) {
+            Ok(buff_fill) => buff_fill,
+            Err(why) => {
+                error!(
+                    "Failed to read uncompressed data from '{}', error: {:?}",
+                    image_path.display(),
+                    why
+                );
+                return fail_res;
             }
-        } else {
+        };
+
+        if buff_fill == 0 {
             break;
         }
-    }
 
-    /*
-    match dd_child.wait_with_output() {
-        Ok(cmd_res) => {
-            if !cmd_res.status.success() {
-                let stderr = match str::from_utf8(&cmd_res.stderr) {
-                    Ok(stderr) => stderr,
-                    Err(_) => "- invalid utf8 -",
-                };
+        // collect runs of blocks that contain data (true) or only zeros (false)
+        let mut runs: Vec<(usize, usize, bool)> = Vec::new();
+        let mut offset: usize = 0;
+        while offset < buff_fill {
+            let block_end = min(offset + ZERO_BLOCK_SIZE, buff_fill);
+            let is_data = buffer[offset..block_end].iter().any(|byte| *byte != 0);
+            match runs.last_mut() {
+                Some((_, ref mut end, run_data)) if *run_data == is_data => *end = block_end,
+                _ => runs.push((offset, block_end, is_data)),
+            }
+            offset = block_end;
+        }
+
+        for (start, end, is_data) in runs {
+            fail_res = FlashResult::FailNonRecoverable;
+            let out_offset = progress.logical + start as u64;
+            if !is_data {
+                progress.add_zeros(out_offset, (end - start) as u64);
+            } else if let Err(why) = progress.write(out_file, out_offset, &buffer[start..end]) {
                 error!(
-                    "dd reported an error: code: {:?}, stderr: {}",
-                    cmd_res.status.code(),
-                    stderr
+                    "Failed to write uncompressed data at offset {}, error {:?}",
+                    out_offset, why
                 );
-                // might pay to still try and finish as all input was written
+                return fail_res;
             }
         }
-        Err(why) => {
-            error!("Error while waiting for dd to terminate:{:?}", why);
-            Logger::flush();
-            return fail_res;
+
+        progress.logical += buff_fill as u64;
+        progress.update();
+
+        if buff_fill < buffer.len() {
+            break;
         }
     }
-    */
 
-    let secs_elapsed = start_time.elapsed().as_secs();
-    info!(
-        "{} written @ {}/sec in {} seconds",
-        format_size_with_unit(write_count as u64),
-        format_size_with_unit(write_count as u64 / secs_elapsed),
-        secs_elapsed
-    );
+    if let Err(why) = progress.flush_zeros(out_file) {
+        error!("Failed to zero unused blocks, error {:?}", why);
+        return FlashResult::FailNonRecoverable;
+    }
 
     FlashResult::Ok
 }
@@ -279,3 +705,182 @@ fn flash_gzip_external(dd_cmd: &str, target_path: &Path, image_path: &Path) -> F
         FlashResult::FailRecoverable
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+    use std::fs::{read, remove_file, write, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use super::{flash_mapped, flash_sparse, FlashProgress, FlashResult};
+    use crate::common::bmap::{BlockMap, ChecksumType, RangeHasher};
+
+    const BLOCK_SIZE: usize = 4096;
+    const IMAGE_BLOCKS: usize = 100;
+
+    // image with data in blocks 0-1 and 60 and a partial block at the end
+    fn make_image() -> Vec<u8> {
+        let mut image: Vec<u8> = vec![0; IMAGE_BLOCKS * BLOCK_SIZE - 100];
+        for byte in image[0..2 * BLOCK_SIZE].iter_mut() {
+            *byte = 1;
+        }
+        image[60 * BLOCK_SIZE + 10] = 2;
+        let len = image.len();
+        image[len - 1] = 3;
+        image
+    }
+
+    fn make_target(name: &str, size: usize) -> PathBuf {
+        let path = std::env::temp_dir().join(name);
+        write(&path, vec![0xffu8; size]).unwrap();
+        path
+    }
+
+    fn range_checksum(data: &[u8]) -> String {
+        let mut hasher = RangeHasher::new(ChecksumType::Sha256);
+        hasher.input(data);
+        hasher.result_str()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn flash_sparse_zeroes_blocks() {
+        let image = make_image();
+        let target = make_target("balena-migrate-test-flash-sparse.img", image.len());
+        let mut progress = FlashProgress::new(false);
+        let compressed = gzip(&image);
+        let res = flash_sparse(
+            &mut GzDecoder::new(compressed.as_slice()),
+            &mut OpenOptions::new().write(true).open(&target).unwrap(),
+            &target,
+            &mut progress,
+        );
+        match res {
+            FlashResult::Ok => (),
+            _ => panic!("flashing failed"),
+        }
+        assert_eq!(progress.logical, image.len() as u64);
+        assert_eq!(progress.written, 4 * BLOCK_SIZE as u64 - 100);
+        assert_eq!(progress.zeroed, 96 * BLOCK_SIZE as u64);
+
+        let written = read(&target).unwrap();
+        assert_eq!(written[0..2 * BLOCK_SIZE], image[0..2 * BLOCK_SIZE]);
+        assert_eq!(written[60 * BLOCK_SIZE + 10], 2);
+        // zero blocks are zeroed on the target, not left as they were
+        assert_eq!(written[2 * BLOCK_SIZE], 0);
+        assert!(written[2 * BLOCK_SIZE..60 * BLOCK_SIZE]
+            .iter()
+            .all(|byte| *byte == 0));
+        assert_eq!(written[written.len() - 1], 3);
+        let _res = remove_file(&target);
+    }
+
+    #[test]
+    fn flash_mapped_checks_ranges() {
+        let image = make_image();
+        let target = make_target("balena-migrate-test-flash-mapped.img", image.len());
+        let bmap = format!(
+            r#"<?xml version="1.0" ?>
+<bmap version="2.0">
+    <ImageSize> {} </ImageSize>
+    <BlockSize> {} </BlockSize>
+    <BlocksCount> {} </BlocksCount>
+    <MappedBlocksCount> 3 </MappedBlocksCount>
+    <ChecksumType> sha256 </ChecksumType>
+    <BlockMap>
+        <Range chksum="{}"> 0-1 </Range>
+        <Range chksum="{}"> 60 </Range>
+    </BlockMap>
+</bmap>
+"#,
+            image.len(),
+            BLOCK_SIZE,
+            IMAGE_BLOCKS,
+            range_checksum(&image[0..2 * BLOCK_SIZE]),
+            range_checksum(&image[60 * BLOCK_SIZE..61 * BLOCK_SIZE]),
+        );
+        let block_map = BlockMap::from_str(&bmap).unwrap();
+
+        let mut out_file = OpenOptions::new().write(true).open(&target).unwrap();
+        let mut progress = FlashProgress::new(false);
+        let res = flash_mapped(
+            &mut image.as_slice(),
+            &mut out_file,
+            &block_map,
+            &target,
+            &mut progress,
+        );
+        match res {
+            FlashResult::Ok => (),
+            _ => panic!("flashing failed"),
+        }
+        assert_eq!(progress.written, 3 * BLOCK_SIZE as u64);
+        assert_eq!(progress.zeroed, 0);
+
+        let written = read(&target).unwrap();
+        assert_eq!(written[0..2 * BLOCK_SIZE], image[0..2 * BLOCK_SIZE]);
+        assert_eq!(written[60 * BLOCK_SIZE + 10], 2);
+        // unmapped, not written
+        assert_eq!(written[written.len() - 1], 0xff);
+
+        // corrupted data must be detected
+        let mut corrupt = image.clone();
+        corrupt[60 * BLOCK_SIZE + 11] = 5;
+        let res = flash_mapped(
+            &mut corrupt.as_slice(),
+            &mut out_file,
+            &block_map,
+            &target,
+            &mut FlashProgress::new(false),
+        );
+        match res {
+            FlashResult::FailNonRecoverable => (),
+            _ => panic!("checksum mismatch was not detected"),
+        }
+        let _res = remove_file(&target);
+    }
+
+    #[test]
+    fn verify_read_back() {
+        let image = make_image();
+        let target = make_target("balena-migrate-test-flash-verify.img", image.len());
+        let mut progress = FlashProgress::new(true);
+        let res = flash_sparse(
+            &mut image.as_slice(),
+            &mut OpenOptions::new().write(true).open(&target).unwrap(),
+            &target,
+            &mut progress,
+        );
+        match res {
+            FlashResult::Ok => (),
+            _ => panic!("flashing failed"),
+        }
+
+        let mut verifier = progress.verifier.unwrap();
+        match verifier.verify(&target) {
+            FlashResult::Ok => (),
+            _ => panic!("verification failed"),
+        }
+        // zeroed blocks are verified too, the whole image is one extent
+        assert_eq!(verifier.extents.len(), 1);
+        assert_eq!(verifier.extents[0].length, image.len() as u64);
+
+        let written = read(&target).unwrap();
+        for offset in &[60 * BLOCK_SIZE + 10, 30 * BLOCK_SIZE] {
+            let mut corrupt = written.clone();
+            corrupt[*offset] = 0xff;
+            write(&target, &corrupt).unwrap();
+            match verifier.verify(&target) {
+                FlashResult::FailNonRecoverable => (),
+                _ => panic!("corrupted data was not detected"),
+            }
+        }
+        let _res = remove_file(&target);
+    }
+}
@@ -14,7 +14,8 @@ use std::time::{Duration, SystemTime};
 use crate::{
     common::{
         call, call_with_stdin,
-        config::balena_config::PartCheck,
+        config::balena_config::{PartCheck, PartTable},
+        disk_util::{LabelType, GPT_TYPE_EFI_SYSTEM, GPT_TYPE_LINUX_FS},
         file_exists, path_append,
         stage2_config::{CheckedFSDump, CheckedImageType, Stage2Config},
         MigErrCtx, MigError, MigErrorKind,
@@ -28,7 +29,6 @@ use crate::{
     },
 };
 
-// TODO: ensure support for GPT partition tables
 // TODO: write tests for partitioning
 
 const FORMAT_WITH_LABEL: bool = true;
@@ -508,27 +508,102 @@ fn partition_sfdisk(
         dev_name.clone()
     };
 
-    let mut part_string = String::new();
+    let alignment_blocks: u64 = DEFAULT_PARTITION_ALIGNMENT_KIB * 1024 / DEF_BLOCK_SIZE as u64;
+    debug!(
+        "partition_sfdisk: Alignment '{}'KiB, {} blocks",
+        DEFAULT_PARTITION_ALIGNMENT_KIB, alignment_blocks
+    );
+
+    let max_data = if let Some(max_data) = fs_dump.max_data {
+        max_data
+    } else {
+        DEFAULT_MAX_DATA
+    };
+
+    let is_gpt = fs_dump.part_table == Some(PartTable::Gpt);
+
+    let part_string = if is_gpt {
+        gpt_part_string(&part_stub, fs_dump, alignment_blocks, max_data)
+    } else {
+        dos_part_string(&part_stub, fs_dump, alignment_blocks, max_data)
+    };
+
+    debug!("call_with_stdin: Writing: '{}'", part_string);
+
+    match call_with_stdin(
+        cmd_path[SFDISK_CMD].as_str(),
+        &["-f", dev_name.as_str()],
+        &mut part_string.as_bytes(),
+        true,
+    ) {
+        Ok(cmd_res) => {
+            if !cmd_res.status.success() {
+                error!(
+                    "sfdisk returned an error status: code: {:?}, stderr: {:?}",
+                    cmd_res.status.code(),
+                    cmd_res.stderr
+                );
+                FlashResult::FailNonRecoverable
+            } else {
+                sync();
+                debug!("partition_sfdisk: sfdisk stdout: {:?}", cmd_res.stdout);
+                if is_gpt {
+                    // make sure sfdisk wrote a GPT with a protective MBR
+                    match LabelType::from_device(device) {
+                        Ok(LabelType::GPT) => FlashResult::Ok,
+                        Ok(label_type) => {
+                            error!(
+                                "partition_sfdisk: expected a GPT on '{}', found label type {:?}",
+                                device.display(),
+                                label_type
+                            );
+                            FlashResult::FailNonRecoverable
+                        }
+                        Err(why) => {
+                            error!(
+                                "partition_sfdisk: failed to read partition table from '{}', error: {:?}",
+                                device.display(),
+                                why
+                            );
+                            FlashResult::FailNonRecoverable
+                        }
+                    }
+                } else {
+                    FlashResult::Ok
+                }
+            }
+        }
+        Err(why) => {
+            error!(
+                "Failed to run command : '{}' with args: {:?}, input: '{}' error: {:?}",
+                cmd_path[SFDISK_CMD].as_str(),
+                &[dev_name],
+                part_string,
+                why
+            );
+            FlashResult::FailRecoverable
+        }
+    }
+}
 
-    // TODO: configure partition type
+fn dos_part_string(
+    part_stub: &str,
+    fs_dump: &CheckedFSDump,
+    alignment_blocks: u64,
+    max_data: bool,
+) -> String {
+    let mut part_string = String::new();
 
-    debug!("partition_sfdisk: Writing label type: dos",);
+    debug!("dos_part_string: Writing label type: dos",);
     part_string.push_str("label : dos\n");
 
     /* TODO: generate random label-id or rather let sfdisk do the job ?
     part_string.push_str("label-id 0x{:x}\n", random_number: u32)
     */
 
-    let alignment_blocks: u64 = DEFAULT_PARTITION_ALIGNMENT_KIB * 1024 / DEF_BLOCK_SIZE as u64;
-    debug!(
-        "partition_sfdisk: Alignment '{}'KiB, {} blocks",
-        DEFAULT_PARTITION_ALIGNMENT_KIB, alignment_blocks
-    );
-
     debug!(
-        "partition_sfdisk: Writing resin-boot as 'size={},bootable,type=e' to '{}'",
-        fs_dump.boot.blocks,
-        device.display()
+        "dos_part_string: Writing resin-boot as 'size={},bootable,type=e' to '{}'",
+        fs_dump.boot.blocks, part_stub
     );
 
     let mut start_block: u64 = alignment_blocks;
@@ -539,7 +614,7 @@ fn partition_sfdisk(
         part_stub, start_block, fs_dump.boot.blocks
     );
     debug!(
-        "partition_sfdisk: Writing resin-boot as '{}', end={}",
+        "dos_part_string: Writing resin-boot as '{}', end={}",
         part_def, end_block
     );
     part_string.push_str(&part_def);
@@ -556,7 +631,7 @@ fn partition_sfdisk(
         part_stub, start_block, fs_dump.root_a.blocks
     );
     debug!(
-        "partition_sfdisk: Writing resin-rootA as '{}', end={}",
+        "dos_part_string: Writing resin-rootA as '{}', end={}",
         part_def, end_block
     );
     part_string.push_str(&part_def);
@@ -572,7 +647,7 @@ fn partition_sfdisk(
         part_stub, start_block, fs_dump.root_b.blocks
     );
     debug!(
-        "partition_sfdisk: Writing resin-rootB as '{}', end={}",
+        "dos_part_string: Writing resin-rootB as '{}', end={}",
         part_def, end_block
     );
     part_string.push_str(&part_def);
@@ -582,12 +657,6 @@ fn partition_sfdisk(
         start_block = (start_block / alignment_blocks + 1) * alignment_blocks;
     }
 
-    let max_data = if let Some(max_data) = fs_dump.max_data {
-        max_data
-    } else {
-        DEFAULT_MAX_DATA
-    };
-
     let (part_def, end_block) = if max_data {
         (
             format!(
@@ -607,7 +676,7 @@ fn partition_sfdisk(
     };
 
     debug!(
-        "partition_sfdisk: Writing extended partition as '{}', end={}",
+        "dos_part_string: Writing extended partition as '{}', end={}",
         part_def, end_block
     );
 
@@ -621,7 +690,7 @@ fn partition_sfdisk(
         part_stub, start_block, fs_dump.state.blocks
     );
     debug!(
-        "partition_sfdisk: Writing resin-state as '{}', end={}",
+        "dos_part_string: Writing resin-state as '{}', end={}",
         part_def, end_block
     );
     part_string.push_str(&part_def);
@@ -654,44 +723,88 @@ fn partition_sfdisk(
     };
 
     debug!(
-        "partition_sfdisk: Writing resin-data as '{}', end={}",
+        "dos_part_string: Writing resin-data as '{}', end={}",
         part_def, end_block
     );
     part_string.push_str(&part_def);
 
-    debug!("call_with_stdin: Writing: '{}'", part_string);
+    part_string
+}
 
-    match call_with_stdin(
-        cmd_path[SFDISK_CMD].as_str(),
-        &["-f", dev_name.as_str()],
-        &mut part_string.as_bytes(),
-        true,
-    ) {
-        Ok(cmd_res) => {
-            if !cmd_res.status.success() {
-                error!(
-                    "sfdisk returned an error status: code: {:?}, stderr: {:?}",
-                    cmd_res.status.code(),
-                    cmd_res.stderr
-                );
-                FlashResult::FailNonRecoverable
-            } else {
-                sync();
-                debug!("partition_sfdisk: sfdisk stdout: {:?}", cmd_res.stdout);
-                FlashResult::Ok
-            }
-        }
-        Err(why) => {
-            error!(
-                "Failed to run command : '{}' with args: {:?}, input: '{}' error: {:?}",
-                cmd_path[SFDISK_CMD].as_str(),
-                &[dev_name],
-                part_string,
-                why
-            );
-            FlashResult::FailRecoverable
+fn gpt_part_string(
+    part_stub: &str,
+    fs_dump: &CheckedFSDump,
+    alignment_blocks: u64,
+    max_data: bool,
+) -> String {
+    let mut part_string = String::new();
+
+    debug!("gpt_part_string: Writing label type: gpt",);
+    part_string.push_str("label : gpt\n");
+
+    let partitions = [
+        (&fs_dump.boot, GPT_TYPE_EFI_SYSTEM),
+        (&fs_dump.root_a, GPT_TYPE_LINUX_FS),
+        (&fs_dump.root_b, GPT_TYPE_LINUX_FS),
+        (&fs_dump.state, GPT_TYPE_LINUX_FS),
+        (&fs_dump.data, GPT_TYPE_LINUX_FS),
+    ];
+
+    // the first partition starts at the alignment boundary, leaving plenty of room for the
+    // protective MBR, the GPT header and the partition entry array
+    let mut start_block: u64 = alignment_blocks;
+
+    for (idx, (part_dump, def_type_guid)) in partitions.iter().enumerate() {
+        let (part_label, _) = PART_INFO[idx];
+        let type_guid = if let Some(ref type_guid) = part_dump.type_guid {
+            type_guid.as_str()
+        } else {
+            def_type_guid
+        };
+
+        // the data partition is the last one, it fills the disk when max_data is set
+        let is_last = idx == partitions.len() - 1;
+
+        let (part_def, end_block) = if is_last && max_data {
+            (
+                format!(
+                    "{}{} : start={}, type={}, name=\"{}\"\n",
+                    part_stub,
+                    idx + 1,
+                    start_block,
+                    type_guid,
+                    part_label
+                ),
+                -1i64,
+            )
+        } else {
+            (
+                format!(
+                    "{}{} : start={}, size={}, type={}, name=\"{}\"\n",
+                    part_stub,
+                    idx + 1,
+                    start_block,
+                    part_dump.blocks,
+                    type_guid,
+                    part_label
+                ),
+                (start_block + part_dump.blocks) as i64,
+            )
+        };
+
+        debug!(
+            "gpt_part_string: Writing {} as '{}', end={}",
+            part_label, part_def, end_block
+        );
+        part_string.push_str(&part_def);
+
+        start_block += part_dump.blocks;
+        if !start_block.is_multiple_of(alignment_blocks) {
+            start_block = (start_block / alignment_blocks + 1) * alignment_blocks;
         }
     }
+
+    part_string
 }
 
 fn part_reread(
@@ -944,3 +1057,50 @@ fn part_reread(
     }
     */
 }
+
+#[cfg(test)]
+mod tests {
+    use super::gpt_part_string;
+    use crate::common::stage2_config::CheckedFSDump;
+
+    fn part_dump(name: &str, blocks: u64, type_guid: Option<&str>) -> String {
+        let mut dump = format!(
+            "{}:\n  blocks: {}\n  archive:\n    rel_path: {}.tgz\n    size: 0\n    hash_info:\n      md5: \"\"\n",
+            name, blocks, name
+        );
+        if let Some(type_guid) = type_guid {
+            dump.push_str(&format!("  type_guid: {}\n", type_guid));
+        }
+        dump
+    }
+
+    #[test]
+    fn gpt_partitions() {
+        let mut fs_dump_str = String::from("extended_blocks: 0\ndevice_slug: genericx86-64-ext\n");
+        fs_dump_str.push_str(&part_dump("boot", 81920, None));
+        fs_dump_str.push_str(&part_dump("root_a", 655360, None));
+        fs_dump_str.push_str(&part_dump("root_b", 655360, None));
+        // not aligned, the data partition has to start at the next alignment boundary
+        fs_dump_str.push_str(&part_dump("state", 40000, None));
+        fs_dump_str.push_str(&part_dump(
+            "data",
+            1000,
+            Some("933AC7E1-2EB4-4F13-B844-0E14E2AEF915"),
+        ));
+        let fs_dump: CheckedFSDump = serde_yaml::from_str(&fs_dump_str).unwrap();
+
+        assert_eq!(
+            gpt_part_string("/dev/sda", &fs_dump, 8192, true),
+            "label : gpt\n\
+             /dev/sda1 : start=8192, size=81920, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, name=\"resin-boot\"\n\
+             /dev/sda2 : start=90112, size=655360, type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, name=\"resin-rootA\"\n\
+             /dev/sda3 : start=745472, size=655360, type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, name=\"resin-rootB\"\n\
+             /dev/sda4 : start=1400832, size=40000, type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, name=\"resin-state\"\n\
+             /dev/sda5 : start=1441792, type=933AC7E1-2EB4-4F13-B844-0E14E2AEF915, name=\"resin-data\"\n"
+        );
+
+        assert!(gpt_part_string("/dev/sda", &fs_dump, 8192, false).ends_with(
+            "/dev/sda5 : start=1441792, size=1000, type=933AC7E1-2EB4-4F13-B844-0E14E2AEF915, name=\"resin-data\"\n"
+        ));
+    }
+}
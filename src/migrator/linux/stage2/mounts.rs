@@ -13,7 +13,9 @@ use nix::{
 
 use crate::{
     common::{
-        call, dir_exists, file_exists, path_append,
+        call, dir_exists,
+        disk_util::LabelType,
+        file_exists, path_append,
         stage2_config::{PathType, Stage2Config},
         MigErrCtx, MigError, MigErrorKind,
     },
@@ -43,6 +45,7 @@ const ROOTA_MNT_DIR: &str = "mnt_rootA";
 const ROOTB_MNT_DIR: &str = "mnt_rootB";
 const STATE_MNT_DIR: &str = "mnt_state";
 const DATA_MNT_DIR: &str = "mnt_data";
+const FORMER_MNT_DIR: &str = "mnt_former";
 
 const UDEVADM_PARAMS: &[&str] = &["settle", "-t", "10"];
 
@@ -474,8 +477,47 @@ impl<'a> Mounts {
         }
     }
 
+    // mount a partition of the former system to access files installed for the migrate boot,
+    // returns the mountpoint and true if the partition was mounted here and needs to be unmounted
+    // using unmount_former. The former boot partition is mounted already.
+    pub fn mount_former(&self, device: &Path, fstype: &str) -> Result<(PathBuf, bool), MigError> {
+        let device = to_std_device_path(device)?;
+        if device == self.boot_part {
+            Ok((self.boot_mountpoint.clone(), false))
+        } else {
+            Ok((Mounts::mount(FORMER_MNT_DIR, &device, fstype)?, true))
+        }
+    }
+
+    pub fn unmount_former(&self, mountpoint: &Path) -> Result<(), MigError> {
+        sync();
+        umount(mountpoint).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "Failed to unmount former device: '{}'",
+                mountpoint.display()
+            ),
+        ))?;
+        Ok(())
+    }
+
     pub fn mount_balena(&mut self, mount_all: bool) -> Result<bool, MigError> {
         let mut parts_found = true;
+
+        // a GPT has no extended partition, so state & data are partitions 4 & 5
+        let (state_idx, data_idx) = match LabelType::from_device(&self.flash_device) {
+            Ok(LabelType::GPT) => (4, 5),
+            Ok(_) => (5, 6),
+            Err(why) => {
+                warn!(
+                    "Failed to determine partition table type of '{}', assuming dos, error: {:?}",
+                    self.flash_device.display(),
+                    why
+                );
+                (5, 6)
+            }
+        };
+
         let mut part_label = path_append(DISK_BY_LABEL_PATH, BALENA_BOOT_PART);
         if !file_exists(&part_label) {
             part_label = drive_to_partition(&self.flash_device, 1)?;
@@ -547,7 +589,7 @@ impl<'a> Mounts {
 
         let mut part_label = path_append(DISK_BY_LABEL_PATH, BALENA_STATE_PART);
         if !file_exists(&part_label) {
-            part_label = drive_to_partition(&self.flash_device, 5)?;
+            part_label = drive_to_partition(&self.flash_device, state_idx)?;
         }
 
         if mount_all {
@@ -573,7 +615,7 @@ impl<'a> Mounts {
 
         let mut part_label = path_append(DISK_BY_LABEL_PATH, BALENA_DATA_PART);
         if !file_exists(&part_label) {
-            part_label = drive_to_partition(&self.flash_device, 6)?;
+            part_label = drive_to_partition(&self.flash_device, data_idx)?;
         }
 
         self.balena_data_mp = match Mounts::mount(DATA_MNT_DIR, &part_label, BALENA_DATA_FSTYPE) {
@@ -0,0 +1,453 @@
+use failure::ResultExt;
+use flate2::Crc;
+use log::{debug, info, trace, warn};
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::common::{MigErrCtx, MigError, MigErrorKind};
+
+// ******************************************************************
+// Native access to binary u-boot environments, replaces fw_printenv /
+// fw_setenv. An environment is a CRC32 protected block of NUL
+// terminated 'name=value' strings stored either in a file (uboot.env)
+// or at a raw offset on a block device. A redundant environment keeps
+// two copies, a flags byte following the CRC selects the active one.
+// Locations are described like in fw_env.config:
+//   <device> <offset> <env size> [<sector size> [<sectors>]]
+// one line for a single environment, two lines for a redundant one.
+// ******************************************************************
+
+const ENV_CRC_SIZE: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct UBootEnvLocation {
+    pub device: PathBuf,
+    // negative offsets are relative to the end of the device
+    pub offset: i64,
+    pub size: u64,
+}
+
+impl UBootEnvLocation {
+    pub fn from_fw_env_config<P: AsRef<Path>>(path: P) -> Result<Vec<UBootEnvLocation>, MigError> {
+        let path = path.as_ref();
+        trace!("from_fw_env_config: entered with '{}'", path.display());
+        let content = read_to_string(path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to read u-boot env config from '{}'", path.display()),
+        ))?;
+        UBootEnvLocation::parse_fw_env_config(&content)
+    }
+
+    pub fn parse_fw_env_config(content: &str) -> Result<Vec<UBootEnvLocation>, MigError> {
+        let mut locations: Vec<UBootEnvLocation> = Vec::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!(
+                        "parse_fw_env_config: invalid u-boot env config line: '{}'",
+                        line
+                    ),
+                ));
+            }
+
+            let size = parse_number(fields[2])?;
+            if size as usize <= ENV_CRC_SIZE + 1 {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!("parse_fw_env_config: invalid u-boot env size: {}", size),
+                ));
+            }
+
+            let offset = if fields[1].starts_with('-') {
+                -(parse_number(&fields[1][1..])? as i64)
+            } else {
+                parse_number(fields[1])? as i64
+            };
+
+            locations.push(UBootEnvLocation {
+                device: PathBuf::from(fields[0]),
+                offset,
+                size,
+            });
+        }
+
+        match locations.len() {
+            1 => Ok(locations),
+            2 => {
+                if locations[0].size == locations[1].size {
+                    Ok(locations)
+                } else {
+                    Err(MigError::from_remark(
+                        MigErrorKind::InvParam,
+                        "parse_fw_env_config: redundant u-boot environments differ in size",
+                    ))
+                }
+            }
+            count => Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "parse_fw_env_config: expected one or two u-boot env locations, found {}",
+                    count
+                ),
+            )),
+        }
+    }
+
+    fn open(&self, write: bool) -> Result<File, MigError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(write)
+            .open(&self.device)
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "Failed to open u-boot env device '{}'",
+                    self.device.display()
+                ),
+            ))?;
+
+        let seek_pos = if self.offset < 0 {
+            SeekFrom::End(self.offset)
+        } else {
+            SeekFrom::Start(self.offset as u64)
+        };
+
+        file.seek(seek_pos).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "Failed to seek to offset {} on u-boot env device '{}'",
+                self.offset,
+                self.device.display()
+            ),
+        ))?;
+        Ok(file)
+    }
+
+    fn read(&self) -> Result<Vec<u8>, MigError> {
+        let mut buffer: Vec<u8> = vec![0; self.size as usize];
+        self.open(false)?
+            .read_exact(&mut buffer)
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "Failed to read {} bytes of u-boot env from '{}'",
+                    self.size,
+                    self.device.display()
+                ),
+            ))?;
+        Ok(buffer)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<(), MigError> {
+        let mut file = self.open(true)?;
+        file.write_all(buffer).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to write u-boot env to '{}'", self.device.display()),
+        ))?;
+        file.sync_all().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to sync u-boot env to '{}'", self.device.display()),
+        ))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct UBootEnv {
+    locations: Vec<UBootEnvLocation>,
+    // index of the active copy, always 0 for a single environment
+    active: usize,
+    flags: u8,
+    vars: Vec<(String, String)>,
+}
+
+impl<'a> UBootEnv {
+    pub fn read(locations: &[UBootEnvLocation]) -> Result<UBootEnv, MigError> {
+        trace!("read: entered with {:?}", locations);
+        match locations.len() {
+            1 => {
+                let buffer = locations[0].read()?;
+                if !check_crc(&buffer, ENV_CRC_SIZE) {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::InvState,
+                        &format!(
+                            "UBootEnv::read: CRC mismatch in u-boot env on '{}'",
+                            locations[0].device.display()
+                        ),
+                    ));
+                }
+                Ok(UBootEnv {
+                    locations: locations.to_vec(),
+                    active: 0,
+                    flags: 0,
+                    vars: parse_vars(&buffer[ENV_CRC_SIZE..]),
+                })
+            }
+            2 => {
+                let buffers = [locations[0].read()?, locations[1].read()?];
+                let valid = [
+                    check_crc(&buffers[0], ENV_CRC_SIZE + 1),
+                    check_crc(&buffers[1], ENV_CRC_SIZE + 1),
+                ];
+                let flags = [buffers[0][ENV_CRC_SIZE], buffers[1][ENV_CRC_SIZE]];
+
+                let active = match valid {
+                    [true, true] => {
+                        // flags are incremented on every write, the newer copy wins
+                        if flags[0] == 0xFF && flags[1] == 0 {
+                            1
+                        } else if flags[1] == 0xFF && flags[0] == 0 {
+                            0
+                        } else if flags[1] > flags[0] {
+                            1
+                        } else {
+                            0
+                        }
+                    }
+                    [true, false] => 0,
+                    [false, true] => 1,
+                    [false, false] => {
+                        return Err(MigError::from_remark(
+                            MigErrorKind::InvState,
+                            &format!(
+                                "UBootEnv::read: CRC mismatch in both copies of u-boot env on '{}', '{}'",
+                                locations[0].device.display(),
+                                locations[1].device.display()
+                            ),
+                        ));
+                    }
+                };
+
+                debug!(
+                    "UBootEnv::read: valid: {:?}, flags: {:?}, using copy {}",
+                    valid, flags, active
+                );
+
+                Ok(UBootEnv {
+                    locations: locations.to_vec(),
+                    active,
+                    flags: flags[active],
+                    vars: parse_vars(&buffers[active][ENV_CRC_SIZE + 1..]),
+                })
+            }
+            _ => Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                "UBootEnv::read: expected one or two u-boot env locations",
+            )),
+        }
+    }
+
+    pub fn is_redundant(&self) -> bool {
+        self.locations.len() == 2
+    }
+
+    pub fn get_locations(&'a self) -> &'a [UBootEnvLocation] {
+        self.locations.as_slice()
+    }
+
+    pub fn get_vars(&'a self) -> &'a [(String, String)] {
+        self.vars.as_slice()
+    }
+
+    pub fn get(&'a self, name: &str) -> Option<&'a str> {
+        if let Some((_, value)) = self.vars.iter().find(|(var, _)| var == name) {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        if let Some(entry) = self.vars.iter_mut().find(|(var, _)| var == name) {
+            entry.1 = String::from(value);
+        } else {
+            self.vars.push((String::from(name), String::from(value)));
+        }
+    }
+
+    // write the environment, a redundant environment is written to the inactive copy which then
+    // becomes the active one
+    pub fn write(&mut self) -> Result<(), MigError> {
+        let header_size = if self.is_redundant() {
+            ENV_CRC_SIZE + 1
+        } else {
+            ENV_CRC_SIZE
+        };
+
+        let env_size = self.locations[0].size as usize;
+        let mut buffer: Vec<u8> = vec![0; header_size];
+        for (name, value) in &self.vars {
+            buffer.extend_from_slice(name.as_bytes());
+            buffer.push(b'=');
+            buffer.extend_from_slice(value.as_bytes());
+            buffer.push(0);
+        }
+        // terminating empty string
+        buffer.push(0);
+
+        if buffer.len() > env_size {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "UBootEnv::write: environment of {} bytes exceeds the env size {}",
+                    buffer.len(),
+                    env_size
+                ),
+            ));
+        }
+        buffer.resize(env_size, 0);
+
+        let (target, flags) = if self.is_redundant() {
+            (1 - self.active, self.flags.wrapping_add(1))
+        } else {
+            (0, 0)
+        };
+
+        if self.is_redundant() {
+            buffer[ENV_CRC_SIZE] = flags;
+        }
+
+        let mut crc = Crc::new();
+        crc.update(&buffer[header_size..]);
+        buffer[0..ENV_CRC_SIZE].copy_from_slice(&crc.sum().to_le_bytes());
+
+        self.locations[target].write(&buffer)?;
+        self.active = target;
+        self.flags = flags;
+
+        info!(
+            "Wrote u-boot environment to '{}' at offset {}",
+            self.locations[target].device.display(),
+            self.locations[target].offset
+        );
+        Ok(())
+    }
+}
+
+fn check_crc(buffer: &[u8], header_size: usize) -> bool {
+    let mut crc = Crc::new();
+    crc.update(&buffer[header_size..]);
+    let mut stored: [u8; ENV_CRC_SIZE] = [0; ENV_CRC_SIZE];
+    stored.copy_from_slice(&buffer[0..ENV_CRC_SIZE]);
+    crc.sum() == u32::from_le_bytes(stored)
+}
+
+fn parse_vars(data: &[u8]) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = Vec::new();
+    for entry in data.split(|byte| *byte == 0) {
+        if entry.is_empty() {
+            // double NUL terminates the environment
+            break;
+        }
+
+        let entry = String::from_utf8_lossy(entry);
+        if let Some(pos) = entry.find('=') {
+            vars.push((
+                String::from(&entry[0..pos]),
+                String::from(&entry[pos + 1..]),
+            ));
+        } else {
+            warn!("parse_vars: ignoring invalid u-boot env entry: '{}'", entry);
+        }
+    }
+    vars
+}
+
+fn parse_number(value: &str) -> Result<u64, MigError> {
+    let res = if value.starts_with("0x") || value.starts_with("0X") {
+        u64::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse::<u64>()
+    };
+
+    Ok(res.context(MigErrCtx::from_remark(
+        MigErrorKind::InvParam,
+        &format!("parse_number: failed to parse number from '{}'", value),
+    ))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UBootEnv, UBootEnvLocation};
+    use std::fs::{read, remove_file, write};
+
+    const ENV_SIZE: usize = 0x400;
+
+    #[test]
+    fn parse_fw_env_config() {
+        let locations = UBootEnvLocation::parse_fw_env_config(
+            "# MMC device\n/dev/mmcblk1  0x260000  0x20000\n/dev/mmcblk1 -0x20000 131072 0x200 1\n",
+        )
+        .unwrap();
+        assert_eq!(locations.len(), 2);
+        assert_eq!(locations[0].offset, 0x260000);
+        assert_eq!(locations[1].offset, -0x20000);
+        assert_eq!(locations[1].size, 0x20000);
+        assert!(UBootEnvLocation::parse_fw_env_config("/dev/mmcblk0 0x260000\n").is_err());
+    }
+
+    #[test]
+    fn read_write_redundant_env() {
+        let paths = [
+            std::env::temp_dir().join("balena-migrate-test-uboot1.env"),
+            std::env::temp_dir().join("balena-migrate-test-uboot2.env"),
+        ];
+        // two invalid copies
+        for path in &paths {
+            write(path, vec![0u8; ENV_SIZE]).unwrap();
+        }
+        let locations: Vec<UBootEnvLocation> = paths
+            .iter()
+            .map(|path| UBootEnvLocation {
+                device: path.clone(),
+                offset: 0,
+                size: ENV_SIZE as u64,
+            })
+            .collect();
+        assert!(UBootEnv::read(&locations).is_err());
+
+        // single env written to the first file
+        let mut single = UBootEnv {
+            locations: locations[0..1].to_vec(),
+            active: 0,
+            flags: 0,
+            vars: Vec::new(),
+        };
+        single.set("bootcmd", "run distro_bootcmd");
+        single.set("bootdelay", "2");
+        single.write().unwrap();
+        let single = UBootEnv::read(&locations[0..1]).unwrap();
+        assert_eq!(single.get("bootcmd"), Some("run distro_bootcmd"));
+        assert_eq!(single.get_vars().len(), 2);
+
+        // redundant env, copies alternate with increasing flags
+        let mut env = UBootEnv {
+            locations: locations.clone(),
+            active: 1,
+            flags: 0xFE,
+            vars: single.get_vars().to_vec(),
+        };
+        env.write().unwrap();
+        env.set("bootcmd", "run balena_migrate_boot");
+        env.write().unwrap();
+        assert_eq!(read(&paths[1]).unwrap()[4], 0);
+        assert_eq!(read(&paths[0]).unwrap()[4], 0xFF);
+
+        let env = UBootEnv::read(&locations).unwrap();
+        assert_eq!(env.active, 1);
+        assert_eq!(env.get("bootcmd"), Some("run balena_migrate_boot"));
+        assert_eq!(env.get("bootdelay"), Some("2"));
+
+        for path in &paths {
+            let _res = remove_file(path);
+        }
+    }
+}
@@ -9,10 +9,10 @@ use std::time::Duration;
 
 use crate::{
     common::{
-        dir_exists, path_append, stage2_config::Stage2ConfigBuilder, Config, MigErrCtx, MigError,
-        MigErrorKind, MigMode,
+        device_catalog::DeviceCatalog, dir_exists, path_append, stage2_config::Stage2ConfigBuilder,
+        Config, MigErrCtx, MigError, MigErrorKind, MigMode,
     },
-    defs::{DeviceType, OSArch, STAGE2_CFG_FILE},
+    defs::{OSArch, STAGE2_CFG_FILE},
     mswin::util::to_linux_path,
 };
 
@@ -89,8 +89,16 @@ impl<'a> MSWMigrator {
         };
 
         let mut stage2_config = Stage2ConfigBuilder::default();
+        let catalog = DeviceCatalog::load(Some(config.migrate.get_work_dir()))?;
         match mig_info.os_arch {
-            OSArch::AMD64 => stage2_config.set_device_type(&DeviceType::IntelNuc),
+            OSArch::AMD64 => {
+                if let Some(device) = catalog.find_by_slug("intel-nuc") {
+                    stage2_config.set_device(device.clone());
+                } else {
+                    error!("The device type 'intel-nuc' was not found in the device catalog");
+                    return Err(MigError::displayed());
+                }
+            }
             _ => {
                 error!(
                     "The {:?} OS architecture is not currently supported on windows devices",
@@ -173,6 +181,9 @@ impl<'a> MSWMigrator {
         self.stage2_config
             .set_gzip_internal(self.config.migrate.is_gzip_internal());
 
+        self.stage2_config
+            .set_flash_verify(self.config.migrate.is_flash_verify());
+
         self.stage2_config
             .set_log_level(String::from(self.config.migrate.get_log_level()));
 
@@ -213,10 +224,7 @@ impl<'a> MSWMigrator {
         if !dir_exists(&stage2_cfg_dir)? {
             create_dir_all(&stage2_cfg_dir).context(MigErrCtx::from_remark(
                 MigErrorKind::Upstream,
-                &format!(
-                    "Fauíled to create directory '{}'",
-                    stage2_cfg_dir.display()
-                ),
+                &format!("Fauíled to create directory '{}'", stage2_cfg_dir.display()),
             ))?;
         }
 
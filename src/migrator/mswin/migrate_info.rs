@@ -137,6 +137,7 @@ impl MigrateInfo {
             file_info.expect_type(match os_info.os_arch {
                 OSArch::AMD64 => &FileType::KernelAMD64,
                 OSArch::ARMHF => &FileType::KernelARMHF,
+                OSArch::AARCH64 => &FileType::KernelAARCH64,
                 OSArch::I386 => &FileType::KernelI386,
             })?;
 